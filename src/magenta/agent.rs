@@ -0,0 +1,557 @@
+/*
+The key agent: holds decrypted pbc and ds private keys in memory and
+uses them for clients over a Unix domain socket, $MAGENTA_AGENT_SOCK or
+agent.sock in the keyring directory. The socket is created readable and
+writable by its owner only. Keys never leave the agent.
+
+Every message is a length (4) followed by that many bytes. A request is
+a type byte and its body; a response is a status byte, 0 on success or
+1 on failure with an error message as the body.
+
+type     request body                       response body
+LIST     -                                  count (2) || { key ID (8) || kind (1)
+                                              || params id length (1) || params id } * count
+SIGN     key ID || name length (1)          params id length (1) || params id
+           || scheme name || digest           || y || r || s
+DECRYPT  key ID || chaining block (16)       plaintext blocks
+           || pbc ciphertext blocks
+UNWRAP   key ID || hybrid slot              content key (16)
+
+Numbers are a length (2) and big-endian bytes. The SIGN digest is the
+rest of the body, 16 or 32 bytes as the `hash` construction gives it,
+with any signed metadata already folded in (see `metadata`). DECRYPT
+takes whole blocks and leaves the padding and size block to the
+client, so a file is decrypted a chunk at a time (see
+`pbc::dec_chunks`) and its length isn't limited by the message size. A
+hybrid slot is encoded as in the `hybrid` header, without its key ID.
+Key kinds are 1 for pbc keys and 2 for ds keys; the params id of a pbc
+key is empty. Clients are served one at a time; one that takes longer
+than `CLIENT_TIMEOUT` to send a request or take a response is dropped,
+so it can't hold the agent up for the others.
+*/
+use std::io::{IoResult, IoError, OtherIoError, EndOfFile, BufReader, MemWriter
+              , Listener, Acceptor, USER_RWX, USER_READ, USER_WRITE};
+use std::io::fs::{unlink, mkdir, rmdir, chmod, rename};
+use std::io::net::pipe::{UnixListener, UnixStream};
+use std::os;
+use std::str;
+use std::slice::bytes::copy_memory;
+use num::bigint::BigUint;
+use ds::DsParams;
+use params::by_id;
+use pbc::PBC;
+use scheme::{Scheme, Signature};
+use hybrid::Slot;
+use keyfile::{PrivateKey, PbcKey, DsKey};
+use keyring;
+use keyring::{KeyKind, PbcSecret, DsSigning, identify_private};
+use fingerprint::{KeyId, key_id};
+use serialize::hex::ToHex;
+use utils::{B16, biguint_from_bytes, biguint_to_bytes, random_bytes, invalid_input};
+
+
+static MAX_MESSAGE: uint = 1 << 26;
+// Milliseconds.
+static CLIENT_TIMEOUT: u64 = 10_000;
+
+static LIST: u8 = 1;
+static SIGN: u8 = 2;
+static DECRYPT: u8 = 3;
+static UNWRAP: u8 = 4;
+
+static OK: u8 = 0;
+static FAILED: u8 = 1;
+
+static PBC_KIND: u8 = 1;
+static DS_KIND: u8 = 2;
+
+
+pub fn socket_path() -> Path {
+    match os::getenv("MAGENTA_AGENT_SOCK") {
+        Some(path) => Path::new(path),
+        None => keyring::default_dir().join("agent.sock"),
+    }
+}
+
+
+// Binds the socket in a new owner-only directory next to `path` and
+// only moves it into place once it is owner-only too, so nobody else
+// can connect in between. Both modes are given explicitly rather than
+// through the process umask.
+fn bind_private(path: &Path) -> IoResult<UnixListener> {
+    let mut dir_name = path.as_vec().to_vec();
+    dir_name.push_all(format!(".{}", random_bytes(8).as_slice().to_hex()).as_bytes());
+    let dir = Path::new(dir_name);
+    try!(mkdir(&dir, USER_RWX));
+    let temp = dir.join("sock");
+    let res = UnixListener::bind(&temp)
+        .and_then(|l| chmod(&temp, USER_READ | USER_WRITE).map(|_| l))
+        .and_then(|l| rename(&temp, path).map(|_| l));
+    if res.is_err() {
+        let _ = unlink(&temp);
+    }
+    let _ = rmdir(&dir);
+    res
+}
+
+
+pub struct Agent {
+    keys: Vec<(KeyId, PrivateKey)>
+}
+
+
+impl Agent {
+    pub fn new() -> Agent {
+        Agent { keys: Vec::new() }
+    }
+
+    pub fn add(&mut self, key: PrivateKey) -> IoResult<KeyId> {
+        let (kind, fp) = identify_private(&key);
+        if kind != PbcSecret && kind != DsSigning {
+            return Err(invalid_input("agent: only pbc and ds keys can be added"));
+        }
+        let id = key_id(&fp);
+        if self.key(&id).is_none() {
+            self.keys.push((id, key));
+        }
+        Ok(id)
+    }
+
+    pub fn len(&self) -> uint {
+        self.keys.len()
+    }
+
+    // Serves clients until the process is stopped. A socket left behind
+    // by an agent that is gone is replaced.
+    pub fn serve(&self, path: &Path) -> IoResult<()> {
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(invalid_input("agent: an agent is already running"));
+            }
+            try!(unlink(path));
+        }
+        let listener = try!(bind_private(path));
+        let mut acceptor = try!(listener.listen());
+
+        for stream in acceptor.incoming() {
+            match stream {
+                Ok(mut client) => {
+                    let _ = self.serve_client(&mut client);
+                },
+                Err(_) => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn serve_client(&self, client: &mut UnixStream) -> IoResult<()> {
+        loop {
+            // A deadline from now for this request and its response.
+            client.set_timeout(Some(CLIENT_TIMEOUT));
+            let (tag, body) = match read_message(client) {
+                Ok(msg) => msg,
+                Err(ref e) if e.kind == EndOfFile => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            match self.handle(tag, body.as_slice()) {
+                Ok(res) => try!(write_message(client, OK, res.as_slice())),
+                Err(e) => try!(write_message(client, FAILED, e.desc.as_bytes())),
+            }
+        }
+    }
+
+    fn handle(&self, tag: u8, body: &[u8]) -> IoResult<Vec<u8>> {
+        let mut reader = BufReader::new(body);
+        let mut res = MemWriter::new();
+        match tag {
+            t if t == LIST => {
+                try!(res.write_be_u16(self.keys.len() as u16));
+                for &(ref id, ref key) in self.keys.iter() {
+                    let (kind, params_id) = match *key {
+                        PbcKey(_) => (PBC_KIND, ""),
+                        DsKey(ref params, _) => (DS_KIND, params.id()),
+                        _ => unreachable!(),
+                    };
+                    try!(res.write(id.as_slice()));
+                    try!(res.write_u8(kind));
+                    try!(res.write_u8(params_id.len() as u8));
+                    try!(res.write(params_id.as_bytes()));
+                }
+            },
+            t if t == SIGN => {
+                let id = try!(read_key_id(&mut reader));
+                let name_len = try!(reader.read_u8()) as uint;
+                let name = try!(reader.read_exact(name_len));
+                let scheme = try!(Scheme::by_name(str::from_utf8(name.as_slice())
+                                                  .unwrap_or("")));
+                let digest = try!(reader.read_to_end());
+                if digest.len() != 16 && digest.len() != 32 {
+                    return Err(invalid_input("agent: incorrect digest length"));
+                }
+                let (params, x) = match self.key(&id) {
+                    Some(&DsKey(ref params, ref x)) => (params, x),
+                    _ => return Err(invalid_input("agent: no such ds key")),
+                };
+
+                let (y, sig) = Signature::sign_prehashed(scheme, params, digest.as_slice(), x);
+                let (r, s) = sig.values();
+                try!(res.write_u8(params.id().len() as u8));
+                try!(res.write(params.id().as_bytes()));
+                try!(write_number(&mut res, &y));
+                try!(write_number(&mut res, r));
+                try!(write_number(&mut res, s));
+            },
+            t if t == DECRYPT => {
+                let id = try!(read_key_id(&mut reader));
+                let mut chain = [0u8, ..16];
+                copy_memory(&mut chain, try!(reader.read_exact(16)).as_slice());
+                let ct = try!(reader.read_to_end());
+                let k = match self.key(&id) {
+                    Some(&PbcKey(ref k)) => k,
+                    _ => return Err(invalid_input("agent: no such pbc key")),
+                };
+                let pt = try!(PBC::new(k, &chain).dec_blocks(ct.as_slice()));
+                try!(res.write(pt.as_slice()));
+            },
+            t if t == UNWRAP => {
+                let id = try!(read_key_id(&mut reader));
+                let slot = try!(Slot::read_body(&mut reader, Some(id)));
+                let cek = match self.key(&id) {
+                    Some(&DsKey(ref params, ref x)) if *params == slot.params => {
+                        slot.unwrap(x)
+                    },
+                    _ => return Err(invalid_input("agent: no such ds key")),
+                };
+                match cek {
+                    Some(k) => try!(res.write(k.as_slice())),
+                    None => return Err(invalid_input("agent: the slot is not for this key")),
+                }
+            },
+            _ => return Err(invalid_input("agent: unknown request")),
+        }
+        Ok(res.unwrap())
+    }
+
+    fn key<'a>(&'a self, id: &KeyId) -> Option<&'a PrivateKey> {
+        self.keys.iter().find(|&&(ref k, _)| k == id).map(|&(_, ref key)| key)
+    }
+}
+
+
+pub struct AgentClient {
+    stream: UnixStream
+}
+
+
+impl AgentClient {
+    pub fn connect(path: &Path) -> IoResult<AgentClient> {
+        let stream = try!(UnixStream::connect(path));
+        Ok(AgentClient { stream: stream })
+    }
+
+    // The agent at the default socket, if one is running.
+    pub fn running() -> Option<AgentClient> {
+        let path = socket_path();
+        if !path.exists() {
+            return None;
+        }
+        AgentClient::connect(&path).ok()
+    }
+
+    pub fn list(&mut self) -> IoResult<Vec<(KeyId, KeyKind)>> {
+        let keys = try!(self.list_params());
+        Ok(keys.into_iter().map(|(id, kind, _)| (id, kind)).collect())
+    }
+
+    // The parameter set of a ds key, needed to sign metadata that names it.
+    pub fn params_of(&mut self, id: &KeyId) -> IoResult<DsParams> {
+        let keys = try!(self.list_params());
+        match keys.into_iter().find(|&(ref k, ref kind, _)| k == id && *kind == DsSigning) {
+            Some((_, _, params_id)) => match by_id(params_id.as_slice()) {
+                Some(p) => Ok(p),
+                None => Err(invalid_input("agent: unknown parameter set")),
+            },
+            None => Err(invalid_input("agent: no such ds key")),
+        }
+    }
+
+    pub fn has_key(&mut self, id: &KeyId) -> bool {
+        match self.list() {
+            Ok(keys) => keys.iter().any(|&(ref k, _)| k == id),
+            Err(_) => false,
+        }
+    }
+
+    pub fn sign(&mut self, id: &KeyId, scheme: Scheme
+                , digest: &[u8]) -> IoResult<(DsParams, BigUint, Signature)> {
+        let mut req = MemWriter::new();
+        try!(req.write(id.as_slice()));
+        try!(req.write_u8(scheme.name().len() as u8));
+        try!(req.write(scheme.name().as_bytes()));
+        try!(req.write(digest));
+
+        let res = try!(self.call(SIGN, req.get_ref()));
+        let mut reader = BufReader::new(res.as_slice());
+        let id_len = try!(reader.read_u8()) as uint;
+        let params_id = try!(reader.read_exact(id_len));
+        let params = match str::from_utf8(params_id.as_slice()).and_then(by_id) {
+            Some(p) => p,
+            None => return Err(invalid_input("agent: unknown parameter set")),
+        };
+        let y = try!(read_number(&mut reader));
+        let r = try!(read_number(&mut reader));
+        let s = try!(read_number(&mut reader));
+        Ok((params, y, Signature::new(scheme, r, s)))
+    }
+
+    // Decrypts whole pbc blocks chained from `chain`; see `pbc::dec_chunks`.
+    pub fn decrypt(&mut self, id: &KeyId, chain: &B16, ct: &[u8]) -> IoResult<Vec<u8>> {
+        let mut req = id.to_vec();
+        req.push_all(chain);
+        req.push_all(ct);
+        self.call(DECRYPT, req.as_slice())
+    }
+
+    pub fn unwrap(&mut self, id: &KeyId, slot: &Slot) -> IoResult<B16> {
+        let mut req = MemWriter::new();
+        try!(req.write(id.as_slice()));
+        try!(slot.write_body(&mut req));
+
+        let res = try!(self.call(UNWRAP, req.get_ref()));
+        PBC::key_from_bytes(res.as_slice())
+    }
+
+    fn list_params(&mut self) -> IoResult<Vec<(KeyId, KeyKind, String)>> {
+        let res = try!(self.call(LIST, &[]));
+        let mut reader = BufReader::new(res.as_slice());
+        let count = try!(reader.read_be_u16()) as uint;
+        let mut keys = Vec::with_capacity(count);
+        for _ in range(0, count) {
+            let id = try!(read_key_id(&mut reader));
+            let kind = match try!(reader.read_u8()) {
+                k if k == PBC_KIND => PbcSecret,
+                k if k == DS_KIND => DsSigning,
+                _ => return Err(invalid_input("agent: unknown key kind")),
+            };
+            let id_len = try!(reader.read_u8()) as uint;
+            let params_id = try!(reader.read_exact(id_len));
+            let params_id = match str::from_utf8(params_id.as_slice()) {
+                Some(s) => s.to_string(),
+                None => return Err(invalid_input("agent: unknown parameter set")),
+            };
+            keys.push((id, kind, params_id));
+        }
+        Ok(keys)
+    }
+
+    fn call(&mut self, tag: u8, body: &[u8]) -> IoResult<Vec<u8>> {
+        try!(write_message(&mut self.stream, tag, body));
+        let (status, res) = try!(read_message(&mut self.stream));
+        if status != OK {
+            return Err(IoError {
+                kind: OtherIoError,
+                desc: "agent: request failed",
+                detail: str::from_utf8(res.as_slice()).map(|s| s.to_string()),
+            });
+        }
+        Ok(res)
+    }
+}
+
+
+fn write_message<W: Writer>(writer: &mut W, tag: u8, body: &[u8]) -> IoResult<()> {
+    if body.len() + 1 > MAX_MESSAGE {
+        return Err(invalid_input("agent: message too long"));
+    }
+    try!(writer.write_be_u32((body.len() + 1) as u32));
+    try!(writer.write_u8(tag));
+    try!(writer.write(body));
+    writer.flush()
+}
+
+
+fn read_message<R: Reader>(reader: &mut R) -> IoResult<(u8, Vec<u8>)> {
+    let len = try!(reader.read_be_u32()) as uint;
+    if len == 0 || len > MAX_MESSAGE {
+        return Err(invalid_input("agent: bad message length"));
+    }
+    let tag = try!(reader.read_u8());
+    let body = try!(reader.read_exact(len - 1));
+    Ok((tag, body))
+}
+
+
+fn read_key_id<R: Reader>(reader: &mut R) -> IoResult<KeyId> {
+    let mut id = [0u8, ..8];
+    copy_memory(&mut id, try!(reader.read_exact(8)).as_slice());
+    Ok(id)
+}
+
+
+fn write_number<W: Writer>(writer: &mut W, n: &BigUint) -> IoResult<()> {
+    let bytes = biguint_to_bytes(n);
+    try!(writer.write_be_u16(bytes.len() as u16));
+    writer.write(bytes.as_slice())
+}
+
+
+fn read_number<R: Reader>(reader: &mut R) -> IoResult<BigUint> {
+    let len = try!(reader.read_be_u16()) as uint;
+    let bytes = try!(reader.read_exact(len));
+    Ok(biguint_from_bytes(bytes.as_slice()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, MemWriter};
+    use ds::DigSig;
+    use pbc::PBC;
+    use params;
+    use scheme::{Signature, Gost, Schnorr};
+    use hybrid;
+    use keyfile::{PbcKey, DsKey};
+    use fingerprint::ds_key_id;
+    use super::{Agent, LIST, SIGN, DECRYPT, UNWRAP, PBC_KIND, DS_KIND, MAX_MESSAGE
+                , read_message, write_message, read_key_id, read_number};
+
+    static MSG: &'static [u8] = b"two blocks of sixteen bytes each";
+
+    #[test]
+    fn messages_round_trip() {
+        let mut writer = MemWriter::new();
+        write_message(&mut writer, SIGN, b"body").unwrap();
+        write_message(&mut writer, LIST, b"").unwrap();
+        let bytes = writer.unwrap();
+        assert_eq!(bytes.slice_to(5), [0u8, 0, 0, 5, SIGN].as_slice());
+
+        let mut reader = BufReader::new(bytes.as_slice());
+        assert_eq!(read_message(&mut reader).unwrap(), (SIGN, b"body".to_vec()));
+        assert_eq!(read_message(&mut reader).unwrap(), (LIST, Vec::new()));
+        assert!(read_message(&mut reader).is_err());
+
+        // Empty, oversized and truncated messages.
+        let bad: [&[u8], ..3] = [&[0, 0, 0, 0], &[0xff, 0xff, 0xff, 0xff, 1]
+                                 , &[0, 0, 0, 5, SIGN, b'b']];
+        for msg in bad.iter() {
+            assert!(read_message(&mut BufReader::new(*msg)).is_err(), "{}", msg);
+        }
+        let long = Vec::from_elem(MAX_MESSAGE, 0u8);
+        assert!(write_message(&mut MemWriter::new(), SIGN, long.as_slice()).is_err());
+    }
+
+    #[test]
+    fn requests_are_handled() {
+        let params = params::default();
+        let x = DigSig::gen_key(&params);
+        let y = DigSig::public_key(&params, &x);
+        let k = PBC::gen_key();
+        let mut agent = Agent::new();
+        assert_eq!(agent.handle(LIST, b"").unwrap(), vec![0u8, 0]);
+        let ds_id = agent.add(DsKey(params.clone(), x.clone())).unwrap();
+        let pbc_id = agent.add(PbcKey(k)).unwrap();
+        assert_eq!(ds_id, ds_key_id(&params, &y));
+        assert_eq!(agent.add(PbcKey(k)).unwrap(), pbc_id);
+        assert_eq!(agent.len(), 2);
+
+        let res = agent.handle(LIST, b"").unwrap();
+        let mut expected = vec![0u8, 2];
+        expected.push_all(ds_id.as_slice());
+        expected.push_all(&[DS_KIND, params.id().len() as u8]);
+        expected.push_all(params.id().as_bytes());
+        expected.push_all(pbc_id.as_slice());
+        expected.push_all(&[PBC_KIND, 0]);
+        assert_eq!(res, expected);
+
+        for &(scheme, digest_len) in [(Gost, 16u), (Schnorr, 32)].iter() {
+            let digest = Vec::from_elem(digest_len, 0x5au8);
+            let mut body = ds_id.to_vec();
+            body.push(scheme.name().len() as u8);
+            body.push_all(scheme.name().as_bytes());
+            body.push_all(digest.as_slice());
+            let res = agent.handle(SIGN, body.as_slice()).unwrap();
+
+            let mut reader = BufReader::new(res.as_slice());
+            let id_len = reader.read_u8().unwrap() as uint;
+            assert_eq!(reader.read_exact(id_len).unwrap().as_slice(), params.id().as_bytes());
+            assert_eq!(read_number(&mut reader).unwrap(), y);
+            let r = read_number(&mut reader).unwrap();
+            let s = read_number(&mut reader).unwrap();
+            assert!(reader.eof());
+            let sig = Signature::new(scheme, r, s);
+            assert!(sig.verify_prehashed(&params, digest.as_slice(), &y), "{}", scheme.name());
+        }
+
+        // Each block is chained from the plaintext before it.
+        let ct = PBC::new(&k, &[0u8, ..16]).enc_bytes(MSG);
+        let mut body = pbc_id.to_vec();
+        body.push_all(&[0u8, ..16]);
+        body.push_all(ct.slice_to(32));
+        assert_eq!(agent.handle(DECRYPT, body.as_slice()).unwrap().as_slice(), MSG);
+        let mut body = pbc_id.to_vec();
+        body.push_all(MSG.slice_to(16));
+        body.push_all(ct.slice(16, 32));
+        assert_eq!(agent.handle(DECRYPT, body.as_slice()).unwrap().as_slice()
+                   , MSG.slice_from(16));
+
+        let other_y = DigSig::public_key(&params, &DigSig::gen_key(&params));
+        let recipients = [(params.clone(), other_y), (params.clone(), y.clone())];
+        let ct = hybrid::enc_bytes(&recipients, MSG).unwrap();
+        let slots = hybrid::read_header(&mut BufReader::new(ct.as_slice())).unwrap();
+        let mut unwrapped = Vec::new();
+        for slot in slots.iter() {
+            let mut body = MemWriter::new();
+            body.write(ds_id.as_slice()).unwrap();
+            slot.write_body(&mut body).unwrap();
+            unwrapped.push(agent.handle(UNWRAP, body.get_ref()).ok());
+        }
+        // Only the second slot is for the agent's key.
+        assert!(unwrapped[0].is_none());
+        let cek = PBC::key_from_bytes(unwrapped[1].as_ref().unwrap().as_slice()).unwrap();
+        let header_len = hybrid::header_bytes(slots.as_slice()).unwrap().len();
+        let mut content = BufReader::new(ct.slice_from(header_len));
+        let mut pt = MemWriter::new();
+        hybrid::dec_content(&cek, slots.as_slice(), &mut content, &mut pt).unwrap();
+        assert_eq!(pt.get_ref(), MSG);
+    }
+
+    #[test]
+    fn malformed_requests_fail() {
+        let params = params::default();
+        let mut agent = Agent::new();
+        let ds_id = agent.add(DsKey(params.clone(), DigSig::gen_key(&params))).unwrap();
+        let pbc_id = agent.add(PbcKey(PBC::gen_key())).unwrap();
+
+        let sign = |id: &[u8], name: &[u8], digest_len: uint| {
+            let mut body = id.to_vec();
+            body.push(name.len() as u8);
+            body.push_all(name);
+            body.push_all(Vec::from_elem(digest_len, 1u8).as_slice());
+            body
+        };
+        let bad_signs = [sign(ds_id.slice_to(4), b"", 0)
+                         , sign(ds_id.as_slice(), b"gost", 15)
+                         , sign(ds_id.as_slice(), b"gost", 33)
+                         , sign(ds_id.as_slice(), b"rsa", 16)
+                         , sign(pbc_id.as_slice(), b"gost", 16)];
+        for body in bad_signs.iter() {
+            assert!(agent.handle(SIGN, body.as_slice()).is_err(), "{}", body);
+        }
+
+        let mut decrypt = pbc_id.to_vec();
+        decrypt.push_all(&[0u8, ..16]);
+        decrypt.push_all(&[0u8, ..15]);
+        assert!(agent.handle(DECRYPT, decrypt.as_slice()).is_err());
+        assert!(agent.handle(DECRYPT, decrypt.slice_to(20)).is_err());
+        let mut decrypt = ds_id.to_vec();
+        decrypt.push_all(&[0u8, ..32]);
+        assert!(agent.handle(DECRYPT, decrypt.as_slice()).is_err());
+
+        let mut unwrap = ds_id.to_vec();
+        unwrap.push_all(b"not a slot");
+        assert!(agent.handle(UNWRAP, unwrap.as_slice()).is_err());
+        assert!(agent.handle(UNWRAP, ds_id.slice_to(7)).is_err());
+        assert!(agent.handle(0, b"").is_err());
+        assert!(read_key_id(&mut BufReader::new(b"short")).is_err());
+    }
+}
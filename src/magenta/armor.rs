@@ -0,0 +1,240 @@
+/*
+ASCII armor in the style of OpenPGP (RFC 4880, section 6.2):
+
+-----BEGIN MAGENTA SIGNATURE-----
+Header: value
+
+<base64 body, 64 columns>
+=<base64 CRC-24 of the body>
+-----END MAGENTA SIGNATURE-----
+*/
+use std::io::IoResult;
+use serialize::base64::{Config, Standard, ToBase64, FromBase64};
+use utils::{nth_byte, invalid_input};
+
+
+pub static MESSAGE: &'static str = "MAGENTA MESSAGE";
+pub static SYMMETRIC_KEY: &'static str = "MAGENTA SYMMETRIC KEY";
+pub static SIGNING_KEY: &'static str = "MAGENTA SIGNING KEY";
+pub static VERIFICATION_KEY: &'static str = "MAGENTA VERIFICATION KEY";
+pub static SIGNATURE: &'static str = "MAGENTA SIGNATURE";
+pub static ENCRYPTED_KEY: &'static str = "MAGENTA ENCRYPTED KEY";
+pub static RSA_PRIVATE_KEY: &'static str = "MAGENTA RSA PRIVATE KEY";
+pub static RSA_PUBLIC_KEY: &'static str = "MAGENTA RSA PUBLIC KEY";
+pub static WRAPPED_KEY: &'static str = "MAGENTA WRAPPED KEY";
+pub static CERTIFICATE: &'static str = "MAGENTA CERTIFICATE";
+
+static BEGIN: &'static str = "-----BEGIN ";
+static END: &'static str = "-----END ";
+static DASHES: &'static str = "-----";
+static LINE_LENGTH: uint = 64;
+static CRC24_INIT: u32 = 0xB704CE;
+static CRC24_POLY: u32 = 0x1864CFB;
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct Armored {
+    pub label: String,
+    pub headers: Vec<(String, String)>,
+    pub data: Vec<u8>
+}
+
+
+impl Armored {
+    pub fn new(label: &str, data: Vec<u8>) -> Armored {
+        Armored {
+            label: label.to_string(),
+            headers: Vec::new(),
+            data: data
+        }
+    }
+
+    pub fn add_header(&mut self, key: &str, value: &str) {
+        self.headers.push((key.to_string(), value.to_string()));
+    }
+
+    pub fn header<'a>(&'a self, key: &str) -> Option<&'a str> {
+        self.headers.iter()
+            .find(|&&(ref k, _)| k.as_slice() == key)
+            .map(|&(_, ref v)| v.as_slice())
+    }
+
+    pub fn encode(&self) -> String {
+        let config = Config {
+            char_set: Standard,
+            pad: true,
+            line_length: None
+        };
+        let body = self.data.as_slice().to_base64(config);
+        let crc = crc24(self.data.as_slice());
+        let crc_bytes = [nth_byte(crc as uint, 2), nth_byte(crc as uint, 1)
+                         , nth_byte(crc as uint, 0)];
+
+        let mut res = String::new();
+        res.push_str(format!("{}{}{}\n", BEGIN, self.label, DASHES).as_slice());
+        for &(ref k, ref v) in self.headers.iter() {
+            res.push_str(format!("{}: {}\n", k, v).as_slice());
+        }
+        res.push_str("\n");
+        for line in body.as_bytes().chunks(LINE_LENGTH) {
+            res.push_str(String::from_utf8(line.to_vec()).unwrap().as_slice());
+            res.push_str("\n");
+        }
+        res.push_str(format!("={}\n", crc_bytes.to_base64(config)).as_slice());
+        res.push_str(format!("{}{}{}\n", END, self.label, DASHES).as_slice());
+        res
+    }
+
+    pub fn decode(text: &str) -> IoResult<Armored> {
+        let mut lines = text.lines().map(|l| l.trim_right()).skip_while(|l| l.is_empty());
+
+        let label = match lines.next() {
+            Some(l) if l.starts_with(BEGIN) && l.ends_with(DASHES)
+                && l.len() > BEGIN.len() + DASHES.len() => {
+                l.slice(BEGIN.len(), l.len() - DASHES.len()).to_string()
+            },
+            _ => return Err(invalid_input("armor: missing BEGIN line")),
+        };
+
+        let mut headers = Vec::new();
+        loop {
+            match lines.next() {
+                Some("") => break,
+                Some(l) => match l.find_str(": ") {
+                    Some(i) => headers.push((l.slice_to(i).to_string()
+                                             , l.slice_from(i + 2).to_string())),
+                    None => return Err(invalid_input("armor: malformed header")),
+                },
+                None => return Err(invalid_input("armor: truncated")),
+            }
+        }
+
+        let mut body = String::new();
+        let mut crc_line = None;
+        let end_line = format!("{}{}{}", END, label, DASHES);
+        loop {
+            match lines.next() {
+                Some(l) if l == end_line.as_slice() => break,
+                Some(l) if l.starts_with("=") && crc_line.is_none() => {
+                    crc_line = Some(l.slice_from(1).to_string());
+                },
+                Some(l) if crc_line.is_none() => body.push_str(l),
+                Some(_) => return Err(invalid_input("armor: data after checksum")),
+                None => return Err(invalid_input("armor: missing END line")),
+            }
+        }
+        if lines.any(|l| !l.is_empty()) {
+            return Err(invalid_input("armor: data after END line"));
+        }
+
+        let data = match body.as_slice().from_base64() {
+            Ok(d) => d,
+            Err(_) => return Err(invalid_input("armor: invalid base64")),
+        };
+        let crc = match crc_line {
+            Some(c) => match c.as_slice().from_base64() {
+                Ok(ref b) if b.len() == 3 => {
+                    (b[0] as u32 << 16) | (b[1] as u32 << 8) | b[2] as u32
+                },
+                _ => return Err(invalid_input("armor: invalid checksum")),
+            },
+            None => return Err(invalid_input("armor: missing checksum")),
+        };
+        if crc != crc24(data.as_slice()) {
+            return Err(invalid_input("armor: checksum mismatch"));
+        }
+
+        Ok(Armored {
+            label: label,
+            headers: headers,
+            data: data
+        })
+    }
+}
+
+
+pub fn armor(label: &str, data: &[u8]) -> String {
+    Armored::new(label, data.to_vec()).encode()
+}
+
+
+pub fn dearmor(bytes: &[u8], label: &str) -> IoResult<Vec<u8>> {
+    let armored = try!(decode_bytes(bytes));
+    if armored.label.as_slice() != label {
+        return Err(invalid_input("armor: unexpected block type"));
+    }
+    Ok(armored.data)
+}
+
+
+pub fn decode_bytes(bytes: &[u8]) -> IoResult<Armored> {
+    match String::from_utf8(bytes.to_vec()) {
+        Ok(text) => Armored::decode(text.as_slice()),
+        Err(_) => Err(invalid_input("armor: not a text file")),
+    }
+}
+
+
+pub fn is_armored(bytes: &[u8]) -> bool {
+    let start = bytes.iter()
+        .position(|b| !(*b as char).is_whitespace())
+        .unwrap_or(bytes.len());
+    bytes.slice_from(start).starts_with(BEGIN.as_bytes())
+}
+
+
+pub fn crc24(data: &[u8]) -> u32 {
+    let mut crc = CRC24_INIT;
+    for b in data.iter() {
+        crc ^= *b as u32 << 16;
+        for _ in range(0u, 8) {
+            crc <<= 1;
+            if crc & 0x1000000 != 0 {
+                crc ^= CRC24_POLY;
+            }
+        }
+    }
+    crc & 0xFFFFFF
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Armored, crc24, armor, dearmor, MESSAGE, SIGNATURE};
+
+    // The CRC-24 check value from RFC 4880's reference code.
+    #[test]
+    fn crc24_vectors() {
+        assert_eq!(crc24(b""), 0xB704CE);
+        assert_eq!(crc24(b"123456789"), 0x21CF02);
+    }
+
+    #[test]
+    fn round_trip() {
+        let text = armor(MESSAGE, b"123456789");
+        assert!(text.as_slice().contains("\nMTIzNDU2Nzg5\n=Ic8C\n"));
+        assert_eq!(dearmor(text.as_bytes(), MESSAGE).unwrap().as_slice(), b"123456789");
+        assert!(dearmor(text.as_bytes(), SIGNATURE).is_err());
+
+        let data = Vec::from_fn(100, |i| i as u8);
+        let mut armored = Armored::new(SIGNATURE, data);
+        armored.add_header("Key-Id", "0123abcd");
+        let decoded = Armored::decode(armored.encode().as_slice()).unwrap();
+        assert_eq!(decoded, armored);
+        assert_eq!(decoded.header("Key-Id"), Some("0123abcd"));
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let text = armor(MESSAGE, b"123456789");
+        let tampered = [text.as_slice().replace("MTIzNDU2Nzg5", "MTIzNDU2Nzg4")
+                        , text.as_slice().replace("=Ic8C", "=Ic8D")
+                        , text.as_slice().replace("=Ic8C\n", "")
+                        , text.as_slice().replace("END MAGENTA MESSAGE", "END MAGENTA KEY")
+                        , format!("{}trailing\n", text)];
+        for t in tampered.iter() {
+            assert!(t.as_slice() != text.as_slice());
+            assert!(dearmor(t.as_bytes(), MESSAGE).is_err(), "{}", t);
+        }
+    }
+}
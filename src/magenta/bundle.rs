@@ -0,0 +1,340 @@
+/*
+Signature bundles: several independent signatures over the same file,
+kept in one file as armored signature blocks one after another (see
+`scheme`). Signatures are only ever appended, so their positions never
+change. Each signature hashes the file with the `hash` construction
+named in its metadata, so one bundle may mix them.
+
+A counter-signature signs another signature in the bundle instead of
+the file. Its block has a "Counter-Signs: <n>" header, n being the
+position of the signature it signs, counted from 1, and the message it
+signs is
+
+    "rust-magenta counter-signature" || 0 || text form of that signature
+
+A policy asks for valid signatures over the file by at least M distinct
+keys out of a set of N trusted keys. Signatures by other keys are
+checked and reported but never count towards it, since anyone can add
+a signature by a key of their own.
+*/
+use std::io::{File, IoResult};
+use ds::DsParams;
+use num::bigint::BigUint;
+use armor::Armored;
+use scheme::SignatureFile;
+use hash::Construction;
+use fingerprint::KeyId;
+use utils::{invalid_input, replace_file};
+
+
+static COUNTER_TAG: &'static [u8] = b"rust-magenta counter-signature";
+
+pub static COUNTER_HEADER: &'static str = "Counter-Signs";
+
+static BEGIN: &'static str = "-----BEGIN ";
+static END: &'static str = "-----END ";
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct Entry {
+    pub sig: SignatureFile,
+    // Index of the counter-signed entry.
+    pub counter_signs: Option<uint>
+}
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum Status {
+    Valid,
+    Expired,
+    Invalid,
+    UnknownKey
+}
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct Bundle {
+    pub entries: Vec<Entry>
+}
+
+
+impl Bundle {
+    pub fn new() -> Bundle {
+        Bundle { entries: Vec::new() }
+    }
+
+    pub fn push(&mut self, sig: SignatureFile) {
+        self.entries.push(Entry { sig: sig, counter_signs: None });
+    }
+
+    pub fn push_counter(&mut self, sig: SignatureFile, target: uint) -> IoResult<()> {
+        if target >= self.entries.len() {
+            return Err(invalid_input("bundle: no such signature"));
+        }
+        self.entries.push(Entry { sig: sig, counter_signs: Some(target) });
+        Ok(())
+    }
+
+    // What a counter-signature of entry `target` signs.
+    pub fn counter_message(&self, target: uint) -> Vec<u8> {
+        let mut msg = COUNTER_TAG.to_vec();
+        msg.push(0);
+        msg.push_all(self.entries[target].sig.to_text().as_bytes());
+        msg
+    }
+
+    // The constructions the signatures over the file hash it with.
+    pub fn hashes(&self) -> Vec<Construction> {
+        let mut hashes = Vec::new();
+        for entry in self.entries.iter().filter(|e| e.counter_signs.is_none()) {
+            let h = entry.sig.hash();
+            if !hashes.contains(&h) {
+                hashes.push(h);
+            }
+        }
+        hashes
+    }
+
+    // Checks every entry at time `at`, given the digests of the signed
+    // file with each of `hashes()` and a way to find keys by ID.
+    pub fn verify(&self, digests: &[(Construction, Vec<u8>)], at: u64
+                  , key: |&KeyId| -> Option<(DsParams, BigUint)>) -> Vec<Status> {
+        let mut statuses = Vec::with_capacity(self.entries.len());
+        for entry in self.entries.iter() {
+            let found = match entry.sig.key_id {
+                Some(ref id) => key(id),
+                None => None,
+            };
+            let y = match found {
+                Some((ref params, ref y)) if *params == entry.sig.params => y.clone(),
+                _ => {
+                    statuses.push(UnknownKey);
+                    continue;
+                },
+            };
+            let is_valid = match entry.counter_signs {
+                Some(target) => {
+                    let msg = self.counter_message(target);
+                    entry.sig.verify_bytes(msg.as_slice(), &y)
+                },
+                None => match digests.iter().find(|&&(h, _)| h == entry.sig.hash()) {
+                    Some(&(_, ref digest)) => entry.sig.verify_digest(digest.as_slice(), &y),
+                    None => false,
+                },
+            };
+            let expired = entry.sig.metadata.as_ref().map_or(false, |m| m.is_expired(at));
+            statuses.push(match (is_valid, expired) {
+                (false, _) => Invalid,
+                (true, true) => Expired,
+                (true, false) => Valid,
+            });
+        }
+        statuses
+    }
+
+    // Distinct keys with a valid signature over the file itself, out of
+    // `signers` unless it is empty. Counter-signatures don't count.
+    pub fn valid_signers(&self, statuses: &[Status], signers: &[KeyId]) -> Vec<KeyId> {
+        let mut res: Vec<KeyId> = Vec::new();
+        for (entry, status) in self.entries.iter().zip(statuses.iter()) {
+            if *status != Valid || entry.counter_signs.is_some() {
+                continue;
+            }
+            let id = entry.sig.key_id.unwrap();
+            if signers.contains(&id) && !res.contains(&id) {
+                res.push(id);
+            }
+        }
+        res
+    }
+
+    pub fn to_string(&self) -> String {
+        let mut res = String::new();
+        for entry in self.entries.iter() {
+            let mut armored = entry.sig.to_armored();
+            match entry.counter_signs {
+                Some(target) => {
+                    armored.add_header(COUNTER_HEADER, (target + 1).to_string().as_slice())
+                },
+                None => {},
+            }
+            res.push_str(armored.encode().as_slice());
+        }
+        res
+    }
+
+    pub fn from_str(content: &str) -> IoResult<Bundle> {
+        let mut bundle = Bundle::new();
+        let mut block = String::new();
+        for line in content.lines() {
+            let line = line.trim_right();
+            if line.starts_with(BEGIN) {
+                block = String::new();
+            }
+            block.push_str(line);
+            block.push('\n');
+            if !line.starts_with(END) {
+                continue;
+            }
+
+            let armored = try!(Armored::decode(block.as_slice()));
+            let sig = try!(SignatureFile::from_armored(&armored));
+            if sig.key_id.is_none() {
+                return Err(invalid_input("bundle: signature without a key ID"));
+            }
+            match armored.header(COUNTER_HEADER) {
+                Some(n) => match from_str::<uint>(n.trim()) {
+                    Some(n) if n > 0 => try!(bundle.push_counter(sig, n - 1)),
+                    _ => return Err(invalid_input("bundle: incorrect Counter-Signs header")),
+                },
+                None => bundle.push(sig),
+            }
+        }
+        Ok(bundle)
+    }
+
+    // A bundle that doesn't exist yet is empty.
+    pub fn load(path: &Path) -> IoResult<Bundle> {
+        if !path.exists() {
+            return Ok(Bundle::new());
+        }
+        let content = try!(File::open(path).and_then(|mut f| f.read_to_string()));
+        Bundle::from_str(content.as_slice())
+    }
+
+    // Written to a temporary file and renamed, so a failed write never
+    // loses the signatures already there.
+    pub fn save(&self, path: &Path) -> IoResult<()> {
+        replace_file(path, self.to_string().as_bytes())
+    }
+}
+
+
+// At least `required` of `signers` must have signed the file.
+pub struct Policy {
+    pub required: uint,
+    pub signers: Vec<KeyId>
+}
+
+
+impl Policy {
+    // A policy no set of signatures could meet is refused.
+    pub fn new(required: uint, signers: Vec<KeyId>) -> IoResult<Policy> {
+        if required == 0 || required > signers.len() {
+            return Err(invalid_input("bundle: more signatures required than trusted keys"));
+        }
+        Ok(Policy { required: required, signers: signers })
+    }
+
+    pub fn is_met(&self, bundle: &Bundle, statuses: &[Status]) -> bool {
+        bundle.valid_signers(statuses, self.signers.as_slice()).len() >= self.required
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, TempDir};
+    use num::bigint::BigUint;
+    use ds::{DigSig, DsParams};
+    use fingerprint;
+    use fingerprint::KeyId;
+    use hash;
+    use hash::hash_bytes;
+    use params;
+    use scheme::{Signature, SignatureFile, Gost};
+    use super::{Bundle, Policy, Valid, Invalid, UnknownKey};
+
+    static FILE: &'static [u8] = b"release 0.2";
+
+    struct Key {
+        params: DsParams,
+        x: BigUint,
+        y: BigUint,
+        id: KeyId
+    }
+
+    fn gen_key() -> Key {
+        let params = params::default();
+        let x = DigSig::gen_key(&params);
+        let y = DigSig::public_key(&params, &x);
+        let id = fingerprint::ds_key_id(&params, &y);
+        Key { params: params, x: x, y: y, id: id }
+    }
+
+    fn sign(key: &Key, msg: &[u8]) -> SignatureFile {
+        let (_, sig) = Signature::sign_reader(Gost, &key.params, &mut BufReader::new(msg)
+                                              , &key.x);
+        SignatureFile::new(key.params.clone(), sig, key.id, None)
+    }
+
+    fn verify(bundle: &Bundle, keys: &[&Key]) -> Vec<super::Status> {
+        let digests = [(hash::Original, hash_bytes(hash::Original, FILE))];
+        bundle.verify(digests.as_slice(), 0, |id| {
+            keys.iter().find(|k| k.id == *id).map(|k| (k.params.clone(), k.y.clone()))
+        })
+    }
+
+    #[test]
+    fn untrusted_signers_dont_count() {
+        let (a, b, c) = (gen_key(), gen_key(), gen_key());
+        let policy = Policy::new(2, vec![a.id, b.id]).unwrap();
+        let mut bundle = Bundle::new();
+        bundle.push(sign(&a, FILE));
+        bundle.push(sign(&a, FILE));
+        // c's key is known, so its signature is checked, but c isn't trusted.
+        bundle.push(sign(&c, FILE));
+        let target = bundle.counter_message(0);
+        bundle.push_counter(sign(&b, target.as_slice()), 0).unwrap();
+
+        let statuses = verify(&bundle, [&a, &b, &c]);
+        assert_eq!(statuses, vec![Valid, Valid, Valid, Valid]);
+        assert_eq!(bundle.valid_signers(statuses.as_slice(), policy.signers.as_slice())
+                   , vec![a.id]);
+        assert!(!policy.is_met(&bundle, statuses.as_slice()));
+
+        bundle.push(sign(&b, FILE));
+        let statuses = verify(&bundle, [&a, &b, &c]);
+        assert!(policy.is_met(&bundle, statuses.as_slice()));
+    }
+
+    #[test]
+    fn required_count_is_bounded() {
+        let (a, b) = (gen_key(), gen_key());
+        assert!(Policy::new(0, vec![a.id, b.id]).is_err());
+        assert!(Policy::new(3, vec![a.id, b.id]).is_err());
+        assert!(Policy::new(1, Vec::new()).is_err());
+        assert!(Policy::new(2, vec![a.id, b.id]).is_ok());
+    }
+
+    #[test]
+    fn counter_signatures_cover_their_target() {
+        let (a, b) = (gen_key(), gen_key());
+        let mut bundle = Bundle::new();
+        bundle.push(sign(&a, FILE));
+        let target = bundle.counter_message(0);
+        bundle.push_counter(sign(&b, target.as_slice()), 0).unwrap();
+        assert_eq!(verify(&bundle, [&a, &b]), vec![Valid, Valid]);
+        assert_eq!(verify(&bundle, [&a]), vec![Valid, UnknownKey]);
+
+        // A different but equally valid signature by the same key.
+        bundle.entries.get_mut(0).sig = sign(&a, FILE);
+        assert_eq!(verify(&bundle, [&a, &b]), vec![Valid, Invalid]);
+        assert!(bundle.push_counter(sign(&b, target.as_slice()), 5).is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let (a, b) = (gen_key(), gen_key());
+        let mut bundle = Bundle::new();
+        bundle.push(sign(&a, FILE));
+        let target = bundle.counter_message(0);
+        bundle.push_counter(sign(&b, target.as_slice()), 0).unwrap();
+
+        let dir = TempDir::new("magenta-bundle").unwrap();
+        let path = dir.path().join("file.dsb");
+        assert_eq!(Bundle::load(&path).unwrap(), Bundle::new());
+        bundle.save(&path).unwrap();
+        assert_eq!(Bundle::load(&path).unwrap(), bundle);
+    }
+}
@@ -0,0 +1,424 @@
+/*
+Key endorsements: a DS key (the issuer) signs another DS public key
+(the subject) together with a name, a validity period and what the
+subject key may be used for.
+
+Certificate ::= SEQUENCE {
+    SEQUENCE {
+        OCTET STRING issuer key ID,
+        SEQUENCE { parameters, y }      subject key (`DigSig::public_key_to_der`)
+        OCTET STRING subject name (UTF-8),
+        INTEGER not before, INTEGER not after,
+        INTEGER usage,
+        OCTET STRING hash OPTIONAL      left out for magenta
+    },
+    SEQUENCE { INTEGER r, INTEGER s }   GOST signature by the issuer
+}
+
+The signature is over h("rust-magenta certificate" || 0 || DER of the
+first SEQUENCE), h being the `hash` construction the certificate names,
+so certificates hashed with magenta keep their original encoding.
+Times are seconds since the Unix epoch, UTC; a certificate is valid
+from not before up to, but not including, not after. Usage is a set of
+flags: 1 sign, 2 certify (endorse other keys), 4 encrypt (receive
+hybrid messages).
+
+A chain is verified from the leaf up: the leaf must allow the usage
+asked for, each certificate must be valid at the time of the check and
+signed by the key of the next one, which must be allowed to certify,
+until one is signed by a trusted root key.
+*/
+use std::io::IoResult;
+use std::str;
+use std::cmp::max;
+use std::num::{Zero, ToPrimitive};
+use std::slice::bytes::copy_memory;
+use num::bigint::{BigUint, ToBigUint};
+use hash;
+use hash::Construction;
+use armor::{Armored, is_armored, decode_bytes, CERTIFICATE};
+use ds::{DigSig, DsParams};
+use der::{DerReader, SEQUENCE, encode_tlv, encode_integer, encode_octet_string
+          , encode_sequence};
+use fingerprint;
+use fingerprint::{KeyId, ds_key_id};
+use params;
+use utils::invalid_input;
+
+
+static TAG: &'static [u8] = b"rust-magenta certificate";
+
+pub static SIGN: u8 = 1;
+pub static CERTIFY: u8 = 2;
+pub static ENCRYPT: u8 = 4;
+
+static USAGES: [(&'static str, u8), ..3] = [("sign", SIGN), ("certify", CERTIFY)
+                                            , ("encrypt", ENCRYPT)];
+
+// Longest chain looked for, counting the leaf.
+static MAX_DEPTH: uint = 8;
+
+
+// The signed part of a certificate.
+#[deriving(Clone, PartialEq, Show)]
+pub struct TbsCertificate {
+    pub issuer: KeyId,
+    pub params: DsParams,
+    pub key: BigUint,
+    pub name: String,
+    pub not_before: u64,
+    pub not_after: u64,
+    pub usage: u8,
+    pub hash: Construction
+}
+
+
+impl TbsCertificate {
+    pub fn to_der(&self) -> Vec<u8> {
+        let mut items = vec![encode_octet_string(self.issuer.as_slice())
+                             , DigSig::public_key_to_der(&self.params, &self.key)
+                             , encode_octet_string(self.name.as_bytes())
+                             , encode_integer(&big(self.not_before))
+                             , encode_integer(&big(self.not_after))
+                             , encode_integer(&big(self.usage as u64))];
+        if self.hash != hash::Original {
+            items.push(encode_octet_string(self.hash.name().as_bytes()));
+        }
+        encode_sequence(items.as_slice())
+    }
+
+    // What the issuer signs.
+    pub fn digest(&self) -> Vec<u8> {
+        let mut data = TAG.to_vec();
+        data.push(0);
+        data.push_all(self.to_der().as_slice());
+        hash::hash_bytes(self.hash, data.as_slice())
+    }
+
+    pub fn key_id(&self) -> KeyId {
+        ds_key_id(&self.params, &self.key)
+    }
+
+    fn from_der_reader(reader: &mut DerReader) -> IoResult<TbsCertificate> {
+        let mut seq = try!(reader.read_sequence());
+        let issuer = try!(read_key_id(try!(seq.read_octet_string())));
+        let key_der = encode_tlv(SEQUENCE, try!(seq.read_tlv(SEQUENCE)));
+        let (params, key) = try!(DigSig::public_key_from_der(key_der.as_slice()));
+        let name = match str::from_utf8(try!(seq.read_octet_string())) {
+            Some(n) => n.to_string(),
+            None => return Err(invalid_input("cert: subject name is not UTF-8")),
+        };
+        let not_before = try!(small(&try!(seq.read_integer())));
+        let not_after = try!(small(&try!(seq.read_integer())));
+        let usage = try!(small(&try!(seq.read_integer())));
+        let hash = if seq.is_empty() {
+            hash::Original
+        } else {
+            match str::from_utf8(try!(seq.read_octet_string())) {
+                Some(name) if name != hash::Original.name() => {
+                    try!(Construction::by_name(name))
+                },
+                _ => return Err(invalid_input("cert: incorrect hash name")),
+            }
+        };
+        try!(seq.finish());
+        if usage > (SIGN | CERTIFY | ENCRYPT) as u64 {
+            return Err(invalid_input("cert: unknown usage flags"));
+        }
+        Ok(TbsCertificate {
+            issuer: issuer,
+            params: params,
+            key: key,
+            name: name,
+            not_before: not_before,
+            not_after: not_after,
+            usage: usage as u8,
+            hash: hash
+        })
+    }
+}
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct Certificate {
+    pub tbs: TbsCertificate,
+    pub sig: DigSig
+}
+
+
+impl Certificate {
+    pub fn sign(tbs: TbsCertificate, issuer_params: &DsParams, x: &BigUint) -> Certificate {
+        let (_, sig) = DigSig::sign_prehashed(issuer_params, tbs.digest().as_slice(), x);
+        Certificate { tbs: tbs, sig: sig }
+    }
+
+    pub fn verify(&self, issuer_params: &DsParams, y: &BigUint) -> bool {
+        ds_key_id(issuer_params, y) == self.tbs.issuer
+            && self.sig.check_range(issuer_params).is_ok()
+            && self.sig.verify_prehashed(issuer_params, self.tbs.digest().as_slice(), y)
+    }
+
+    pub fn is_valid_at(&self, at: u64) -> bool {
+        self.tbs.not_before <= at && at < self.tbs.not_after
+    }
+
+    pub fn allows(&self, usage: u8) -> bool {
+        self.tbs.usage & usage == usage
+    }
+
+    pub fn to_der(&self) -> Vec<u8> {
+        encode_sequence(&[self.tbs.to_der(), self.sig.to_der()])
+    }
+
+    pub fn from_der(bytes: &[u8]) -> IoResult<Certificate> {
+        let mut reader = DerReader::new(bytes);
+        let mut seq = try!(reader.read_sequence());
+        let tbs = try!(TbsCertificate::from_der_reader(&mut seq));
+        let mut sig_seq = try!(seq.read_sequence());
+        let r = try!(sig_seq.read_integer());
+        let s = try!(sig_seq.read_integer());
+        try!(sig_seq.finish());
+        try!(seq.finish());
+        try!(reader.finish());
+
+        // The issuer's group isn't known yet, so this only bounds r and s
+        // by the widest named one; `verify` checks them against the
+        // issuer's.
+        let (p_bits, q_bits) = max_group_bits();
+        if r.is_zero() || s.is_zero() || r.bits() > p_bits || s.bits() > q_bits {
+            return Err(invalid_input("cert: signature out of range"));
+        }
+        Ok(Certificate { tbs: tbs, sig: DigSig::new(r, s) })
+    }
+
+    pub fn to_armor(&self) -> String {
+        let mut armored = Armored::new(CERTIFICATE, self.to_der());
+        fingerprint::add_header(&mut armored, &self.tbs.key_id());
+        armored.add_header("Issuer", fingerprint::format_key_id(&self.tbs.issuer).as_slice());
+        armored.add_header("Subject", self.tbs.name.as_slice());
+        armored.encode()
+    }
+
+    // DER or armored.
+    pub fn from_bytes(bytes: &[u8]) -> IoResult<Certificate> {
+        if !is_armored(bytes) {
+            return Certificate::from_der(bytes);
+        }
+        let armored = try!(decode_bytes(bytes));
+        if armored.label.as_slice() != CERTIFICATE {
+            return Err(invalid_input("cert: not a certificate"));
+        }
+        Certificate::from_der(armored.data.as_slice())
+    }
+}
+
+
+// Verifies `leaf` for `usage` at time `at` up to one of `roots`, using
+// the certificates in `others` as intermediates. Returns the chain from
+// the leaf up, without the root.
+pub fn verify_chain(leaf: &Certificate, others: &[Certificate]
+                    , roots: &[(DsParams, BigUint)], at: u64
+                    , usage: u8) -> IoResult<Vec<Certificate>> {
+    if !leaf.allows(usage) {
+        return Err(invalid_input("cert: key may not be used for this"));
+    }
+    let mut chain = vec![leaf.clone()];
+    loop {
+        let cert = chain.last().unwrap().clone();
+        if !cert.is_valid_at(at) {
+            return Err(invalid_input(if at < cert.tbs.not_before {
+                "cert: certificate not yet valid"
+            } else {
+                "cert: certificate expired"
+            }));
+        }
+        if chain.len() > 1 && !cert.allows(CERTIFY) {
+            return Err(invalid_input("cert: issuer may not certify"));
+        }
+
+        for &(ref params, ref y) in roots.iter() {
+            if ds_key_id(params, y) == cert.tbs.issuer {
+                if !cert.verify(params, y) {
+                    return Err(invalid_input("cert: incorrect signature"));
+                }
+                return Ok(chain);
+            }
+        }
+
+        if chain.len() == MAX_DEPTH {
+            return Err(invalid_input("cert: chain too long"));
+        }
+        let issuer = match others.iter().find(|c| c.tbs.key_id() == cert.tbs.issuer) {
+            Some(c) => c.clone(),
+            None => return Err(invalid_input("cert: no chain to a trusted root")),
+        };
+        if !cert.verify(&issuer.tbs.params, &issuer.tbs.key) {
+            return Err(invalid_input("cert: incorrect signature"));
+        }
+        chain.push(issuer);
+    }
+}
+
+
+fn max_group_bits() -> (uint, uint) {
+    params::ids().iter().map(|id| params::by_id(*id).unwrap())
+        .fold((0, 0), |(p, q), params| (max(p, params.p.bits()), max(q, params.q.bits())))
+}
+
+
+// "sign,certify" and so on.
+pub fn parse_usage(s: &str) -> IoResult<u8> {
+    let mut usage = 0;
+    for name in s.split(',').map(|n| n.trim()).filter(|n| !n.is_empty()) {
+        match USAGES.iter().find(|&&(n, _)| n == name) {
+            Some(&(_, flag)) => usage |= flag,
+            None => return Err(invalid_input("cert: unknown usage")),
+        }
+    }
+    Ok(usage)
+}
+
+
+pub fn format_usage(usage: u8) -> String {
+    let names: Vec<&str> = USAGES.iter()
+        .filter(|&&(_, flag)| usage & flag != 0)
+        .map(|&(name, _)| name)
+        .collect();
+    names.connect(",")
+}
+
+
+fn read_key_id(bytes: &[u8]) -> IoResult<KeyId> {
+    if bytes.len() != 8 {
+        return Err(invalid_input("cert: incorrect issuer key ID"));
+    }
+    let mut id = [0u8, ..8];
+    copy_memory(&mut id, bytes);
+    Ok(id)
+}
+
+
+fn big(n: u64) -> BigUint {
+    n.to_biguint().unwrap()
+}
+
+
+fn small(n: &BigUint) -> IoResult<u64> {
+    match n.to_u64() {
+        Some(n) => Ok(n),
+        None => Err(invalid_input("cert: number out of range")),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num::bigint::{BigUint, ToBigUint};
+    use std::num::Zero;
+    use ds::{DigSig, DsParams};
+    use fingerprint::ds_key_id;
+    use hash;
+    use hash::Construction;
+    use params;
+    use super::{Certificate, TbsCertificate, verify_chain, SIGN, CERTIFY, ENCRYPT};
+
+    static AT: u64 = 1420000000;
+
+    fn issue(params: &DsParams, subject: &BigUint, usage: u8, hash: Construction
+             , issuer_x: &BigUint) -> Certificate {
+        let issuer_y = DigSig::public_key(params, issuer_x);
+        let tbs = TbsCertificate {
+            issuer: ds_key_id(params, &issuer_y),
+            params: params.clone(),
+            key: subject.clone(),
+            name: "release signing".to_string(),
+            not_before: 1412345678,
+            not_after: 1443881678,
+            usage: usage,
+            hash: hash
+        };
+        Certificate::sign(tbs, params, issuer_x)
+    }
+
+    fn issued(hash: Construction) -> (Certificate, DsParams, BigUint) {
+        let params = params::default();
+        let x = DigSig::gen_key(&params);
+        let y = DigSig::public_key(&params, &x);
+        (issue(&params, &y, SIGN, hash, &x), params, y)
+    }
+
+    #[test]
+    fn every_hash_round_trips() {
+        for &h in [hash::Original, hash::DaviesMeyer, hash::MatyasMeyerOseas
+                   , hash::MiyaguchiPreneel, hash::Hirose].iter() {
+            let (cert, params, y) = issued(h);
+            let read = Certificate::from_der(cert.to_der().as_slice()).unwrap();
+            assert_eq!(read, cert);
+            assert!(read.verify(&params, &y), "{}", h);
+
+            let mut other = read.clone();
+            other.tbs.hash = if h == hash::Original { hash::Hirose } else { hash::Original };
+            assert!(!other.verify(&params, &y));
+        }
+    }
+
+    #[test]
+    fn magenta_hash_is_left_out() {
+        let (cert, _, _) = issued(hash::Original);
+        let der = cert.tbs.to_der();
+        assert!(!der.as_slice().windows(7).any(|w| w == b"magenta"));
+        let (cert, _, _) = issued(hash::Hirose);
+        let der = cert.tbs.to_der();
+        assert!(der.as_slice().windows(6).any(|w| w == b"hirose"));
+    }
+
+    #[test]
+    fn signature_range_is_checked() {
+        let (cert, params, y) = issued(hash::Original);
+        let zero: BigUint = Zero::zero();
+        let one = 1u.to_biguint().unwrap();
+        let wide = one << 8192;
+        let bad = [DigSig::new(zero.clone(), cert.sig.s.clone())
+                   , DigSig::new(cert.sig.r.clone(), zero)
+                   , DigSig::new(wide.clone(), cert.sig.s.clone())
+                   , DigSig::new(cert.sig.r.clone(), wide)];
+        for sig in bad.iter() {
+            let other = Certificate { tbs: cert.tbs.clone(), sig: sig.clone() };
+            assert!(Certificate::from_der(other.to_der().as_slice()).is_err());
+        }
+
+        // In range for the widest group but not for the issuer's.
+        let other = Certificate {
+            tbs: cert.tbs.clone(),
+            sig: DigSig::new(cert.sig.r.clone(), cert.sig.s + params.q)
+        };
+        assert!(!other.verify(&params, &y));
+    }
+
+    #[test]
+    fn chains_check_usage() {
+        let params = params::default();
+        let root_x = DigSig::gen_key(&params);
+        let roots = [(params.clone(), DigSig::public_key(&params, &root_x))];
+        let ca_x = DigSig::gen_key(&params);
+        let ca_y = DigSig::public_key(&params, &ca_x);
+        let leaf_y = DigSig::public_key(&params, &DigSig::gen_key(&params));
+
+        let ca = issue(&params, &ca_y, CERTIFY, hash::Original, &root_x);
+        let leaf = issue(&params, &leaf_y, SIGN | ENCRYPT, hash::Hirose, &ca_x);
+        let chain = verify_chain(&leaf, &[ca.clone()], &roots, AT, SIGN).unwrap();
+        assert_eq!(chain, vec![leaf.clone(), ca.clone()]);
+        assert!(verify_chain(&leaf, &[ca.clone()], &roots, AT, SIGN | ENCRYPT).is_ok());
+
+        // The leaf may not certify, and the CA may not sign.
+        assert!(verify_chain(&leaf, &[ca.clone()], &roots, AT, CERTIFY).is_err());
+        assert!(verify_chain(&ca, &[], &roots, AT, SIGN).is_err());
+        assert!(verify_chain(&ca, &[], &roots, AT, CERTIFY).is_ok());
+
+        // An issuer without CERTIFY breaks the chain, whatever the leaf allows.
+        let signer = issue(&params, &ca_y, SIGN, hash::Original, &root_x);
+        assert!(verify_chain(&leaf, &[signer], &roots, AT, SIGN).is_err());
+        assert!(verify_chain(&leaf, &[], &roots, AT, SIGN).is_err());
+        assert!(verify_chain(&leaf, &[ca], &roots, 1443881678, SIGN).is_err());
+    }
+}
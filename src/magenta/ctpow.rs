@@ -0,0 +1,281 @@
+/*
+Constant-time modular exponentiation for secret exponents.
+
+Numbers are held as fixed-width vectors of 32-bit limbs, least
+significant first, as wide as the modulus. Montgomery multiplication
+(CIOS, Koc et al. 1996) always runs the same number of limb steps and
+ends with a masked rather than branching subtraction. `CtMont::pow`
+uses a fixed 4-bit window over a caller-given number of exponent bits.
+The window digits come from a little-endian byte string of that fixed
+length, and each table entry is picked by a masked scan over all 16,
+so neither the sequence of operations nor the memory access pattern
+depends on the exponent. `CtMont::fixed_base` precomputes the powers of
+one base for `pow_fixed`, which needs no squarings and reads every
+table row the same way.
+*/
+use num::Integer;
+use num::bigint::{BigUint, ToBigUint};
+use std::num::{One, ToPrimitive};
+use utils::{biguint_from_bytes, biguint_to_fixed_bytes};
+
+
+static WINDOW: uint = 4;
+
+
+pub struct CtMont {
+    m: Vec<u32>,
+    m_big: BigUint,
+    m0_inv: u32,
+    r2: Vec<u32>
+}
+
+
+// rows[i][d] = base^(d * 16^i) in Montgomery form
+pub struct CtTable {
+    rows: Vec<Vec<Vec<u32>>>
+}
+
+
+impl CtMont {
+    pub fn new(m: &BigUint) -> CtMont {
+        let one: BigUint = One::one();
+        if !m.is_odd() || *m == one {
+            fail!("ctpow: modulus must be odd and greater than one");
+        }
+
+        let n = (m.bits() + 31) / 32;
+        let limbs = to_limbs(m, n);
+
+        // Newton iteration for m^-1 mod 2^32, five steps give 32 bits.
+        let mut inv = 1u32;
+        for _ in range(0u, 5) {
+            inv = inv * (2 - limbs[0] * inv);
+        }
+
+        let r2 = (one << (64 * n)) % *m;
+        CtMont {
+            m: limbs,
+            m_big: m.clone(),
+            m0_inv: -inv,
+            r2: to_limbs(&r2, n)
+        }
+    }
+
+    // base^exp mod m, where exp must fit in exp_bits bits. Only exp_bits
+    // leaks, so reduce the exponent and pass the bit length of the group
+    // order rather than of the exponent itself.
+    pub fn pow(&self, base: &BigUint, exp: &BigUint, exp_bits: uint) -> BigUint {
+        let n = self.m.len();
+        let windows = (exp_bits + WINDOW - 1) / WINDOW;
+        if exp.bits() > windows * WINDOW {
+            fail!("ctpow: exponent too long");
+        }
+        let digits = digits(exp, windows);
+
+        // table[d] = base^d in Montgomery form
+        let b = self.mul(&to_limbs(&(*base % self.m_big), n), &self.r2);
+        let mut table = vec![self.mul(&one_limbs(n), &self.r2), b.clone()];
+        for d in range(2, 1u << WINDOW) {
+            let next = self.mul(&table[d - 1], &b);
+            table.push(next);
+        }
+
+        let mut acc = table[0].clone();
+        for i in range(0, windows).rev() {
+            for _ in range(0, WINDOW) {
+                acc = self.mul(&acc, &acc);
+            }
+            acc = self.mul(&acc, &select(&table, digits[i]));
+        }
+        from_limbs(&self.mul(&acc, &one_limbs(n)))
+    }
+
+    // The table for `pow_fixed` with exponents of up to exp_bits bits.
+    pub fn fixed_base(&self, base: &BigUint, exp_bits: uint) -> CtTable {
+        let n = self.m.len();
+        let windows = (exp_bits + WINDOW - 1) / WINDOW;
+        let one = self.mul(&one_limbs(n), &self.r2);
+        let mut row_base = self.mul(&to_limbs(&(*base % self.m_big), n), &self.r2);
+        let mut rows = Vec::with_capacity(windows);
+        for _ in range(0, windows) {
+            let mut row = vec![one.clone(), row_base.clone()];
+            for d in range(2, 1u << WINDOW) {
+                let next = self.mul(&row[d - 1], &row_base);
+                row.push(next);
+            }
+            row_base = self.mul(&row[(1u << WINDOW) - 1], &row_base);
+            rows.push(row);
+        }
+        CtTable { rows: rows }
+    }
+
+    // base^exp mod m for the base of `table`; exp must fit in the bits
+    // the table was built for. Every row is used, zero windows included.
+    pub fn pow_fixed(&self, table: &CtTable, exp: &BigUint) -> BigUint {
+        let n = self.m.len();
+        let windows = table.rows.len();
+        if exp.bits() > windows * WINDOW {
+            fail!("ctpow: exponent too long for the table");
+        }
+        let digits = digits(exp, windows);
+
+        let mut acc = table.rows[0][0].clone();
+        for (i, row) in table.rows.iter().enumerate() {
+            acc = self.mul(&acc, &select(row, digits[i]));
+        }
+        from_limbs(&self.mul(&acc, &one_limbs(n)))
+    }
+
+    fn mul(&self, a: &Vec<u32>, b: &Vec<u32>) -> Vec<u32> {
+        let n = self.m.len();
+        let mut t = Vec::from_elem(n + 2, 0u32);
+
+        for i in range(0, n) {
+            let mut c = 0u64;
+            for j in range(0, n) {
+                let s = t[j] as u64 + a[j] as u64 * b[i] as u64 + c;
+                *t.get_mut(j) = s as u32;
+                c = s >> 32;
+            }
+            let s = t[n] as u64 + c;
+            *t.get_mut(n) = s as u32;
+            *t.get_mut(n + 1) = (s >> 32) as u32;
+
+            let u = t[0] * self.m0_inv;
+            let s = t[0] as u64 + u as u64 * self.m[0] as u64;
+            let mut c = s >> 32;
+            for j in range(1, n) {
+                let s = t[j] as u64 + u as u64 * self.m[j] as u64 + c;
+                *t.get_mut(j - 1) = s as u32;
+                c = s >> 32;
+            }
+            let s = t[n] as u64 + c;
+            *t.get_mut(n - 1) = s as u32;
+            *t.get_mut(n) = t[n + 1] + (s >> 32) as u32;
+        }
+
+        // t < 2m here; subtract m unless that borrows past t[n].
+        let mut d = Vec::from_elem(n, 0u32);
+        let mut borrow = 0u64;
+        for j in range(0, n) {
+            let s = (t[j] as u64) - (self.m[j] as u64) - borrow;
+            *d.get_mut(j) = s as u32;
+            borrow = (s >> 63) & 1;
+        }
+        let keep_t = (t[n] as u64 ^ 1) & borrow;
+        let mask = -(keep_t as u32);
+        Vec::from_fn(n, |j| (t[j] & mask) | (d[j] & !mask))
+    }
+}
+
+
+// The exponent as `windows` digits, least significant first. It is
+// first written out as exactly as many little-endian bytes as the
+// windows cover, so the loop count doesn't depend on its length.
+fn digits(exp: &BigUint, windows: uint) -> Vec<uint> {
+    let len = (windows * WINDOW + 7) / 8;
+    let mask = 0xffu.to_biguint().unwrap();
+    let mut t = exp.clone();
+    let mut bytes = Vec::with_capacity(len);
+    for _ in range(0, len) {
+        bytes.push((t & mask).to_u8().unwrap());
+        t = t >> 8;
+    }
+    Vec::from_fn(windows, |i| {
+        let bit = i * WINDOW;
+        ((bytes[bit / 8] >> (bit % 8)) & ((1 << WINDOW) - 1)) as uint
+    })
+}
+
+
+fn select(table: &Vec<Vec<u32>>, d: uint) -> Vec<u32> {
+    let mut res = Vec::from_elem(table[0].len(), 0u32);
+    for (i, entry) in table.iter().enumerate() {
+        let x = (i ^ d) as u32;
+        let mask = ((x | -x) >> 31) - 1;
+        for j in range(0, res.len()) {
+            *res.get_mut(j) |= entry[j] & mask;
+        }
+    }
+    res
+}
+
+
+fn one_limbs(n: uint) -> Vec<u32> {
+    let mut res = Vec::from_elem(n, 0u32);
+    *res.get_mut(0) = 1;
+    res
+}
+
+
+fn to_limbs(x: &BigUint, n: uint) -> Vec<u32> {
+    let bytes = biguint_to_fixed_bytes(x, 4 * n);
+    Vec::from_fn(n, |i| {
+        let k = 4 * (n - 1 - i);
+        (bytes[k] as u32 << 24) | (bytes[k + 1] as u32 << 16)
+            | (bytes[k + 2] as u32 << 8) | bytes[k + 3] as u32
+    })
+}
+
+
+fn from_limbs(limbs: &Vec<u32>) -> BigUint {
+    let mut bytes = Vec::with_capacity(4 * limbs.len());
+    for l in limbs.iter().rev() {
+        bytes.push_all(&[(*l >> 24) as u8, (*l >> 16) as u8, (*l >> 8) as u8, *l as u8]);
+    }
+    biguint_from_bytes(bytes.as_slice())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num::bigint::{BigUint, ToBigUint, RandBigInt};
+    use std::num::One;
+    use std::rand;
+    use params;
+    use utils::to_bits_le;
+    use super::CtMont;
+
+    // Left-to-right square-and-multiply on BigUint.
+    fn plain_pow(base: &BigUint, exp: &BigUint, m: &BigUint) -> BigUint {
+        let mut acc: BigUint = One::one();
+        for bit in to_bits_le(exp).iter().rev() {
+            acc = acc * acc % *m;
+            if *bit {
+                acc = acc * *base % *m;
+            }
+        }
+        acc
+    }
+
+    #[test]
+    fn matches_square_and_multiply() {
+        let params = params::default();
+        let ctx = CtMont::new(&params.p);
+        let table = ctx.fixed_base(&params.g, params.q.bits());
+        let mut rng = rand::task_rng();
+        let mut exps = vec![0u.to_biguint().unwrap(), 1u.to_biguint().unwrap()
+                            , params.q - 1u.to_biguint().unwrap()];
+        for _ in range(0u, 8) {
+            exps.push(rng.gen_biguint_below(&params.q));
+        }
+        for e in exps.iter() {
+            let expected = plain_pow(&params.g, e, &params.p);
+            assert_eq!(ctx.pow_fixed(&table, e), expected);
+            assert_eq!(ctx.pow(&params.g, e, params.q.bits()), expected);
+            // A base that is not g, and more windows than needed.
+            let base = params.g + 1u.to_biguint().unwrap();
+            let expected = plain_pow(&base, e, &params.p);
+            assert_eq!(ctx.pow(&base, e, params.q.bits() + 7), expected);
+        }
+    }
+
+    #[test]
+    #[should_fail]
+    fn long_exponents_are_refused() {
+        let params = params::default();
+        let ctx = CtMont::new(&params.p);
+        // q needs one window more than this allows.
+        ctx.pow(&params.g, &params.q, params.q.bits() - 4);
+    }
+}
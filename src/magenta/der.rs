@@ -0,0 +1,223 @@
+/*
+Strict DER (ITU-T X.690) for the few types the crate needs:
+non-negative INTEGER, OCTET STRING and SEQUENCE.
+Anything that isn't the unique encoding of a value is rejected.
+*/
+use std::io::IoResult;
+use num::bigint::BigUint;
+use utils::{biguint_from_bytes, biguint_to_bytes, invalid_input};
+
+
+pub static INTEGER: u8 = 0x02;
+pub static OCTET_STRING: u8 = 0x04;
+pub static SEQUENCE: u8 = 0x30;
+
+
+pub fn encode_tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut res = vec![tag];
+    let len = content.len();
+
+    if len < 0x80 {
+        res.push(len as u8);
+    } else {
+        let mut len_bytes = Vec::new();
+        let mut l = len;
+        while l > 0 {
+            len_bytes.push(l as u8);
+            l = l >> 8;
+        }
+        len_bytes.reverse();
+        res.push(0x80 | len_bytes.len() as u8);
+        res.push_all(len_bytes.as_slice());
+    }
+    res.push_all(content);
+    res
+}
+
+pub fn encode_integer(n: &BigUint) -> Vec<u8> {
+    let bytes = biguint_to_bytes(n);
+    let mut content = Vec::new();
+    if bytes.is_empty() || bytes[0] & 0x80 != 0 {
+        content.push(0u8);
+    }
+    content.push_all(bytes.as_slice());
+    encode_tlv(INTEGER, content.as_slice())
+}
+
+pub fn encode_octet_string(bytes: &[u8]) -> Vec<u8> {
+    encode_tlv(OCTET_STRING, bytes)
+}
+
+pub fn encode_sequence(items: &[Vec<u8>]) -> Vec<u8> {
+    let mut content = Vec::new();
+    for item in items.iter() {
+        content.push_all(item.as_slice());
+    }
+    encode_tlv(SEQUENCE, content.as_slice())
+}
+
+
+pub struct DerReader<'a> {
+    data: &'a [u8],
+    pos: uint
+}
+
+
+impl<'a> DerReader<'a> {
+    pub fn new(data: &'a [u8]) -> DerReader<'a> {
+        DerReader { data: data, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    pub fn finish(&self) -> IoResult<()> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid_input("der: trailing data"))
+        }
+    }
+
+    pub fn read_tlv(&mut self, tag: u8) -> IoResult<&'a [u8]> {
+        if try!(self.next_byte()) != tag {
+            return Err(invalid_input("der: unexpected tag"));
+        }
+
+        let first = try!(self.next_byte());
+        let len = if first < 0x80 {
+            first as uint
+        } else {
+            let n = (first & 0x7f) as uint;
+            if n == 0 || n > 4 {
+                return Err(invalid_input("der: unsupported length encoding"));
+            }
+            let mut l = 0u;
+            for i in range(0, n) {
+                let b = try!(self.next_byte());
+                if i == 0 && b == 0 {
+                    return Err(invalid_input("der: non-minimal length"));
+                }
+                l = (l << 8) | b as uint;
+            }
+            if l < 0x80 {
+                return Err(invalid_input("der: non-minimal length"));
+            }
+            l
+        };
+
+        if self.data.len() - self.pos < len {
+            return Err(invalid_input("der: truncated value"));
+        }
+        let content = self.data.slice(self.pos, self.pos + len);
+        self.pos += len;
+        Ok(content)
+    }
+
+    pub fn read_integer(&mut self) -> IoResult<BigUint> {
+        let content = try!(self.read_tlv(INTEGER));
+        if content.is_empty() {
+            return Err(invalid_input("der: empty integer"));
+        }
+        if content[0] & 0x80 != 0 {
+            return Err(invalid_input("der: negative integer"));
+        }
+        if content.len() > 1 && content[0] == 0 && content[1] & 0x80 == 0 {
+            return Err(invalid_input("der: non-minimal integer"));
+        }
+        Ok(biguint_from_bytes(content))
+    }
+
+    pub fn read_octet_string(&mut self) -> IoResult<&'a [u8]> {
+        self.read_tlv(OCTET_STRING)
+    }
+
+    pub fn read_sequence(&mut self) -> IoResult<DerReader<'a>> {
+        let content = try!(self.read_tlv(SEQUENCE));
+        Ok(DerReader::new(content))
+    }
+
+    fn next_byte(&mut self) -> IoResult<u8> {
+        if self.is_empty() {
+            return Err(invalid_input("der: truncated value"));
+        }
+        let b = self.data[self.pos];
+        self.pos += 1;
+        Ok(b)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::IoResult;
+    use num::bigint::{BigUint, ToBigUint};
+    use super::{DerReader, encode_integer, encode_octet_string, encode_sequence};
+
+    fn read_int(bytes: &[u8]) -> IoResult<BigUint> {
+        let mut reader = DerReader::new(bytes);
+        let n = try!(reader.read_integer());
+        try!(reader.finish());
+        Ok(n)
+    }
+
+    #[test]
+    fn round_trip() {
+        for &n in [0u, 1, 0x7f, 0x80, 0xff, 0x100, 0xdeadbeef].iter() {
+            let n = n.to_biguint().unwrap();
+            assert_eq!(read_int(encode_integer(&n).as_slice()).unwrap(), n);
+        }
+        // Long-form lengths, one and two bytes.
+        for &len in [0x7fu, 0x80, 0xff, 0x100, 0x1234].iter() {
+            let content = Vec::from_elem(len, 0xa5u8);
+            let der = encode_sequence(&[encode_octet_string(content.as_slice())]);
+            let mut reader = DerReader::new(der.as_slice());
+            let mut seq = reader.read_sequence().unwrap();
+            assert_eq!(seq.read_octet_string().unwrap(), content.as_slice());
+            assert!(seq.finish().is_ok() && reader.finish().is_ok());
+        }
+    }
+
+    #[test]
+    fn integers_must_be_minimal_and_non_negative() {
+        assert_eq!(read_int(&[0x02, 0x01, 0x00]).unwrap(), 0u.to_biguint().unwrap());
+        assert_eq!(read_int(&[0x02, 0x02, 0x00, 0x80]).unwrap(), 0x80u.to_biguint().unwrap());
+
+        let bad: [&[u8], ..6] = [&[0x02, 0x00]
+                                 , &[0x02, 0x02, 0x00, 0x00]
+                                 , &[0x02, 0x02, 0x00, 0x7f]
+                                 , &[0x02, 0x01, 0x80]
+                                 , &[0x02, 0x01, 0xff]
+                                 , &[0x04, 0x01, 0x01]];
+        for der in bad.iter() {
+            assert!(read_int(*der).is_err(), "{}", der);
+        }
+    }
+
+    #[test]
+    fn lengths_must_be_minimal_and_definite() {
+        let bad: [&[u8], ..5] = [&[0x02, 0x81, 0x01, 0x05]
+                                 , &[0x02, 0x82, 0x00, 0x01, 0x05]
+                                 , &[0x02, 0x80, 0x05, 0x00, 0x00]
+                                 , &[0x02, 0x85, 0x00, 0x00, 0x00, 0x00, 0x01, 0x05]
+                                 , &[0x02, 0x02, 0x05]];
+        for der in bad.iter() {
+            assert!(read_int(*der).is_err(), "{}", der);
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        assert!(read_int(&[0x02, 0x01, 0x05]).is_ok());
+        assert!(read_int(&[0x02, 0x01, 0x05, 0x00]).is_err());
+
+        // Inside a sequence as well as after it.
+        let der = [0x30u8, 0x04, 0x02, 0x01, 0x05, 0x00];
+        let mut reader = DerReader::new(&der);
+        let mut seq = reader.read_sequence().unwrap();
+        assert!(seq.read_integer().is_ok());
+        assert!(seq.finish().is_err());
+        assert!(reader.finish().is_ok());
+    }
+}
@@ -0,0 +1,118 @@
+/*
+Diffie-Hellman key agreement in the prime-order subgroup used by
+`DigSig`. DS key pairs double as static DH keys; `DhKeyPair::generate`
+gives ephemeral ones. Magenta keys are derived from the shared value
+with HKDF-HMAC-Magenta.
+*/
+use std::io::IoResult;
+use std::slice::bytes::copy_memory;
+use num::bigint::BigUint;
+use std::num::One;
+use ds::{DsParams, DsContext};
+use kdf::hkdf;
+use utils::{B16, biguint_to_fixed_bytes, invalid_input};
+
+
+static KDF_SALT: &'static [u8] = b"rust-magenta dh";
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct DhKeyPair {
+    pub x: BigUint,
+    pub y: BigUint
+}
+
+
+impl DhKeyPair {
+    pub fn generate(params: &DsParams) -> DhKeyPair {
+        DhKeyPair::from_private(params, params.random_exponent())
+    }
+
+    pub fn from_private(params: &DsParams, x: BigUint) -> DhKeyPair {
+        let y = DsContext::cached(params).public_key(&x);
+        DhKeyPair { x: x, y: y }
+    }
+
+    pub fn agree(&self, params: &DsParams, peer_y: &BigUint) -> IoResult<BigUint> {
+        shared_secret(params, &self.x, peer_y)
+    }
+}
+
+
+pub fn shared_secret(params: &DsParams, x: &BigUint, peer_y: &BigUint) -> IoResult<BigUint> {
+    if !params.is_group_element(peer_y) {
+        return Err(invalid_input("dh: peer key is not in the group"));
+    }
+    let z = DsContext::cached(params).pow_secret(peer_y, x);
+    let one: BigUint = One::one();
+    if z == one {
+        return Err(invalid_input("dh: degenerate shared secret"));
+    }
+    Ok(z)
+}
+
+
+pub fn derive_key_material(params: &DsParams, z: &BigUint, info: &[u8]
+                           , len: uint) -> Vec<u8> {
+    let z_bytes = biguint_to_fixed_bytes(z, params.p_len());
+    hkdf(KDF_SALT, z_bytes.as_slice(), info, len)
+}
+
+
+pub fn derive_key(params: &DsParams, z: &BigUint, info: &[u8]) -> B16 {
+    let okm = derive_key_material(params, z, info, 16);
+    let mut key: B16 = [0, ..16];
+    copy_memory(&mut key, okm.as_slice());
+    key
+}
+
+
+pub fn key_info(params: &DsParams, y1: &BigUint, y2: &BigUint) -> Vec<u8> {
+    let (lo, hi) = if y1 <= y2 { (y1, y2) } else { (y2, y1) };
+    let mut info = biguint_to_fixed_bytes(lo, params.p_len());
+    info.push_all(biguint_to_fixed_bytes(hi, params.p_len()).as_slice());
+    info
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num::bigint::{BigUint, ToBigUint};
+    use std::num::{One, Zero};
+    use params;
+    use super::{DhKeyPair, shared_secret, derive_key, key_info};
+
+    #[test]
+    fn both_sides_agree() {
+        let params = params::default();
+        let a = DhKeyPair::generate(&params);
+        let b = DhKeyPair::generate(&params);
+        let z = a.agree(&params, &b.y).unwrap();
+        assert_eq!(b.agree(&params, &a.y).unwrap(), z);
+
+        let info = key_info(&params, &a.y, &b.y);
+        assert_eq!(info, key_info(&params, &b.y, &a.y));
+        let key = derive_key(&params, &z, info.as_slice());
+        let z_b = b.agree(&params, &a.y).unwrap();
+        assert_eq!(key.as_slice(), derive_key(&params, &z_b, info.as_slice()).as_slice());
+
+        let c = DhKeyPair::generate(&params);
+        assert!(a.agree(&params, &c.y).unwrap() != z);
+    }
+
+    #[test]
+    fn peers_outside_the_subgroup_are_rejected() {
+        let params = params::default();
+        let a = DhKeyPair::generate(&params);
+        let b = DhKeyPair::generate(&params);
+        let zero: BigUint = Zero::zero();
+        let one: BigUint = One::one();
+        // -y has order 2q: (-y)^q = -1 for odd q.
+        let minus_y = params.p - b.y;
+        for y in [zero, one.clone(), params.p - one, params.p.clone(), minus_y].iter() {
+            assert!(shared_secret(&params, &a.x, y).is_err(), "{}", y);
+        }
+        let two = 2u.to_biguint().unwrap();
+        assert!(shared_secret(&params, &a.x, &(params.p + two)).is_err());
+    }
+}
@@ -1,18 +1,232 @@
-use std::io::File;
+use std::io::{File, IoResult};
 use std::rand;
+use std::rc::Rc;
 use num::bigint::{BigUint, ToBigUint, RandBigInt};
-use std::num::One;
-use hash::h_file;
-use utils::mod_pow;
+use std::num::{One, Zero};
+use hash::{h_bytes, h_reader};
+use utils::{mod_pow, biguint_from_bytes, biguint_to_fixed_bytes, byte_len
+            , invalid_input, jacobi};
+use der::{DerReader, encode_integer, encode_sequence};
+use armor::{Armored, is_armored, SIGNATURE, SIGNING_KEY, VERIFICATION_KEY};
+use params;
+use fingerprint;
+use montgomery::{MontCtx, FixedBase};
+use ctpow::{CtMont, CtTable};
 use std::num::from_str_radix;
 
 
+#[deriving(Clone, PartialEq, Show)]
 pub struct DigSig {
     pub r: BigUint,
     pub s: BigUint
 }
 
 
+#[deriving(Clone, PartialEq, Show)]
+pub struct DsParams {
+    pub p: BigUint,
+    pub q: BigUint,
+    pub g: BigUint
+}
+
+
+impl DsParams {
+    pub fn builtin() -> DsParams {
+        let (p, q) = DigSig::get_p_and_q();
+        let g = DigSig::calc_g(&p, &q);
+        DsParams { p: p, q: q, g: g }
+    }
+
+    pub fn by_id(id: &str) -> IoResult<DsParams> {
+        match params::by_id(id) {
+            Some(p) => Ok(p),
+            None => Err(invalid_input("ds: unknown parameter set")),
+        }
+    }
+
+    pub fn id(&self) -> &'static str {
+        match params::id_of(self) {
+            Some(id) => id,
+            None => fail!("ds: parameters have no id"),
+        }
+    }
+
+    pub fn p_len(&self) -> uint {
+        byte_len(&self.p)
+    }
+
+    pub fn q_len(&self) -> uint {
+        byte_len(&self.q)
+    }
+
+    pub fn random_exponent(&self) -> BigUint {
+        DigSig::random_k(2, &self.q)
+    }
+
+    pub fn is_group_element(&self, y: &BigUint) -> bool {
+        let one: BigUint = One::one();
+        if *y <= one || *y >= self.p {
+            return false;
+        }
+        // For a safe prime the subgroup of order q is the set of
+        // quadratic residues.
+        if self.p == (self.q << 1) + one {
+            return jacobi(y, &self.p) == 1;
+        }
+        mod_pow(y.clone(), self.q.clone(), self.p.clone()) == one
+    }
+
+    pub fn to_der(&self) -> Vec<u8> {
+        encode_sequence(&[encode_integer(&self.p), encode_integer(&self.q)
+                         , encode_integer(&self.g)])
+    }
+
+    pub fn from_der_reader(reader: &mut DerReader) -> IoResult<DsParams> {
+        let mut seq = try!(reader.read_sequence());
+        let p = try!(seq.read_integer());
+        let q = try!(seq.read_integer());
+        let g = try!(seq.read_integer());
+        try!(seq.finish());
+        Ok(DsParams { p: p, q: q, g: g })
+    }
+
+    pub fn from_armor_header(armored: &Armored) -> IoResult<DsParams> {
+        match armored.header("Params") {
+            Some(id) => DsParams::by_id(id),
+            None => Ok(DsParams::builtin()),
+        }
+    }
+
+    // Text files start with the parameter ID; files without one predate
+    // parameter sets and belong to the builtin group.
+    pub fn from_text_lines<'a>(lines: &mut Vec<&'a str>) -> IoResult<DsParams> {
+        match lines.as_slice().head() {
+            Some(l) if !l.chars().all(|c| c.is_digit()) => {
+                let id = lines.remove(0).unwrap();
+                DsParams::by_id(id)
+            },
+            _ => Ok(DsParams::builtin()),
+        }
+    }
+}
+
+
+// Exponentiation state for one parameter set. `precomputed` adds
+// fixed-base tables for g, one for public and one for secret exponents;
+// `cached` keeps a precomputed context per named set for the rest of
+// the task, so key generation, signing and verification share them.
+// Secret exponents (private keys and nonces) never go through `pow` or
+// `g_pow`, only through the constant-time `pow_secret` and
+// `g_pow_secret`.
+pub struct DsContext {
+    pub params: DsParams,
+    p_ctx: MontCtx,
+    ct_ctx: CtMont,
+    g_table: Option<FixedBase>,
+    g_ct_table: Option<CtTable>
+}
+
+
+local_data_key!(CONTEXTS: Vec<(&'static str, Rc<DsContext>)>)
+
+
+impl DsContext {
+    pub fn new(params: &DsParams) -> DsContext {
+        DsContext {
+            params: params.clone(),
+            p_ctx: MontCtx::new(&params.p),
+            ct_ctx: CtMont::new(&params.p),
+            g_table: None,
+            g_ct_table: None
+        }
+    }
+
+    pub fn precomputed(params: &DsParams) -> DsContext {
+        let ct_ctx = CtMont::new(&params.p);
+        let g_ct_table = ct_ctx.fixed_base(&params.g, params.q.bits());
+        DsContext {
+            params: params.clone(),
+            p_ctx: MontCtx::new(&params.p),
+            ct_ctx: ct_ctx,
+            g_table: Some(FixedBase::new(&params.g, &params.p, params.q.bits())),
+            g_ct_table: Some(g_ct_table)
+        }
+    }
+
+    // The precomputed context of a named parameter set, built on first
+    // use in this task. Unnamed sets get a plain context.
+    pub fn cached(params: &DsParams) -> Rc<DsContext> {
+        let id = match params::id_of(params) {
+            Some(id) => id,
+            None => return Rc::new(DsContext::new(params)),
+        };
+        let mut contexts = CONTEXTS.replace(None).unwrap_or(Vec::new());
+        let found = contexts.iter().find(|&&(i, _)| i == id).map(|&(_, ref c)| c.clone());
+        let ctx = match found {
+            Some(c) => c,
+            None => {
+                let c = Rc::new(DsContext::precomputed(params));
+                contexts.push((id, c.clone()));
+                c
+            },
+        };
+        CONTEXTS.replace(Some(contexts));
+        ctx
+    }
+
+    pub fn g_pow(&self, exp: &BigUint) -> BigUint {
+        match self.g_table {
+            Some(ref t) => t.pow(exp),
+            None => self.p_ctx.pow(&self.params.g, exp),
+        }
+    }
+
+    pub fn pow(&self, base: &BigUint, exp: &BigUint) -> BigUint {
+        self.p_ctx.pow(base, exp)
+    }
+
+    // base must be a group element: the exponent is reduced mod q so
+    // that every secret takes the same number of windows.
+    pub fn pow_secret(&self, base: &BigUint, exp: &BigUint) -> BigUint {
+        let q = &self.params.q;
+        self.ct_ctx.pow(base, &(*exp % *q), q.bits())
+    }
+
+    pub fn g_pow_secret(&self, exp: &BigUint) -> BigUint {
+        match self.g_ct_table {
+            Some(ref t) => self.ct_ctx.pow_fixed(t, &(*exp % self.params.q)),
+            None => self.pow_secret(&self.params.g, exp),
+        }
+    }
+
+    pub fn multi_pow(&self, terms: &[(BigUint, BigUint)]) -> BigUint {
+        self.p_ctx.multi_pow(terms)
+    }
+
+    pub fn public_key(&self, x: &BigUint) -> BigUint {
+        DigSig::calc_y(self, x)
+    }
+
+    pub fn sign_prehashed(&self, digest: &[u8], x: &BigUint) -> (BigUint, DigSig) {
+        let q = &self.params.q;
+        let k = DigSig::random_k(1, q);
+        let h = DigSig::calc_h(digest, q);
+        let y = DigSig::calc_y(self, x);
+        let r = DigSig::calc_r(self, &k);
+        let s = DigSig::calc_s(q, &k, &h, &r, x);
+        (y, DigSig{r: r, s: s})
+    }
+
+    pub fn verify_prehashed(&self, ds: &DigSig, digest: &[u8], y: &BigUint) -> bool {
+        let p = &self.params.p;
+        let h = DigSig::calc_h(digest, &self.params.q);
+        let rho = DigSig::calc_rho(&ds.r, &self.params.q);
+
+        self.pow(&ds.r, &h) == self.g_pow(&ds.s) * self.pow(y, &rho) % *p
+    }
+}
+
+
 impl DigSig {
     pub fn new(r: BigUint, s: BigUint) -> DigSig {
         DigSig {
@@ -21,67 +235,254 @@ impl DigSig {
         }
     }
 
-    pub fn from_file(ds_path: &str) -> DigSig {
+    pub fn from_file(ds_path: &str) -> (DsParams, DigSig) {
         let mut file = File::open(&Path::new(ds_path)).unwrap();
         let s = file.read_to_string().unwrap();
 
-        let ls: Vec<&str> = s.as_slice().lines().collect();
+        if is_armored(s.as_bytes()) {
+            let armored = Armored::decode(s.as_slice()).unwrap();
+            if armored.label.as_slice() != SIGNATURE
+                || armored.header("Scheme").unwrap_or("gost") != "gost" {
+                fail!("incorrect digital signature file");
+            }
+            let params = DsParams::from_armor_header(&armored).unwrap();
+            let ds = DigSig::from_der(&params, armored.data.as_slice()).unwrap();
+            return (params, ds);
+        }
+
+        let mut ls: Vec<&str> = s.as_slice().lines().collect();
+        fingerprint::take_text_line(&mut ls).unwrap();
+        let params = DsParams::from_text_lines(&mut ls).unwrap();
         if ls.len() != 2 {
             fail!("incorrect digital signature file");
         }
-        
-        DigSig {
+
+        (params, DigSig {
             r: from_str(ls[0]).unwrap(),
-            s: from_str(ls[1]).unwrap() 
-        }
+            s: from_str(ls[1]).unwrap()
+        })
     }
 
     pub fn of_file(file: &mut File, x: &BigUint) -> (BigUint, DigSig) {
-        let (p, q) = DigSig::get_p_and_q();
-        let k = DigSig::random_k(1, &q);
-        let h = DigSig::calc_h(file);
-        let g = DigSig::calc_g(&p, &q);
-        let y = DigSig::calc_y(&p, &g, x);
-        let r = DigSig::calc_r(&p, &k, &g);
-        let s = DigSig::calc_s(&q, &k, &h, &r, x);
-        (y, DigSig{r: r, s: s})
+        DigSig::sign_reader(&DsParams::builtin(), file, x)
+    }
+
+    pub fn sign(params: &DsParams, msg: &[u8], x: &BigUint) -> (BigUint, DigSig) {
+        DigSig::sign_prehashed(params, h_bytes(msg).as_slice(), x)
+    }
+
+    pub fn sign_reader<R: Reader>(params: &DsParams, reader: &mut R
+                                  , x: &BigUint) -> (BigUint, DigSig) {
+        DigSig::sign_prehashed(params, h_reader(reader).as_slice(), x)
+    }
+
+    // `digest` is any of the `hash` digests.
+    pub fn sign_prehashed(params: &DsParams, digest: &[u8], x: &BigUint) -> (BigUint, DigSig) {
+        DsContext::cached(params).sign_prehashed(digest, x)
     }
 
     pub fn verify_file(self, file: &mut File, y: BigUint) -> bool {
-        let (p, q) = DigSig::get_p_and_q();
-        let g = DigSig::calc_g(&p, &q);
-        let h = DigSig::calc_h(file);
-        let rho = DigSig::calc_rho(&self.r, &q);
+        self.verify_reader(&DsParams::builtin(), file, &y)
+    }
+
+    pub fn verify(&self, params: &DsParams, msg: &[u8], y: &BigUint) -> bool {
+        self.verify_prehashed(params, h_bytes(msg).as_slice(), y)
+    }
+
+    pub fn verify_reader<R: Reader>(&self, params: &DsParams, reader: &mut R
+                                    , y: &BigUint) -> bool {
+        self.verify_prehashed(params, h_reader(reader).as_slice(), y)
+    }
 
-        mod_pow(self.r, h, p.clone()) == mod_pow(g, self.s, p.clone()) *
-            mod_pow(y.clone(), rho, p.clone()) % p
+    pub fn verify_prehashed(&self, params: &DsParams, digest: &[u8], y: &BigUint) -> bool {
+        DsContext::cached(params).verify_prehashed(self, digest, y)
     }
 
-    pub fn to_file(&self, file_path: &str) {
+    pub fn to_bytes(&self, params: &DsParams) -> Vec<u8> {
+        let mut res = biguint_to_fixed_bytes(&self.r, params.p_len());
+        res.push_all(biguint_to_fixed_bytes(&self.s, params.q_len()).as_slice());
+        res
+    }
+
+    pub fn from_bytes(params: &DsParams, bytes: &[u8]) -> IoResult<DigSig> {
+        let p_len = params.p_len();
+        if bytes.len() != p_len + params.q_len() {
+            return Err(invalid_input("ds: incorrect signature length"));
+        }
+        let ds = DigSig {
+            r: biguint_from_bytes(bytes.slice_to(p_len)),
+            s: biguint_from_bytes(bytes.slice_from(p_len))
+        };
+        try!(ds.check_range(params));
+        Ok(ds)
+    }
+
+    pub fn to_der(&self) -> Vec<u8> {
+        encode_sequence(&[encode_integer(&self.r), encode_integer(&self.s)])
+    }
+
+    pub fn from_der(params: &DsParams, bytes: &[u8]) -> IoResult<DigSig> {
+        let mut reader = DerReader::new(bytes);
+        let mut seq = try!(reader.read_sequence());
+        let r = try!(seq.read_integer());
+        let s = try!(seq.read_integer());
+        try!(seq.finish());
+        try!(reader.finish());
+
+        let ds = DigSig { r: r, s: s };
+        try!(ds.check_range(params));
+        Ok(ds)
+    }
+
+    pub fn public_key_to_bytes(params: &DsParams, y: &BigUint) -> Vec<u8> {
+        biguint_to_fixed_bytes(y, params.p_len())
+    }
+
+    pub fn public_key_from_bytes(params: &DsParams, bytes: &[u8]) -> IoResult<BigUint> {
+        if bytes.len() != params.p_len() {
+            return Err(invalid_input("ds: incorrect public key length"));
+        }
+        let y = biguint_from_bytes(bytes);
+        try!(DigSig::check_public_key(params, &y));
+        Ok(y)
+    }
+
+    pub fn public_key_to_der(params: &DsParams, y: &BigUint) -> Vec<u8> {
+        encode_sequence(&[params.to_der(), encode_integer(y)])
+    }
+
+    pub fn public_key_from_der(bytes: &[u8]) -> IoResult<(DsParams, BigUint)> {
+        let mut reader = DerReader::new(bytes);
+        let mut seq = try!(reader.read_sequence());
+        let params = try!(DsParams::from_der_reader(&mut seq));
+        let y = try!(seq.read_integer());
+        try!(seq.finish());
+        try!(reader.finish());
+
+        if params::id_of(&params).is_none() {
+            return Err(invalid_input("ds: unknown parameters"));
+        }
+        try!(DigSig::check_public_key(&params, &y));
+        Ok((params, y))
+    }
+
+    pub fn private_key_to_bytes(params: &DsParams, x: &BigUint) -> Vec<u8> {
+        biguint_to_fixed_bytes(x, params.q_len())
+    }
+
+    pub fn private_key_from_bytes(params: &DsParams, bytes: &[u8]) -> IoResult<BigUint> {
+        if bytes.len() != params.q_len() {
+            return Err(invalid_input("ds: incorrect private key length"));
+        }
+        let x = biguint_from_bytes(bytes);
+        if x.is_zero() || x >= params.q {
+            return Err(invalid_input("ds: private key out of range"));
+        }
+        Ok(x)
+    }
+
+    pub fn check_range(&self, params: &DsParams) -> IoResult<()> {
+        if self.r.is_zero() || self.r >= params.p || self.s >= params.q {
+            Err(invalid_input("ds: signature out of range"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_public_key(params: &DsParams, y: &BigUint) -> IoResult<()> {
+        let one: BigUint = One::one();
+        if *y <= one || *y >= params.p {
+            Err(invalid_input("ds: public key out of range"))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn to_file(&self, file_path: &str, params: &DsParams) {
         let mut file = File::create(&Path::new(file_path)).unwrap();
         let r_str = format!("{}", self.r);
         let s_str = format!("{}", self.s);
+        file.write_str(params.id());
+        file.write_char('\n');
         file.write_str(r_str.as_slice());
         file.write_char('\n');
         file.write_str(s_str.as_slice());
     }
 
-    pub fn key_to_file(file_path: &str, k: &BigUint) {
+    pub fn key_to_file(file_path: &str, params: &DsParams, k: &BigUint) {
         let mut file = File::create(&Path::new(file_path)).unwrap();
         let k_str = format!("{}", k);
+        file.write_str(params.id());
+        file.write_char('\n');
         file.write_str(k_str.as_slice());
     }
 
-    pub fn key_from_file(file_path: &str) -> BigUint {
+    pub fn key_from_file(file_path: &str) -> (DsParams, BigUint) {
         let mut file = File::open(&Path::new(file_path)).unwrap();
         let content = file.read_to_string().unwrap();
-        from_str_radix(content.as_slice(), 10).unwrap()
+        DigSig::key_from_str(content.as_slice()).unwrap()
     }
 
-    pub fn rnd_key(file_path: &str) {
-        let (_, q) = DigSig::get_p_and_q();
-        let key = DigSig::random_k(2, &q);
-        DigSig::key_to_file(file_path, &key);
+    pub fn key_from_str(content: &str) -> IoResult<(DsParams, BigUint)> {
+        if is_armored(content.as_bytes()) {
+            let armored = try!(Armored::decode(content));
+            return match armored.label.as_slice() {
+                l if l == SIGNING_KEY => {
+                    let params = try!(DsParams::from_armor_header(&armored));
+                    let x = try!(DigSig::private_key_from_bytes(&params
+                                                                , armored.data.as_slice()));
+                    Ok((params, x))
+                },
+                l if l == VERIFICATION_KEY => {
+                    DigSig::public_key_from_der(armored.data.as_slice())
+                },
+                _ => Err(invalid_input("ds: incorrect key file")),
+            };
+        }
+
+        let mut ls: Vec<&str> = content.lines().map(|l| l.trim())
+            .filter(|l| !l.is_empty()).collect();
+        let params = try!(DsParams::from_text_lines(&mut ls));
+        if ls.len() != 1 {
+            return Err(invalid_input("ds: incorrect key file"));
+        }
+        match from_str_radix(ls[0], 10) {
+            Some(k) => Ok((params, k)),
+            None => Err(invalid_input("ds: incorrect key file")),
+        }
+    }
+
+    pub fn to_armor(&self, params: &DsParams) -> String {
+        let mut armored = Armored::new(SIGNATURE, self.to_der());
+        armored.add_header("Params", params.id());
+        armored.encode()
+    }
+
+    pub fn private_key_to_armor(params: &DsParams, x: &BigUint) -> String {
+        let mut armored = Armored::new(SIGNING_KEY
+                                       , DigSig::private_key_to_bytes(params, x));
+        armored.add_header("Params", params.id());
+        armored.encode()
+    }
+
+    pub fn public_key_to_armor(params: &DsParams, y: &BigUint) -> String {
+        let mut armored = Armored::new(VERIFICATION_KEY
+                                       , DigSig::public_key_to_der(params, y));
+        fingerprint::add_header(&mut armored, &fingerprint::ds_key_id(params, y));
+        armored.add_header("Params", params.id());
+        armored.encode()
+    }
+
+    pub fn public_key(params: &DsParams, x: &BigUint) -> BigUint {
+        DsContext::cached(params).public_key(x)
+    }
+
+    pub fn gen_key(params: &DsParams) -> BigUint {
+        DigSig::random_k(2, &params.q)
+    }
+
+    pub fn rnd_key(file_path: &str, params: &DsParams) {
+        DigSig::key_to_file(file_path, params, &DigSig::gen_key(params));
     }
 
     fn get_p_and_q() -> (BigUint, BigUint) {
@@ -97,29 +498,38 @@ impl DigSig {
         rng.gen_biguint_range(&low, &high)
     }
 
-    fn calc_h(file: &mut File) -> BigUint {
-        let box h_array = h_file(file);
-        let h_vec: Vec<u32> = h_array.iter().map(|&x| x as u32).collect();
-        BigUint::new(h_vec)
+    // 128-bit digests keep the original mapping, one byte per 32-bit
+    // digit, so that old magenta signatures still verify. Longer digests
+    // are read big-endian and reduced mod q, with 0 taken as 1 as in
+    // GOST R 34.10.
+    fn calc_h(digest: &[u8], q: &BigUint) -> BigUint {
+        if digest.len() == 16 {
+            let h_vec: Vec<u32> = digest.iter().map(|&x| x as u32).collect();
+            return BigUint::new(h_vec);
+        }
+        let h = biguint_from_bytes(digest) % *q;
+        if h.is_zero() { One::one() } else { h }
     }
 
     fn calc_s(q: &BigUint, k: &BigUint, h: &BigUint,
               r: &BigUint, x: &BigUint) -> BigUint {
-        let one: BigUint = One::one();
         let rho: BigUint = DigSig::calc_rho(r, q);
-        ((*h) * (*k) - rho * (*x)) % *q
+        // h * k - rho * x (mod q), kept non-negative for BigUint.
+        let hk = ((*h) * (*k)) % *q;
+        let rho_x = (rho * (*x)) % *q;
+        (hk + *q - rho_x) % *q
     }
 
     fn calc_rho(r: &BigUint, q: &BigUint) -> BigUint {
         *r % *q
     }
 
-    fn calc_y(p: &BigUint, g: &BigUint, x: &BigUint) -> BigUint {
-        mod_pow(g.clone(), x.clone(), p.clone())
+    fn calc_y(ctx: &DsContext, x: &BigUint) -> BigUint {
+        ctx.g_pow_secret(x)
     }
 
-    fn calc_r(p: &BigUint, k: &BigUint, g: &BigUint) -> BigUint {
-        mod_pow(g.clone(), k.clone(), p.clone())
+    fn calc_r(ctx: &DsContext, k: &BigUint) -> BigUint {
+        ctx.g_pow_secret(k)
     }
 
     fn calc_g(p: &BigUint, q: &BigUint) -> BigUint {
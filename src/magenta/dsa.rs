@@ -0,0 +1,242 @@
+/*
+DSA (FIPS 186-4, section 4) over the `DigSig` parameter sets and keys.
+
+    r = (g^k mod p) mod q
+    s = k^-1 (z + x r) mod q
+
+where z is the leftmost min(N, outlen) bits of the message digest and
+N is the bit length of q. The hash is pluggable through `DsaHash`;
+`MagentaHash` is the crate's own. To check published test vectors,
+hash with the vector's algorithm and use `sign_digest_with_k` and
+`verify_digest` directly.
+*/
+use std::io::{IoResult, BufReader};
+use num::bigint::BigUint;
+use std::num::{One, Zero};
+use hash::h_reader;
+use ds::{DsParams, DsContext};
+use ctpow::CtMont;
+use der::{DerReader, encode_integer, encode_sequence};
+use utils::{biguint_from_bytes, biguint_to_fixed_bytes, mod_inverse, invalid_input};
+
+
+pub trait DsaHash {
+    fn name(&self) -> &'static str;
+    fn hash_reader<R: Reader>(&self, reader: &mut R) -> Vec<u8>;
+}
+
+
+pub struct MagentaHash;
+
+
+impl DsaHash for MagentaHash {
+    fn name(&self) -> &'static str {
+        "magenta"
+    }
+
+    fn hash_reader<R: Reader>(&self, reader: &mut R) -> Vec<u8> {
+        h_reader(reader).to_vec()
+    }
+}
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct DsaSig {
+    pub r: BigUint,
+    pub s: BigUint
+}
+
+
+impl DsaSig {
+    pub fn sign<H: DsaHash>(params: &DsParams, hash: &H, msg: &[u8]
+                            , x: &BigUint) -> DsaSig {
+        DsaSig::sign_reader(params, hash, &mut BufReader::new(msg), x)
+    }
+
+    pub fn sign_reader<H: DsaHash, R: Reader>(params: &DsParams, hash: &H, reader: &mut R
+                                              , x: &BigUint) -> DsaSig {
+        DsaSig::sign_digest(params, hash.hash_reader(reader).as_slice(), x)
+    }
+
+    pub fn sign_digest(params: &DsParams, digest: &[u8], x: &BigUint) -> DsaSig {
+        loop {
+            let k = params.random_exponent();
+            match DsaSig::sign_digest_with_k(params, digest, x, &k) {
+                Some(sig) => return sig,
+                None => {},
+            }
+        }
+    }
+
+    // Signs with the given per-message secret k, for known-answer tests.
+    // Returns None when r or s comes out zero and another k is needed.
+    // Two signatures with the same k reveal the private key.
+    pub fn sign_digest_with_k(params: &DsParams, digest: &[u8], x: &BigUint
+                              , k: &BigUint) -> Option<DsaSig> {
+        let q = &params.q;
+        let one: BigUint = One::one();
+        let ctx = DsContext::cached(params);
+
+        let r = ctx.g_pow_secret(k) % *q;
+        if r.is_zero() {
+            return None;
+        }
+        // k^-1 = k^(q - 2) mod q, in constant time since k is secret.
+        let k_inv = CtMont::new(q).pow(k, &(*q - one - one), q.bits());
+        let z = DsaSig::digest_to_z(params, digest);
+        let s = k_inv * ((z + (*x % *q) * r) % *q) % *q;
+        if s.is_zero() {
+            return None;
+        }
+        Some(DsaSig { r: r, s: s })
+    }
+
+    pub fn verify<H: DsaHash>(&self, params: &DsParams, hash: &H, msg: &[u8]
+                              , y: &BigUint) -> bool {
+        self.verify_reader(params, hash, &mut BufReader::new(msg), y)
+    }
+
+    pub fn verify_reader<H: DsaHash, R: Reader>(&self, params: &DsParams, hash: &H
+                                                , reader: &mut R, y: &BigUint) -> bool {
+        self.verify_digest(params, hash.hash_reader(reader).as_slice(), y)
+    }
+
+    pub fn verify_digest(&self, params: &DsParams, digest: &[u8], y: &BigUint) -> bool {
+        if self.check_range(params).is_err() {
+            return false;
+        }
+        let q = &params.q;
+        let w = match mod_inverse(&self.s, q) {
+            Some(w) => w,
+            None => return false,
+        };
+        let z = DsaSig::digest_to_z(params, digest);
+        let u1 = z * w % *q;
+        let u2 = self.r * w % *q;
+
+        let ctx = DsContext::cached(params);
+        let v = ctx.multi_pow(&[(params.g.clone(), u1), (y.clone(), u2)]) % *q;
+        v == self.r
+    }
+
+    pub fn to_bytes(&self, params: &DsParams) -> Vec<u8> {
+        let mut res = biguint_to_fixed_bytes(&self.r, params.q_len());
+        res.push_all(biguint_to_fixed_bytes(&self.s, params.q_len()).as_slice());
+        res
+    }
+
+    pub fn from_bytes(params: &DsParams, bytes: &[u8]) -> IoResult<DsaSig> {
+        let q_len = params.q_len();
+        if bytes.len() != 2 * q_len {
+            return Err(invalid_input("dsa: incorrect signature length"));
+        }
+        let sig = DsaSig {
+            r: biguint_from_bytes(bytes.slice_to(q_len)),
+            s: biguint_from_bytes(bytes.slice_from(q_len))
+        };
+        try!(sig.check_range(params));
+        Ok(sig)
+    }
+
+    // Dss-Sig-Value (RFC 3279, section 2.2.2).
+    pub fn to_der(&self) -> Vec<u8> {
+        encode_sequence(&[encode_integer(&self.r), encode_integer(&self.s)])
+    }
+
+    pub fn from_der(params: &DsParams, bytes: &[u8]) -> IoResult<DsaSig> {
+        let mut reader = DerReader::new(bytes);
+        let mut seq = try!(reader.read_sequence());
+        let r = try!(seq.read_integer());
+        let s = try!(seq.read_integer());
+        try!(seq.finish());
+        try!(reader.finish());
+
+        let sig = DsaSig { r: r, s: s };
+        try!(sig.check_range(params));
+        Ok(sig)
+    }
+
+    fn check_range(&self, params: &DsParams) -> IoResult<()> {
+        if self.r.is_zero() || self.r >= params.q || self.s.is_zero() || self.s >= params.q {
+            Err(invalid_input("dsa: signature out of range"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn digest_to_z(params: &DsParams, digest: &[u8]) -> BigUint {
+        let n = params.q.bits();
+        let z = biguint_from_bytes(digest);
+        if digest.len() * 8 > n {
+            z >> (digest.len() * 8 - n)
+        } else {
+            z
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num::bigint::BigUint;
+    use std::num::from_str_radix;
+    use ds::DsParams;
+    use utils::{biguint_to_fixed_bytes, mod_pow};
+    use super::DsaSig;
+
+    fn hex(s: &str) -> BigUint {
+        from_str_radix(s, 16).unwrap()
+    }
+
+    // RFC 6979, appendix A.2.1: DSA with a 1024-bit p and 160-bit q.
+    fn rfc6979_params() -> DsParams {
+        DsParams {
+            p: hex("86F5CA03DCFEB225063FF830A0C769B9DD9D6153AD91D7CE27F787C43278B447\
+                    E6533B86B18BED6E8A48B784A14C252C5BE0DBF60B86D6385BD2F12FB763ED88\
+                    73ABFD3F5BA2E0A8C0A59082EAC056935E529DAF7C610467899C77ADEDFC846C\
+                    881870B7B19B2B58F9BE0521A17002E3BDD6B86685EE90B3D9A1B02B782B1779"),
+            q: hex("996F967F6C8E388D9E28D01E205FBA957A5698B1"),
+            g: hex("07B0F92546150B62514BB771E2A0C0CE387F03BDA6C56B505209FF25FD3C133D\
+                    89BBCD97E904E09114D9A7DEFDEADFC9078EA544D2E401AEECC40BB9FBBF78FD\
+                    87995A10A1C27CB7789B594BA7EFB5C4326A9FE59A070E136DB77175464ADCA4\
+                    17BE5DCE2F40D10A46A3A3943F26AB7FD9C0398FF8C76EE0A56826A8A88F1DBD")
+        }
+    }
+
+    fn check_vector(digest: &str, k: &str, r: &str, s: &str) {
+        let params = rfc6979_params();
+        let x = hex("411602CB19A6CCC34494D79D98EF1E7ED5AF25F7");
+        let y = hex("5DF5E01DED31D0297E274E1691C192FE5868FEF9E19A84776454B100CF16F653\
+                     92195A38B90523E2542EE61871C0440CB87C322FC4B4D2EC5E1E7EC766E1BE8D\
+                     4CE935437DC11C3C8FD426338933EBFE739CB3465F4D3668C5E473508253B1E6\
+                     82F65CBDC4FAE93C2EA212390E54905A86E2223170B44EAA7DA5DD9FFCFB7F3B");
+        assert_eq!(mod_pow(params.g.clone(), x.clone(), params.p.clone()), y);
+
+        let digest = biguint_to_fixed_bytes(&hex(digest), digest.len() / 2);
+        let sig = DsaSig::sign_digest_with_k(&params, digest.as_slice(), &x, &hex(k)).unwrap();
+        assert_eq!(sig, DsaSig { r: hex(r), s: hex(s) });
+        assert!(sig.verify_digest(&params, digest.as_slice(), &y));
+
+        let mut other = digest.clone();
+        *other.get_mut(0) ^= 1;
+        assert!(!sig.verify_digest(&params, other.as_slice(), &y));
+    }
+
+    // With SHA-1 the digest is as long as q and is used whole.
+    #[test]
+    fn rfc6979_sha1_sample() {
+        check_vector("8151325dcdbae9e0ff95f9f9658432dbedfdb209"
+                     , "7BDB6B0FF756E1BB5D53583EF979082F9AD5BD5B"
+                     , "2E1A0C2562B2912CAAF89186FB0F42001585DA55"
+                     , "29EFB6B0AFF2D7A68EB70CA313022253B9A88DF5");
+    }
+
+    // With SHA-256 only the leftmost 160 bits of the digest are used.
+    #[test]
+    fn rfc6979_sha256_sample() {
+        check_vector("af2bdbe1aa9b6ec1e2ade1d694f41fc71a831d0268e9891562113d8a62add1bf"
+                     , "519BA0546D0C39202A7D34D7DFA5E760B318BCFB"
+                     , "81F2F5850BE5BC123C43F71A3033E9384611C545"
+                     , "4CDD914B65EB6C66A8AAAD27299BEE6B035F5E89");
+    }
+}
@@ -0,0 +1,408 @@
+/*
+Elliptic curves y^2 = x^3 + a x + b over a prime field, selected by ID.
+All named curves have prime order q (cofactor 1), so any point on the
+curve other than infinity generates the whole group.
+
+gost-2001-test  GOST R 34.10-2001 test curve (RFC 5832, section 7)
+cryptopro-a     id-GostR3410-2001-CryptoPro-A-ParamSet (RFC 4357)
+p-256           NIST P-256 (FIPS 186-4, D.1.2.3), default
+
+Points are added in Jacobian coordinates, so a scalar multiplication
+needs a single field inversion. `mul_secret` is a Montgomery ladder
+that does one addition and one doubling per bit of q for every scalar,
+so the operation count doesn't give the scalar away the way
+double-and-add does. It is not constant time: the ladder branches on
+the scalar bits, the point formulas take shortcuts at infinity and for
+equal points, and BigUint arithmetic time depends on its operands.
+
+EC keys are library-only for now: key files, the keyring, the agent
+and the command line all handle DS and RSA keys, not curve points.
+*/
+use std::io::IoResult;
+use std::rand;
+use std::cmp::max;
+use std::num::from_str_radix;
+use num::Integer;
+use num::bigint::{BigUint, ToBigUint, RandBigInt};
+use std::num::{One, Zero};
+use utils::{mod_inverse, mod_sqrt, to_bits_le, byte_len, biguint_from_bytes
+            , biguint_to_fixed_bytes, invalid_input};
+
+
+pub static DEFAULT: &'static str = "p-256";
+
+
+struct NamedCurve {
+    id: &'static str,
+    p: &'static str,
+    a: &'static str,
+    b: &'static str,
+    q: &'static str,
+    x: &'static str,
+    y: &'static str
+}
+
+
+static NAMED: &'static [NamedCurve] = &[
+    NamedCurve {
+        id: "gost-2001-test",
+        p: "8000000000000000000000000000000000000000000000000000000000000431",
+        a: "7",
+        b: "5FBFF498AA938CE739B8E022FBAFEF40563F6E6A3472FC2A514C0CE9DAE23B7E",
+        q: "8000000000000000000000000000000150FE8A1892976154C59CFC193ACCF5B3",
+        x: "2",
+        y: "08E2A8A0E65147D4BD6316030E16D19C85C97F0A9CA267122B96ABBCEA7E8FC8"
+    },
+    NamedCurve {
+        id: "cryptopro-a",
+        p: "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFD97",
+        a: "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFD94",
+        b: "A6",
+        q: "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF6C611070995AD10045841B09B761B893",
+        x: "1",
+        y: "8D91E471E0989CDA27DF505A453F2B7635294F2DDF23E3B122ACC99C9E9F1E14"
+    },
+    NamedCurve {
+        id: "p-256",
+        p: "FFFFFFFF00000001000000000000000000000000FFFFFFFFFFFFFFFFFFFFFFFF",
+        a: "FFFFFFFF00000001000000000000000000000000FFFFFFFFFFFFFFFFFFFFFFFC",
+        b: "5AC635D8AA3A93E7B3EBBD55769886BC651D06B0CC53B0F63BCE3C3E27D2604B",
+        q: "FFFFFFFF00000000FFFFFFFFFFFFFFFFBCE6FAADA7179E84F3B9CAC2FC632551",
+        x: "6B17D1F2E12C4247F8BCE6E563A440F277037D812DEB33A0F4A13945D898C296",
+        y: "4FE342E2FE1A7F9B8EE7EB4A7C0F9E162BCE33576B315ECECBB6406837BF51F5"
+    },
+];
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum Point {
+    Infinity,
+    Affine(BigUint, BigUint)
+}
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct Curve {
+    pub id: &'static str,
+    pub p: BigUint,
+    pub a: BigUint,
+    pub b: BigUint,
+    pub q: BigUint,
+    pub g: Point
+}
+
+
+// (X, Y, Z) stands for (X / Z^2, Y / Z^3); Z = 0 is infinity.
+struct Jacobian {
+    x: BigUint,
+    y: BigUint,
+    z: BigUint
+}
+
+
+pub fn ids() -> Vec<&'static str> {
+    NAMED.iter().map(|c| c.id).collect()
+}
+
+
+pub fn default() -> Curve {
+    Curve::by_id(DEFAULT).unwrap()
+}
+
+
+impl Curve {
+    pub fn by_id(id: &str) -> IoResult<Curve> {
+        match NAMED.iter().find(|c| c.id == id) {
+            Some(c) => Ok(Curve {
+                id: c.id,
+                p: from_hex(c.p),
+                a: from_hex(c.a),
+                b: from_hex(c.b),
+                q: from_hex(c.q),
+                g: Affine(from_hex(c.x), from_hex(c.y))
+            }),
+            None => Err(invalid_input("ec: unknown curve")),
+        }
+    }
+
+    pub fn p_len(&self) -> uint {
+        byte_len(&self.p)
+    }
+
+    pub fn q_len(&self) -> uint {
+        byte_len(&self.q)
+    }
+
+    pub fn is_on_curve(&self, pt: &Point) -> bool {
+        match *pt {
+            Infinity => true,
+            Affine(ref x, ref y) => {
+                *x < self.p && *y < self.p
+                    && (*y * *y) % self.p == self.rhs(x)
+            },
+        }
+    }
+
+    pub fn neg(&self, pt: &Point) -> Point {
+        match *pt {
+            Infinity => Infinity,
+            Affine(ref x, ref y) => Affine(x.clone(), (self.p - *y) % self.p),
+        }
+    }
+
+    pub fn add(&self, p1: &Point, p2: &Point) -> Point {
+        self.to_affine(&self.jadd(&self.to_jacobian(p1), &self.to_jacobian(p2)))
+    }
+
+    pub fn double(&self, pt: &Point) -> Point {
+        self.to_affine(&self.jdouble(&self.to_jacobian(pt)))
+    }
+
+    // k * pt for a public scalar, by double-and-add.
+    pub fn mul(&self, k: &BigUint, pt: &Point) -> Point {
+        let base = self.to_jacobian(pt);
+        let mut acc = self.to_jacobian(&Infinity);
+        for bit in to_bits_le(k).iter().rev() {
+            acc = self.jdouble(&acc);
+            if *bit {
+                acc = self.jadd(&acc, &base);
+            }
+        }
+        self.to_affine(&acc)
+    }
+
+    // k * pt for a secret scalar by a Montgomery ladder. k is replaced
+    // by k + q or k + 2q, whichever is q.bits() + 1 bits long, so the
+    // ladder always starts from pt and 2 pt rather than at infinity and
+    // runs q.bits() steps whatever the length of k.
+    pub fn mul_secret(&self, k: &BigUint, pt: &Point) -> Point {
+        let n = self.q.bits();
+        let k = *k % self.q + self.q;
+        let k = if k.bits() > n { k } else { k + self.q };
+        let bits = to_bits_le(&k);
+        let mut r0 = self.to_jacobian(pt);
+        let mut r1 = self.jdouble(&r0);
+        for i in range(0, n).rev() {
+            let bit = bits[i];
+            let sum = self.jadd(&r0, &r1);
+            if bit {
+                r0 = sum;
+                r1 = self.jdouble(&r1);
+            } else {
+                r1 = sum;
+                r0 = self.jdouble(&r0);
+            }
+        }
+        self.to_affine(&r0)
+    }
+
+    // u1 * g + u2 * pt with one shared chain of doublings.
+    pub fn mul_add(&self, u1: &BigUint, u2: &BigUint, pt: &Point) -> Point {
+        let g = self.to_jacobian(&self.g);
+        let q = self.to_jacobian(pt);
+        let gq = self.jadd(&g, &q);
+        let b1 = to_bits_le(u1);
+        let b2 = to_bits_le(u2);
+
+        let mut acc = self.to_jacobian(&Infinity);
+        for i in range(0, max(b1.len(), b2.len())).rev() {
+            acc = self.jdouble(&acc);
+            match (i < b1.len() && b1[i], i < b2.len() && b2[i]) {
+                (true, true) => acc = self.jadd(&acc, &gq),
+                (true, false) => acc = self.jadd(&acc, &g),
+                (false, true) => acc = self.jadd(&acc, &q),
+                (false, false) => {},
+            }
+        }
+        self.to_affine(&acc)
+    }
+
+    pub fn random_scalar(&self) -> BigUint {
+        let one: BigUint = One::one();
+        rand::task_rng().gen_biguint_range(&one, &self.q)
+    }
+
+    pub fn public_key(&self, d: &BigUint) -> Point {
+        self.mul_secret(d, &self.g)
+    }
+
+    // SEC 1, 2.3.3: 04 || x || y, or 02 / 03 || x when compressed.
+    pub fn encode_point(&self, pt: &Point, compressed: bool) -> Vec<u8> {
+        match *pt {
+            Infinity => vec![0u8],
+            Affine(ref x, ref y) => {
+                let x_bytes = biguint_to_fixed_bytes(x, self.p_len());
+                if compressed {
+                    let mut res = vec![if y.is_even() { 2u8 } else { 3u8 }];
+                    res.push_all(x_bytes.as_slice());
+                    res
+                } else {
+                    let mut res = vec![4u8];
+                    res.push_all(x_bytes.as_slice());
+                    res.push_all(biguint_to_fixed_bytes(y, self.p_len()).as_slice());
+                    res
+                }
+            },
+        }
+    }
+
+    pub fn decode_point(&self, bytes: &[u8]) -> IoResult<Point> {
+        let len = self.p_len();
+        let pt = match bytes.head() {
+            Some(&4) if bytes.len() == 1 + 2 * len => {
+                Affine(biguint_from_bytes(bytes.slice(1, 1 + len))
+                       , biguint_from_bytes(bytes.slice_from(1 + len)))
+            },
+            Some(&t) if (t == 2 || t == 3) && bytes.len() == 1 + len => {
+                let x = biguint_from_bytes(bytes.slice_from(1));
+                if x >= self.p {
+                    return Err(invalid_input("ec: invalid point"));
+                }
+                let y = match mod_sqrt(&self.rhs(&x), &self.p) {
+                    Some(y) => y,
+                    None => return Err(invalid_input("ec: invalid point")),
+                };
+                if y.is_even() == (t == 2) {
+                    Affine(x, y)
+                } else {
+                    Affine(x, (self.p - y) % self.p)
+                }
+            },
+            _ => return Err(invalid_input("ec: invalid point encoding")),
+        };
+        if !self.is_on_curve(&pt) {
+            return Err(invalid_input("ec: point is not on the curve"));
+        }
+        Ok(pt)
+    }
+
+    // A public key must be a point on the curve other than infinity;
+    // with cofactor 1 that makes it a generator.
+    pub fn check_public_key(&self, pt: &Point) -> IoResult<()> {
+        match *pt {
+            Affine(..) if self.is_on_curve(pt) => Ok(()),
+            _ => Err(invalid_input("ec: invalid public key")),
+        }
+    }
+
+    fn rhs(&self, x: &BigUint) -> BigUint {
+        let p = &self.p;
+        ((*x * *x % *p + self.a) * *x + self.b) % *p
+    }
+
+    fn to_jacobian(&self, pt: &Point) -> Jacobian {
+        match *pt {
+            Infinity => Jacobian { x: One::one(), y: One::one(), z: Zero::zero() },
+            Affine(ref x, ref y) => Jacobian { x: x.clone(), y: y.clone(), z: One::one() },
+        }
+    }
+
+    fn to_affine(&self, pt: &Jacobian) -> Point {
+        if pt.z.is_zero() {
+            return Infinity;
+        }
+        let p = &self.p;
+        let z_inv = mod_inverse(&pt.z, p).unwrap();
+        let z_inv2 = z_inv * z_inv % *p;
+        Affine(pt.x * z_inv2 % *p, pt.y * z_inv2 % *p * z_inv % *p)
+    }
+
+    fn jdouble(&self, pt: &Jacobian) -> Jacobian {
+        let p = &self.p;
+        if pt.z.is_zero() || pt.y.is_zero() {
+            return self.to_jacobian(&Infinity);
+        }
+        let y2 = pt.y * pt.y % *p;
+        let s = small(4) * pt.x % *p * y2 % *p;
+        let z2 = pt.z * pt.z % *p;
+        let m = (small(3) * pt.x % *p * pt.x + self.a * (z2 * z2 % *p)) % *p;
+        let x3 = sub(&(m * m % *p), &(small(2) * s % *p), p);
+        let y3 = sub(&(m * sub(&s, &x3, p) % *p), &(small(8) * (y2 * y2 % *p) % *p), p);
+        let z3 = small(2) * pt.y % *p * pt.z % *p;
+        Jacobian { x: x3, y: y3, z: z3 }
+    }
+
+    fn jadd(&self, p1: &Jacobian, p2: &Jacobian) -> Jacobian {
+        let p = &self.p;
+        if p1.z.is_zero() {
+            return Jacobian { x: p2.x.clone(), y: p2.y.clone(), z: p2.z.clone() };
+        }
+        if p2.z.is_zero() {
+            return Jacobian { x: p1.x.clone(), y: p1.y.clone(), z: p1.z.clone() };
+        }
+        let z1z1 = p1.z * p1.z % *p;
+        let z2z2 = p2.z * p2.z % *p;
+        let u1 = p1.x * z2z2 % *p;
+        let u2 = p2.x * z1z1 % *p;
+        let s1 = p1.y * z2z2 % *p * p2.z % *p;
+        let s2 = p2.y * z1z1 % *p * p1.z % *p;
+        if u1 == u2 {
+            return if s1 == s2 { self.jdouble(p1) } else { self.to_jacobian(&Infinity) };
+        }
+
+        let h = sub(&u2, &u1, p);
+        let r = sub(&s2, &s1, p);
+        let h2 = h * h % *p;
+        let h3 = h2 * h % *p;
+        let u1h2 = u1 * h2 % *p;
+        let x3 = sub(&sub(&(r * r % *p), &h3, p), &(small(2) * u1h2 % *p), p);
+        let y3 = sub(&(r * sub(&u1h2, &x3, p) % *p), &(s1 * h3 % *p), p);
+        let z3 = h * p1.z % *p * p2.z % *p;
+        Jacobian { x: x3, y: y3, z: z3 }
+    }
+}
+
+
+fn sub(a: &BigUint, b: &BigUint, p: &BigUint) -> BigUint {
+    (*a + *p - *b) % *p
+}
+
+
+fn small(n: uint) -> BigUint {
+    n.to_biguint().unwrap()
+}
+
+
+fn from_hex(s: &str) -> BigUint {
+    from_str_radix(s, 16).unwrap()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num::bigint::{BigUint, ToBigUint};
+    use std::num::One;
+    use super::{Curve, Infinity, ids};
+
+    #[test]
+    fn generator_has_order_q() {
+        let one: BigUint = One::one();
+        for id in ids().into_iter() {
+            let curve = Curve::by_id(id).unwrap();
+            assert!(curve.is_on_curve(&curve.g), "{}: G is on the curve", id);
+            assert_eq!(curve.mul(&curve.q, &curve.g), Infinity);
+            assert!(curve.mul(&(curve.q - one), &curve.g) == curve.neg(&curve.g)
+                    , "{}: (q - 1) G = -G", id);
+        }
+    }
+
+    #[test]
+    fn mul_secret_matches_mul() {
+        for id in ids().into_iter() {
+            let curve = Curve::by_id(id).unwrap();
+            let one: BigUint = One::one();
+            let mut scalars = vec![0u.to_biguint().unwrap(), one.clone()
+                                   , 2u.to_biguint().unwrap(), curve.q - one, curve.q.clone()
+                                   , curve.q + one];
+            for _ in range(0u, 4) {
+                scalars.push(curve.random_scalar());
+            }
+            for k in scalars.iter() {
+                let expected = curve.mul(k, &curve.g);
+                assert!(curve.mul_secret(k, &curve.g) == expected, "{}: {} G", id, k);
+                assert!(curve.is_on_curve(&expected));
+            }
+            assert_eq!(curve.mul_secret(&one, &Infinity), Infinity);
+        }
+    }
+}
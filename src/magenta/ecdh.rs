@@ -0,0 +1,116 @@
+/*
+Elliptic-curve Diffie-Hellman over the named curves in `ec`. The shared
+value is the x coordinate of d * Q; Magenta keys are derived from it
+with HKDF-HMAC-Magenta, as in `dh`. Unlike DH, it is not yet used
+by hybrid encryption or the CLI.
+*/
+use std::io::IoResult;
+use std::slice::bytes::copy_memory;
+use num::bigint::BigUint;
+use ec::{Curve, Point, Affine, Infinity};
+use kdf::hkdf;
+use utils::{B16, biguint_to_fixed_bytes, invalid_input};
+
+
+static KDF_SALT: &'static [u8] = b"rust-magenta ecdh";
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct EcdhKeyPair {
+    pub d: BigUint,
+    pub pk: Point
+}
+
+
+impl EcdhKeyPair {
+    pub fn generate(curve: &Curve) -> EcdhKeyPair {
+        EcdhKeyPair::from_private(curve, curve.random_scalar())
+    }
+
+    pub fn from_private(curve: &Curve, d: BigUint) -> EcdhKeyPair {
+        let pk = curve.public_key(&d);
+        EcdhKeyPair { d: d, pk: pk }
+    }
+
+    pub fn agree(&self, curve: &Curve, peer: &Point) -> IoResult<BigUint> {
+        shared_secret(curve, &self.d, peer)
+    }
+}
+
+
+pub fn shared_secret(curve: &Curve, d: &BigUint, peer: &Point) -> IoResult<BigUint> {
+    try!(curve.check_public_key(peer));
+    match curve.mul_secret(d, peer) {
+        Affine(x, _) => Ok(x),
+        Infinity => Err(invalid_input("ecdh: degenerate shared secret")),
+    }
+}
+
+
+pub fn derive_key_material(curve: &Curve, z: &BigUint, info: &[u8], len: uint) -> Vec<u8> {
+    let z_bytes = biguint_to_fixed_bytes(z, curve.p_len());
+    hkdf(KDF_SALT, z_bytes.as_slice(), info, len)
+}
+
+
+pub fn derive_key(curve: &Curve, z: &BigUint, info: &[u8]) -> B16 {
+    let okm = derive_key_material(curve, z, info, 16);
+    let mut key: B16 = [0, ..16];
+    copy_memory(&mut key, okm.as_slice());
+    key
+}
+
+
+pub fn key_info(curve: &Curve, pk1: &Point, pk2: &Point) -> Vec<u8> {
+    let b1 = curve.encode_point(pk1, true);
+    let b2 = curve.encode_point(pk2, true);
+    let (lo, hi) = if b1 <= b2 { (b1, b2) } else { (b2, b1) };
+    let mut info = lo;
+    info.push_all(hi.as_slice());
+    info
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num::bigint::BigUint;
+    use std::num::One;
+    use ec::{Curve, Affine, Infinity, ids};
+    use super::{EcdhKeyPair, shared_secret, derive_key, key_info};
+
+    #[test]
+    fn both_sides_agree() {
+        for id in ids().into_iter() {
+            let curve = Curve::by_id(id).unwrap();
+            let a = EcdhKeyPair::generate(&curve);
+            let b = EcdhKeyPair::generate(&curve);
+            let z = a.agree(&curve, &b.pk).unwrap();
+            assert!(b.agree(&curve, &a.pk).unwrap() == z, "{}", id);
+
+            let info = key_info(&curve, &a.pk, &b.pk);
+            assert_eq!(info, key_info(&curve, &b.pk, &a.pk));
+            let key = derive_key(&curve, &z, info.as_slice());
+            assert!(key.as_slice() != derive_key(&curve, &z, b"other").as_slice());
+        }
+    }
+
+    #[test]
+    fn invalid_peer_points_are_rejected() {
+        let one: BigUint = One::one();
+        for id in ids().into_iter() {
+            let curve = Curve::by_id(id).unwrap();
+            let a = EcdhKeyPair::generate(&curve);
+            let b = EcdhKeyPair::generate(&curve);
+            let (x, y) = match b.pk {
+                Affine(x, y) => (x, y),
+                Infinity => fail!("public key at infinity"),
+            };
+            // Off the curve, the same point with unreduced coordinates,
+            // and the point at infinity.
+            let bad = [Affine(x.clone(), y + one), Affine(x + curve.p, y.clone()), Infinity];
+            for pt in bad.iter() {
+                assert!(shared_secret(&curve, &a.d, pt).is_err(), "{}: {}", id, pt);
+            }
+        }
+    }
+}
@@ -0,0 +1,203 @@
+/*
+Elliptic-curve signatures in the style of GOST R 34.10-2001 (RFC 5832),
+with the Magenta hash for the message digest.
+
+    e = H(m) mod q, or 1 if that is 0
+    C = k G, r = x_C mod q
+    s = r d + k e mod q
+
+The signature is s || r, each q_len bytes, as in GOST. It verifies when
+the x coordinate of (s v) G + (-r v) Q is r mod q, with v = e^-1 mod q.
+There is no key file format or CLI command for these signatures yet;
+see `ec`.
+*/
+use std::io::IoResult;
+use num::bigint::BigUint;
+use std::num::{One, Zero};
+use hash::{h_bytes, h_reader};
+use ec::{Curve, Point, Affine, Infinity};
+use utils::{B16, mod_inverse, biguint_from_bytes, biguint_to_fixed_bytes
+            , invalid_input};
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct EcSig {
+    pub r: BigUint,
+    pub s: BigUint
+}
+
+
+impl EcSig {
+    pub fn sign(curve: &Curve, msg: &[u8], d: &BigUint) -> EcSig {
+        EcSig::sign_prehashed(curve, &*h_bytes(msg), d)
+    }
+
+    pub fn sign_reader<R: Reader>(curve: &Curve, reader: &mut R, d: &BigUint) -> EcSig {
+        EcSig::sign_prehashed(curve, &*h_reader(reader), d)
+    }
+
+    pub fn sign_prehashed(curve: &Curve, digest: &B16, d: &BigUint) -> EcSig {
+        EcSig::sign_digest(curve, digest, d)
+    }
+
+    // Digests of any length, read as a big-endian integer.
+    pub fn sign_digest(curve: &Curve, digest: &[u8], d: &BigUint) -> EcSig {
+        loop {
+            let k = curve.random_scalar();
+            match EcSig::sign_digest_with_k(curve, digest, d, &k) {
+                Some(sig) => return sig,
+                None => {},
+            }
+        }
+    }
+
+    // Signs with the given per-message secret k, for known-answer tests
+    // such as RFC 5832, section 7.1. Returns None when r or s is zero.
+    pub fn sign_digest_with_k(curve: &Curve, digest: &[u8], d: &BigUint
+                              , k: &BigUint) -> Option<EcSig> {
+        let q = &curve.q;
+        let e = EcSig::calc_e(curve, digest);
+        let r = match curve.mul_secret(k, &curve.g) {
+            Affine(x, _) => x % *q,
+            Infinity => return None,
+        };
+        let s = ((r * (*d % *q)) % *q + (*k % *q) * e) % *q;
+        if r.is_zero() || s.is_zero() {
+            return None;
+        }
+        Some(EcSig { r: r, s: s })
+    }
+
+    pub fn verify(&self, curve: &Curve, msg: &[u8], pk: &Point) -> bool {
+        self.verify_prehashed(curve, &*h_bytes(msg), pk)
+    }
+
+    pub fn verify_reader<R: Reader>(&self, curve: &Curve, reader: &mut R
+                                    , pk: &Point) -> bool {
+        self.verify_prehashed(curve, &*h_reader(reader), pk)
+    }
+
+    pub fn verify_prehashed(&self, curve: &Curve, digest: &B16, pk: &Point) -> bool {
+        self.verify_digest(curve, digest, pk)
+    }
+
+    pub fn verify_digest(&self, curve: &Curve, digest: &[u8], pk: &Point) -> bool {
+        let q = &curve.q;
+        if self.check_range(curve).is_err() || curve.check_public_key(pk).is_err() {
+            return false;
+        }
+        let e = EcSig::calc_e(curve, digest);
+        let v = match mod_inverse(&e, q) {
+            Some(v) => v,
+            None => return false,
+        };
+        let z1 = self.s * v % *q;
+        let z2 = (*q - self.r * v % *q) % *q;
+        match curve.mul_add(&z1, &z2, pk) {
+            Affine(x, _) => x % *q == self.r,
+            Infinity => false,
+        }
+    }
+
+    pub fn to_bytes(&self, curve: &Curve) -> Vec<u8> {
+        let mut res = biguint_to_fixed_bytes(&self.s, curve.q_len());
+        res.push_all(biguint_to_fixed_bytes(&self.r, curve.q_len()).as_slice());
+        res
+    }
+
+    pub fn from_bytes(curve: &Curve, bytes: &[u8]) -> IoResult<EcSig> {
+        let q_len = curve.q_len();
+        if bytes.len() != 2 * q_len {
+            return Err(invalid_input("ecds: incorrect signature length"));
+        }
+        let sig = EcSig {
+            s: biguint_from_bytes(bytes.slice_to(q_len)),
+            r: biguint_from_bytes(bytes.slice_from(q_len))
+        };
+        try!(sig.check_range(curve));
+        Ok(sig)
+    }
+
+    pub fn private_key_to_bytes(curve: &Curve, d: &BigUint) -> Vec<u8> {
+        biguint_to_fixed_bytes(d, curve.q_len())
+    }
+
+    pub fn private_key_from_bytes(curve: &Curve, bytes: &[u8]) -> IoResult<BigUint> {
+        if bytes.len() != curve.q_len() {
+            return Err(invalid_input("ecds: incorrect private key length"));
+        }
+        let d = biguint_from_bytes(bytes);
+        if d.is_zero() || d >= curve.q {
+            return Err(invalid_input("ecds: private key out of range"));
+        }
+        Ok(d)
+    }
+
+    pub fn public_key_to_bytes(curve: &Curve, pk: &Point) -> Vec<u8> {
+        curve.encode_point(pk, true)
+    }
+
+    pub fn public_key_from_bytes(curve: &Curve, bytes: &[u8]) -> IoResult<Point> {
+        let pk = try!(curve.decode_point(bytes));
+        try!(curve.check_public_key(&pk));
+        Ok(pk)
+    }
+
+    pub fn gen_key(curve: &Curve) -> (BigUint, Point) {
+        let d = curve.random_scalar();
+        let pk = curve.public_key(&d);
+        (d, pk)
+    }
+
+    fn check_range(&self, curve: &Curve) -> IoResult<()> {
+        if self.r.is_zero() || self.r >= curve.q || self.s.is_zero() || self.s >= curve.q {
+            Err(invalid_input("ecds: signature out of range"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn calc_e(curve: &Curve, digest: &[u8]) -> BigUint {
+        let e = biguint_from_bytes(digest) % curve.q;
+        if e.is_zero() { One::one() } else { e }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num::bigint::BigUint;
+    use std::num::from_str_radix;
+    use ec::{Curve, Affine};
+    use utils::biguint_to_fixed_bytes;
+    use super::EcSig;
+
+    fn hex(s: &str) -> BigUint {
+        from_str_radix(s, 16).unwrap()
+    }
+
+    // RFC 5832, section 7.1, on the GOST R 34.10-2001 test curve.
+    #[test]
+    fn rfc5832_example() {
+        let curve = Curve::by_id("gost-2001-test").unwrap();
+        let d = hex("7A929ADE789BB9BE10ED359DD39A72C11B60961F49397EEE1D19CE9891EC3B28");
+        let pk = Affine(
+            hex("7F2B49E270DB6D90D8595BEC458B50C58585BA1D4E9B788F6689DBD8E56FD80B")
+            , hex("26F1B489D6701DD185C8413A977B3CBBAF64D1C593D26627DFFB101A87FF77DA"));
+        let e = hex("2DFBC1B372D89A1188C09C52E0EEC61FCE52032AB1022E8E67ECE6672B043EE5");
+        let k = hex("77105C9B20BCD3122823C8CF6FCC7B956DE33814E95B7FE64FED924594DCEAB3");
+        assert_eq!(curve.public_key(&d), pk);
+
+        let digest = biguint_to_fixed_bytes(&e, curve.q_len());
+        let sig = EcSig::sign_digest_with_k(&curve, digest.as_slice(), &d, &k).unwrap();
+        assert_eq!(sig, EcSig {
+            r: hex("41AA28D2F1AB148280CD9ED56FEDA41974053554A42767B83AD043FD39DC0493"),
+            s: hex("01456C64BA4642A1653C235A98A60249BCD6D3F746B631DF928014F6C5BF9C40")
+        });
+        assert!(sig.verify_digest(&curve, digest.as_slice(), &pk));
+
+        let mut other = digest.clone();
+        *other.get_mut(31) ^= 1;
+        assert!(!sig.verify_digest(&curve, other.as_slice(), &pk));
+    }
+}
@@ -0,0 +1,182 @@
+/*
+Key fingerprints and key IDs.
+
+A fingerprint is the Magenta hash of a type tag and the canonical
+encoding of a public key, so keys of different kinds never share one:
+
+ds   "ds" || 0 || SEQUENCE { parameters, y }   (`DigSig::public_key_to_der`)
+rsa  "rsa" || 0 || RSAPublicKey (PKCS #1)
+ec   "ec" || 0 || curve id || 0 || compressed point
+pbc  "pbc" || 0 || key
+
+A pbc key is secret, so its fingerprint only serves as a check value
+that names the key in the keyring; it is never written next to data.
+
+The key ID is the last 8 bytes of the fingerprint. It is written as 16
+hex digits in a Key-Id armor header, or a "Key-Id: <hex>" line in text
+files, and is what key lookups match on; the full fingerprint is for
+comparing keys by eye.
+*/
+use std::io::{File, IoResult};
+use std::io::fs::readdir;
+use std::slice::bytes::copy_memory;
+use num::bigint::BigUint;
+use serialize::hex::{ToHex, FromHex};
+use hash::h_bytes;
+use armor::{Armored, is_armored, VERIFICATION_KEY};
+use ds::{DigSig, DsParams};
+use rsa::RsaPublicKey;
+use ec::{Curve, Point};
+use utils::{B8, B16, invalid_input};
+
+
+pub type KeyId = B8;
+
+
+pub static KEY_ID_HEADER: &'static str = "Key-Id";
+
+
+pub fn ds_fingerprint(params: &DsParams, y: &BigUint) -> B16 {
+    tagged_hash("ds", DigSig::public_key_to_der(params, y).as_slice())
+}
+
+
+pub fn rsa_fingerprint(key: &RsaPublicKey) -> B16 {
+    tagged_hash("rsa", key.to_der().as_slice())
+}
+
+
+pub fn ec_fingerprint(curve: &Curve, pk: &Point) -> B16 {
+    let mut data = curve.id.as_bytes().to_vec();
+    data.push(0);
+    data.push_all(curve.encode_point(pk, true).as_slice());
+    tagged_hash("ec", data.as_slice())
+}
+
+
+pub fn pbc_fingerprint(key: &B16) -> B16 {
+    tagged_hash("pbc", key)
+}
+
+
+pub fn key_id(fingerprint: &B16) -> KeyId {
+    let mut id = [0u8, ..8];
+    copy_memory(&mut id, fingerprint.slice_from(8));
+    id
+}
+
+
+pub fn ds_key_id(params: &DsParams, y: &BigUint) -> KeyId {
+    key_id(&ds_fingerprint(params, y))
+}
+
+
+// Groups of four hex digits: "1a2b 3c4d ...".
+pub fn format_fingerprint(fingerprint: &B16) -> String {
+    let hex = fingerprint.to_hex();
+    let mut res = String::new();
+    for (i, c) in hex.as_slice().chars().enumerate() {
+        if i > 0 && i % 4 == 0 {
+            res.push(' ');
+        }
+        res.push(c);
+    }
+    res
+}
+
+
+pub fn format_key_id(id: &KeyId) -> String {
+    id.to_hex()
+}
+
+
+pub fn parse_key_id(s: &str) -> IoResult<KeyId> {
+    let bytes = match s.trim().from_hex() {
+        Ok(b) => b,
+        Err(_) => return Err(invalid_input("fingerprint: key ID is not hex")),
+    };
+    if bytes.len() != 8 {
+        return Err(invalid_input("fingerprint: key ID must be 16 hex digits"));
+    }
+    let mut id = [0u8, ..8];
+    copy_memory(&mut id, bytes.as_slice());
+    Ok(id)
+}
+
+
+pub fn add_header(armored: &mut Armored, id: &KeyId) {
+    armored.add_header(KEY_ID_HEADER, format_key_id(id).as_slice());
+}
+
+
+pub fn from_header(armored: &Armored) -> IoResult<Option<KeyId>> {
+    match armored.header(KEY_ID_HEADER) {
+        Some(s) => parse_key_id(s).map(|id| Some(id)),
+        None => Ok(None),
+    }
+}
+
+
+pub fn text_line(id: &KeyId) -> String {
+    format!("{}: {}", KEY_ID_HEADER, format_key_id(id))
+}
+
+
+// Removes the "Key-Id: <hex>" line from the lines of a text file, if
+// there is one. Files written before key IDs have none.
+pub fn take_text_line(lines: &mut Vec<&str>) -> IoResult<Option<KeyId>> {
+    let pos = lines.iter().position(|l| l.trim().starts_with(KEY_ID_HEADER));
+    match pos {
+        Some(i) => {
+            let line = lines.remove(i).unwrap().trim();
+            let value = line.slice_from(KEY_ID_HEADER.len()).trim_left_chars(':');
+            parse_key_id(value).map(|id| Some(id))
+        },
+        None => Ok(None),
+    }
+}
+
+
+// Looks through a directory for the DS verification key with the given
+// ID: armored verification keys and text keys in ".dsok" files. Files
+// that can't be read as keys are skipped.
+pub fn find_ds_key(dir: &Path, id: &KeyId) -> IoResult<Option<(DsParams, BigUint)>> {
+    let mut paths = try!(readdir(dir));
+    paths.sort();
+    for path in paths.iter() {
+        if !path.is_file() {
+            continue;
+        }
+        let content = match File::open(path).and_then(|mut f| f.read_to_string()) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+        let is_public = if is_armored(content.as_bytes()) {
+            match Armored::decode(content.as_slice()) {
+                Ok(a) => a.label.as_slice() == VERIFICATION_KEY,
+                Err(_) => false,
+            }
+        } else {
+            path.extension_str() == Some("dsok")
+        };
+        if !is_public {
+            continue;
+        }
+        match DigSig::key_from_str(content.as_slice()) {
+            Ok((params, y)) => if ds_key_id(&params, &y) == *id {
+                return Ok(Some((params, y)));
+            },
+            Err(_) => {},
+        }
+    }
+    Ok(None)
+}
+
+
+fn tagged_hash(tag: &str, encoding: &[u8]) -> B16 {
+    let mut data = tag.as_bytes().to_vec();
+    data.push(0);
+    data.push_all(encoding);
+    let box h = h_bytes(data.as_slice());
+    h
+}
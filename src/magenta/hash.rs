@@ -1,54 +1,364 @@
-use std::io::{File, EndOfFile};
-use utils::{B16, fill_with_end_bits, is_file_size_can_fit, fill_with_file_size
-            , xor_array_16};
+/*
+Hash functions built from Magenta in Merkle–Damgård mode, chosen by
+name. All pad the message with a 1 bit, zeros and the 64-bit message
+length in bytes, in 16-byte blocks m_i. E_k is Magenta keyed with k.
+
+magenta  h_i = E_{m_i}(m_i ^ h_{i-1}) ^ m_i ^ h_{i-1}, h_0 = 0   (`h_reader`)
+dm       Davies–Meyer:         h_i = E_{m_i}(h_{i-1}) ^ h_{i-1}
+mmo      Matyas–Meyer–Oseas:   h_i = E_{h_{i-1}}(m_i) ^ m_i
+mp       Miyaguchi–Preneel:    h_i = E_{h_{i-1}}(m_i) ^ m_i ^ h_{i-1}
+hirose   Hirose's double-block-length hash, with Magenta's 256-bit key:
+             g_i = E_{h_{i-1} || m_i}(g_{i-1}) ^ g_{i-1}
+             h_i = E_{h_{i-1} || m_i}(g_{i-1} ^ c) ^ g_{i-1} ^ c
+         and the digest g || h
+
+The 128-bit digests are only 64-bit collision resistant; hirose gives a
+256-bit digest. dm, mmo and mp start from IV, hirose from (IV, IV2),
+the fractional digits of pi; c is 1.
+*/
+use std::io::{File, BufReader, IoResult};
+use serialize::hex::{ToHex, FromHex};
+use utils::{B16, B32, fill_with_end_bits, is_file_size_can_fit, fill_with_size
+            , xor_array_16, read_block, invalid_input};
 use magenta::Magenta;
 
 
+static IV: B16 = [0x24, 0x3f, 0x6a, 0x88, 0x85, 0xa3, 0x08, 0xd3
+                  , 0x13, 0x19, 0x8a, 0x2e, 0x03, 0x70, 0x73, 0x44];
+static IV2: B16 = [0xa4, 0x09, 0x38, 0x22, 0x29, 0x9f, 0x31, 0xd0
+                   , 0x08, 0x2e, 0xfa, 0x98, 0xec, 0x4e, 0x6c, 0x89];
+static HIROSE_C: B16 = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum Construction {
+    Original,
+    DaviesMeyer,
+    MatyasMeyerOseas,
+    MiyaguchiPreneel,
+    Hirose
+}
+
+
+impl Construction {
+    pub fn by_name(name: &str) -> IoResult<Construction> {
+        match name {
+            "magenta" => Ok(Original),
+            "dm" => Ok(DaviesMeyer),
+            "mmo" => Ok(MatyasMeyerOseas),
+            "mp" => Ok(MiyaguchiPreneel),
+            "hirose" => Ok(Hirose),
+            _ => Err(invalid_input("hash: unknown hash function")),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Original => "magenta",
+            DaviesMeyer => "dm",
+            MatyasMeyerOseas => "mmo",
+            MiyaguchiPreneel => "mp",
+            Hirose => "hirose",
+        }
+    }
+
+    // Digest length in bytes.
+    pub fn len(&self) -> uint {
+        match *self {
+            Hirose => 32,
+            _ => 16,
+        }
+    }
+}
+
+
 pub fn h_file(file: &mut File) -> Box<B16> {
+    h_reader(file)
+}
+
+
+pub fn h_bytes(data: &[u8]) -> Box<B16> {
+    h_reader(&mut BufReader::new(data))
+}
+
+
+pub fn h_reader<R: Reader>(reader: &mut R) -> Box<B16> {
+    let mut h = [0u8, ..16];
+    md_blocks(reader, |m| {
+        let box next = h_func(m, &h);
+        h = next;
+    });
+    box h
+}
+
+
+pub fn hash_bytes(construction: Construction, data: &[u8]) -> Vec<u8> {
+    hash_reader(construction, &mut BufReader::new(data))
+}
+
+
+pub fn hash_reader<R: Reader>(construction: Construction, reader: &mut R) -> Vec<u8> {
+    match construction {
+        Original => {
+            let box h = h_reader(reader);
+            h.to_vec()
+        },
+        Hirose => {
+            let (mut g, mut h) = (IV, IV2);
+            md_blocks(reader, |m| {
+                let (next_g, next_h) = hirose(&g, &h, m);
+                g = next_g;
+                h = next_h;
+            });
+            let mut res = g.to_vec();
+            res.push_all(h.as_slice());
+            res
+        },
+        c => {
+            let mut h = IV;
+            md_blocks(reader, |m| h = single_block(c, &h, m));
+            h.to_vec()
+        },
+    }
+}
+
+
+// A checksum file line, as written by sha256sum and friends:
+// "<hex digest>  <path>".
+pub fn format_checksum(digest: &[u8], path: &str) -> String {
+    format!("{}  {}", digest.to_hex(), path)
+}
+
+
+// Also takes the " *" binary mode marker in place of the second space.
+pub fn parse_checksum(line: &str) -> IoResult<(Vec<u8>, String)> {
+    let bytes = line.as_bytes();
+    let i = match line.find(' ') {
+        Some(i) if i > 0 && bytes.len() > i + 2
+            && (bytes[i + 1] == b' ' || bytes[i + 1] == b'*') => i,
+        _ => return Err(invalid_input("hash: incorrect checksum line")),
+    };
+    match line.slice_to(i).from_hex() {
+        Ok(digest) => Ok((digest, line.slice_from(i + 2).to_string())),
+        Err(_) => Err(invalid_input("hash: incorrect checksum line")),
+    }
+}
+
+
+// Feeds the padded message to `f` one block at a time.
+fn md_blocks<R: Reader>(reader: &mut R, f: |&B16|) {
     let buf_len = 16;
-    let h0: Box<B16> = box [0, ..16];
-    let mut h = h0;
-    let mut buf: B16;
-    let mut is_filled = false;
-    let mut byte_count: uint;
+    let mut byte_total: u64 = 0;
 
     loop {
-        buf = [0, ..16];
-        match file.read(buf) {
-            Err(e) => {
-                if e.kind == EndOfFile && is_filled {
-                    break;
-                } else if is_filled {
-                    fail!("h_file: {}", e.desc);
-                } else {
-                    byte_count = 0;
-                }
-            },
-            Ok(i) => { byte_count = i },
+        let mut buf = [0u8, ..16];
+        let byte_count = match read_block(reader, &mut buf) {
+            Err(e) => fail!("h_reader: {}", e.desc),
+            Ok(i) => i,
         };
+        byte_total += byte_count as u64;
 
         if byte_count < buf_len {
             fill_with_end_bits(buf.slice_from_mut(byte_count));
 
             if !is_file_size_can_fit(byte_count, buf_len) {
-                h = h_func(&buf, &*h);
-                buf = [0, ..16];
+                f(&buf);
+                buf = [0u8, ..16];
             }
-            fill_with_file_size(file, buf);
-            is_filled = true;
+            fill_with_size(&mut buf, byte_total);
+            f(&buf);
+            return;
         }
 
-        h = h_func(&buf, &*h);
+        f(&buf);
     }
-
-    h
 }
 
 
-fn h_func(buf: &B16, h: &B16) -> Box<B16> {
+// The compression function of the magenta hash: message block `buf`
+// and chaining value `h`, without any padding.
+pub fn h_func(buf: &B16, h: &B16) -> Box<B16> {
     let a = buf;
     let box b = xor_array_16(buf, &*h);
     let box e = Magenta::new_128(a).enc(&b);
     let c = &b;
     xor_array_16(&e, c)
 }
+
+
+fn single_block(construction: Construction, h: &B16, m: &B16) -> B16 {
+    let res = match construction {
+        DaviesMeyer => xor_array_16(&*Magenta::new_128(m).enc(h), h),
+        MatyasMeyerOseas => xor_array_16(&*Magenta::new_128(h).enc(m), m),
+        MiyaguchiPreneel => {
+            let box e = Magenta::new_128(h).enc(m);
+            xor_array_16(&*xor_array_16(&e, m), h)
+        },
+        _ => unreachable!(),
+    };
+    *res
+}
+
+
+fn hirose(g: &B16, h: &B16, m: &B16) -> (B16, B16) {
+    let mut key: B32 = [0u8, ..32];
+    for i in range(0u, 16) {
+        key[i] = h[i];
+        key[i + 16] = m[i];
+    }
+    let cipher = Magenta::new_256(&key);
+    let box gc = xor_array_16(g, &HIROSE_C);
+    let box next_g = xor_array_16(&*cipher.enc(g), g);
+    let box next_h = xor_array_16(&*cipher.enc(&gc), &gc);
+    (next_g, next_h)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serialize::hex::ToHex;
+    use magenta::Magenta;
+    use utils::{B16, xor_array_16};
+    use super::{Construction, Original, DaviesMeyer, MatyasMeyerOseas, MiyaguchiPreneel, Hirose
+                , h_bytes, hash_bytes, h_func, IV, IV2, HIROSE_C};
+
+    // Digests of the bytes 0, 1, 2, ... of each length: empty, one
+    // byte, 8 and 9 (the longest tail that leaves room for the length in
+    // its block and the shortest that doesn't), and around one and two
+    // blocks.
+    static LENGTHS: [uint, ..8] = [0, 1, 8, 9, 15, 16, 17, 32];
+
+    fn check(construction: Construction, digests: [&str, ..8]) {
+        for (&len, &digest) in LENGTHS.iter().zip(digests.iter()) {
+            let data: Vec<u8> = range(0, len).map(|i| i as u8).collect();
+            let h = hash_bytes(construction, data.as_slice());
+            assert_eq!(h.len(), construction.len());
+            assert!(h.as_slice().to_hex().as_slice() == digest, "{} {}", construction, len);
+        }
+    }
+
+    #[test]
+    fn magenta_vectors() {
+        check(Original, ["890fa0c21bc9f0cdd3c58f857264ce94"
+                       , "575238cb14159f98e4684fa73f985e29"
+                       , "30b89f14b18a6e8c319a8f76533c531f"
+                       , "3d5aae7c89f2fc33f7ca667482bd7db7"
+                       , "fd5c31f0336f99cab38a42d16cf28b16"
+                       , "a070dc8be8c4b45c9ec2e3767a7a58d0"
+                       , "c5d87db2e15f0df55aa0447e089c2ff6"
+                       , "9997f0cbe9b5a80132e9ae982fc0238e"]);
+    }
+
+    #[test]
+    fn davies_meyer_vectors() {
+        check(DaviesMeyer, ["c96d4b370199471af0a030374e85060b"
+                          , "5baa3265cde61fdf536f35522e7cd14c"
+                          , "3f27f82da52c90de8388e7b7ce8ba2d9"
+                          , "d721fe8e95a1191355e5d72d7e02729e"
+                          , "c676c94a32078c038133d4bcfc25d55c"
+                          , "265b7ac591b11434bb5bf0ad6abba898"
+                          , "9fa69b47975e34de17b2c35653ac95c4"
+                          , "d6d38e77b1f811b35fbb96642050adb8"]);
+    }
+
+    #[test]
+    fn matyas_meyer_oseas_vectors() {
+        check(MatyasMeyerOseas, ["e74f7c82cfa2fde3d05e15c3d4f02504"
+                               , "bab7bc195ab7a2e879e48c713639b02d"
+                               , "9c532b70b3b43eb173f417665ee0219e"
+                               , "53a270b0e3fb4cd71f023ffec10ebcf6"
+                               , "ba77cc7b30817cb51da962eae8deaefa"
+                               , "688cab1a9cee1b2732d75a45c0705229"
+                               , "06f6f5e2c9c3796a5ea2c32d46a5bbcb"
+                               , "4f35737f8a2df676a1fd75e83834e34b"]);
+    }
+
+    #[test]
+    fn miyaguchi_preneel_vectors() {
+        check(MiyaguchiPreneel, ["c370160a4a01f530c3479fedd7805640"
+                               , "9e88d691df14aa3b6afd065f3549c369"
+                               , "b86c41f83617366260ed9d485d9052da"
+                               , "4de5efe84e508127078a69b0204d8956"
+                               , "d8666a4f28fc795585fff7aeccd9a5c1"
+                               , "c7c4a9e5d53f83e50590d614cf901eb0"
+                               , "9c0973e401a21220aa2470fd4bda1b16"
+                               , "e5a1f003bdaba192745815d0fcf65fec"]);
+    }
+
+    #[test]
+    fn hirose_vectors() {
+        check(Hirose, ["705c79f32305fb32044988a7d9ddd082fe68146197e624bc99a53de1c3608d3b"
+                     , "4f5be1b21cb79c34450242cccc8ed9395f9608c78078eb21c861c9a3e63a07cd"
+                     , "626252381f5e4cd0c18dbc21dddb7037fb3703c78e866e4f3b6958bdd05cf8f0"
+                     , "fc76a546ad8bbfd5bc2fa6aaa9306fc8874813a83b621227cf15951072b34fa4"
+                     , "db2b73e563874adf55aec466d5ad2c0387e69911992598604f91e6b2a570785a"
+                     , "9eb9d30518d354415439d4e42d83fc8877a97161c6e54bb2395385a65e9d3c1a"
+                     , "3dee1a65a6b4363ea3988ccb1926c0311926bb26520265ffb4afa4fdd750647d"
+                     , "a611aa18a40d5140c822a31b0dbe026e9356501c55d9482757342ee9a7fd8880"]);
+    }
+
+    // "abc" padded to one block: the 0x40 marker, zeros and the length in
+    // the last 8 bytes.
+    fn abc_block() -> B16 {
+        let mut m = [0u8, ..16];
+        m[0] = b'a';
+        m[1] = b'b';
+        m[2] = b'c';
+        m[3] = 0x40;
+        m[15] = 3;
+        m
+    }
+
+    fn xor(a: &B16, b: &B16) -> B16 {
+        *xor_array_16(a, b)
+    }
+
+    fn enc(k: &B16, m: &B16) -> B16 {
+        *Magenta::new_128(k).enc(m)
+    }
+
+    #[test]
+    fn one_block_by_hand() {
+        let m = abc_block();
+        let zero = [0u8, ..16];
+        let cases = [(Original, xor(&enc(&m, &m), &m))
+                     , (DaviesMeyer, xor(&enc(&m, &IV), &IV))
+                     , (MatyasMeyerOseas, xor(&enc(&IV, &m), &m))
+                     , (MiyaguchiPreneel, xor(&xor(&enc(&IV, &m), &m), &IV))];
+        for &(construction, ref expected) in cases.iter() {
+            let h = hash_bytes(construction, b"abc");
+            assert!(h.as_slice() == expected.as_slice(), "{}", construction);
+        }
+        assert_eq!(hash_bytes(Original, b"abc").as_slice(), h_func(&m, &zero).as_slice());
+    }
+
+    #[test]
+    fn hirose_block_by_hand() {
+        let mut c = [0u8, ..16];
+        c[15] = 1;
+        assert_eq!(HIROSE_C.as_slice(), c.as_slice());
+
+        let m = abc_block();
+        let mut key = [0u8, ..32];
+        for i in range(0u, 16) {
+            key[i] = IV2[i];
+            key[i + 16] = m[i];
+        }
+        let cipher = Magenta::new_256(&key);
+        let gc = xor(&IV, &c);
+        let g = xor(&*cipher.enc(&IV), &IV);
+        let h = xor(&*cipher.enc(&gc), &gc);
+
+        let digest = hash_bytes(Hirose, b"abc");
+        assert_eq!(digest.slice_to(16), g.as_slice());
+        assert_eq!(digest.slice_from(16), h.as_slice());
+        // Without c both halves would be the same.
+        assert!(g != h);
+    }
+
+    #[test]
+    fn original_is_h_bytes() {
+        let data = b"rust-magenta";
+        assert_eq!(hash_bytes(Original, data), h_bytes(data).to_vec());
+    }
+}
@@ -0,0 +1,305 @@
+/*
+Hybrid public-key encryption to one or more DS public keys (DHIES style).
+
+A random content key is wrapped for every recipient: an ephemeral
+E = g^e in the recipient's group gives the shared value y^e, HKDF turns
+it into a 32-byte key and `seal` wraps the content key with it. Header:
+
+"MGHY" || version (3) || recipient count (2)
+    || { key ID (8) || id length (1) || parameter set id || E (|p| bytes)
+         || wrapped length (2) || wrapped key } * count
+
+The key ID (see `fingerprint`) names the recipient of each slot, so a
+decrypting key only tries its own. The header is followed by the
+content sealed (encrypt-then-MAC, see `seal`) with the header as
+associated data, under encryption and MAC keys derived from the content
+key with HKDF. Changing a header field or a ciphertext byte, or cutting
+the file short, makes decryption fail before any plaintext is written;
+the content is held in memory for that. Versions 1 and 2, whose content
+was bare PBC without a MAC, are no longer read.
+*/
+use std::io::{IoResult, BufReader, MemWriter};
+use std::str;
+use std::slice::bytes::copy_memory;
+use num::bigint::BigUint;
+use ds::DsParams;
+use params::by_id;
+use fingerprint::{KeyId, ds_key_id};
+use dh::{DhKeyPair, shared_secret, derive_key_material};
+use kdf::hkdf;
+use seal::{seal, open, seal_with_ad, open_with_ad};
+use pbc::PBC;
+use utils::{B16, B32, biguint_from_bytes, biguint_to_fixed_bytes, invalid_input};
+
+
+static MAGIC: &'static [u8] = b"MGHY";
+static VERSION: u8 = 3;
+static CONTENT_INFO: &'static [u8] = b"rust-magenta hybrid content";
+
+
+pub fn is_hybrid(head: &[u8]) -> bool {
+    head.starts_with(MAGIC)
+}
+
+
+pub fn enc_stream<R: Reader, W: Writer>(recipients: &[(DsParams, BigUint)]
+                                        , reader: &mut R, writer: &mut W) -> IoResult<u64> {
+    if recipients.is_empty() || recipients.len() > 0xffff {
+        return Err(invalid_input("hybrid: bad recipient count"));
+    }
+    let cek = PBC::gen_key();
+
+    let mut slots = Vec::with_capacity(recipients.len());
+    for &(ref params, ref y) in recipients.iter() {
+        if !params.is_group_element(y) {
+            return Err(invalid_input("hybrid: recipient key is not in the group"));
+        }
+        let eph = DhKeyPair::generate(params);
+        let z = try!(eph.agree(params, y));
+        let box kek = wrapping_key(params, &z, &eph.y, y);
+        let wrapped = seal(&kek, &cek);
+
+        slots.push(Slot {
+            key_id: Some(ds_key_id(params, y)),
+            params: params.clone(),
+            e: eph.y,
+            wrapped: wrapped
+        });
+    }
+    let header = try!(header_bytes(slots.as_slice()));
+    let plaintext = try!(reader.read_to_end());
+    try!(writer.write(header.as_slice()));
+    let box key = content_key(&cek);
+    try!(writer.write(seal_with_ad(&key, header.as_slice(), plaintext.as_slice()).as_slice()));
+    Ok(plaintext.len() as u64)
+}
+
+
+pub fn dec_stream<R: Reader, W: Writer>(params: &DsParams, x: &BigUint
+                                        , reader: &mut R, writer: &mut W) -> IoResult<u64> {
+    let slots = try!(read_header(reader));
+    let own_id = ds_key_id(params, &DhKeyPair::from_private(params, x.clone()).y);
+    let mut cek: Option<B16> = None;
+    for slot in slots.iter() {
+        if slot.key_id.map_or(true, |id| id == own_id) && slot.params == *params {
+            cek = slot.unwrap(x);
+            if cek.is_some() {
+                break;
+            }
+        }
+    }
+
+    match cek {
+        Some(k) => dec_content(&k, slots.as_slice(), reader, writer),
+        None => Err(invalid_input("hybrid: not encrypted to this key")),
+    }
+}
+
+
+// Reads the whole header, so the content starts right after it.
+pub fn read_header<R: Reader>(reader: &mut R) -> IoResult<Vec<Slot>> {
+    if try!(reader.read_exact(MAGIC.len())).as_slice() != MAGIC {
+        return Err(invalid_input("hybrid: not a public-key encrypted file"));
+    }
+    if try!(reader.read_u8()) != VERSION {
+        return Err(invalid_input("hybrid: unsupported version"));
+    }
+    let count = try!(reader.read_be_u16()) as uint;
+
+    let mut slots = Vec::with_capacity(count);
+    for _ in range(0, count) {
+        let mut id = [0u8, ..8];
+        copy_memory(&mut id, try!(reader.read_exact(8)).as_slice());
+        slots.push(try!(Slot::read_body(reader, Some(id))));
+    }
+    Ok(slots)
+}
+
+
+// The header as written before the content, the associated data of the
+// sealed content.
+pub fn header_bytes(slots: &[Slot]) -> IoResult<Vec<u8>> {
+    if slots.is_empty() || slots.len() > 0xffff {
+        return Err(invalid_input("hybrid: bad recipient count"));
+    }
+    let mut writer = MemWriter::new();
+    try!(writer.write(MAGIC));
+    try!(writer.write_u8(VERSION));
+    try!(writer.write_be_u16(slots.len() as u16));
+    for slot in slots.iter() {
+        match slot.key_id {
+            Some(id) => try!(writer.write(id.as_slice())),
+            None => return Err(invalid_input("hybrid: slot without a key ID")),
+        }
+        try!(slot.write_body(&mut writer));
+    }
+    Ok(writer.unwrap())
+}
+
+
+// Checks the content after a header of `slots` and decrypts it with the
+// content key, writing nothing unless it is intact.
+pub fn dec_content<R: Reader, W: Writer>(cek: &B16, slots: &[Slot], reader: &mut R
+                                         , writer: &mut W) -> IoResult<u64> {
+    let header = try!(header_bytes(slots));
+    let sealed = try!(reader.read_to_end());
+    let box key = content_key(cek);
+    let plaintext = match open_with_ad(&key, header.as_slice(), sealed.as_slice()) {
+        Ok(p) => p,
+        Err(_) => return Err(invalid_input("hybrid: the file was modified or corrupted")),
+    };
+    try!(writer.write(plaintext.as_slice()));
+    Ok(plaintext.len() as u64)
+}
+
+
+#[deriving(Clone)]
+pub struct Slot {
+    pub key_id: Option<KeyId>,
+    pub params: DsParams,
+    pub e: BigUint,
+    pub wrapped: Vec<u8>
+}
+
+
+impl Slot {
+    // Everything in a slot after the key ID.
+    pub fn read_body<R: Reader>(reader: &mut R, key_id: Option<KeyId>) -> IoResult<Slot> {
+        let id_len = try!(reader.read_u8()) as uint;
+        let id = try!(reader.read_exact(id_len));
+        let params = match str::from_utf8(id.as_slice()).and_then(by_id) {
+            Some(p) => p,
+            None => return Err(invalid_input("hybrid: unknown parameter set")),
+        };
+        let e_bytes = try!(reader.read_exact(params.p_len()));
+        let wrapped_len = try!(reader.read_be_u16()) as uint;
+        let wrapped = try!(reader.read_exact(wrapped_len));
+        Ok(Slot {
+            key_id: key_id,
+            params: params,
+            e: biguint_from_bytes(e_bytes.as_slice()),
+            wrapped: wrapped
+        })
+    }
+
+    pub fn write_body<W: Writer>(&self, writer: &mut W) -> IoResult<()> {
+        let id = self.params.id();
+        try!(writer.write_u8(id.len() as u8));
+        try!(writer.write(id.as_bytes()));
+        try!(writer.write(biguint_to_fixed_bytes(&self.e, self.params.p_len()).as_slice()));
+        try!(writer.write_be_u16(self.wrapped.len() as u16));
+        writer.write(self.wrapped.as_slice())
+    }
+
+    // The content key, if the slot was made for the private key x.
+    pub fn unwrap(&self, x: &BigUint) -> Option<B16> {
+        let params = &self.params;
+        let z = match shared_secret(params, x, &self.e) {
+            Ok(z) => z,
+            Err(_) => return None,
+        };
+        let y = DhKeyPair::from_private(params, x.clone()).y;
+        let box kek = wrapping_key(params, &z, &self.e, &y);
+        match open(&kek, self.wrapped.as_slice()) {
+            Ok(ref k) => PBC::key_from_bytes(k.as_slice()).ok(),
+            Err(_) => None,
+        }
+    }
+}
+
+
+pub fn enc_bytes(recipients: &[(DsParams, BigUint)], data: &[u8]) -> IoResult<Vec<u8>> {
+    let mut writer = MemWriter::new();
+    try!(enc_stream(recipients, &mut BufReader::new(data), &mut writer));
+    Ok(writer.unwrap())
+}
+
+
+pub fn dec_bytes(params: &DsParams, x: &BigUint, data: &[u8]) -> IoResult<Vec<u8>> {
+    let mut writer = MemWriter::new();
+    try!(dec_stream(params, x, &mut BufReader::new(data), &mut writer));
+    Ok(writer.unwrap())
+}
+
+
+// Encryption and MAC keys for the content, as one key for `seal`.
+fn content_key(cek: &B16) -> Box<B32> {
+    let okm = hkdf(b"", cek, CONTENT_INFO, 32);
+    let mut key: B32 = [0, ..32];
+    copy_memory(&mut key, okm.as_slice());
+    box key
+}
+
+
+fn wrapping_key(params: &DsParams, z: &BigUint, e: &BigUint, y: &BigUint) -> Box<B32> {
+    let mut info = biguint_to_fixed_bytes(e, params.p_len());
+    info.push_all(biguint_to_fixed_bytes(y, params.p_len()).as_slice());
+
+    let okm = derive_key_material(params, z, info.as_slice(), 32);
+    let mut kek: B32 = [0, ..32];
+    copy_memory(&mut kek, okm.as_slice());
+    box kek
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use num::bigint::BigUint;
+    use ds::{DigSig, DsParams};
+    use params;
+    use super::{enc_bytes, dec_bytes, read_header, header_bytes};
+
+    static MSG: &'static [u8] = b"a message long enough to take several pbc blocks";
+
+    fn key_pair(params: &DsParams) -> (BigUint, BigUint) {
+        let x = DigSig::gen_key(params);
+        let y = DigSig::public_key(params, &x);
+        (x, y)
+    }
+
+    fn header_len(ct: &[u8]) -> uint {
+        let slots = read_header(&mut BufReader::new(ct)).unwrap();
+        header_bytes(slots.as_slice()).unwrap().len()
+    }
+
+    #[test]
+    fn every_recipient_decrypts() {
+        let params = params::default();
+        let (x1, y1) = key_pair(&params);
+        let (x2, y2) = key_pair(&params);
+        let (x3, _) = key_pair(&params);
+        let ct = enc_bytes(&[(params.clone(), y1), (params.clone(), y2)], MSG).unwrap();
+        assert_eq!(dec_bytes(&params, &x1, ct.as_slice()).unwrap().as_slice(), MSG);
+        assert_eq!(dec_bytes(&params, &x2, ct.as_slice()).unwrap().as_slice(), MSG);
+        assert!(dec_bytes(&params, &x3, ct.as_slice()).is_err());
+
+        let empty = enc_bytes(&[(params.clone(), DigSig::public_key(&params, &x3))], b"")
+            .unwrap();
+        assert!(dec_bytes(&params, &x3, empty.as_slice()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let params = params::default();
+        let (x1, y1) = key_pair(&params);
+        let (_, y2) = key_pair(&params);
+        let ct = enc_bytes(&[(params.clone(), y1), (params.clone(), y2)], MSG).unwrap();
+        let header_len = header_len(ct.as_slice());
+
+        // The other recipient's slot, the first content block and the tag.
+        for &i in [header_len - 1, header_len + 20, ct.len() - 1].iter() {
+            let mut bad = ct.clone();
+            *bad.get_mut(i) ^= 1;
+            assert!(dec_bytes(&params, &x1, bad.as_slice()).is_err(), "byte {}", i);
+        }
+        // Cut short by a block, or to the header.
+        for &len in [ct.len() - 16, header_len].iter() {
+            assert!(dec_bytes(&params, &x1, ct.slice_to(len)).is_err(), "length {}", len);
+        }
+        // Older versions had no MAC.
+        let mut old = ct.clone();
+        *old.get_mut(4) = 2;
+        assert!(dec_bytes(&params, &x1, old.as_slice()).is_err());
+    }
+}
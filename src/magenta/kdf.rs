@@ -0,0 +1,146 @@
+/*
+Key derivation with HMAC-Magenta as the PRF:
+PBKDF2 (RFC 2898, section 5.2) for passwords and
+HKDF (RFC 5869) for high-entropy secrets such as DH shared values.
+*/
+use mac::hmac;
+use utils::{nth_byte, xor_array_16};
+
+
+pub static HLEN: uint = 16;
+pub static DEFAULT_ITERATIONS: uint = 4096;
+// Iteration counts read from files are capped, so a crafted header
+// can't keep us busy for hours.
+pub static MAX_ITERATIONS: uint = 10_000_000;
+
+
+pub fn pbkdf2(password: &[u8], salt: &[u8], iterations: uint, len: uint) -> Vec<u8> {
+    if iterations == 0 {
+        fail!("pbkdf2: iteration count must be positive");
+    }
+
+    let mut dk = Vec::with_capacity(len);
+    let mut block_index = 1u;
+
+    while dk.len() < len {
+        let mut msg = salt.to_vec();
+        for i in range(0u, 4).rev() {
+            msg.push(nth_byte(block_index, i));
+        }
+
+        let mut u = hmac(password, msg.as_slice());
+        let mut t = u.clone();
+        for _ in range(1, iterations) {
+            u = hmac(password, &*u);
+            t = xor_array_16(&*t, &*u);
+        }
+
+        let take = if len - dk.len() < HLEN { len - dk.len() } else { HLEN };
+        dk.push_all(t.slice_to(take));
+        block_index += 1;
+    }
+    dk
+}
+
+
+pub fn hkdf(salt: &[u8], ikm: &[u8], info: &[u8], len: uint) -> Vec<u8> {
+    if len > 255 * HLEN {
+        fail!("hkdf: output too long");
+    }
+    let box prk = hmac(salt, ikm);
+
+    let mut okm = Vec::with_capacity(len);
+    let mut t: Vec<u8> = Vec::new();
+    let mut counter = 1u8;
+
+    while okm.len() < len {
+        let mut msg = t.clone();
+        msg.push_all(info);
+        msg.push(counter);
+        t = hmac(&prk, msg.as_slice()).to_vec();
+
+        let take = if len - okm.len() < HLEN { len - okm.len() } else { HLEN };
+        okm.push_all(t.slice_to(take));
+        counter += 1;
+    }
+    okm
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serialize::hex::ToHex;
+    use mac::hmac;
+    use super::{pbkdf2, hkdf};
+
+    fn bytes(from: uint, to: uint) -> Vec<u8> {
+        range(from, to).map(|i| i as u8).collect()
+    }
+
+    // The inputs of the RFC 6070 PBKDF2-HMAC-SHA1 vectors; the outputs are
+    // those of PBKDF2-HMAC-Magenta.
+    #[test]
+    fn pbkdf2_vectors() {
+        let vectors = [(b"password", b"salt", 1u, 16u, "002d9b9e791e09491bb7ec962ddf01da")
+                       , (b"password", b"salt", 2, 16, "e40a1efd88771ada59f90116c489d7cb")
+                       , (b"password", b"salt", 4096, 16, "44fe3bad43f615f73d5608bec4496d76")];
+        for &(password, salt, iterations, len, dk) in vectors.iter() {
+            let res = pbkdf2(password, salt, iterations, len);
+            assert!(res.as_slice().to_hex().as_slice() == dk, "{} iterations", iterations);
+        }
+
+        // A long password is hashed into the HMAC key, and the last
+        // block is truncated.
+        let dk = pbkdf2(b"passwordPASSWORDpassword", b"saltSALTsaltSALTsaltSALTsaltSALTsalt"
+                        , 4096, 25);
+        assert_eq!(dk.as_slice().to_hex().as_slice()
+                   , "a3919790dd414e00f406b097183980db5ad0d4f121968a07c0");
+    }
+
+    #[test]
+    fn pbkdf2_single_iteration_is_hmac() {
+        let box t1 = hmac(b"password", b"salt\x00\x00\x00\x01");
+        let box t2 = hmac(b"password", b"salt\x00\x00\x00\x02");
+        let dk = pbkdf2(b"password", b"salt", 1, 32);
+        assert_eq!(dk.slice_to(16), t1.as_slice());
+        assert_eq!(dk.slice_from(16), t2.as_slice());
+    }
+
+    #[test]
+    #[should_fail]
+    fn pbkdf2_needs_an_iteration() {
+        pbkdf2(b"password", b"salt", 0, 16);
+    }
+
+    // The inputs of RFC 5869 test cases 1 to 3, with HMAC-Magenta.
+    #[test]
+    fn hkdf_vectors() {
+        let ikm = Vec::from_elem(22, 0x0bu8);
+        let okm = hkdf(bytes(0, 13).as_slice(), ikm.as_slice()
+                       , bytes(0xf0, 0xfa).as_slice(), 42);
+        assert_eq!(okm.as_slice().to_hex().as_slice()
+                   , concat!("1a883336833e810c4274be3bf3f1a81bf9e6267117163b6322c9"
+                             , "3bfe98fd45975c3f591aa01259611fdb"));
+
+        // Every input longer than the HMAC block.
+        let okm = hkdf(bytes(0x60, 0xb0).as_slice(), bytes(0, 0x50).as_slice()
+                       , bytes(0xb0, 0x100).as_slice(), 82);
+        assert_eq!(okm.as_slice().to_hex().as_slice()
+                   , concat!("66f5169d2c76618915654342482702dab7650ed82b6899e41cf3"
+                             , "d3aaec2ede0a70fdde7fe651fc50031d96cd214e5581eadff9ca"
+                             , "a8d926d70fc714140417d3a7d4ab09ea7463576f259c458549ff"
+                             , "3bad215c"));
+
+        // An empty salt and info.
+        let okm = hkdf(b"", ikm.as_slice(), b"", 42);
+        assert_eq!(okm.as_slice().to_hex().as_slice()
+                   , concat!("33eec8c5c02558dbb77d55c7de78abc8570f947d7f76c75b32d3"
+                             , "64ab5c0c9920d9f9b72ae7014b6d1f04"));
+    }
+
+    #[test]
+    #[should_fail]
+    fn hkdf_output_is_bounded() {
+        hkdf(b"salt", b"ikm", b"", 255 * 16 + 1);
+    }
+}
@@ -0,0 +1,253 @@
+/*
+Private key files, optionally protected by a passphrase.
+
+A protected file is an armored MAGENTA ENCRYPTED KEY block. The key
+that seals it is derived from the passphrase with PBKDF2-HMAC-Magenta;
+the salt and iteration count travel in the armor headers. The sealed
+plaintext is a key type byte followed by the key's binary encoding;
+DS keys also carry their parameter set ID, RSA keys are PKCS#1 DER.
+*/
+use std::io::{File, IoResult};
+use std::str;
+use std::slice::bytes::copy_memory;
+use num::bigint::BigUint;
+use std::num::One;
+use serialize::hex::{ToHex, FromHex};
+use armor::{Armored, is_armored, decode_bytes, ENCRYPTED_KEY, SYMMETRIC_KEY
+            , SIGNING_KEY, RSA_PRIVATE_KEY};
+use kdf::{pbkdf2, DEFAULT_ITERATIONS, MAX_ITERATIONS};
+use seal::{seal, open};
+use pbc::PBC;
+use ds::{DigSig, DsParams};
+use rsa::RsaPrivateKey;
+use utils::{B16, B32, random_bytes, invalid_input, replace_file};
+
+
+static KDF_NAME: &'static str = "PBKDF2-HMAC-MAGENTA";
+static SALT_LEN: uint = 16;
+static PBC_KEY_TYPE: u8 = 1;
+static DS_KEY_TYPE: u8 = 2;
+static RSA_KEY_TYPE: u8 = 3;
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum PrivateKey {
+    PbcKey(B16),
+    DsKey(DsParams, BigUint),
+    RsaKey(RsaPrivateKey)
+}
+
+
+impl PrivateKey {
+    pub fn to_plain_bytes(&self, is_armor: bool) -> Vec<u8> {
+        match (self, is_armor) {
+            (&PbcKey(ref k), false) => k.to_vec(),
+            (&PbcKey(ref k), true) => {
+                Armored::new(SYMMETRIC_KEY, k.to_vec()).encode().into_bytes()
+            },
+            (&DsKey(ref params, ref x), false) => {
+                format!("{}\n{}", params.id(), x).into_bytes()
+            },
+            (&DsKey(ref params, ref x), true) => {
+                DigSig::private_key_to_armor(params, x).into_bytes()
+            },
+            (&RsaKey(ref k), false) => k.to_der(),
+            (&RsaKey(ref k), true) => k.to_armor().into_bytes(),
+        }
+    }
+
+    fn to_typed_bytes(&self) -> Vec<u8> {
+        match *self {
+            PbcKey(ref k) => {
+                let mut res = vec![PBC_KEY_TYPE];
+                res.push_all(k);
+                res
+            },
+            DsKey(ref params, ref x) => {
+                let id = params.id();
+                let mut res = vec![DS_KEY_TYPE, id.len() as u8];
+                res.push_all(id.as_bytes());
+                res.push_all(DigSig::private_key_to_bytes(params, x).as_slice());
+                res
+            },
+            RsaKey(ref k) => {
+                let mut res = vec![RSA_KEY_TYPE];
+                res.push_all(k.to_der().as_slice());
+                res
+            },
+        }
+    }
+
+    fn from_typed_bytes(bytes: &[u8]) -> IoResult<PrivateKey> {
+        if bytes.is_empty() {
+            return Err(invalid_input("keyfile: empty key"));
+        }
+        match bytes[0] {
+            t if t == PBC_KEY_TYPE => {
+                Ok(PbcKey(try!(PBC::key_from_bytes(bytes.slice_from(1)))))
+            },
+            t if t == DS_KEY_TYPE && bytes.len() > 2
+                && bytes.len() >= 2 + bytes[1] as uint => {
+                let id_end = 2 + bytes[1] as uint;
+                let params = match str::from_utf8(bytes.slice(2, id_end)) {
+                    Some(id) => try!(DsParams::by_id(id)),
+                    None => return Err(invalid_input("keyfile: invalid parameter id")),
+                };
+                let x = try!(DigSig::private_key_from_bytes(&params
+                                                            , bytes.slice_from(id_end)));
+                Ok(DsKey(params, x))
+            },
+            t if t == RSA_KEY_TYPE => {
+                Ok(RsaKey(try!(RsaPrivateKey::from_der(bytes.slice_from(1)))))
+            },
+            _ => Err(invalid_input("keyfile: unknown key type")),
+        }
+    }
+}
+
+
+pub fn protect(key: &PrivateKey, passphrase: &str, iterations: uint) -> String {
+    let salt = random_bytes(SALT_LEN);
+    let box wrap_key = derive_key(passphrase, salt.as_slice(), iterations);
+
+    let mut armored = Armored::new(ENCRYPTED_KEY
+                                   , seal(&wrap_key, key.to_typed_bytes().as_slice()));
+    armored.add_header("Kdf", KDF_NAME);
+    armored.add_header("Iterations", format!("{}", iterations).as_slice());
+    armored.add_header("Salt", salt.as_slice().to_hex().as_slice());
+    armored.encode()
+}
+
+
+pub fn unprotect(content: &[u8], passphrase: &str) -> IoResult<PrivateKey> {
+    let armored = try!(decode_bytes(content));
+    if armored.label.as_slice() != ENCRYPTED_KEY {
+        return Err(invalid_input("keyfile: not an encrypted key"));
+    }
+    if armored.header("Kdf") != Some(KDF_NAME) {
+        return Err(invalid_input("keyfile: unsupported kdf"));
+    }
+    let iterations: uint = match armored.header("Iterations").and_then(from_str) {
+        Some(i) if i > 0 && i <= MAX_ITERATIONS => i,
+        _ => return Err(invalid_input("keyfile: invalid iteration count")),
+    };
+    let salt = match armored.header("Salt").map(|s| s.from_hex()) {
+        Some(Ok(s)) => s,
+        _ => return Err(invalid_input("keyfile: invalid salt")),
+    };
+
+    let box wrap_key = derive_key(passphrase, salt.as_slice(), iterations);
+    let plain = match open(&wrap_key, armored.data.as_slice()) {
+        Ok(p) => p,
+        Err(_) => return Err(invalid_input("keyfile: wrong passphrase or corrupted key")),
+    };
+    PrivateKey::from_typed_bytes(plain.as_slice())
+}
+
+
+pub fn is_protected(content: &[u8]) -> bool {
+    is_armored(content) && match decode_bytes(content) {
+        Ok(a) => a.label.as_slice() == ENCRYPTED_KEY,
+        Err(_) => false,
+    }
+}
+
+
+pub fn load(file_path: &str, passphrase: || -> String) -> IoResult<PrivateKey> {
+    let mut file = try!(File::open(&Path::new(file_path)));
+    let content = try!(file.read_to_end());
+    from_bytes(content.as_slice(), passphrase)
+}
+
+
+pub fn from_bytes(content: &[u8], passphrase: || -> String) -> IoResult<PrivateKey> {
+    if is_protected(content) {
+        return unprotect(content, passphrase().as_slice());
+    }
+
+    if is_armored(content) {
+        let armored = try!(decode_bytes(content));
+        return match armored.label.as_slice() {
+            l if l == SYMMETRIC_KEY => {
+                Ok(PbcKey(try!(PBC::key_from_bytes(armored.data.as_slice()))))
+            },
+            l if l == SIGNING_KEY => {
+                let (params, x) = try!(DigSig::key_from_str(content_str(content)));
+                Ok(DsKey(params, x))
+            },
+            l if l == RSA_PRIVATE_KEY => {
+                Ok(RsaKey(try!(RsaPrivateKey::from_der(armored.data.as_slice()))))
+            },
+            _ => Err(invalid_input("keyfile: not a private key")),
+        };
+    }
+
+    let decimal = str::from_utf8(content)
+        .and_then(|s| DigSig::key_from_str(s).ok());
+    let one: BigUint = One::one();
+    match decimal {
+        Some((ref params, ref x)) if *x <= one || *x >= params.q => {
+            Err(invalid_input("keyfile: ds key out of range"))
+        },
+        Some((params, x)) => Ok(DsKey(params, x)),
+        None if content.len() > 16 && content[0] == 0x30 => {
+            Ok(RsaKey(try!(RsaPrivateKey::from_der(content))))
+        },
+        None if content.len() == 16 => {
+            let mut key: B16 = [0, ..16];
+            copy_memory(&mut key, content);
+            Ok(PbcKey(key))
+        },
+        None => Err(invalid_input("keyfile: unrecognized key file")),
+    }
+}
+
+
+pub fn load_pbc_key(file_path: &str, passphrase: || -> String) -> IoResult<B16> {
+    match try!(load(file_path, passphrase)) {
+        PbcKey(k) => Ok(k),
+        _ => Err(invalid_input("keyfile: expected a pbc key")),
+    }
+}
+
+
+pub fn load_ds_key(file_path: &str, passphrase: || -> String)
+                   -> IoResult<(DsParams, BigUint)> {
+    match try!(load(file_path, passphrase)) {
+        DsKey(params, x) => Ok((params, x)),
+        _ => Err(invalid_input("keyfile: expected a ds key")),
+    }
+}
+
+
+pub fn load_rsa_key(file_path: &str, passphrase: || -> String) -> IoResult<RsaPrivateKey> {
+    match try!(load(file_path, passphrase)) {
+        RsaKey(k) => Ok(k),
+        _ => Err(invalid_input("keyfile: expected an rsa key")),
+    }
+}
+
+
+// Replaces the file only once the new content is fully written, so
+// protecting a key in place can't lose it.
+pub fn save(file_path: &str, key: &PrivateKey, passphrase: Option<&str>
+            , is_armor: bool) -> IoResult<()> {
+    let content = match passphrase {
+        Some(p) => protect(key, p, DEFAULT_ITERATIONS).into_bytes(),
+        None => key.to_plain_bytes(is_armor),
+    };
+    replace_file(&Path::new(file_path), content.as_slice())
+}
+
+
+fn content_str<'a>(content: &'a [u8]) -> &'a str {
+    str::from_utf8(content).unwrap_or("")
+}
+
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: uint) -> Box<B32> {
+    let dk = pbkdf2(passphrase.as_bytes(), salt, iterations, 32);
+    let mut key: B32 = [0, ..32];
+    copy_memory(&mut key, dk.as_slice());
+    box key
+}
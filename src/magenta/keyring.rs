@@ -0,0 +1,485 @@
+/*
+The keyring: a directory, $MAGENTA_HOME or ~/.magenta, that holds
+imported keys under their key IDs (see `fingerprint`).
+
+index          one line per key: <key ID> <kind> <label>
+default        key ID of the default key
+<id>.<kind>    the key file, byte for byte as imported
+
+Private key files keep their passphrase protection. The directory and
+everything in it must belong to the user and be accessible by them
+only; a keyring that isn't is refused. Files are created owner-only
+and written through a temporary file, so an interrupted write leaves
+the old index or default in place. Keys are looked up by label or by
+at least four leading hex digits of their key ID. A private key and
+its public key share a key ID and may both be in the keyring.
+*/
+use std::io::{File, IoResult, IoError, PermissionDenied, FilePermission
+              , USER_RWX, GROUP_RWX, OTHER_RWX};
+use std::io::fs::{mkdir_recursive, chmod, unlink, stat};
+use std::os;
+use std::str;
+use libc::funcs::posix88::unistd::getuid;
+use armor::{is_armored, decode_bytes, VERIFICATION_KEY, RSA_PUBLIC_KEY};
+use keyfile;
+use keyfile::{PrivateKey, PbcKey, DsKey, RsaKey, is_protected};
+use ds::DigSig;
+use rsa::RsaPublicKey;
+use fingerprint;
+use fingerprint::{KeyId, key_id, format_key_id, parse_key_id};
+use utils::{B16, invalid_input, replace_file};
+
+
+static INDEX: &'static str = "index";
+static DEFAULT: &'static str = "default";
+static MIN_PREFIX: uint = 4;
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum KeyKind {
+    PbcSecret,
+    DsSigning,
+    DsVerification,
+    RsaPrivate,
+    RsaPublic
+}
+
+
+impl KeyKind {
+    pub fn by_name(name: &str) -> IoResult<KeyKind> {
+        match name {
+            "pbc" => Ok(PbcSecret),
+            "ds" => Ok(DsSigning),
+            "ds-public" => Ok(DsVerification),
+            "rsa" => Ok(RsaPrivate),
+            "rsa-public" => Ok(RsaPublic),
+            _ => Err(invalid_input("keyring: unknown key kind")),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PbcSecret => "pbc",
+            DsSigning => "ds",
+            DsVerification => "ds-public",
+            RsaPrivate => "rsa",
+            RsaPublic => "rsa-public",
+        }
+    }
+
+    pub fn is_private(&self) -> bool {
+        match *self {
+            DsVerification | RsaPublic => false,
+            _ => true,
+        }
+    }
+}
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct Entry {
+    pub id: KeyId,
+    pub kind: KeyKind,
+    pub label: String
+}
+
+
+pub struct Keyring {
+    dir: Path,
+    entries: Vec<Entry>,
+    default: Option<KeyId>
+}
+
+
+pub fn default_dir() -> Path {
+    match os::getenv("MAGENTA_HOME") {
+        Some(dir) => Path::new(dir),
+        None => os::homedir().unwrap_or(Path::new(".")).join(".magenta"),
+    }
+}
+
+
+// The kind of key in a key file and its fingerprint; private keys are
+// fingerprinted by their public key. Text DS keys don't say whether
+// they are private or public, so they are read as public only when
+// `is_dsok` is set.
+pub fn identify(content: &[u8], is_dsok: bool
+                , passphrase: || -> String) -> IoResult<(KeyKind, B16)> {
+    if is_armored(content) && !is_protected(content) {
+        let armored = try!(decode_bytes(content));
+        let label = armored.label.as_slice();
+        if label == VERIFICATION_KEY {
+            let (params, y) = try!(DigSig::public_key_from_der(armored.data.as_slice()));
+            return Ok((DsVerification, fingerprint::ds_fingerprint(&params, &y)));
+        }
+        if label == RSA_PUBLIC_KEY {
+            let key = try!(RsaPublicKey::from_der(armored.data.as_slice()));
+            return Ok((RsaPublic, fingerprint::rsa_fingerprint(&key)));
+        }
+    } else if is_dsok {
+        let text = match str::from_utf8(content) {
+            Some(t) => t,
+            None => return Err(invalid_input("keyring: incorrect key file")),
+        };
+        let (params, y) = try!(DigSig::key_from_str(text));
+        return Ok((DsVerification, fingerprint::ds_fingerprint(&params, &y)));
+    } else if content.len() > 16 && content[0] == 0x30 {
+        match RsaPublicKey::from_der(content) {
+            Ok(key) => return Ok((RsaPublic, fingerprint::rsa_fingerprint(&key))),
+            Err(_) => {},
+        }
+    }
+
+    let key = try!(keyfile::from_bytes(content, passphrase));
+    Ok(identify_private(&key))
+}
+
+
+pub fn identify_private(key: &PrivateKey) -> (KeyKind, B16) {
+    match *key {
+        PbcKey(ref k) => (PbcSecret, fingerprint::pbc_fingerprint(k)),
+        DsKey(ref params, ref x) => {
+            let y = DigSig::public_key(params, x);
+            (DsSigning, fingerprint::ds_fingerprint(params, &y))
+        },
+        RsaKey(ref k) => (RsaPrivate, fingerprint::rsa_fingerprint(&k.public_key())),
+    }
+}
+
+
+impl Keyring {
+    pub fn open(dir: &Path) -> IoResult<Keyring> {
+        if !dir.is_dir() {
+            return Err(invalid_input("keyring: no keyring, import a key first"));
+        }
+        try!(check_owner_only(dir));
+
+        let mut entries = Vec::new();
+        let index_path = dir.join(INDEX);
+        if index_path.exists() {
+            let index = try!(read_private(&index_path));
+            for line in content_str(&index).lines() {
+                if !line.trim().is_empty() {
+                    entries.push(try!(parse_entry(line)));
+                }
+            }
+        }
+        let default_path = dir.join(DEFAULT);
+        let default = if default_path.exists() {
+            let content = try!(read_private(&default_path));
+            Some(try!(parse_key_id(content_str(&content))))
+        } else {
+            None
+        };
+        Ok(Keyring { dir: dir.clone(), entries: entries, default: default })
+    }
+
+    pub fn create(dir: &Path) -> IoResult<Keyring> {
+        if !dir.exists() {
+            try!(mkdir_recursive(dir, USER_RWX));
+            try!(chmod(dir, USER_RWX));
+        }
+        Keyring::open(dir)
+    }
+
+    pub fn dir<'a>(&'a self) -> &'a Path {
+        &self.dir
+    }
+
+    pub fn entries<'a>(&'a self) -> &'a [Entry] {
+        self.entries.as_slice()
+    }
+
+    pub fn default<'a>(&'a self) -> Option<&'a Entry> {
+        match self.default {
+            Some(ref id) => self.entries.iter().find(|e| e.id == *id && e.kind.is_private()),
+            None => None,
+        }
+    }
+
+    pub fn is_default(&self, entry: &Entry) -> bool {
+        self.default == Some(entry.id)
+    }
+
+    fn path_of(&self, entry: &Entry) -> Path {
+        self.dir.join(format!("{}.{}", format_key_id(&entry.id), entry.kind.name()))
+    }
+
+    // The path of a key file, once it is known to be the owner's only.
+    pub fn key_path(&self, entry: &Entry) -> IoResult<Path> {
+        let path = self.path_of(entry);
+        try!(check_owner_only(&path));
+        Ok(path)
+    }
+
+    pub fn read(&self, entry: &Entry) -> IoResult<Vec<u8>> {
+        read_private(&self.path_of(entry))
+    }
+
+    // Looks a key up by label, then by key ID prefix. Only the given kinds
+    // are considered, the first one present is preferred; an empty list
+    // allows any. A query that matches more than one key is an error.
+    pub fn find<'a>(&'a self, query: &str, kinds: &[KeyKind]) -> IoResult<&'a Entry> {
+        let mut found: Vec<&Entry> = self.entries.iter()
+            .filter(|e| e.label.as_slice() == query && is_wanted(*e, kinds)).collect();
+        if found.is_empty() && is_id_prefix(query) {
+            let prefix: String = query.chars().map(|c| c.to_lowercase()).collect();
+            found = self.entries.iter()
+                .filter(|e| format_key_id(&e.id).as_slice().starts_with(prefix.as_slice()))
+                .filter(|e| is_wanted(*e, kinds)).collect();
+        }
+
+        if found.is_empty() {
+            return Err(invalid_input("keyring: no such key"));
+        }
+        if found.iter().any(|e| e.id != found[0].id) {
+            return Err(invalid_input("keyring: more than one key matches"));
+        }
+        for kind in kinds.iter() {
+            match found.iter().find(|e| e.kind == *kind) {
+                Some(e) => return Ok(*e),
+                None => {},
+            }
+        }
+        Ok(found[0])
+    }
+
+    pub fn find_id<'a>(&'a self, id: &KeyId, kinds: &[KeyKind]) -> Option<&'a Entry> {
+        self.find(format_key_id(id).as_slice(), kinds).ok()
+    }
+
+    pub fn import(&mut self, content: &[u8], kind: KeyKind, fingerprint: &B16
+                  , label: &str) -> IoResult<Entry> {
+        let label = label.trim();
+        if label.is_empty() || label.contains_char('\n') {
+            return Err(invalid_input("keyring: incorrect label"));
+        }
+        let id = key_id(fingerprint);
+        if self.entries.iter().any(|e| e.id == id && e.kind == kind) {
+            return Err(invalid_input("keyring: key is already in the keyring"));
+        }
+        if self.entries.iter().any(|e| e.id != id && e.label.as_slice() == label) {
+            return Err(invalid_input("keyring: label is taken by another key"));
+        }
+
+        let entry = Entry { id: id, kind: kind, label: label.to_string() };
+        try!(replace_file(&self.path_of(&entry), content));
+        self.entries.push(entry.clone());
+        try!(self.save_index());
+        Ok(entry)
+    }
+
+    // Removes every entry of the key the query names: its private and
+    // public halves alike. The index is saved first, so a failure part
+    // way leaves at worst a stray file rather than an entry without one.
+    pub fn delete(&mut self, query: &str) -> IoResult<Vec<Entry>> {
+        let id = try!(self.find(query, &[])).id;
+        let (removed, kept) = self.entries.clone().partition(|e| e.id == id);
+        self.entries = kept;
+        try!(self.save_index());
+        if self.default == Some(id) {
+            self.default = None;
+            try!(unlink(&self.dir.join(DEFAULT)));
+        }
+        for entry in removed.iter() {
+            try!(unlink(&self.path_of(entry)));
+        }
+        Ok(removed)
+    }
+
+    pub fn set_default(&mut self, query: &str) -> IoResult<Entry> {
+        let entry = try!(self.find(query, &[DsSigning, PbcSecret, RsaPrivate])).clone();
+        try!(replace_file(&self.dir.join(DEFAULT), format_key_id(&entry.id).as_bytes()));
+        self.default = Some(entry.id);
+        Ok(entry)
+    }
+
+    fn save_index(&self) -> IoResult<()> {
+        let mut index = String::new();
+        for e in self.entries.iter() {
+            index.push_str(format!("{} {} {}\n", format_key_id(&e.id), e.kind.name()
+                                   , e.label).as_slice());
+        }
+        replace_file(&self.dir.join(INDEX), index.as_bytes())
+    }
+}
+
+
+fn parse_entry(line: &str) -> IoResult<Entry> {
+    let (id, rest) = try!(split_word(line.trim()));
+    let (kind, label) = try!(split_word(rest));
+    Ok(Entry {
+        id: try!(parse_key_id(id)),
+        kind: try!(KeyKind::by_name(kind)),
+        label: label.trim().to_string()
+    })
+}
+
+
+fn split_word<'a>(s: &'a str) -> IoResult<(&'a str, &'a str)> {
+    match s.find(' ') {
+        Some(i) => Ok((s.slice_to(i), s.slice_from(i + 1))),
+        None => Err(invalid_input("keyring: corrupted index")),
+    }
+}
+
+
+fn is_wanted(entry: &Entry, kinds: &[KeyKind]) -> bool {
+    kinds.is_empty() || kinds.contains(&entry.kind)
+}
+
+
+fn is_id_prefix(query: &str) -> bool {
+    query.len() >= MIN_PREFIX && query.len() <= 16
+        && query.chars().all(|c| c.is_digit_radix(16))
+}
+
+
+fn content_str<'a>(content: &'a Vec<u8>) -> &'a str {
+    str::from_utf8(content.as_slice()).unwrap_or("")
+}
+
+
+fn check_owner_only(path: &Path) -> IoResult<()> {
+    let info = try!(stat(path));
+    let others: FilePermission = GROUP_RWX | OTHER_RWX;
+    if info.unstable.uid != unsafe { getuid() } as u64 {
+        Err(IoError {
+            kind: PermissionDenied,
+            desc: "keyring: owned by another user",
+            detail: Some(path.display().to_string()),
+        })
+    } else if info.perm.intersects(others) {
+        Err(IoError {
+            kind: PermissionDenied,
+            desc: "keyring: accessible by other users, allow the owner only",
+            detail: Some(path.display().to_string()),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+
+fn read_private(path: &Path) -> IoResult<Vec<u8>> {
+    try!(check_owner_only(path));
+    let mut file = try!(File::open(path));
+    file.read_to_end()
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::TempDir;
+    use std::os;
+    use utils::B16;
+    use super::{Keyring, Entry, KeyKind, PbcSecret, DsSigning, DsVerification, RsaPublic
+                , default_dir};
+
+    // A fingerprint whose key ID is "<a><b>000000000000<n>".
+    fn fingerprint(a: u8, b: u8, n: u8) -> B16 {
+        let mut fp = [0u8, ..16];
+        fp[8] = a;
+        fp[9] = b;
+        fp[15] = n;
+        fp
+    }
+
+    fn import(keyring: &mut Keyring, kind: KeyKind, fp: &B16, label: &str) -> Entry {
+        keyring.import(label.as_bytes(), kind, fp, label).unwrap()
+    }
+
+    // Every test gets its own keyring directory under a fresh home.
+    fn temp_keyring() -> (TempDir, Keyring) {
+        let home = TempDir::new("magenta-keyring").unwrap();
+        let keyring = Keyring::create(&home.path().join(".magenta")).unwrap();
+        (home, keyring)
+    }
+
+    #[test]
+    fn magenta_home_is_the_default_dir() {
+        let home = TempDir::new("magenta-home").unwrap();
+        os::setenv("MAGENTA_HOME", home.path().as_str().unwrap());
+        assert_eq!(default_dir(), home.path().clone());
+        assert!(Keyring::open(&default_dir()).unwrap().entries().is_empty());
+    }
+
+    #[test]
+    fn imported_keys_are_found() {
+        let (_home, mut keyring) = temp_keyring();
+        assert!(Keyring::open(&keyring.dir().join("missing")).is_err());
+        let alice = import(&mut keyring, DsSigning, &fingerprint(0xab, 0xcd, 1), "alice");
+        import(&mut keyring, RsaPublic, &fingerprint(0x12, 0x34, 2), "bob");
+
+        let keyring = Keyring::open(keyring.dir()).unwrap();
+        assert_eq!(keyring.entries().len(), 2);
+        assert_eq!(keyring.find("alice", &[]).unwrap(), &alice);
+        assert_eq!(keyring.find("abcd", &[]).unwrap(), &alice);
+        assert_eq!(keyring.find("ABCD00", &[]).unwrap(), &alice);
+        assert_eq!(keyring.find("abcd000000000001", &[]).unwrap(), &alice);
+        assert_eq!(keyring.read(&alice).unwrap().as_slice(), b"alice");
+        assert_eq!(keyring.find_id(&alice.id, &[DsSigning]), Some(&alice));
+
+        // Too short a prefix, another kind, nothing at all.
+        assert!(keyring.find("abc", &[]).is_err());
+        assert!(keyring.find("alice", &[RsaPublic]).is_err());
+        assert!(keyring.find("carol", &[]).is_err());
+    }
+
+    #[test]
+    fn ambiguous_queries_fail() {
+        let (_home, mut keyring) = temp_keyring();
+        import(&mut keyring, DsVerification, &fingerprint(0xab, 0xcd, 1), "alice");
+        import(&mut keyring, DsVerification, &fingerprint(0xab, 0xcd, 2), "bob");
+        assert!(keyring.find("abcd", &[]).is_err());
+        assert!(keyring.find("abcd000000000002", &[]).unwrap().label.as_slice() == "bob");
+
+        // The same key twice, or a label that belongs to another key.
+        let fp = fingerprint(0xab, 0xcd, 1);
+        assert!(keyring.import(b"", DsVerification, &fp, "alice").is_err());
+        assert!(keyring.import(b"", DsSigning, &fingerprint(1, 2, 3), "bob").is_err());
+        assert!(keyring.import(b"", DsSigning, &fp, " ").is_err());
+    }
+
+    #[test]
+    fn both_halves_are_deleted() {
+        let (_home, mut keyring) = temp_keyring();
+        let fp = fingerprint(0xab, 0xcd, 1);
+        let private = import(&mut keyring, DsSigning, &fp, "alice");
+        let public = import(&mut keyring, DsVerification, &fp, "alice");
+        let bob = import(&mut keyring, PbcSecret, &fingerprint(0x12, 0x34, 2), "bob");
+        // The private half is preferred when it is asked for first.
+        assert_eq!(keyring.find("alice", &[DsSigning, DsVerification]).unwrap(), &private);
+
+        let paths = vec![keyring.key_path(&private).unwrap()
+                         , keyring.key_path(&public).unwrap()];
+        let removed = keyring.delete("alice").unwrap();
+        assert_eq!(removed, vec![private, public]);
+        assert!(paths.iter().all(|p| !p.exists()));
+
+        let keyring = Keyring::open(keyring.dir()).unwrap();
+        assert_eq!(keyring.entries(), [bob].as_slice());
+        assert!(keyring.find("abcd", &[]).is_err());
+    }
+
+    #[test]
+    fn default_follows_the_key() {
+        let (_home, mut keyring) = temp_keyring();
+        let fp = fingerprint(0xab, 0xcd, 1);
+        import(&mut keyring, DsVerification, &fp, "alice");
+        // Only private keys can be the default.
+        assert!(keyring.set_default("alice").is_err());
+        let private = import(&mut keyring, DsSigning, &fp, "alice");
+        assert!(keyring.default().is_none());
+
+        assert_eq!(keyring.set_default("alice").unwrap(), private);
+        let mut keyring = Keyring::open(keyring.dir()).unwrap();
+        assert_eq!(keyring.default(), Some(&private));
+        assert!(keyring.is_default(&private));
+
+        keyring.delete("alice").unwrap();
+        assert!(!keyring.dir().join("default").exists());
+        assert!(Keyring::open(keyring.dir()).unwrap().default().is_none());
+    }
+}
@@ -1,9 +1,39 @@
 #![crate_name = "magenta"]
 
+extern crate libc;
 extern crate num;
+extern crate serialize;
+extern crate time;
 
 pub mod magenta;
 pub mod pbc;
 pub mod hash;
 pub mod ds;
 pub mod utils;
+pub mod der;
+pub mod armor;
+pub mod mac;
+pub mod kdf;
+pub mod seal;
+pub mod keyfile;
+pub mod pbe;
+pub mod dh;
+pub mod hybrid;
+pub mod params;
+pub mod montgomery;
+pub mod ctpow;
+pub mod schnorr;
+pub mod dsa;
+pub mod scheme;
+pub mod rsa;
+pub mod ec;
+pub mod ecds;
+pub mod ecdh;
+pub mod fingerprint;
+pub mod keyring;
+pub mod agent;
+pub mod metadata;
+pub mod cert;
+pub mod bundle;
+pub mod manifest;
+pub mod treehash;
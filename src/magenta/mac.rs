@@ -0,0 +1,35 @@
+/*
+HMAC (RFC 2104) over the Magenta-based hash from `hash`.
+Both the block size and the digest size of that hash are 16 bytes.
+*/
+use utils::{B16, ct_eq};
+use hash::h_bytes;
+
+
+static BLOCK_LEN: uint = 16;
+static IPAD: u8 = 0x36;
+static OPAD: u8 = 0x5c;
+
+
+pub fn hmac(key: &[u8], msg: &[u8]) -> Box<B16> {
+    let mut k = if key.len() > BLOCK_LEN {
+        h_bytes(key).to_vec()
+    } else {
+        key.to_vec()
+    };
+    k.grow(BLOCK_LEN - k.len(), 0u8);
+
+    let mut inner: Vec<u8> = k.iter().map(|b| *b ^ IPAD).collect();
+    inner.push_all(msg);
+    let box inner_h = h_bytes(inner.as_slice());
+
+    let mut outer: Vec<u8> = k.iter().map(|b| *b ^ OPAD).collect();
+    outer.push_all(&inner_h);
+    h_bytes(outer.as_slice())
+}
+
+
+pub fn verify_hmac(key: &[u8], msg: &[u8], tag: &[u8]) -> bool {
+    let box expected = hmac(key, msg);
+    ct_eq(&expected, tag)
+}
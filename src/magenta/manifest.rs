@@ -0,0 +1,261 @@
+/*
+Signed manifests of directory trees. A manifest has one line per file,
+sorted by path:
+
+<hex digest>  <size in bytes>  <path relative to the directory>
+
+The digests are magenta hashes unless a first "Hash: <name>" line names
+another `hash` construction. Paths use "/" between components. Only
+regular files are listed: symbolic links are not followed, and FIFOs,
+sockets and devices, which could block or never end when read, are
+left out. The manifest is signed as a whole; the signature follows the
+last line as an armored signature block (see `scheme`) and covers
+everything before it.
+*/
+use std::io::{File, IoResult, TypeFile, TypeDirectory};
+use std::io::fs::{readdir, lstat};
+use std::str;
+use serialize::hex::{ToHex, FromHex};
+use hash;
+use hash::Construction;
+use scheme::SignatureFile;
+use utils::invalid_input;
+
+
+static BEGIN: &'static str = "-----BEGIN ";
+static HASH: &'static str = "Hash: ";
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub digest: Vec<u8>
+}
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct Manifest {
+    pub hash: Construction,
+    pub entries: Vec<ManifestEntry>
+}
+
+
+// Differences of a directory from its manifest, as paths.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>
+}
+
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+
+impl Manifest {
+    // Hashes every file under `dir` except `skip`, typically the
+    // manifest itself.
+    pub fn from_dir(dir: &Path, skip: Option<&Path>
+                    , hash: Construction) -> IoResult<Manifest> {
+        let skip = match skip {
+            Some(p) if p.exists() => {
+                let stat = try!(lstat(p));
+                Some((stat.unstable.device, stat.unstable.inode))
+            },
+            _ => None,
+        };
+        let mut entries = Vec::new();
+        try!(add_dir(dir, dir, skip, hash, &mut entries));
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Manifest { hash: hash, entries: entries })
+    }
+
+    pub fn to_string(&self) -> String {
+        let mut res = String::new();
+        if self.hash != hash::Original {
+            res.push_str(format!("{}{}\n", HASH, self.hash.name()).as_slice());
+        }
+        for e in self.entries.iter() {
+            res.push_str(format!("{}  {}  {}\n", e.digest.as_slice().to_hex(), e.size
+                                 , e.path).as_slice());
+        }
+        res
+    }
+
+    pub fn from_str(body: &str) -> IoResult<Manifest> {
+        let mut lines = body.lines().filter(|l| !l.is_empty()).peekable();
+        let mut hash = hash::Original;
+        if lines.peek().map_or(false, |l| l.starts_with(HASH)) {
+            let line = lines.next().unwrap();
+            hash = try!(Construction::by_name(line.slice_from(HASH.len())));
+        }
+        let mut entries = Vec::new();
+        for line in lines {
+            entries.push(try!(parse_line(line, hash.len() * 2)));
+        }
+        for w in entries.as_slice().windows(2) {
+            if w[0].path >= w[1].path {
+                return Err(invalid_input("manifest: paths are not sorted"));
+            }
+        }
+        Ok(Manifest { hash: hash, entries: entries })
+    }
+
+    // How `actual` differs from this manifest, in one walk over both
+    // entry lists. Both must be sorted by path, as `from_dir` and
+    // `from_str` leave them.
+    pub fn compare(&self, actual: &Manifest) -> Changes {
+        let mut changes = Changes {
+            added: Vec::new(),
+            removed: Vec::new(),
+            modified: Vec::new()
+        };
+        let (expected, actual) = (self.entries.as_slice(), actual.entries.as_slice());
+        let (mut i, mut j) = (0u, 0u);
+        while i < expected.len() || j < actual.len() {
+            if j == actual.len() || (i < expected.len() && expected[i].path < actual[j].path) {
+                changes.removed.push(expected[i].path.clone());
+                i += 1;
+            } else if i == expected.len() || actual[j].path < expected[i].path {
+                changes.added.push(actual[j].path.clone());
+                j += 1;
+            } else {
+                let (e, a) = (&expected[i], &actual[j]);
+                if a.size != e.size || a.digest != e.digest {
+                    changes.modified.push(e.path.clone());
+                }
+                i += 1;
+                j += 1;
+            }
+        }
+        changes
+    }
+}
+
+
+// A signed manifest: the text the signature covers and the signature.
+pub fn split_signed<'a>(content: &'a str) -> IoResult<(&'a str, SignatureFile)> {
+    let pos = if content.starts_with(BEGIN) {
+        Some(0)
+    } else {
+        content.find_str(format!("\n{}", BEGIN).as_slice()).map(|i| i + 1)
+    };
+    match pos {
+        Some(i) => {
+            let sig = try!(SignatureFile::from_str(content.slice_from(i)));
+            Ok((content.slice_to(i), sig))
+        },
+        None => Err(invalid_input("manifest: not signed")),
+    }
+}
+
+
+// `skip` is the device and inode of a file to leave out.
+fn add_dir(root: &Path, dir: &Path, skip: Option<(u64, u64)>, hash: Construction
+           , entries: &mut Vec<ManifestEntry>) -> IoResult<()> {
+    for path in try!(readdir(dir)).iter() {
+        let stat = try!(lstat(path));
+        if skip == Some((stat.unstable.device, stat.unstable.inode)) {
+            continue;
+        }
+        if stat.kind == TypeDirectory {
+            try!(add_dir(root, path, skip, hash, entries));
+            continue;
+        }
+        if stat.kind != TypeFile {
+            continue;
+        }
+        let rel = match path.path_relative_from(root) {
+            Some(rel) => rel,
+            None => return Err(invalid_input("manifest: file outside the directory")),
+        };
+        let rel = match str::from_utf8(rel.as_vec()) {
+            Some(s) if !s.contains_char('\n') => s.to_string(),
+            _ => return Err(invalid_input("manifest: unsupported file name")),
+        };
+        let digest = hash::hash_reader(hash, &mut try!(File::open(path)));
+        entries.push(ManifestEntry { path: rel, size: stat.size, digest: digest });
+    }
+    Ok(())
+}
+
+
+// `hex_len` is the length of the digest in hex digits.
+fn parse_line(line: &str, hex_len: uint) -> IoResult<ManifestEntry> {
+    if line.len() < hex_len + 4 || line.as_bytes().slice(hex_len, hex_len + 2) != b"  " {
+        return Err(invalid_input("manifest: incorrect line"));
+    }
+    let digest = match line.slice_to(hex_len).from_hex() {
+        Ok(d) => d,
+        Err(_) => return Err(invalid_input("manifest: incorrect digest")),
+    };
+    let rest = line.slice_from(hex_len + 2);
+    let (size, path) = match rest.find_str("  ") {
+        Some(i) => (rest.slice_to(i), rest.slice_from(i + 2)),
+        None => return Err(invalid_input("manifest: incorrect line")),
+    };
+    let size = match from_str::<u64>(size) {
+        Some(s) => s,
+        None => return Err(invalid_input("manifest: incorrect size")),
+    };
+    if path.is_empty() {
+        return Err(invalid_input("manifest: empty path"));
+    }
+    Ok(ManifestEntry { path: path.to_string(), size: size, digest: digest })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hash;
+    use hash::Construction;
+    use super::{Manifest, ManifestEntry};
+
+    fn manifest_with(hash: Construction, entries: &[(&str, u8)]) -> Manifest {
+        Manifest {
+            hash: hash,
+            entries: entries.iter().map(|&(path, d)| {
+                ManifestEntry { path: path.to_string(), size: 1
+                                , digest: Vec::from_elem(hash.len(), d) }
+            }).collect()
+        }
+    }
+
+    fn manifest(entries: &[(&str, u8)]) -> Manifest {
+        manifest_with(hash::Original, entries)
+    }
+
+    #[test]
+    fn text_round_trips() {
+        let entries: &[(&str, u8)] = &[("a", 0), ("b/c d", 1)];
+        for &h in [hash::Original, hash::MiyaguchiPreneel, hash::Hirose].iter() {
+            let m = manifest_with(h, entries);
+            let text = m.to_string();
+            assert_eq!(text.as_slice().starts_with("Hash: "), h != hash::Original);
+            assert_eq!(Manifest::from_str(text.as_slice()).unwrap(), m);
+        }
+        // Digests must be as long as the construction's.
+        let text = manifest_with(hash::Hirose, entries).to_string();
+        assert!(Manifest::from_str(text.as_slice().slice_from(13)).is_err());
+        assert!(Manifest::from_str("Hash: sha1\n").is_err());
+    }
+
+    #[test]
+    fn compare_finds_every_change() {
+        let expected = manifest(&[("a", 0), ("b/c", 1), ("d", 2), ("f", 3)]);
+        let actual = manifest(&[("0", 9), ("b/c", 1), ("d", 7), ("e", 4), ("g", 5)]);
+        let changes = expected.compare(&actual);
+        assert_eq!(changes.added, vec!["0".to_string(), "e".to_string(), "g".to_string()]);
+        assert_eq!(changes.removed, vec!["a".to_string(), "f".to_string()]);
+        assert_eq!(changes.modified, vec!["d".to_string()]);
+
+        assert!(expected.compare(&expected).is_empty());
+        assert_eq!(manifest(&[]).compare(&expected).added.len(), 4);
+        assert_eq!(expected.compare(&manifest(&[])).removed.len(), 4);
+    }
+}
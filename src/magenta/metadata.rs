@@ -0,0 +1,236 @@
+/*
+Signed signature metadata: who signed, when, with which hash and
+parameter set, until when the signature is valid and an optional
+comment. A signature with metadata signs
+
+    h("rust-magenta signature metadata" || 0 || DER || h(message))
+
+instead of h(message), where h is the `hash` construction named in the
+metadata and DER is
+
+    SEQUENCE { OCTET STRING key ID, INTEGER created,
+               OCTET STRING hash, OCTET STRING parameter set id,
+               INTEGER expires (0 for never), OCTET STRING comment }
+
+Times are seconds since the Unix epoch, UTC. Signature files carry the
+fields as headers or "Name: value" lines (see `scheme`); the parameter
+set id is the file's own. Both are read back trimmed, so a comment must
+be a single non-empty line without leading or trailing whitespace to
+come back as it was signed.
+*/
+use std::io::IoResult;
+use std::num::from_str_radix;
+use num::bigint::{BigUint, ToBigUint};
+use time;
+use hash;
+use hash::Construction;
+use armor::Armored;
+use ds::DsParams;
+use der::{encode_integer, encode_octet_string, encode_sequence};
+use fingerprint::KeyId;
+use utils::invalid_input;
+
+
+static TAG: &'static [u8] = b"rust-magenta signature metadata";
+
+static CREATED: &'static str = "Created";
+static EXPIRES: &'static str = "Expires";
+static HASH_FIELD: &'static str = "Hash";
+static COMMENT: &'static str = "Comment";
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct Metadata {
+    pub key_id: KeyId,
+    pub created: u64,
+    pub expires: Option<u64>,
+    pub hash: Construction,
+    pub params_id: String,
+    pub comment: Option<String>
+}
+
+
+pub fn now() -> u64 {
+    time::get_time().sec as u64
+}
+
+
+pub fn format_time(t: u64) -> String {
+    time::at_utc(time::Timespec::new(t as i64, 0)).rfc3339()
+}
+
+
+// Whether a comment comes back from a signature file as it was signed.
+pub fn check_comment(comment: &str) -> IoResult<()> {
+    if comment.is_empty() || comment.trim() != comment
+        || comment.chars().any(|c| c.is_control()) {
+        Err(invalid_input("metadata: the comment must be a non-empty line \
+                           without leading or trailing spaces"))
+    } else {
+        Ok(())
+    }
+}
+
+
+impl Metadata {
+    // Created now, with no expiry or comment.
+    pub fn new(key_id: &KeyId, params: &DsParams, hash: Construction) -> Metadata {
+        Metadata {
+            key_id: *key_id,
+            created: now(),
+            expires: None,
+            hash: hash,
+            params_id: params.id().to_string(),
+            comment: None
+        }
+    }
+
+    pub fn to_der(&self) -> Vec<u8> {
+        let comment = match self.comment {
+            Some(ref c) => c.as_bytes(),
+            None => b"",
+        };
+        encode_sequence(&[encode_octet_string(self.key_id.as_slice())
+                          , encode_integer(&big(self.created))
+                          , encode_octet_string(self.hash.name().as_bytes())
+                          , encode_octet_string(self.params_id.as_bytes())
+                          , encode_integer(&big(self.expires.unwrap_or(0)))
+                          , encode_octet_string(comment)])
+    }
+
+    // What the signature signs in place of the message digest, which
+    // must come from `self.hash`.
+    pub fn signed_digest(&self, digest: &[u8]) -> Vec<u8> {
+        let mut data = TAG.to_vec();
+        data.push(0);
+        data.push_all(self.to_der().as_slice());
+        data.push_all(digest);
+        hash::hash_bytes(self.hash, data.as_slice())
+    }
+
+    pub fn is_expired(&self, at: u64) -> bool {
+        match self.expires {
+            Some(t) => at >= t,
+            None => false,
+        }
+    }
+
+    pub fn add_headers(&self, armored: &mut Armored) {
+        for &(name, ref value) in self.fields().iter() {
+            armored.add_header(name, value.as_slice());
+        }
+    }
+
+    pub fn from_headers(armored: &Armored, key_id: &Option<KeyId>
+                        , params: &DsParams) -> IoResult<Option<Metadata>> {
+        Metadata::from_fields(|name| armored.header(name).map(|v| v.to_string())
+                              , key_id, params)
+    }
+
+    pub fn text_lines(&self) -> Vec<String> {
+        self.fields().iter()
+            .map(|&(name, ref value)| format!("{}: {}", name, value))
+            .collect()
+    }
+
+    // Removes the metadata lines from the lines of a text signature file,
+    // returning them as (name, value) pairs for `from_text_fields`.
+    pub fn take_text_fields(lines: &mut Vec<&str>) -> Vec<(String, String)> {
+        let mut fields = Vec::new();
+        for &name in [CREATED, EXPIRES, HASH_FIELD, COMMENT].iter() {
+            let prefix = format!("{}:", name);
+            match lines.iter().position(|l| l.trim().starts_with(prefix.as_slice())) {
+                Some(i) => {
+                    let line = lines.remove(i).unwrap().trim();
+                    let value = line.slice_from(prefix.len()).trim();
+                    fields.push((name.to_string(), value.to_string()));
+                },
+                None => {},
+            }
+        }
+        fields
+    }
+
+    pub fn from_text_fields(fields: &[(String, String)], key_id: &Option<KeyId>
+                            , params: &DsParams) -> IoResult<Option<Metadata>> {
+        Metadata::from_fields(|name| fields.iter().find(|&&(ref n, _)| n.as_slice() == name)
+                                           .map(|&(_, ref v)| v.clone())
+                              , key_id, params)
+    }
+
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![(CREATED, self.created.to_string())];
+        match self.expires {
+            Some(t) => fields.push((EXPIRES, t.to_string())),
+            None => {},
+        }
+        fields.push((HASH_FIELD, self.hash.name().to_string()));
+        match self.comment {
+            Some(ref c) => fields.push((COMMENT, c.clone())),
+            None => {},
+        }
+        fields
+    }
+
+    // Files without a Created field have no metadata.
+    fn from_fields(field: |&str| -> Option<String>, key_id: &Option<KeyId>
+                   , params: &DsParams) -> IoResult<Option<Metadata>> {
+        let created = match field(CREATED) {
+            Some(c) => try!(parse_time(c.as_slice())),
+            None => return Ok(None),
+        };
+        let key_id = match *key_id {
+            Some(id) => id,
+            None => return Err(invalid_input("metadata: no key ID")),
+        };
+        let expires = match field(EXPIRES) {
+            Some(e) => Some(try!(parse_time(e.as_slice()))),
+            None => None,
+        };
+        let hash = match field(HASH_FIELD) {
+            Some(h) => try!(Construction::by_name(h.as_slice())),
+            None => return Err(invalid_input("metadata: no hash algorithm")),
+        };
+        let comment = field(COMMENT);
+        match comment {
+            Some(ref c) => try!(check_comment(c.as_slice())),
+            None => {},
+        }
+        Ok(Some(Metadata {
+            key_id: key_id,
+            created: created,
+            expires: expires,
+            hash: hash,
+            params_id: params.id().to_string(),
+            comment: comment
+        }))
+    }
+}
+
+
+fn parse_time(s: &str) -> IoResult<u64> {
+    match from_str_radix(s.trim(), 10) {
+        Some(t) => Ok(t),
+        None => Err(invalid_input("metadata: incorrect time")),
+    }
+}
+
+
+fn big(n: u64) -> BigUint {
+    n.to_biguint().unwrap()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::check_comment;
+
+    #[test]
+    fn comments_must_survive_trimming() {
+        assert!(check_comment("release 0.2").is_ok());
+        assert!(check_comment("a  b").is_ok());
+        for c in ["", " ", "release ", " release", "two\nlines", "tab\t", "cr\r"].iter() {
+            assert!(check_comment(*c).is_err(), "{}", c);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+/*
+Modular exponentiation in Montgomery form.
+
+`MontCtx::pow` is left-to-right sliding-window exponentiation
+(HAC, algorithm 14.85) over Montgomery multiplication (HAC, 14.36),
+so no step needs a full division. `MontCtx::multi_pow` computes a
+product of powers with one shared chain of squarings (Straus, "Shamir's
+trick"). `FixedBase` precomputes a table of powers of one base so that
+exponentiation needs no squarings at all.
+*/
+use std::cmp::min;
+use num::Integer;
+use num::bigint::BigUint;
+use std::num::One;
+use utils::to_bits_le;
+
+
+pub struct MontCtx {
+    m: BigUint,
+    r_bits: uint,
+    mask: BigUint,
+    m_prime: BigUint,
+    r2: BigUint
+}
+
+
+impl MontCtx {
+    pub fn new(m: &BigUint) -> MontCtx {
+        let one: BigUint = One::one();
+        if !m.is_odd() || *m == one {
+            fail!("montgomery: modulus must be odd and greater than one");
+        }
+
+        let r_bits = (m.bits() + 31) / 32 * 32;
+        let r = one << r_bits;
+        let mask = r - one;
+
+        // Newton iteration for m^-1 mod R; each step doubles the correct bits.
+        let two = one + one;
+        let mut inv = one.clone();
+        let mut correct_bits = 1u;
+        while correct_bits < r_bits {
+            let t = (*m * inv) & mask;
+            inv = (inv * ((two + r - t) & mask)) & mask;
+            correct_bits *= 2;
+        }
+
+        MontCtx {
+            m: m.clone(),
+            r_bits: r_bits,
+            mask: mask.clone(),
+            m_prime: (r - inv) & mask,
+            r2: (r * r) % *m
+        }
+    }
+
+    pub fn modulus<'a>(&'a self) -> &'a BigUint {
+        &self.m
+    }
+
+    pub fn to_mont(&self, a: &BigUint) -> BigUint {
+        self.redc(&((*a % self.m) * self.r2))
+    }
+
+    pub fn from_mont(&self, a: &BigUint) -> BigUint {
+        self.redc(a)
+    }
+
+    pub fn one(&self) -> BigUint {
+        self.to_mont(&One::one())
+    }
+
+    pub fn mul(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        self.redc(&((*a) * (*b)))
+    }
+
+    pub fn pow(&self, base: &BigUint, exp: &BigUint) -> BigUint {
+        let mut bits = to_bits_le(exp);
+        bits.reverse();
+        let w = window_size(bits.len());
+
+        // odd[i] = base^(2i + 1)
+        let b = self.to_mont(base);
+        let b2 = self.mul(&b, &b);
+        let mut odd = vec![b];
+        for i in range(1, 1u << (w - 1)) {
+            let next = self.mul(&odd[i - 1], &b2);
+            odd.push(next);
+        }
+
+        let mut acc = self.one();
+        let mut i = 0u;
+        while i < bits.len() {
+            if !bits[i] {
+                acc = self.mul(&acc, &acc);
+                i += 1;
+                continue;
+            }
+
+            let mut j = min(i + w, bits.len());
+            while !bits[j - 1] {
+                j -= 1;
+            }
+            let mut value = 0u;
+            for k in range(i, j) {
+                acc = self.mul(&acc, &acc);
+                value = (value << 1) | bits[k] as uint;
+            }
+            acc = self.mul(&acc, &odd[value >> 1]);
+            i = j;
+        }
+        self.from_mont(&acc)
+    }
+
+    // The product of base^exp over all terms.
+    pub fn multi_pow(&self, terms: &[(BigUint, BigUint)]) -> BigUint {
+        let bases: Vec<BigUint> = terms.iter().map(|&(ref b, _)| self.to_mont(b)).collect();
+        let bits: Vec<Vec<bool>> = terms.iter().map(|&(_, ref e)| to_bits_le(e)).collect();
+        let len = bits.iter().map(|b| b.len()).max().unwrap_or(0);
+
+        let mut acc = self.one();
+        for i in range(0, len).rev() {
+            acc = self.mul(&acc, &acc);
+            for (b, base) in bits.iter().zip(bases.iter()) {
+                if i < b.len() && b[i] {
+                    acc = self.mul(&acc, base);
+                }
+            }
+        }
+        self.from_mont(&acc)
+    }
+
+    fn redc(&self, t: &BigUint) -> BigUint {
+        let u = ((*t & self.mask) * self.m_prime) & self.mask;
+        let res = (*t + u * self.m) >> self.r_bits;
+        if res >= self.m { res - self.m } else { res }
+    }
+}
+
+
+pub struct FixedBase {
+    ctx: MontCtx,
+    base: BigUint,
+    window: uint,
+    // table[i][d] = base^(d * 2^(window * i)), in Montgomery form
+    table: Vec<Vec<BigUint>>
+}
+
+
+impl FixedBase {
+    pub fn new(base: &BigUint, m: &BigUint, max_exp_bits: uint) -> FixedBase {
+        let ctx = MontCtx::new(m);
+        let window = 4u;
+        let rows = (max_exp_bits + window - 1) / window;
+
+        let mut table = Vec::with_capacity(rows);
+        let mut row_base = ctx.to_mont(base);
+        for _ in range(0, rows) {
+            let mut row = vec![ctx.one(), row_base.clone()];
+            for d in range(2, 1u << window) {
+                let next = ctx.mul(&row[d - 1], &row_base);
+                row.push(next);
+            }
+            row_base = ctx.mul(&row[(1u << window) - 1], &row_base);
+            table.push(row);
+        }
+
+        FixedBase {
+            ctx: ctx,
+            base: base.clone(),
+            window: window,
+            table: table
+        }
+    }
+
+    pub fn pow(&self, exp: &BigUint) -> BigUint {
+        if exp.bits() > self.table.len() * self.window {
+            return self.ctx.pow(&self.base, exp);
+        }
+
+        let bits = to_bits_le(exp);
+        let mut acc = self.ctx.one();
+        for (i, row) in self.table.iter().enumerate() {
+            let mut d = 0u;
+            for k in range(0, self.window).rev() {
+                let bit = i * self.window + k;
+                d = (d << 1) | (bit < bits.len() && bits[bit]) as uint;
+            }
+            if d != 0 {
+                acc = self.ctx.mul(&acc, &row[d]);
+            }
+        }
+        self.ctx.from_mont(&acc)
+    }
+}
+
+
+fn window_size(exp_bits: uint) -> uint {
+    match exp_bits {
+        0..32 => 1,
+        33..128 => 3,
+        129..512 => 4,
+        _ => 5,
+    }
+}
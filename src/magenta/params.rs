@@ -0,0 +1,269 @@
+/*
+Named parameter sets for `DigSig`, `dh` and `hybrid`, selected by ID.
+Key and signature files record the ID of the set they belong to.
+
+magenta-256       the original 256-bit p / 128-bit q group
+gost-94-test      GOST R 34.10-94 test parameters, 512-bit p / 256-bit q
+1.2.643.2.2.32.2  CryptoPro-A (RFC 4357, section 11.2), 1024-bit p / 256-bit q
+1.2.643.2.2.32.3  CryptoPro-B
+1.2.643.2.2.32.5  CryptoPro-D
+1.2.643.2.2.33.1  CryptoPro-XchA
+1.2.643.2.2.33.2  CryptoPro-XchB
+1.2.643.2.2.33.3  CryptoPro-XchC
+rfc5114-1024-160  RFC 5114, section 2.1
+rfc5114-2048-224  RFC 5114, section 2.2
+rfc5114-2048-256  RFC 5114, section 2.3 (default for new keys)
+rfc3526-3072      RFC 3526 group 15, q = (p - 1) / 2, g = 2
+
+The CryptoPro sets are named by their object identifiers. CryptoPro-C
+(1.2.643.2.2.32.4) is missing: we have no copy of its generator that
+passes the checks in the tests below, and a wrong one would be worse
+than none.
+*/
+use std::num::from_str_radix;
+use num::bigint::BigUint;
+use ds::DsParams;
+
+
+pub static BUILTIN: &'static str = "magenta-256";
+pub static DEFAULT: &'static str = "rfc5114-2048-256";
+
+
+struct NamedParams {
+    id: &'static str,
+    p: &'static str,
+    q: &'static str,
+    g: &'static str
+}
+
+
+static NAMED: &'static [NamedParams] = &[
+    NamedParams {
+        id: "gost-94-test",
+        p: "EE8172AE8996608FB69359B89EB82A69854510E2977A4D63BC97322CE5DC3386\
+           EA0A12B343E9190F23177539845839786BB0C345D165976EF2195EC9B1C379E3",
+        q: "98915E7EC8265EDFCDA31E88F24809DDB064BDC7285DD50D7289F0AC6F49DD2D",
+        g: "9E96031500C8774A869582D4AFDE2127AFAD2538B4B6270A6F7C8837B50D50F2\
+           06755984A49E509304D648BE2AB5AAB18EBE2CD46AC3D8495B142AA6CE23E21C"
+    },
+    NamedParams {
+        id: "1.2.643.2.2.32.2",
+        p: "B4E25EFB018E3C8B87505E2A67553C5EDC56C2914B7E4F89D23F03F03377E70A\
+           2903489DD60E78418D3D851EDB5317C4871E40B04228C3B7902963C4B7D85D52\
+           B9AA88F2AFDBEB28DA8869D6DF846A1D98924E925561BD69300B9DDD05D247B5\
+           922D967CBB02671881C57D10E5EF72D3E6DAD4223DC82AA1F7D0294651A480DF",
+        q: "972432A437178B30BD96195B773789AB2FFF15594B176DD175B63256EE5AF2CF",
+        g: "8FD36731237654BBE41F5F1F8453E71CA414FFC22C25D915309E5D2E62A2A26C\
+           7111F3FC79568DAFA028042FE1A52A0489805C0DE9A1A469C844C7CABBEE625C\
+           3078888C1D85EEA883F1AD5BC4E6776E8E1A0750912DF64F79956499F1E18247\
+           5B0B60E2632ADCD8CF94E9C54FD1F3B109D81F00BF2AB8CB862ADF7D40B9369A"
+    },
+    NamedParams {
+        id: "1.2.643.2.2.32.3",
+        p: "C6971FC57524B30C9018C5E621DE15499736854F56A6F8AEE65A7A404632B1BC\
+           F0349FFCAFCB0A103177971FC1612ADCDB8C8CC938C70225C8FD12AFF01B1D06\
+           4E0AD6FDE6AB9159166CB9F2FC171D92F0CC7B6A6B2CD7FA342ACBE2C9315A42\
+           D576B1ECCE77A963157F3D0BD96A8EB0B0F3502AD238101B05116334F1E5B7AB",
+        q: "B09D634C10899CD7D4C3A7657403E05810B07C61A688BAB2C37F475E308B0607",
+        g: "3D26B467D94A3FFC9D71BF8DB8934084137264F3C2E9EB16DCA214B8BC7C8724\
+           85336744934FD2EF5943F9ED0B745B90AA3EC8D70CDC91682478B664A2E1F8FB\
+           56CEF2972FEE7EDB084AF746419B854FAD02CC3E3646FF2E1A18DD4BEB3C44F7\
+           F2745588029649674546CC9187C207FB8F2CECE8E2293F68395C4704AF04BAB5"
+    },
+    NamedParams {
+        id: "1.2.643.2.2.32.5",
+        p: "80F102D32B0FD167D069C27A307ADAD2C466091904DBAA55D5B8CC7026F2F7A1\
+           919B890CB652C40E054E1E9306735B43D7B279EDDF9102001CD9E1A831FE8A16\
+           3EED89AB07CF2ABE8242AC9DEDDDBF98D62CDDD1EA4F5F15D3A42A6677BDD293\
+           B24260C0F27C0F1D15948614D567B66FA902BAA11A69AE3BCEADBB83E399C9B5",
+        q: "F0F544C418AAC234F683F033511B65C21651A6078BDA2D69BB9F732867502149",
+        g: "6BCC0B4FADB3889C1E06ADD23CC09B8AB6ECDEDF73F04632595EE4250005D6AF\
+           5F5ADE44CB1E26E6263C672347CFA26F9E9393681E6B759733784CDE5DBD9A14\
+           A39369DFD99FA85CC0D10241C4010343F34A91393A706CF12677CBFA1F578D6B\
+           6CFBE8A1242CFCC94B3B653A476E145E3862C18CC3FED8257CFEF74CDB205BF1"
+    },
+    NamedParams {
+        id: "1.2.643.2.2.33.1",
+        p: "CA3B3F2EEE9FD46317D49595A9E7518E6C63D8F4EB4D22D10D28AF0B8839F079\
+           F8289E603B03530784B9BB5A1E76859E4850C670C7B71C0DF84CA3E0D6C177FE\
+           9F78A9D8433230A883CD82A2B2B5C7A3306980278570CDB79BF01074A69C9623\
+           348824B0C53791D53C6A78CAB69E1CFB28368611A397F50F541E16DB348DBE5F",
+        q: "CAE4D85F80C147704B0CA48E85FB00A9057AA4ACC44668E17F1996D7152690D9",
+        g: "BE27D652F2F1E339DA734211B85B06AE4DE236AA8FBEEB3F1ADCC52CD4385377\
+           7E834A6A518138678A8ADBD3A55C70A7EAB1BA7A0719548677AAF4E609FFB47F\
+           6B9D7E45B0D06D83D7ADC53310ABD85783E7317F7EC73268B6A9C08D260B85D8\
+           485696CA39C17B17F044D1E050489036ABD381C5E6BF82BA352A1AFF136601AF"
+    },
+    NamedParams {
+        id: "1.2.643.2.2.33.2",
+        p: "9286DBDA91ECCFC3060AA5598318E2A639F5BA90A4CA656157B2673FB191CD05\
+           89EE05F4CEF1BD13508408271458C30851CE7A4EF534742BFB11F4743C8F787B\
+           11193BA304C0E6BCA25701BF88AF1CB9B8FD4711D89F88E32B37D95316541BF1\
+           E5DBB4989B3DF13659B88C0F97A3C1087B9F2D5317D557DCD4AFC6D0A754E279",
+        q: "C966E9B3B8B7CDD82FF0F83AF87036C38F42238EC50A876CD390E43D67B6013F",
+        g: "7E9C3096676F51E3B2F9884CF0AC2156779496F410E049CED7E53D8B7B5B366B\
+           1A6008E5196605A55E89C3190DABF80B9F1163C979FCD18328DAE5E9048811B3\
+           70107BB7715F82091BB9DE0E33EE2FED6255474F8769FCE5EAFAEEF1CB5A32E0\
+           D5C6C2F0FC0B3447072947F5B4C387666993A333FC06568E534AD56D2338D729"
+    },
+    NamedParams {
+        id: "1.2.643.2.2.33.3",
+        p: "B194036ACE14139D36D64295AE6C50FC4B7D65D8B340711366CA93F383653908\
+           EE637BE428051D86612670AD7B402C09B820FA77D9DA29C8111A8496DA6C261A\
+           53ED252E4D8A69A20376E6ADDB3BDCD331749A491A184B8FDA6D84C31CF05F91\
+           19B5ED35246EA4562D85928BA1136A8D0E5A7E5C764BA8902029A1336C631A1D",
+        q: "96120477DF0F3896628E6F4A88D83C93204C210FF262BCCB7DAE450355125259",
+        g: "3F1817052BAA7598FE3E4F4FC5C5F616E122CFF9EBD89EF81DC7CE8BF56CC64B\
+           43586C80F1C4F56DD5718FDD76300BE336784259CA25AADE5A483F64C02A20CF\
+           4A10F9C189C433DEFE31D263E6C9764660A731ECCAECB74C8279303731E8CF69\
+           205BC73E5A70BDF93E5BB681DAB4EEB9C733CAAB2F673C475E0ECA921D29782E"
+    },
+    NamedParams {
+        id: "rfc5114-1024-160",
+        p: "B10B8F96A080E01DDE92DE5EAE5D54EC52C99FBCFB06A3C69A6A9DCA52D23B61\
+           6073E28675A23D189838EF1E2EE652C013ECB4AEA906112324975C3CD49B83BF\
+           ACCBDD7D90C4BD7098488E9C219A73724EFFD6FAE5644738FAA31A4FF55BCCC0\
+           A151AF5F0DC8B4BD45BF37DF365C1A65E68CFDA76D4DA708DF1FB2BC2E4A4371",
+        q: "F518AA8781A8DF278ABA4E7D64B7CB9D49462353",
+        g: "A4D1CBD5C3FD34126765A442EFB99905F8104DD258AC507FD6406CFF14266D31\
+           266FEA1E5C41564B777E690F5504F213160217B4B01B886A5E91547F9E2749F4\
+           D7FBD7D3B9A92EE1909D0D2263F80A76A6A24C087A091F531DBF0A0169B6A28A\
+           D662A4D18E73AFA32D779D5918D08BC8858F4DCEF97C2A24855E6EEB22B3B2E5"
+    },
+    NamedParams {
+        id: "rfc5114-2048-224",
+        p: "AD107E1E9123A9D0D660FAA79559C51FA20D64E5683B9FD1B54B1597B61D0A75\
+           E6FA141DF95A56DBAF9A3C407BA1DF15EB3D688A309C180E1DE6B85A1274A0A6\
+           6D3F8152AD6AC2129037C9EDEFDA4DF8D91E8FEF55B7394B7AD5B7D0B6C12207\
+           C9F98D11ED34DBF6C6BA0B2C8BBC27BE6A00E0A0B9C49708B3BF8A3170918836\
+           81286130BC8985DB1602E714415D9330278273C7DE31EFDC7310F7121FD5A074\
+           15987D9ADC0A486DCDF93ACC44328387315D75E198C641A480CD86A1B9E587E8\
+           BE60E69CC928B2B9C52172E413042E9B23F10B0E16E79763C9B53DCF4BA80A29\
+           E3FB73C16B8E75B97EF363E2FFA31F71CF9DE5384E71B81C0AC4DFFE0C10E64F",
+        q: "801C0D34C58D93FE997177101F80535A4738CEBCBF389A99B36371EB",
+        g: "AC4032EF4F2D9AE39DF30B5C8FFDAC506CDEBE7B89998CAF74866A08CFE4FFE3\
+           A6824A4E10B9A6F0DD921F01A70C4AFAAB739D7700C29F52C57DB17C620A8652\
+           BE5E9001A8D66AD7C17669101999024AF4D027275AC1348BB8A762D0521BC98A\
+           E247150422EA1ED409939D54DA7460CDB5F6C6B250717CBEF180EB34118E98D1\
+           19529A45D6F834566E3025E316A330EFBB77A86F0C1AB15B051AE3D428C8F8AC\
+           B70A8137150B8EEB10E183EDD19963DDD9E263E4770589EF6AA21E7F5F2FF381\
+           B539CCE3409D13CD566AFBB48D6C019181E1BCFE94B30269EDFE72FE9B6AA4BD\
+           7B5A0F1C71CFFF4C19C418E1F6EC017981BC087F2A7065B384B890D3191F2BFA"
+    },
+    NamedParams {
+        id: "rfc5114-2048-256",
+        p: "87A8E61DB4B6663CFFBBD19C651959998CEEF608660DD0F25D2CEED4435E3B00\
+           E00DF8F1D61957D4FAF7DF4561B2AA3016C3D91134096FAA3BF4296D830E9A7C\
+           209E0C6497517ABD5A8A9D306BCF67ED91F9E6725B4758C022E0B1EF4275BF7B\
+           6C5BFC11D45F9088B941F54EB1E59BB8BC39A0BF12307F5C4FDB70C581B23F76\
+           B63ACAE1CAA6B7902D52526735488A0EF13C6D9A51BFA4AB3AD8347796524D8E\
+           F6A167B5A41825D967E144E5140564251CCACB83E6B486F6B3CA3F7971506026\
+           C0B857F689962856DED4010ABD0BE621C3A3960A54E710C375F26375D7014103\
+           A4B54330C198AF126116D2276E11715F693877FAD7EF09CADB094AE91E1A1597",
+        q: "8CF83642A709A097B447997640129DA299B1A47D1EB3750BA308B0FE64F5FBD3",
+        g: "3FB32C9B73134D0B2E77506660EDBD484CA7B18F21EF205407F4793A1A0BA125\
+           10DBC15077BE463FFF4FED4AAC0BB555BE3A6C1B0C6B47B1BC3773BF7E8C6F62\
+           901228F8C28CBB18A55AE31341000A650196F931C77A57F2DDF463E5E9EC144B\
+           777DE62AAAB8A8628AC376D282D6ED3864E67982428EBC831D14348F6F2F9193\
+           B5045AF2767164E1DFC967C1FB3F2E55A4BD1BFFE83B9C80D052B985D182EA0A\
+           DB2A3B7313D3FE14C8484B1E052588B9B7D2BBD2DF016199ECD06E1557CD0915\
+           B3353BBB64E0EC377FD028370DF92B52C7891428CDC67EB6184B523D1DB246C3\
+           2F63078490F00EF8D647D148D47954515E2327CFEF98C582664B4C0F6CC41659"
+    },
+    NamedParams {
+        id: "rfc3526-3072",
+        p: "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+           020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+           4FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED\
+           EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF05\
+           98DA48361C55D39A69163FA8FD24CF5F83655D23DCA3AD961C62F356208552BB\
+           9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B\
+           E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF695581718\
+           3995497CEA956AE515D2261898FA051015728E5A8AAAC42DAD33170D04507A33\
+           A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7\
+           ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864\
+           D87602733EC86A64521F2B18177B200CBBE117577A615D6C770988C0BAD946E2\
+           08E24FA074E5AB3143DB5BFCE0FD108E4B82D120A93AD2CAFFFFFFFFFFFFFFFF",
+        q: "7FFFFFFFFFFFFFFFE487ED5110B4611A62633145C06E0E68948127044533E63A\
+           0105DF531D89CD9128A5043CC71A026EF7CA8CD9E69D218D98158536F92F8A1B\
+           A7F09AB6B6A8E122F242DABB312F3F637A262174D31BF6B585FFAE5B7A035BF6\
+           F71C35FDAD44CFD2D74F9208BE258FF324943328F6722D9EE1003E5C50B1DF82\
+           CC6D241B0E2AE9CD348B1FD47E9267AFC1B2AE91EE51D6CB0E3179AB1042A95D\
+           CF6A9483B84B4B36B3861AA7255E4C0278BA3604650C10BE19482F23171B671D\
+           F1CF3B960C074301CD93C1D17603D147DAE2AEF837A62964EF15E5FB4AAC0B8C\
+           1CCAA4BE754AB5728AE9130C4C7D02880AB9472D45556216D6998B8682283D19\
+           D42A90D5EF8E5D32767DC2822C6DF785457538ABAE83063ED9CB87C2D370F263\
+           D5FAD7466D8499EB8F464A702512B0CEE771E9130D697735F897FD036CC50432\
+           6C3B01399F643532290F958C0BBD90065DF08BABBD30AEB63B84C4605D6CA371\
+           047127D03A72D598A1EDADFE707E884725C16890549D69657FFFFFFFFFFFFFFF",
+        g: "2"
+    },
+];
+
+
+pub fn ids() -> Vec<&'static str> {
+    let mut ids = vec![BUILTIN];
+    ids.extend(NAMED.iter().map(|n| n.id));
+    ids
+}
+
+
+pub fn by_id(id: &str) -> Option<DsParams> {
+    if id == BUILTIN {
+        return Some(DsParams::builtin());
+    }
+    NAMED.iter().find(|n| n.id == id).map(|n| {
+        DsParams {
+            p: from_hex(n.p),
+            q: from_hex(n.q),
+            g: from_hex(n.g)
+        }
+    })
+}
+
+
+pub fn id_of(params: &DsParams) -> Option<&'static str> {
+    ids().into_iter().find(|id| by_id(*id).as_ref() == Some(params))
+}
+
+
+pub fn default() -> DsParams {
+    by_id(DEFAULT).unwrap()
+}
+
+
+fn from_hex(s: &str) -> BigUint {
+    from_str_radix(s, 16).unwrap()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num::bigint::BigUint;
+    use std::num::{One, Zero};
+    use utils::{is_probable_prime, mod_pow};
+    use super::{ids, by_id, id_of};
+
+    #[test]
+    fn every_set_is_a_prime_order_subgroup() {
+        let one: BigUint = One::one();
+        for id in ids().iter() {
+            let params = by_id(*id).unwrap();
+            assert!(is_probable_prime(&params.p), "{}: p is not prime", id);
+            assert!(is_probable_prime(&params.q), "{}: q is not prime", id);
+            assert!(((params.p - one) % params.q).is_zero()
+                    , "{}: q doesn't divide p - 1", id);
+            assert!(params.g > one && params.g < params.p, "{}: g out of range", id);
+            assert!(mod_pow(params.g.clone(), params.q.clone(), params.p.clone()) == one
+                    , "{}: g doesn't have order q", id);
+        }
+    }
+
+    #[test]
+    fn ids_round_trip() {
+        for id in ids().iter() {
+            assert_eq!(id_of(&by_id(*id).unwrap()), Some(*id));
+        }
+        assert!(by_id("1.2.643.2.2.32.4").is_none());
+    }
+}
@@ -1,11 +1,18 @@
 #![warn(unused_must_use)]
-use std::io::{File, IoResult, EndOfFile};
-use utils::{B16, fill_with_end_bits, is_file_size_can_fit
-            , fill_with_file_size, xor_array_16, file_size, get_original_size};
+use std::io::{File, IoResult, BufReader, MemWriter};
+use std::cmp::min;
+use std::slice::bytes::copy_memory;
+use utils::{B16, fill_with_end_bits, is_file_size_can_fit, fill_with_size
+            , xor_array_16, get_original_size, read_block, invalid_input};
+use armor::{is_armored, armor, dearmor, SYMMETRIC_KEY};
 use std::rand::random;
 use magenta::Magenta;
 
 
+// Ciphertext handed to the block decryption at a time by `dec_chunks`.
+pub static CHUNK_LEN: uint = 1 << 16;
+
+
 pub struct PBC {
     k: B16,
     p0: B16
@@ -19,62 +26,95 @@ impl PBC {
 
     pub fn key_from_file(file_path: &str) -> Box<B16> {
         let mut file = File::open(&Path::new(file_path)).unwrap();
+        let content = file.read_to_end().unwrap();
         let mut key: B16 = [0, ..16];
-        file.read(key);
+
+        if is_armored(content.as_slice()) {
+            let data = dearmor(content.as_slice(), SYMMETRIC_KEY).unwrap();
+            key = PBC::key_from_bytes(data.as_slice()).unwrap();
+        } else {
+            let len = min(content.len(), 16);
+            copy_memory(&mut key, content.slice_to(len));
+        }
         box key
     }
 
-    pub fn rnd_key(file_path: &str) {
+    pub fn key_from_bytes(bytes: &[u8]) -> IoResult<B16> {
+        if bytes.len() != 16 {
+            return Err(invalid_input("pbc: key must be 16 bytes"));
+        }
+        let mut key: B16 = [0, ..16];
+        copy_memory(&mut key, bytes);
+        Ok(key)
+    }
+
+    pub fn gen_key() -> B16 {
         let mut key: B16 = [0, ..16];
         for i in range(0, 16) {
             key[i] = random();
         }
+        key
+    }
 
+    pub fn key_to_file(file_path: &str, key: &B16, is_armor: bool) {
         let mut file = File::create(&Path::new(file_path)).unwrap();
-        file.write(key);
+        if is_armor {
+            file.write_str(armor(SYMMETRIC_KEY, key).as_slice()).unwrap();
+        } else {
+            file.write(key).unwrap();
+        }
+    }
+
+    pub fn rnd_key(file_path: &str) {
+        PBC::key_to_file(file_path, &PBC::gen_key(), false);
     }
 
     pub fn enc_file(&self, file_in: &mut File, file_out: &mut File) -> IoResult<u64> {
+        self.enc_stream(file_in, file_out)
+    }
+
+    pub fn enc_bytes(&self, data: &[u8]) -> Vec<u8> {
+        let mut writer = MemWriter::new();
+        self.enc_stream(&mut BufReader::new(data), &mut writer).unwrap();
+        writer.unwrap()
+    }
+
+    pub fn enc_stream<R: Reader, W: Writer>(&self, reader: &mut R
+                                            , writer: &mut W) -> IoResult<u64> {
         let buf_len = 16;
         let mut ci: B16;
         let mut buf_prev: B16 = self.p0;
         let mut buf_cur: B16;
-        let mut is_filled = false;
         let mut byte_count: uint;
+        let mut byte_in: u64 = 0;
         let mut byte_total: u64 = 0;
 
         loop {
             buf_cur = [0, ..16];
-            match file_in.read(buf_cur) {
-                Err(e) => {
-                    if e.kind == EndOfFile && is_filled {
-                        break;
-                    } else if is_filled {
-                        fail!("pbc: {}", e.desc);
-                    } else {
-                        byte_count = 0;
-                    }
-                },
-                Ok(i) => { byte_count = i; },
-            };
+            byte_count = try!(read_block(reader, &mut buf_cur));
+            byte_in += byte_count as u64;
 
             if byte_count < buf_len {
                 fill_with_end_bits(buf_cur.slice_from_mut(byte_count));
 
                 if !is_file_size_can_fit(byte_count, buf_len) {
                     ci = *self.enc_func(&buf_cur, &buf_prev);
-                    try!(file_out.write(&ci));
+                    try!(writer.write(&ci));
                     byte_total += buf_len as u64;
                     buf_prev = buf_cur;
 
                     buf_cur = [0, ..16];
                 }
-                fill_with_file_size(file_in, buf_cur);
-                is_filled = true;
+                fill_with_size(&mut buf_cur, byte_in);
+
+                ci = *self.enc_func(&buf_cur, &buf_prev);
+                try!(writer.write(&ci));
+                byte_total += buf_len as u64;
+                break;
             }
 
             ci = *self.enc_func(&buf_cur, &buf_prev);
-            try!(file_out.write(&ci));
+            try!(writer.write(&ci));
             byte_total += buf_len as u64;
             buf_prev = buf_cur;
         }
@@ -82,37 +122,40 @@ impl PBC {
     }
 
     pub fn dec_file(&self, file_in: &mut File, file_out: &mut File) {
-        let buf_len = 16;
-        let mut p_cur: B16;
-        let mut p_prev: B16 = self.p0;
-        let mut c_cur: B16;
-        let fs = file_size(file_in);
-        let block_count = fs / buf_len;
-
-        for i in range(0, block_count - 2) {
-            c_cur = [0, ..16];
-            file_in.read(c_cur);
-            p_cur = *self.dec_func(&p_prev, &c_cur);
-            file_out.write(&p_cur);
-            p_prev = p_cur;
+        match self.dec_stream(file_in, file_out) {
+            Err(e) => fail!("pbc: {}", e.desc),
+            Ok(_) => {},
         }
+    }
 
-        c_cur = [0, ..16];
-        file_in.read(c_cur);
-        let first_block: B16 = *self.dec_func(&p_prev, &c_cur);
-        c_cur = [0, ..16];
-        file_in.read(c_cur);
-        let second_block: B16 = *self.dec_func(&first_block, &c_cur);
-
-        let original_size = get_original_size(&second_block);
-        let remain_size = original_size - (block_count - 2) * buf_len;
-        let block_remain = (remain_size - buf_len) as uint;
-        if remain_size >= buf_len {
-            file_out.write(&first_block);
-            file_out.write(second_block.slice_to(block_remain));
-        } else {
-            file_out.write(first_block.slice_to(remain_size as uint));
+    pub fn dec_bytes(&self, data: &[u8]) -> IoResult<Vec<u8>> {
+        let mut writer = MemWriter::new();
+        try!(self.dec_stream(&mut BufReader::new(data), &mut writer));
+        Ok(writer.unwrap())
+    }
+
+    pub fn dec_stream<R: Reader, W: Writer>(&self, reader: &mut R
+                                            , writer: &mut W) -> IoResult<u64> {
+        dec_chunks(reader, writer, &self.p0, |chain, ct| {
+            PBC::new(&self.k, chain).dec_blocks(ct)
+        })
+    }
+
+    // Whole blocks chained from p0, without the padding and size block
+    // handling of `dec_stream`.
+    pub fn dec_blocks(&self, data: &[u8]) -> IoResult<Vec<u8>> {
+        if data.len() % 16 != 0 {
+            return Err(invalid_input("pbc: truncated ciphertext"));
+        }
+        let mut p_prev: B16 = self.p0;
+        let mut c_cur: B16 = [0, ..16];
+        let mut res = Vec::with_capacity(data.len());
+        for block in data.chunks(16) {
+            copy_memory(&mut c_cur, block);
+            p_prev = *self.dec_func(&p_prev, &c_cur);
+            res.push_all(&p_prev);
         }
+        Ok(res)
     }
 
     fn enc_func(&self, buf_cur: &B16, buf_prev: &B16) -> Box<B16> {
@@ -125,3 +168,58 @@ impl PBC {
         Magenta::new_128(&self.k).dec(&m)
     }
 }
+
+
+// Decrypts a stream up to CHUNK_LEN bytes at a time with `dec`, which
+// gets the chaining block (the last plaintext block, p0 at first) and
+// whole ciphertext blocks and returns their plaintext. The key can thus
+// be held elsewhere, as by the agent, whatever the length of the stream.
+pub fn dec_chunks<R: Reader, W: Writer>(reader: &mut R, writer: &mut W, p0: &B16
+                                        , dec: |&B16, &[u8]| -> IoResult<Vec<u8>>)
+                                        -> IoResult<u64> {
+    let buf_len = 16;
+    let mut chain: B16 = *p0;
+    let mut ct = Vec::from_elem(CHUNK_LEN, 0u8);
+    // The size block and the block before it may both hold padding,
+    // so the last two plaintext blocks are held back until the end.
+    let mut pending: Vec<u8> = Vec::new();
+    let mut byte_total: u64 = 0;
+
+    loop {
+        let n = try!(read_block(reader, ct.as_mut_slice()));
+        if n % buf_len != 0 {
+            return Err(invalid_input("pbc: truncated ciphertext"));
+        }
+        if n > 0 {
+            let pt = try!(dec(&chain, ct.slice_to(n)));
+            if pt.len() != n {
+                return Err(invalid_input("pbc: incorrect block decryption"));
+            }
+            copy_memory(&mut chain, pt.slice_from(n - buf_len));
+            pending.push_all(pt.as_slice());
+            if pending.len() > 2 * buf_len {
+                let done = pending.len() - 2 * buf_len;
+                try!(writer.write(pending.slice_to(done)));
+                byte_total += done as u64;
+                pending = pending.slice_from(done).to_vec();
+            }
+        }
+        if n < CHUNK_LEN {
+            break;
+        }
+    }
+
+    if pending.is_empty() {
+        return Err(invalid_input("pbc: empty ciphertext"));
+    }
+    let mut last: B16 = [0, ..16];
+    copy_memory(&mut last, pending.slice_from(pending.len() - buf_len));
+    let original_size = get_original_size(&last);
+    // 8 bytes of size and at least one byte of end bits are not data.
+    let available = (pending.len() - 9) as u64;
+    if original_size < byte_total || original_size - byte_total > available {
+        return Err(invalid_input("pbc: corrupted ciphertext"));
+    }
+    try!(writer.write(pending.slice_to((original_size - byte_total) as uint)));
+    Ok(original_size)
+}
@@ -0,0 +1,175 @@
+/*
+Password-based file encryption.
+
+The key is derived with PBKDF2-HMAC-Magenta from the password, a random
+salt and an iteration count, which are stored in the header:
+
+"MGPB" || version (1) || iterations (4, big-endian) || salt (16) || check (16)
+
+The first 32 bytes of the PBKDF2 output are a `seal` key and the last 16
+are the check value; the check only lets a wrong password be reported
+as such. The header is followed by the plaintext sealed with the header
+as associated data, so a modified file is rejected before anything is
+written. Version 1 files carried bare PBC ciphertext and are no longer
+read. Iteration counts above `kdf::MAX_ITERATIONS` are refused both ways.
+*/
+use std::io::{IoResult, BufReader, MemWriter};
+use std::slice::bytes::copy_memory;
+use kdf::{pbkdf2, MAX_ITERATIONS};
+use seal::{seal_with_ad, open_with_ad};
+use utils::{B32, random_bytes, ct_eq, invalid_input};
+
+
+static MAGIC: &'static [u8] = b"MGPB";
+static VERSION: u8 = 2;
+static SALT_LEN: uint = 16;
+static CHECK_LEN: uint = 16;
+
+
+pub fn is_pbe(head: &[u8]) -> bool {
+    head.starts_with(MAGIC)
+}
+
+
+pub fn enc_stream<R: Reader, W: Writer>(password: &str, iterations: uint
+                                        , reader: &mut R, writer: &mut W) -> IoResult<u64> {
+    if iterations == 0 || iterations > MAX_ITERATIONS {
+        return Err(invalid_input("pbe: invalid iteration count"));
+    }
+    let salt = random_bytes(SALT_LEN);
+    let (key, check) = derive_key(password, salt.as_slice(), iterations);
+
+    let header = try!(header_bytes(iterations, salt.as_slice(), check.as_slice()));
+    let plaintext = try!(reader.read_to_end());
+    try!(writer.write(header.as_slice()));
+    try!(writer.write(seal_with_ad(&key, header.as_slice(), plaintext.as_slice()).as_slice()));
+    Ok(plaintext.len() as u64)
+}
+
+
+// Nothing is written unless both the password and the MAC check out.
+pub fn dec_stream<R: Reader, W: Writer>(password: &str, reader: &mut R
+                                        , writer: &mut W) -> IoResult<u64> {
+    let (key, header) = try!(read_header(password, reader));
+    let sealed = try!(reader.read_to_end());
+    let plaintext = match open_with_ad(&key, header.as_slice(), sealed.as_slice()) {
+        Ok(p) => p,
+        Err(_) => return Err(invalid_input("pbe: the file was modified or corrupted")),
+    };
+    try!(writer.write(plaintext.as_slice()));
+    Ok(plaintext.len() as u64)
+}
+
+
+pub fn enc_bytes(password: &str, iterations: uint, data: &[u8]) -> Vec<u8> {
+    let mut writer = MemWriter::new();
+    enc_stream(password, iterations, &mut BufReader::new(data), &mut writer).unwrap();
+    writer.unwrap()
+}
+
+
+pub fn dec_bytes(password: &str, data: &[u8]) -> IoResult<Vec<u8>> {
+    let mut writer = MemWriter::new();
+    try!(dec_stream(password, &mut BufReader::new(data), &mut writer));
+    Ok(writer.unwrap())
+}
+
+
+// Reads the header and checks the password. Returns the sealing key and
+// the raw header bytes, which the MAC covers.
+fn read_header<R: Reader>(password: &str, reader: &mut R) -> IoResult<(B32, Vec<u8>)> {
+    if try!(reader.read_exact(MAGIC.len())).as_slice() != MAGIC {
+        return Err(invalid_input("pbe: not a password-encrypted file"));
+    }
+    if try!(reader.read_u8()) != VERSION {
+        return Err(invalid_input("pbe: unsupported version"));
+    }
+    let iterations = try!(reader.read_be_u32()) as uint;
+    if iterations == 0 || iterations > MAX_ITERATIONS {
+        return Err(invalid_input("pbe: invalid iteration count"));
+    }
+    let salt = try!(reader.read_exact(SALT_LEN));
+    let stored_check = try!(reader.read_exact(CHECK_LEN));
+
+    let (key, check) = derive_key(password, salt.as_slice(), iterations);
+    if !ct_eq(check.as_slice(), stored_check.as_slice()) {
+        return Err(invalid_input("pbe: wrong password"));
+    }
+    let header = try!(header_bytes(iterations, salt.as_slice(), check.as_slice()));
+    Ok((key, header))
+}
+
+
+fn header_bytes(iterations: uint, salt: &[u8], check: &[u8]) -> IoResult<Vec<u8>> {
+    let mut writer = MemWriter::new();
+    try!(writer.write(MAGIC));
+    try!(writer.write_u8(VERSION));
+    try!(writer.write_be_u32(iterations as u32));
+    try!(writer.write(salt));
+    try!(writer.write(check));
+    Ok(writer.unwrap())
+}
+
+
+fn derive_key(password: &str, salt: &[u8], iterations: uint) -> (B32, Vec<u8>) {
+    let dk = pbkdf2(password.as_bytes(), salt, iterations, 32 + CHECK_LEN);
+    let mut key: B32 = [0, ..32];
+    copy_memory(&mut key, dk.slice_to(32));
+    (key, dk.slice_from(32).to_vec())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, MemWriter};
+    use kdf::MAX_ITERATIONS;
+    use super::{enc_bytes, dec_bytes, enc_stream};
+
+    static MSG: &'static [u8] = b"a message long enough to take several pbc blocks";
+    static HEADER_LEN: uint = 4 + 1 + 4 + 16 + 16;
+
+    #[test]
+    fn round_trip() {
+        let ct = enc_bytes("correct horse", 10, MSG);
+        assert_eq!(dec_bytes("correct horse", ct.as_slice()).unwrap().as_slice(), MSG);
+        assert!(dec_bytes("wrong horse", ct.as_slice()).is_err());
+
+        let empty = enc_bytes("correct horse", 10, b"");
+        assert!(dec_bytes("correct horse", empty.as_slice()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let ct = enc_bytes("correct horse", 10, MSG);
+
+        // The check value, the IV, the first content block and the tag.
+        for &i in [HEADER_LEN - 1, HEADER_LEN, HEADER_LEN + 20, ct.len() - 1].iter() {
+            let mut bad = ct.clone();
+            *bad.get_mut(i) ^= 1;
+            assert!(dec_bytes("correct horse", bad.as_slice()).is_err(), "byte {}", i);
+        }
+        for &len in [ct.len() - 16, HEADER_LEN].iter() {
+            assert!(dec_bytes("correct horse", ct.slice_to(len)).is_err(), "length {}", len);
+        }
+        // Version 1 had no MAC.
+        let mut old = ct.clone();
+        *old.get_mut(4) = 1;
+        assert!(dec_bytes("correct horse", old.as_slice()).is_err());
+    }
+
+    #[test]
+    fn iteration_count_is_bounded() {
+        for &n in [0, MAX_ITERATIONS + 1].iter() {
+            let mut out = MemWriter::new();
+            assert!(enc_stream("pw", n, &mut BufReader::new(MSG), &mut out).is_err());
+        }
+
+        // A header claiming too many iterations is refused without running PBKDF2.
+        let mut ct = enc_bytes("pw", 10, MSG);
+        let n = MAX_ITERATIONS as u32 + 1;
+        for i in range(0u, 4) {
+            *ct.get_mut(5 + i) = (n >> (8 * (3 - i))) as u8;
+        }
+        assert!(dec_bytes("pw", ct.as_slice()).is_err());
+    }
+}
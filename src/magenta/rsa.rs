@@ -0,0 +1,482 @@
+/*
+RSA (RFC 8017) with the Magenta hash: RSASSA-PSS signatures and
+RSAES-OAEP encryption, both with MGF1 over `h_bytes` and a 16-byte
+salt. Private operations use the CRT with blinding; the exponents
+dp and dq go through the constant-time exponentiation. Keys are
+stored as PKCS#1 RSAPrivateKey and RSAPublicKey DER.
+*/
+use std::io::IoResult;
+use std::rand;
+use num::Integer;
+use num::bigint::{BigUint, ToBigUint, RandBigInt};
+use std::num::{One, Zero};
+use hash::{h_bytes, h_reader};
+use ctpow::CtMont;
+use der::{DerReader, encode_integer, encode_sequence};
+use armor::{Armored, is_armored, decode_bytes, RSA_PRIVATE_KEY, RSA_PUBLIC_KEY};
+use utils::{B16, mod_pow, mod_inverse, gen_prime, biguint_from_bytes
+            , biguint_to_fixed_bytes, byte_len, random_bytes, ct_eq, invalid_input};
+
+
+pub static DEFAULT_BITS: uint = 2048;
+pub static MIN_BITS: uint = 1024;
+static PUBLIC_EXPONENT: uint = 65537;
+static HLEN: uint = 16;
+static SLEN: uint = 16;
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct RsaPublicKey {
+    pub n: BigUint,
+    pub e: BigUint
+}
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct RsaPrivateKey {
+    pub n: BigUint,
+    pub e: BigUint,
+    pub d: BigUint,
+    pub p: BigUint,
+    pub q: BigUint,
+    pub dp: BigUint,
+    pub dq: BigUint,
+    pub qinv: BigUint
+}
+
+
+impl RsaPublicKey {
+    pub fn size(&self) -> uint {
+        byte_len(&self.n)
+    }
+
+    pub fn encrypt_raw(&self, m: &BigUint) -> BigUint {
+        mod_pow(m.clone(), self.e.clone(), self.n.clone())
+    }
+
+    pub fn verify_pss(&self, msg: &[u8], sig: &[u8]) -> bool {
+        self.verify_pss_prehashed(&*h_bytes(msg), sig)
+    }
+
+    pub fn verify_pss_reader<R: Reader>(&self, reader: &mut R, sig: &[u8]) -> bool {
+        self.verify_pss_prehashed(&*h_reader(reader), sig)
+    }
+
+    pub fn verify_pss_prehashed(&self, digest: &B16, sig: &[u8]) -> bool {
+        if sig.len() != self.size() {
+            return false;
+        }
+        let s = biguint_from_bytes(sig);
+        if s >= self.n {
+            return false;
+        }
+        let em_bits = self.n.bits() - 1;
+        let em_len = (em_bits + 7) / 8;
+        let m = self.encrypt_raw(&s);
+        if byte_len(&m) > em_len {
+            return false;
+        }
+        pss_verify(digest, biguint_to_fixed_bytes(&m, em_len).as_slice(), em_bits)
+    }
+
+    pub fn encrypt_oaep(&self, msg: &[u8], label: &[u8]) -> IoResult<Vec<u8>> {
+        let k = self.size();
+        if msg.len() + 2 * HLEN + 2 > k {
+            return Err(invalid_input("rsa: message too long"));
+        }
+
+        let mut db = h_bytes(label).to_vec();
+        db.grow(k - msg.len() - 2 * HLEN - 2, 0u8);
+        db.push(1);
+        db.push_all(msg);
+        let seed = random_bytes(HLEN);
+        xor_in_place(db.as_mut_slice(), mgf1(seed.as_slice(), k - HLEN - 1).as_slice());
+        let mut masked_seed = seed.clone();
+        xor_in_place(masked_seed.as_mut_slice(), mgf1(db.as_slice(), HLEN).as_slice());
+
+        let mut em = vec![0u8];
+        em.push_all(masked_seed.as_slice());
+        em.push_all(db.as_slice());
+        let c = self.encrypt_raw(&biguint_from_bytes(em.as_slice()));
+        Ok(biguint_to_fixed_bytes(&c, k))
+    }
+
+    pub fn wrap_key(&self, key: &B16) -> IoResult<Vec<u8>> {
+        self.encrypt_oaep(key, b"")
+    }
+
+    pub fn to_der(&self) -> Vec<u8> {
+        encode_sequence(&[encode_integer(&self.n), encode_integer(&self.e)])
+    }
+
+    pub fn from_der(bytes: &[u8]) -> IoResult<RsaPublicKey> {
+        let mut reader = DerReader::new(bytes);
+        let mut seq = try!(reader.read_sequence());
+        let n = try!(seq.read_integer());
+        let e = try!(seq.read_integer());
+        try!(seq.finish());
+        try!(reader.finish());
+
+        let three = 3u.to_biguint().unwrap();
+        if n.bits() < MIN_BITS || !n.is_odd() || e < three || !e.is_odd() || e >= n {
+            return Err(invalid_input("rsa: invalid public key"));
+        }
+        Ok(RsaPublicKey { n: n, e: e })
+    }
+
+    pub fn to_armor(&self) -> String {
+        Armored::new(RSA_PUBLIC_KEY, self.to_der()).encode()
+    }
+
+    pub fn from_bytes(content: &[u8]) -> IoResult<RsaPublicKey> {
+        if !is_armored(content) {
+            return RsaPublicKey::from_der(content);
+        }
+        let armored = try!(decode_bytes(content));
+        if armored.label.as_slice() != RSA_PUBLIC_KEY {
+            return Err(invalid_input("rsa: not a public key"));
+        }
+        RsaPublicKey::from_der(armored.data.as_slice())
+    }
+}
+
+
+impl RsaPrivateKey {
+    pub fn generate(bits: uint) -> RsaPrivateKey {
+        if bits < MIN_BITS {
+            fail!("rsa: keys need at least {} bits", MIN_BITS);
+        }
+        let one: BigUint = One::one();
+        let e = PUBLIC_EXPONENT.to_biguint().unwrap();
+        loop {
+            let p = gen_prime(bits - bits / 2);
+            let q = gen_prime(bits / 2);
+            let n = p * q;
+            if p == q || n.bits() != bits {
+                continue;
+            }
+            let lambda = (p - one).lcm(&(q - one));
+            match mod_inverse(&e, &lambda) {
+                Some(d) => return RsaPrivateKey::from_primes(p, q, e, d).unwrap(),
+                None => continue,
+            }
+        }
+    }
+
+    fn from_primes(p: BigUint, q: BigUint, e: BigUint, d: BigUint)
+                   -> IoResult<RsaPrivateKey> {
+        let one: BigUint = One::one();
+        let (p, q) = if p > q { (p, q) } else { (q, p) };
+        // Equal or non-coprime factors have no CRT coefficient.
+        let qinv = match mod_inverse(&q, &p) {
+            Some(qinv) => qinv,
+            None => return Err(invalid_input("rsa: inconsistent private key")),
+        };
+        Ok(RsaPrivateKey {
+            n: p * q,
+            dp: d % (p - one),
+            dq: d % (q - one),
+            qinv: qinv,
+            e: e,
+            d: d,
+            p: p,
+            q: q
+        })
+    }
+
+    pub fn public_key(&self) -> RsaPublicKey {
+        RsaPublicKey { n: self.n.clone(), e: self.e.clone() }
+    }
+
+    pub fn size(&self) -> uint {
+        byte_len(&self.n)
+    }
+
+    // c^d mod n by the CRT, blinded with a random r^e so that the
+    // timing of the big-number arithmetic doesn't depend on c.
+    pub fn decrypt_raw(&self, c: &BigUint) -> BigUint {
+        let one: BigUint = One::one();
+        let mut rng = rand::task_rng();
+        let mut r;
+        let r_inv;
+        loop {
+            r = rng.gen_biguint_range(&(one + one), &self.n);
+            match mod_inverse(&r, &self.n) {
+                Some(inv) => {
+                    r_inv = inv;
+                    break;
+                },
+                None => {},
+            }
+        }
+        let blinded = *c * mod_pow(r, self.e.clone(), self.n.clone()) % self.n;
+
+        let m1 = CtMont::new(&self.p).pow(&blinded, &self.dp, self.p.bits());
+        let m2 = CtMont::new(&self.q).pow(&blinded, &self.dq, self.q.bits());
+        let h = self.qinv * ((m1 + self.p - m2 % self.p) % self.p) % self.p;
+        let m = (m2 + h * self.q) * r_inv % self.n;
+
+        if mod_pow(m.clone(), self.e.clone(), self.n.clone()) != *c % self.n {
+            fail!("rsa: private key operation failed");
+        }
+        m
+    }
+
+    pub fn sign_pss(&self, msg: &[u8]) -> Vec<u8> {
+        self.sign_pss_prehashed(&*h_bytes(msg))
+    }
+
+    pub fn sign_pss_reader<R: Reader>(&self, reader: &mut R) -> Vec<u8> {
+        self.sign_pss_prehashed(&*h_reader(reader))
+    }
+
+    pub fn sign_pss_prehashed(&self, digest: &B16) -> Vec<u8> {
+        let em_bits = self.n.bits() - 1;
+        let em = pss_encode(digest, em_bits);
+        let s = self.decrypt_raw(&biguint_from_bytes(em.as_slice()));
+        biguint_to_fixed_bytes(&s, self.size())
+    }
+
+    pub fn decrypt_oaep(&self, ct: &[u8], label: &[u8]) -> IoResult<Vec<u8>> {
+        let k = self.size();
+        let c = biguint_from_bytes(ct);
+        if ct.len() != k || k < 2 * HLEN + 2 || c >= self.n {
+            return Err(invalid_input("rsa: decryption error"));
+        }
+        let em = biguint_to_fixed_bytes(&self.decrypt_raw(&c), k);
+
+        let mut seed = em.slice(1, 1 + HLEN).to_vec();
+        let mut db = em.slice_from(1 + HLEN).to_vec();
+        xor_in_place(seed.as_mut_slice(), mgf1(db.as_slice(), HLEN).as_slice());
+        xor_in_place(db.as_mut_slice(), mgf1(seed.as_slice(), k - HLEN - 1).as_slice());
+
+        // Every check runs before any of them is acted on, and all fail
+        // the same way, so a padding oracle learns nothing (Manger 2001).
+        let mut bad = em[0] != 0;
+        bad |= !ct_eq(db.slice_to(HLEN), h_bytes(label).as_slice());
+        let mut sep = 0u;
+        let mut looking = true;
+        for (i, b) in db.iter().enumerate().skip(HLEN) {
+            let found = looking && *b == 1;
+            bad |= looking && *b != 0 && *b != 1;
+            if found {
+                sep = i;
+            }
+            looking = looking && !found;
+        }
+        bad |= looking;
+        if bad {
+            return Err(invalid_input("rsa: decryption error"));
+        }
+        Ok(db.slice_from(sep + 1).to_vec())
+    }
+
+    pub fn unwrap_key(&self, wrapped: &[u8]) -> IoResult<B16> {
+        let key = try!(self.decrypt_oaep(wrapped, b""));
+        if key.len() != 16 {
+            return Err(invalid_input("rsa: wrapped key has the wrong size"));
+        }
+        let mut res: B16 = [0, ..16];
+        for (i, b) in key.iter().enumerate() {
+            res[i] = *b;
+        }
+        Ok(res)
+    }
+
+    pub fn to_der(&self) -> Vec<u8> {
+        let version: BigUint = Zero::zero();
+        encode_sequence(&[encode_integer(&version), encode_integer(&self.n)
+                         , encode_integer(&self.e), encode_integer(&self.d)
+                         , encode_integer(&self.p), encode_integer(&self.q)
+                         , encode_integer(&self.dp), encode_integer(&self.dq)
+                         , encode_integer(&self.qinv)])
+    }
+
+    pub fn from_der(bytes: &[u8]) -> IoResult<RsaPrivateKey> {
+        let mut reader = DerReader::new(bytes);
+        let mut seq = try!(reader.read_sequence());
+        let version = try!(seq.read_integer());
+        if !version.is_zero() {
+            return Err(invalid_input("rsa: unsupported key version"));
+        }
+        let n = try!(seq.read_integer());
+        let e = try!(seq.read_integer());
+        let d = try!(seq.read_integer());
+        let p = try!(seq.read_integer());
+        let q = try!(seq.read_integer());
+        try!(seq.read_integer());
+        try!(seq.read_integer());
+        try!(seq.read_integer());
+        try!(seq.finish());
+        try!(reader.finish());
+
+        let one: BigUint = One::one();
+        if p * q != n || p <= one || q <= one || n.bits() < MIN_BITS
+            || (d * e) % (p - one).lcm(&(q - one)) != one {
+            return Err(invalid_input("rsa: inconsistent private key"));
+        }
+        // The CRT values are recomputed rather than trusted.
+        RsaPrivateKey::from_primes(p, q, e, d)
+    }
+
+    pub fn to_armor(&self) -> String {
+        Armored::new(RSA_PRIVATE_KEY, self.to_der()).encode()
+    }
+}
+
+
+// MGF1 (RFC 8017, B.2.1) over the Magenta hash.
+pub fn mgf1(seed: &[u8], len: uint) -> Vec<u8> {
+    let mut res = Vec::with_capacity(len + HLEN);
+    let mut counter = 0u32;
+    while res.len() < len {
+        let mut buf = seed.to_vec();
+        buf.push_all(&[(counter >> 24) as u8, (counter >> 16) as u8
+                       , (counter >> 8) as u8, counter as u8]);
+        res.push_all(h_bytes(buf.as_slice()).as_slice());
+        counter += 1;
+    }
+    res.truncate(len);
+    res
+}
+
+
+// EMSA-PSS-ENCODE (RFC 8017, 9.1.1).
+fn pss_encode(digest: &B16, em_bits: uint) -> Vec<u8> {
+    let em_len = (em_bits + 7) / 8;
+    let salt = random_bytes(SLEN);
+    let h = pss_hash(digest, salt.as_slice());
+
+    let mut db = Vec::from_elem(em_len - SLEN - HLEN - 2, 0u8);
+    db.push(1);
+    db.push_all(salt.as_slice());
+    xor_in_place(db.as_mut_slice(), mgf1(h.as_slice(), em_len - HLEN - 1).as_slice());
+    db.as_mut_slice()[0] &= 0xffu8 >> (8 * em_len - em_bits);
+
+    let mut em = db;
+    em.push_all(h.as_slice());
+    em.push(0xbc);
+    em
+}
+
+
+// EMSA-PSS-VERIFY (RFC 8017, 9.1.2).
+fn pss_verify(digest: &B16, em: &[u8], em_bits: uint) -> bool {
+    let em_len = em.len();
+    if em_len < HLEN + SLEN + 2 || em[em_len - 1] != 0xbc {
+        return false;
+    }
+    let top_mask = !(0xffu8 >> (8 * em_len - em_bits));
+    if em[0] & top_mask != 0 {
+        return false;
+    }
+
+    let h = em.slice(em_len - HLEN - 1, em_len - 1);
+    let mut db = em.slice_to(em_len - HLEN - 1).to_vec();
+    xor_in_place(db.as_mut_slice(), mgf1(h, em_len - HLEN - 1).as_slice());
+    db.as_mut_slice()[0] &= !top_mask;
+
+    let ps_len = em_len - HLEN - SLEN - 2;
+    if db.slice_to(ps_len).iter().any(|b| *b != 0) || db[ps_len] != 1 {
+        return false;
+    }
+    ct_eq(pss_hash(digest, db.slice_from(ps_len + 1)).as_slice(), h)
+}
+
+
+fn pss_hash(digest: &B16, salt: &[u8]) -> Vec<u8> {
+    let mut m = Vec::from_elem(8, 0u8);
+    m.push_all(digest);
+    m.push_all(salt);
+    h_bytes(m.as_slice()).to_vec()
+}
+
+
+fn xor_in_place(data: &mut [u8], mask: &[u8]) {
+    for (d, m) in data.iter_mut().zip(mask.iter()) {
+        *d ^= *m;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num::Integer;
+    use num::bigint::{BigUint, ToBigUint};
+    use std::num::{One, Zero};
+    use der::{encode_integer, encode_sequence};
+    use utils::mod_inverse;
+    use super::{RsaPrivateKey, RsaPublicKey, MIN_BITS};
+
+    static MSG: &'static [u8] = b"a message to sign";
+
+    #[test]
+    fn pss_round_trip() {
+        let key = RsaPrivateKey::generate(MIN_BITS);
+        let public = key.public_key();
+        let sig = key.sign_pss(MSG);
+        assert_eq!(sig.len(), key.size());
+        assert!(public.verify_pss(MSG, sig.as_slice()));
+        assert!(!public.verify_pss(b"another message", sig.as_slice()));
+
+        for &i in [0, sig.len() / 2, sig.len() - 1].iter() {
+            let mut bad = sig.clone();
+            *bad.get_mut(i) ^= 1;
+            assert!(!public.verify_pss(MSG, bad.as_slice()), "byte {}", i);
+        }
+        assert!(!public.verify_pss(MSG, sig.slice_from(1)));
+    }
+
+    #[test]
+    fn oaep_round_trip() {
+        let key = RsaPrivateKey::generate(MIN_BITS);
+        let public = key.public_key();
+        let ct = public.encrypt_oaep(MSG, b"label").unwrap();
+        assert_eq!(key.decrypt_oaep(ct.as_slice(), b"label").unwrap().as_slice(), MSG);
+        assert!(key.decrypt_oaep(ct.as_slice(), b"other label").is_err());
+
+        let mut bad = ct.clone();
+        *bad.get_mut(ct.len() - 1) ^= 1;
+        assert!(key.decrypt_oaep(bad.as_slice(), b"label").is_err());
+
+        let cek = [7u8, ..16];
+        let wrapped = public.wrap_key(&cek).unwrap();
+        assert_eq!(key.unwrap_key(wrapped.as_slice()).unwrap().as_slice(), cek.as_slice());
+        // A message of the wrong size is not a wrapped key.
+        assert!(key.unwrap_key(ct.as_slice()).is_err());
+    }
+
+    #[test]
+    fn der_round_trip() {
+        let key = RsaPrivateKey::generate(MIN_BITS);
+        assert_eq!(RsaPrivateKey::from_der(key.to_der().as_slice()).unwrap(), key);
+        let public = key.public_key();
+        assert_eq!(RsaPublicKey::from_der(public.to_der().as_slice()).unwrap(), public);
+
+        let der = key.to_der();
+        assert!(RsaPrivateKey::from_der(der.slice_to(der.len() - 1)).is_err());
+    }
+
+    // A private key DER with the given factors and a matching d.
+    fn private_der(p: &BigUint, q: &BigUint) -> Vec<u8> {
+        let one: BigUint = One::one();
+        let zero: BigUint = Zero::zero();
+        let e = 65537u.to_biguint().unwrap();
+        let d = mod_inverse(&e, &(*p - one).lcm(&(*q - one))).unwrap();
+        encode_sequence(&[encode_integer(&zero), encode_integer(&(*p * *q))
+                         , encode_integer(&e), encode_integer(&d)
+                         , encode_integer(p), encode_integer(q)
+                         , encode_integer(&zero), encode_integer(&zero)
+                         , encode_integer(&zero)])
+    }
+
+    #[test]
+    fn bad_factors_are_rejected() {
+        let key = RsaPrivateKey::generate(MIN_BITS);
+        assert!(RsaPrivateKey::from_der(private_der(&key.p, &key.q).as_slice()).is_ok());
+        assert!(RsaPrivateKey::from_der(private_der(&key.p, &key.p).as_slice()).is_err());
+        let three = 3u.to_biguint().unwrap();
+        let multiple = key.q * three;
+        assert!(RsaPrivateKey::from_der(private_der(&multiple, &key.q).as_slice()).is_err());
+    }
+}
@@ -0,0 +1,425 @@
+/*
+Signature schemes over the `DigSig` parameter sets and keys, chosen by
+name: gost (the original `DigSig`), schnorr and dsa.
+
+GOST signature files keep their original layout. Files of the other
+schemes name the scheme on the first line in text form, before the
+parameter set ID, and in a Scheme header in armored form. The key ID
+of the signing key goes first, in a Key-Id line or header (see
+`fingerprint`), followed by the signed metadata, if any (see
+`metadata`); files without them are still read. The Hash line names
+the `hash` construction the message was hashed with; files without
+metadata use magenta:
+
+Key-Id: 3f2a9c0d7e6b1845
+Created: 1412345678
+Expires: 1443881678
+Hash: magenta
+Comment: release 0.2
+dsa
+rfc5114-2048-256
+<r>
+<s>
+*/
+use std::io::{File, IoResult};
+use num::bigint::BigUint;
+use std::num::from_str_radix;
+use ds::{DigSig, DsParams};
+use schnorr::SchnorrSig;
+use dsa::{DsaSig, MagentaHash};
+use armor::{Armored, is_armored, SIGNATURE};
+use fingerprint;
+use fingerprint::KeyId;
+use metadata::Metadata;
+use hash;
+use hash::{Construction, h_reader};
+use utils::invalid_input;
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum Scheme {
+    Gost,
+    Schnorr,
+    Dsa
+}
+
+
+impl Scheme {
+    pub fn by_name(name: &str) -> IoResult<Scheme> {
+        match name {
+            "gost" => Ok(Gost),
+            "schnorr" => Ok(Schnorr),
+            "dsa" => Ok(Dsa),
+            _ => Err(invalid_input("scheme: unknown signature scheme")),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Gost => "gost",
+            Schnorr => "schnorr",
+            Dsa => "dsa",
+        }
+    }
+}
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum Signature {
+    GostSignature(DigSig),
+    SchnorrSignature(SchnorrSig),
+    DsaSignature(DsaSig)
+}
+
+
+impl Signature {
+    pub fn sign_reader<R: Reader>(scheme: Scheme, params: &DsParams, reader: &mut R
+                                  , x: &BigUint) -> (BigUint, Signature) {
+        Signature::sign_prehashed(scheme, params, h_reader(reader).as_slice(), x)
+    }
+
+    // `digest` may come from any `hash` construction: GOST reduces it mod
+    // q, Schnorr hashes it into the challenge and DSA keeps its leftmost
+    // bits.
+    pub fn sign_prehashed(scheme: Scheme, params: &DsParams, digest: &[u8]
+                          , x: &BigUint) -> (BigUint, Signature) {
+        match scheme {
+            Gost => {
+                let (y, ds) = DigSig::sign_prehashed(params, digest, x);
+                (y, GostSignature(ds))
+            },
+            Schnorr => {
+                let sig = SchnorrSig::sign_prehashed(params, digest, x);
+                (DigSig::public_key(params, x), SchnorrSignature(sig))
+            },
+            Dsa => {
+                let sig = DsaSig::sign_digest(params, digest, x);
+                (DigSig::public_key(params, x), DsaSignature(sig))
+            },
+        }
+    }
+
+    pub fn verify_reader<R: Reader>(&self, params: &DsParams, reader: &mut R
+                                    , y: &BigUint) -> bool {
+        match *self {
+            GostSignature(ref ds) => ds.verify_reader(params, reader, y),
+            SchnorrSignature(ref sig) => sig.verify_reader(params, reader, y),
+            DsaSignature(ref sig) => sig.verify_reader(params, &MagentaHash, reader, y),
+        }
+    }
+
+    pub fn verify_prehashed(&self, params: &DsParams, digest: &[u8], y: &BigUint) -> bool {
+        match *self {
+            GostSignature(ref ds) => ds.verify_prehashed(params, digest, y),
+            SchnorrSignature(ref sig) => sig.verify_prehashed(params, digest, y),
+            DsaSignature(ref sig) => sig.verify_digest(params, digest, y),
+        }
+    }
+
+    pub fn new(scheme: Scheme, r: BigUint, s: BigUint) -> Signature {
+        match scheme {
+            Gost => GostSignature(DigSig::new(r, s)),
+            Schnorr => SchnorrSignature(SchnorrSig { r: r, s: s }),
+            Dsa => DsaSignature(DsaSig { r: r, s: s }),
+        }
+    }
+
+    pub fn values<'a>(&'a self) -> (&'a BigUint, &'a BigUint) {
+        match *self {
+            GostSignature(ref ds) => (&ds.r, &ds.s),
+            SchnorrSignature(ref sig) => (&sig.r, &sig.s),
+            DsaSignature(ref sig) => (&sig.r, &sig.s),
+        }
+    }
+
+    pub fn scheme(&self) -> Scheme {
+        match *self {
+            GostSignature(_) => Gost,
+            SchnorrSignature(_) => Schnorr,
+            DsaSignature(_) => Dsa,
+        }
+    }
+}
+
+
+// A signature as stored in a signature file: the signature, its
+// parameter set, the signing key's ID and the signed metadata, if any.
+#[deriving(Clone, PartialEq, Show)]
+pub struct SignatureFile {
+    pub params: DsParams,
+    pub sig: Signature,
+    pub key_id: Option<KeyId>,
+    pub metadata: Option<Metadata>
+}
+
+
+impl SignatureFile {
+    pub fn new(params: DsParams, sig: Signature, key_id: KeyId
+               , metadata: Option<Metadata>) -> SignatureFile {
+        SignatureFile {
+            params: params,
+            sig: sig,
+            key_id: Some(key_id),
+            metadata: metadata
+        }
+    }
+
+    // With metadata, the signature covers the metadata as well as the
+    // message (see `metadata`).
+    pub fn verify_reader<R: Reader>(&self, reader: &mut R, y: &BigUint) -> bool {
+        self.verify_digest(hash::hash_reader(self.hash(), reader).as_slice(), y)
+    }
+
+    pub fn verify_bytes(&self, msg: &[u8], y: &BigUint) -> bool {
+        self.verify_digest(hash::hash_bytes(self.hash(), msg).as_slice(), y)
+    }
+
+    // The construction the message is hashed with.
+    pub fn hash(&self) -> Construction {
+        match self.metadata {
+            Some(ref meta) => meta.hash,
+            None => hash::Original,
+        }
+    }
+
+    // `digest` is the hash of the message with `hash()`.
+    pub fn verify_digest(&self, digest: &[u8], y: &BigUint) -> bool {
+        if digest.len() != self.hash().len() {
+            return false;
+        }
+        let digest = match self.metadata {
+            Some(ref meta) => meta.signed_digest(digest),
+            None => digest.to_vec(),
+        };
+        self.sig.verify_prehashed(&self.params, digest.as_slice(), y)
+    }
+
+    pub fn to_armor(&self) -> String {
+        self.to_armored().encode()
+    }
+
+    pub fn to_armored(&self) -> Armored {
+        let params = &self.params;
+        let data = match self.sig {
+            GostSignature(ref ds) => ds.to_der(),
+            SchnorrSignature(ref sig) => sig.to_bytes(params),
+            DsaSignature(ref sig) => sig.to_der(),
+        };
+        let mut armored = Armored::new(SIGNATURE, data);
+        match self.key_id {
+            Some(ref id) => fingerprint::add_header(&mut armored, id),
+            None => {},
+        }
+        match self.metadata {
+            Some(ref meta) => meta.add_headers(&mut armored),
+            None => {},
+        }
+        armored.add_header("Params", params.id());
+        if self.sig.scheme() != Gost {
+            armored.add_header("Scheme", self.sig.scheme().name());
+        }
+        armored
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut lines = Vec::new();
+        match self.key_id {
+            Some(ref id) => lines.push(fingerprint::text_line(id)),
+            None => {},
+        }
+        match self.metadata {
+            Some(ref meta) => lines.push_all(meta.text_lines().as_slice()),
+            None => {},
+        }
+        if self.sig.scheme() != Gost {
+            lines.push(self.sig.scheme().name().to_string());
+        }
+        let (r, s) = self.sig.values();
+        lines.push(self.params.id().to_string());
+        lines.push(r.to_string());
+        lines.push(s.to_string());
+        lines.connect("\n")
+    }
+
+    pub fn to_file(&self, file_path: &str, is_armor: bool) {
+        let mut file = File::create(&Path::new(file_path)).unwrap();
+        let text = if is_armor { self.to_armor() } else { self.to_text() };
+        file.write_str(text.as_slice()).unwrap();
+    }
+
+    pub fn from_file(file_path: &str) -> SignatureFile {
+        let mut file = File::open(&Path::new(file_path)).unwrap();
+        let content = file.read_to_string().unwrap();
+        match SignatureFile::from_str(content.as_slice()) {
+            Ok(res) => res,
+            Err(e) => fail!("{}: {}", file_path, e.desc),
+        }
+    }
+
+    pub fn from_armored(armored: &Armored) -> IoResult<SignatureFile> {
+        if armored.label.as_slice() != SIGNATURE {
+            return Err(invalid_input("scheme: not a signature"));
+        }
+        let params = try!(DsParams::from_armor_header(armored));
+        let key_id = try!(fingerprint::from_header(armored));
+        let metadata = try!(Metadata::from_headers(armored, &key_id, &params));
+        let scheme = try!(Scheme::by_name(armored.header("Scheme").unwrap_or("gost")));
+        let data = armored.data.as_slice();
+        let sig = match scheme {
+            Gost => GostSignature(try!(DigSig::from_der(&params, data))),
+            Schnorr => SchnorrSignature(try!(SchnorrSig::from_bytes(&params, data))),
+            Dsa => DsaSignature(try!(DsaSig::from_der(&params, data))),
+        };
+        Ok(SignatureFile {
+            params: params,
+            sig: sig,
+            key_id: key_id,
+            metadata: metadata
+        })
+    }
+
+    pub fn from_str(content: &str) -> IoResult<SignatureFile> {
+        if is_armored(content.as_bytes()) {
+            return SignatureFile::from_armored(&try!(Armored::decode(content)));
+        }
+
+        let mut ls: Vec<&str> = content.lines().map(|l| l.trim())
+            .filter(|l| !l.is_empty()).collect();
+        let key_id = try!(fingerprint::take_text_line(&mut ls));
+        let fields = Metadata::take_text_fields(&mut ls);
+        let has_scheme = ls.len() > 1 && !is_number(ls[0]) && !is_number(ls[1]);
+        let scheme = if has_scheme {
+            try!(Scheme::by_name(ls.remove(0).unwrap()))
+        } else {
+            Gost
+        };
+        let params = try!(DsParams::from_text_lines(&mut ls));
+        let metadata = try!(Metadata::from_text_fields(fields.as_slice(), &key_id, &params));
+        if ls.len() != 2 {
+            return Err(invalid_input("scheme: incorrect signature file"));
+        }
+        let (r, s): (BigUint, BigUint) = match (from_str_radix(ls[0], 10)
+                                                , from_str_radix(ls[1], 10)) {
+            (Some(r), Some(s)) => (r, s),
+            _ => return Err(invalid_input("scheme: incorrect signature file")),
+        };
+        Ok(SignatureFile {
+            params: params,
+            sig: Signature::new(scheme, r, s),
+            key_id: key_id,
+            metadata: metadata
+        })
+    }
+}
+
+
+fn is_number(s: &str) -> bool {
+    s.chars().all(|c| c.is_digit())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num::bigint::BigUint;
+    use ds::DigSig;
+    use fingerprint;
+    use hash;
+    use hash::{Construction, hash_bytes};
+    use metadata::Metadata;
+    use params;
+    use super::{Scheme, Signature, SignatureFile, Gost, Schnorr, Dsa};
+
+    static MSG: &'static [u8] = b"release 0.2";
+
+    // Signed with metadata naming `hash` when there is a comment.
+    fn signed_with(scheme: Scheme, hash: Construction
+                   , comment: Option<&str>) -> (SignatureFile, BigUint) {
+        let params = params::default();
+        let x = DigSig::gen_key(&params);
+        let y = DigSig::public_key(&params, &x);
+        let id = fingerprint::ds_key_id(&params, &y);
+        let meta = comment.map(|c| {
+            let mut meta = Metadata::new(&id, &params, hash);
+            meta.expires = Some(meta.created + 86400);
+            meta.comment = Some(c.to_string());
+            meta
+        });
+        let digest = hash_bytes(hash, MSG);
+        let digest = match meta {
+            Some(ref m) => m.signed_digest(digest.as_slice()),
+            None => digest,
+        };
+        let (_, sig) = Signature::sign_prehashed(scheme, &params, digest.as_slice(), &x);
+        (SignatureFile::new(params, sig, id, meta), y)
+    }
+
+    fn signed(scheme: Scheme) -> (SignatureFile, BigUint) {
+        signed_with(scheme, hash::Original, None)
+    }
+
+    #[test]
+    fn key_id_round_trips() {
+        for &scheme in [Gost, Schnorr, Dsa].iter() {
+            let (sig_file, y) = signed(scheme);
+            for text in [sig_file.to_text(), sig_file.to_armor()].iter() {
+                let read = SignatureFile::from_str(text.as_slice()).unwrap();
+                assert_eq!(read.key_id, sig_file.key_id);
+                assert_eq!(read, sig_file);
+                assert!(read.verify_bytes(MSG, &y));
+            }
+        }
+    }
+
+    #[test]
+    fn metadata_round_trips() {
+        for &scheme in [Gost, Schnorr, Dsa].iter() {
+            let (sig_file, y) = signed_with(scheme, hash::Original
+                                            , Some("release 0.2: see  NEWS"));
+            for text in [sig_file.to_text(), sig_file.to_armor()].iter() {
+                let read = SignatureFile::from_str(text.as_slice()).unwrap();
+                assert_eq!(read.metadata, sig_file.metadata);
+                assert!(read.verify_bytes(MSG, &y));
+                assert!(!read.verify_bytes(b"release 0.3", &y));
+            }
+        }
+    }
+
+    #[test]
+    fn every_hash_round_trips() {
+        let hashes = [hash::Original, hash::DaviesMeyer, hash::MatyasMeyerOseas
+                      , hash::MiyaguchiPreneel, hash::Hirose];
+        for &scheme in [Gost, Schnorr, Dsa].iter() {
+            for &h in hashes.iter() {
+                let (sig_file, y) = signed_with(scheme, h, Some("release 0.2"));
+                let text = sig_file.to_text();
+                assert!(text.as_slice().contains(format!("Hash: {}", h.name()).as_slice()));
+                let read = SignatureFile::from_str(text.as_slice()).unwrap();
+                assert_eq!(read.hash(), h);
+                assert!(read.verify_bytes(MSG, &y), "{} {}", scheme, h);
+                assert!(!read.verify_bytes(b"release 0.3", &y));
+                assert!(read.verify_digest(hash_bytes(h, MSG).as_slice(), &y));
+            }
+        }
+    }
+
+    #[test]
+    fn hash_field_is_signed() {
+        let (sig_file, y) = signed_with(Gost, hash::Hirose, Some("release 0.2"));
+        let text = sig_file.to_text().as_slice().replace("Hash: hirose", "Hash: mmo");
+        let read = SignatureFile::from_str(text.as_slice()).unwrap();
+        assert_eq!(read.hash(), hash::MatyasMeyerOseas);
+        assert!(!read.verify_bytes(MSG, &y));
+
+        let text = sig_file.to_text().as_slice().replace("Hash: hirose", "Hash: sha1");
+        assert!(SignatureFile::from_str(text.as_slice()).is_err());
+    }
+
+    #[test]
+    fn files_without_key_id_are_read() {
+        let (mut sig_file, y) = signed(Gost);
+        sig_file.key_id = None;
+        let read = SignatureFile::from_str(sig_file.to_text().as_slice()).unwrap();
+        assert_eq!(read.key_id, None);
+        assert!(read.verify_bytes(MSG, &y));
+    }
+}
@@ -0,0 +1,293 @@
+/*
+Schnorr signatures over the `DigSig` groups, with the Magenta hash.
+
+    k random in [2, q), R = g^k mod p
+    e = H(R || y || H(m)) mod q
+    s = k + e * x mod q
+
+The signature is (R, s); it verifies when g^s = R * y^e mod p. H(m)
+may come from any `hash` construction; H is always the magenta hash.
+Keeping R rather than e lets `BatchVerifier` check many signatures
+with one multi-exponentiation: for random 128-bit z_i it tests
+
+    g^(sum z_i * s_i) = prod R_i^z_i * prod y_j^(sum z_i * e_i over y_j)
+
+which fails for an invalid signature except with probability 2^-128
+(Bellare, Garay, Rabin 1998). The test is only sound for elements of
+the subgroup: two R_i negated by the signer would cancel in the
+product. So every R_i and every distinct key y_j is checked for
+membership on its own, with a Jacobi symbol in safe-prime groups and
+an exponentiation by q otherwise, where batching saves less.
+*/
+use std::collections::HashMap;
+use std::io::IoResult;
+use std::rand;
+use std::rc::Rc;
+use num::bigint::{BigUint, RandBigInt};
+use std::num::{One, Zero};
+use hash::{h_bytes, h_reader};
+use ds::{DsParams, DsContext};
+use utils::{biguint_from_bytes, biguint_to_bytes, biguint_to_fixed_bytes
+            , invalid_input};
+
+
+static BATCH_SECURITY_BITS: uint = 128;
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct SchnorrSig {
+    pub r: BigUint,
+    pub s: BigUint
+}
+
+
+impl SchnorrSig {
+    pub fn sign(params: &DsParams, msg: &[u8], x: &BigUint) -> SchnorrSig {
+        SchnorrSig::sign_prehashed(params, h_bytes(msg).as_slice(), x)
+    }
+
+    pub fn sign_reader<R: Reader>(params: &DsParams, reader: &mut R
+                                  , x: &BigUint) -> SchnorrSig {
+        SchnorrSig::sign_prehashed(params, h_reader(reader).as_slice(), x)
+    }
+
+    pub fn sign_prehashed(params: &DsParams, digest: &[u8], x: &BigUint) -> SchnorrSig {
+        let ctx = DsContext::cached(params);
+        let q = &params.q;
+        let y = ctx.public_key(x);
+        let k = params.random_exponent();
+        let r = ctx.g_pow_secret(&k);
+        let e = challenge(params, &r, &y, digest);
+        SchnorrSig { r: r, s: (k + e * (*x % *q)) % *q }
+    }
+
+    pub fn verify(&self, params: &DsParams, msg: &[u8], y: &BigUint) -> bool {
+        self.verify_prehashed(params, h_bytes(msg).as_slice(), y)
+    }
+
+    pub fn verify_reader<R: Reader>(&self, params: &DsParams, reader: &mut R
+                                    , y: &BigUint) -> bool {
+        self.verify_prehashed(params, h_reader(reader).as_slice(), y)
+    }
+
+    pub fn verify_prehashed(&self, params: &DsParams, digest: &[u8], y: &BigUint) -> bool {
+        if self.check_range(params).is_err() {
+            return false;
+        }
+        let ctx = DsContext::cached(params);
+        let e = challenge(params, &self.r, y, digest);
+        ctx.g_pow(&self.s) == self.r * ctx.pow(y, &e) % params.p
+    }
+
+    pub fn to_bytes(&self, params: &DsParams) -> Vec<u8> {
+        let mut res = biguint_to_fixed_bytes(&self.r, params.p_len());
+        res.push_all(biguint_to_fixed_bytes(&self.s, params.q_len()).as_slice());
+        res
+    }
+
+    pub fn from_bytes(params: &DsParams, bytes: &[u8]) -> IoResult<SchnorrSig> {
+        let p_len = params.p_len();
+        if bytes.len() != p_len + params.q_len() {
+            return Err(invalid_input("schnorr: incorrect signature length"));
+        }
+        let sig = SchnorrSig {
+            r: biguint_from_bytes(bytes.slice_to(p_len)),
+            s: biguint_from_bytes(bytes.slice_from(p_len))
+        };
+        try!(sig.check_range(params));
+        Ok(sig)
+    }
+
+    fn check_range(&self, params: &DsParams) -> IoResult<()> {
+        if self.r.is_zero() || self.r >= params.p || self.s >= params.q {
+            Err(invalid_input("schnorr: signature out of range"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+
+pub struct BatchVerifier {
+    ctx: Rc<DsContext>,
+    items: Vec<(SchnorrSig, BigUint, BigUint)>
+}
+
+
+impl BatchVerifier {
+    pub fn new(params: &DsParams) -> BatchVerifier {
+        BatchVerifier {
+            ctx: DsContext::cached(params),
+            items: Vec::new()
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.items.len()
+    }
+
+    pub fn add(&mut self, sig: SchnorrSig, msg: &[u8], y: BigUint) {
+        self.add_prehashed(sig, h_bytes(msg).as_slice(), y);
+    }
+
+    pub fn add_reader<R: Reader>(&mut self, sig: SchnorrSig, reader: &mut R, y: BigUint) {
+        self.add_prehashed(sig, h_reader(reader).as_slice(), y);
+    }
+
+    pub fn add_prehashed(&mut self, sig: SchnorrSig, digest: &[u8], y: BigUint) {
+        let e = challenge(&self.ctx.params, &sig.r, &y, digest);
+        self.items.push((sig, e, y));
+    }
+
+    // True only if every signature added so far is valid. On false, use
+    // `find_invalid` to tell which ones.
+    pub fn verify(&self) -> bool {
+        let params = &self.ctx.params;
+        let q = &params.q;
+        let one: BigUint = One::one();
+        let mut rng = rand::task_rng();
+
+        let mut r_terms: Vec<(BigUint, BigUint)> = Vec::with_capacity(self.items.len());
+        let mut keys: Vec<(BigUint, BigUint)> = Vec::new();
+        let mut key_index: HashMap<Vec<u8>, uint> = HashMap::new();
+        let mut g_exp: BigUint = Zero::zero();
+
+        for &(ref sig, ref e, ref y) in self.items.iter() {
+            if sig.check_range(params).is_err() || !params.is_group_element(&sig.r) {
+                return false;
+            }
+            let z = rng.gen_biguint(BATCH_SECURITY_BITS) | one;
+            g_exp = (g_exp + z * sig.s) % *q;
+
+            let ze = z * *e % *q;
+            let key_bytes = biguint_to_bytes(y);
+            match key_index.find_copy(&key_bytes) {
+                Some(i) => {
+                    let (_, ref mut c) = *keys.get_mut(i);
+                    *c = (*c + ze) % *q;
+                },
+                None => {
+                    if !params.is_group_element(y) {
+                        return false;
+                    }
+                    key_index.insert(key_bytes, keys.len());
+                    keys.push((y.clone(), ze));
+                },
+            }
+            r_terms.push((sig.r.clone(), z));
+        }
+
+        let r_prod = self.ctx.multi_pow(r_terms.as_slice());
+        self.ctx.g_pow(&g_exp) == r_prod * self.ctx.multi_pow(keys.as_slice()) % params.p
+    }
+
+    // Indices of the invalid signatures, checked one by one.
+    pub fn find_invalid(&self) -> Vec<uint> {
+        let params = &self.ctx.params;
+        self.items.iter().enumerate()
+            .filter(|&(_, &(ref sig, ref e, ref y))| {
+                sig.check_range(params).is_err()
+                    || self.ctx.g_pow(&sig.s) != sig.r * self.ctx.pow(y, e) % params.p
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+
+fn challenge(params: &DsParams, r: &BigUint, y: &BigUint, digest: &[u8]) -> BigUint {
+    let mut buf = biguint_to_fixed_bytes(r, params.p_len());
+    buf.push_all(biguint_to_fixed_bytes(y, params.p_len()).as_slice());
+    buf.push_all(digest);
+    biguint_from_bytes(&*h_bytes(buf.as_slice())) % params.q
+}
+
+
+#[cfg(test)]
+mod tests {
+    use num::bigint::BigUint;
+    use ds::{DigSig, DsParams};
+    use hash::h_bytes;
+    use params;
+    use super::{SchnorrSig, BatchVerifier, challenge};
+
+    // n signatures over three keys, so keys repeat within the batch.
+    fn batch(params: &DsParams, n: uint) -> Vec<(SchnorrSig, Vec<u8>, BigUint)> {
+        let keys: Vec<BigUint> = range(0u, 3).map(|_| params.random_exponent()).collect();
+        range(0, n).map(|i| {
+            let msg = format!("message {}", i).into_bytes();
+            let x = &keys[i % keys.len()];
+            let sig = SchnorrSig::sign(params, msg.as_slice(), x);
+            (sig, msg, DigSig::public_key(params, x))
+        }).collect()
+    }
+
+    fn verifier(params: &DsParams, items: &[(SchnorrSig, Vec<u8>, BigUint)]) -> BatchVerifier {
+        let mut v = BatchVerifier::new(params);
+        for &(ref sig, ref msg, ref y) in items.iter() {
+            v.add(sig.clone(), msg.as_slice(), y.clone());
+        }
+        v
+    }
+
+    #[test]
+    fn valid_batch_verifies() {
+        let params = params::default();
+        let items = batch(&params, 8);
+        for &(ref sig, ref msg, ref y) in items.iter() {
+            assert!(sig.verify(&params, msg.as_slice(), y));
+        }
+        let v = verifier(&params, items.as_slice());
+        assert_eq!(v.len(), 8);
+        assert!(v.verify());
+        assert!(v.find_invalid().is_empty());
+    }
+
+    #[test]
+    fn one_forged_signature_fails_the_batch() {
+        let params = params::default();
+        let mut items = batch(&params, 8);
+        // A valid signature moved onto another message.
+        let forged = match items[2] { (ref sig, _, _) => sig.clone() };
+        match *items.get_mut(5) { (ref mut sig, _, _) => *sig = forged };
+        let v = verifier(&params, items.as_slice());
+        assert!(!v.verify());
+        assert_eq!(v.find_invalid(), vec![5u]);
+    }
+
+    #[test]
+    fn r_outside_the_subgroup_fails_the_batch() {
+        let params = params::default();
+        let mut items = batch(&params, 4);
+        // -R has order 2q, so g^s = -R * y^e never holds.
+        match *items.get_mut(1) { (ref mut sig, _, _) => sig.r = params.p - sig.r };
+        let v = verifier(&params, items.as_slice());
+        assert!(!v.verify());
+        assert_eq!(v.find_invalid(), vec![1u]);
+    }
+
+    // Signed with R' = p - g^k and e computed from R', as a signer trying
+    // to get signatures through the batch that fail on their own would.
+    fn negated_r_signature(params: &DsParams, msg: &[u8], x: &BigUint) -> SchnorrSig {
+        let k = params.random_exponent();
+        let r = params.p - DigSig::public_key(params, &k);
+        let e = challenge(params, &r, &DigSig::public_key(params, x), h_bytes(msg).as_slice());
+        SchnorrSig { r: r, s: (k + e * *x) % params.q }
+    }
+
+    #[test]
+    fn two_negated_r_fail_the_batch() {
+        let params = params::default();
+        let x = params.random_exponent();
+        let y = DigSig::public_key(&params, &x);
+        let mut v = BatchVerifier::new(&params);
+        for msg in [b"first", b"second"].iter() {
+            let sig = negated_r_signature(&params, *msg, &x);
+            assert!(!sig.verify(&params, *msg, &y));
+            v.add(sig, *msg, y.clone());
+        }
+        // The two factors of -1 cancel in prod R_i^z_i, z_i being odd.
+        assert!(!v.verify());
+        assert_eq!(v.find_invalid(), vec![0u, 1]);
+    }
+}
@@ -0,0 +1,99 @@
+/*
+Authenticated encryption: PBC under a random IV, then HMAC-Magenta
+over IV || ciphertext (encrypt-then-MAC).
+
+The 32-byte key is split into a 16-byte encryption key and a 16-byte
+MAC key. Output: IV (16) || ciphertext || tag (16).
+
+`seal_with_ad` also authenticates associated data that is stored
+elsewhere, such as a file header: the MAC then covers
+len(AD) (8, big-endian) || AD || IV || ciphertext.
+*/
+use std::io::IoResult;
+use std::slice::bytes::copy_memory;
+use utils::{B16, B32, random_bytes, invalid_input, nth_byte_u64};
+use mac::{hmac, verify_hmac};
+use pbc::PBC;
+
+
+static IV_LEN: uint = 16;
+static TAG_LEN: uint = 16;
+
+
+pub fn seal(key: &B32, plaintext: &[u8]) -> Vec<u8> {
+    seal_parts(key, None, plaintext)
+}
+
+
+pub fn seal_with_ad(key: &B32, ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    seal_parts(key, Some(ad), plaintext)
+}
+
+
+pub fn open(key: &B32, sealed: &[u8]) -> IoResult<Vec<u8>> {
+    open_parts(key, None, sealed)
+}
+
+
+pub fn open_with_ad(key: &B32, ad: &[u8], sealed: &[u8]) -> IoResult<Vec<u8>> {
+    open_parts(key, Some(ad), sealed)
+}
+
+
+fn seal_parts(key: &B32, ad: Option<&[u8]>, plaintext: &[u8]) -> Vec<u8> {
+    let (enc_key, mac_key) = split_key(key);
+    let mut iv: B16 = [0, ..16];
+    copy_memory(&mut iv, random_bytes(IV_LEN).as_slice());
+
+    let mut res = iv.to_vec();
+    res.push_all(PBC::new(&enc_key, &iv).enc_bytes(plaintext).as_slice());
+    let box tag = hmac(&mac_key, mac_input(ad, res.as_slice()).as_slice());
+    res.push_all(&tag);
+    res
+}
+
+
+fn open_parts(key: &B32, ad: Option<&[u8]>, sealed: &[u8]) -> IoResult<Vec<u8>> {
+    if sealed.len() < IV_LEN + TAG_LEN {
+        return Err(invalid_input("seal: message too short"));
+    }
+    let (enc_key, mac_key) = split_key(key);
+    let body_len = sealed.len() - TAG_LEN;
+
+    let msg = mac_input(ad, sealed.slice_to(body_len));
+    if !verify_hmac(&mac_key, msg.as_slice(), sealed.slice_from(body_len)) {
+        return Err(invalid_input("seal: authentication failed"));
+    }
+
+    let mut iv: B16 = [0, ..16];
+    copy_memory(&mut iv, sealed.slice_to(IV_LEN));
+    PBC::new(&enc_key, &iv).dec_bytes(sealed.slice(IV_LEN, body_len))
+}
+
+
+// What the tag covers: IV || ciphertext, after the length-prefixed
+// associated data if there is any.
+fn mac_input(ad: Option<&[u8]>, body: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::new();
+    match ad {
+        Some(ad) => {
+            let len = ad.len() as u64;
+            for i in range(0u, 8).rev() {
+                msg.push(nth_byte_u64(len, i));
+            }
+            msg.push_all(ad);
+        },
+        None => {},
+    }
+    msg.push_all(body);
+    msg
+}
+
+
+fn split_key(key: &B32) -> (B16, B16) {
+    let mut enc_key: B16 = [0, ..16];
+    let mut mac_key: B16 = [0, ..16];
+    copy_memory(&mut enc_key, key.slice_to(16));
+    copy_memory(&mut mac_key, key.slice_from(16));
+    (enc_key, mac_key)
+}
@@ -0,0 +1,387 @@
+/*
+Merkle tree hashing. The input is cut into chunks of a fixed size (the
+last one may be shorter); the leaves are hashed on several tasks and
+combined into nodes:
+
+leaf = h(0 || chunk)
+node = f(right, f(left, IV_node))
+
+where h is the Magenta hash (`hash::h_reader`) and f its compression
+function, taking a message block and a chaining value. A node needs two
+compressions where hashing the padded 33 bytes would take three. Leaves
+and nodes stay apart: a leaf's chain starts at the zero IV and always
+ends with a padding block, a node's starts at IV_node = 1 || 0^15, so
+passing one off as the other takes a collision or a preimage of f.
+
+The tree has the shape of RFC 6962: the left subtree of n leaves holds
+the largest power of two smaller than n, so a chunk's proof is the
+list of sibling hashes from the leaf up, and one chunk can be checked
+against the root without the others. An empty input is one empty chunk.
+
+A proof file holds the chunk size, the number of chunks, the index of
+the chunk and the sibling hashes in hex, one per line:
+
+chunk-size: 1048576
+chunks: 4096
+index: 17
+<hash>
+...
+*/
+use std::io::{IoResult, IoError, OtherIoError, EndOfFile};
+use std::sync::{Arc, Mutex};
+use std::slice::bytes::copy_memory;
+use serialize::hex::{ToHex, FromHex};
+use hash::{h_bytes, h_func};
+use utils::{B16, invalid_input};
+
+
+pub static DEFAULT_CHUNK_SIZE: uint = 1 << 20;
+
+static LEAF: u8 = 0;
+static NODE_IV: B16 = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+
+pub fn leaf_hash(chunk: &[u8]) -> B16 {
+    let mut data = Vec::with_capacity(chunk.len() + 1);
+    data.push(LEAF);
+    data.push_all(chunk);
+    let box h = h_bytes(data.as_slice());
+    h
+}
+
+
+pub fn node_hash(left: &B16, right: &B16) -> B16 {
+    let box h = h_func(right, &*h_func(left, &NODE_IV));
+    h
+}
+
+
+// Reads `reader` chunk by chunk and hashes the chunks on `threads`
+// tasks. Returns the leaves in order.
+pub fn leaves<R: Reader>(reader: &mut R, chunk_size: uint
+                         , threads: uint) -> IoResult<Vec<B16>> {
+    if chunk_size == 0 || threads == 0 {
+        return Err(invalid_input("treehash: chunk size and threads must not be 0"));
+    }
+    let (job_tx, job_rx) = sync_channel::<(uint, Vec<u8>)>(threads * 2);
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let (res_tx, res_rx) = channel::<(uint, B16)>();
+    for _ in range(0, threads) {
+        let job_rx = job_rx.clone();
+        let res_tx = res_tx.clone();
+        spawn(proc() {
+            loop {
+                let job = job_rx.lock().recv_opt();
+                match job {
+                    Ok((i, chunk)) => res_tx.send((i, leaf_hash(chunk.as_slice()))),
+                    Err(_) => break,
+                }
+            }
+        });
+    }
+    drop(res_tx);
+
+    let mut count = 0u;
+    loop {
+        let chunk = try!(read_chunk(reader, chunk_size));
+        let is_last = chunk.len() < chunk_size;
+        if !is_last || chunk.len() > 0 || count == 0 {
+            job_tx.send((count, chunk));
+            count += 1;
+        }
+        if is_last {
+            break;
+        }
+    }
+    drop(job_tx);
+
+    let mut leaves = Vec::from_elem(count, [0u8, ..16]);
+    let mut received = 0u;
+    for (i, h) in res_rx.iter() {
+        *leaves.get_mut(i) = h;
+        received += 1;
+    }
+    if received != count {
+        return Err(IoError {
+            kind: OtherIoError,
+            desc: "treehash: a hashing task failed",
+            detail: None,
+        });
+    }
+    Ok(leaves)
+}
+
+
+pub fn root(leaves: &[B16]) -> B16 {
+    match leaves.len() {
+        0 => leaf_hash(&[]),
+        1 => leaves[0],
+        n => {
+            let k = split(n);
+            node_hash(&root(leaves.slice_to(k)), &root(leaves.slice_from(k)))
+        },
+    }
+}
+
+
+pub fn tree_hash<R: Reader>(reader: &mut R, chunk_size: uint, threads: uint) -> IoResult<B16> {
+    let leaves = try!(leaves(reader, chunk_size, threads));
+    Ok(root(leaves.as_slice()))
+}
+
+
+#[deriving(Clone, PartialEq, Show)]
+pub struct ChunkProof {
+    pub chunk_size: uint,
+    pub count: uint,
+    pub index: uint,
+    pub path: Vec<B16>
+}
+
+
+impl ChunkProof {
+    pub fn new(leaves: &[B16], chunk_size: uint, index: uint) -> IoResult<ChunkProof> {
+        if index >= leaves.len() {
+            return Err(invalid_input("treehash: no such chunk"));
+        }
+        Ok(ChunkProof {
+            chunk_size: chunk_size,
+            count: leaves.len(),
+            index: index,
+            path: path(leaves, index)
+        })
+    }
+
+    // Checks that `chunk` is the chunk at `index` of the input with the
+    // tree hash `root`. All chunks but the last are full size.
+    pub fn verify(&self, chunk: &[u8], root: &B16) -> bool {
+        if self.index >= self.count || chunk.len() > self.chunk_size {
+            return false;
+        }
+        if self.index + 1 < self.count && chunk.len() != self.chunk_size {
+            return false;
+        }
+        match root_from_path(leaf_hash(chunk), self.index, self.count, self.path.as_slice()) {
+            Some(r) => r == *root,
+            None => false,
+        }
+    }
+
+    pub fn to_string(&self) -> String {
+        let mut res = format!("chunk-size: {}\nchunks: {}\nindex: {}\n"
+                              , self.chunk_size, self.count, self.index);
+        for h in self.path.iter() {
+            res.push_str(h.to_hex().as_slice());
+            res.push('\n');
+        }
+        res
+    }
+
+    pub fn from_str(content: &str) -> IoResult<ChunkProof> {
+        let mut ls: Vec<&str> = content.lines().map(|l| l.trim())
+            .filter(|l| !l.is_empty()).collect();
+        if ls.len() < 3 {
+            return Err(invalid_input("treehash: incorrect proof file"));
+        }
+        let chunk_size = try!(field(ls.remove(0).unwrap(), "chunk-size"));
+        let count = try!(field(ls.remove(0).unwrap(), "chunks"));
+        let index = try!(field(ls.remove(0).unwrap(), "index"));
+        let mut path = Vec::with_capacity(ls.len());
+        for l in ls.iter() {
+            path.push(try!(parse_hash(*l)));
+        }
+        Ok(ChunkProof { chunk_size: chunk_size, count: count, index: index, path: path })
+    }
+}
+
+
+pub fn parse_hash(s: &str) -> IoResult<B16> {
+    let bytes = match s.trim().from_hex() {
+        Ok(b) => b,
+        Err(_) => return Err(invalid_input("treehash: hash is not hex")),
+    };
+    if bytes.len() != 16 {
+        return Err(invalid_input("treehash: hash must be 32 hex digits"));
+    }
+    let mut h = [0u8, ..16];
+    copy_memory(&mut h, bytes.as_slice());
+    Ok(h)
+}
+
+
+// Size of the left subtree over n > 1 leaves.
+fn split(n: uint) -> uint {
+    let mut k = 1;
+    while k << 1 < n {
+        k = k << 1;
+    }
+    k
+}
+
+
+fn path(leaves: &[B16], index: uint) -> Vec<B16> {
+    let n = leaves.len();
+    if n == 1 {
+        return Vec::new();
+    }
+    let k = split(n);
+    if index < k {
+        let mut p = path(leaves.slice_to(k), index);
+        p.push(root(leaves.slice_from(k)));
+        p
+    } else {
+        let mut p = path(leaves.slice_from(k), index - k);
+        p.push(root(leaves.slice_to(k)));
+        p
+    }
+}
+
+
+fn root_from_path(leaf: B16, index: uint, n: uint, path: &[B16]) -> Option<B16> {
+    if n == 1 {
+        return if path.is_empty() { Some(leaf) } else { None };
+    }
+    let last = match path.last() {
+        Some(h) => h,
+        None => return None,
+    };
+    let rest = path.slice_to(path.len() - 1);
+    let k = split(n);
+    if index < k {
+        root_from_path(leaf, index, k, rest).map(|l| node_hash(&l, last))
+    } else {
+        root_from_path(leaf, index - k, n - k, rest).map(|r| node_hash(last, &r))
+    }
+}
+
+
+// Up to `size` bytes; fewer only at the end of the input.
+fn read_chunk<R: Reader>(reader: &mut R, size: uint) -> IoResult<Vec<u8>> {
+    let mut buf = Vec::from_elem(size, 0u8);
+    let mut n = 0;
+    while n < size {
+        match reader.read(buf.as_mut_slice().slice_from_mut(n)) {
+            Ok(k) => n += k,
+            Err(ref e) if e.kind == EndOfFile => break,
+            Err(e) => return Err(e),
+        }
+    }
+    buf.truncate(n);
+    Ok(buf)
+}
+
+
+fn field(line: &str, name: &str) -> IoResult<uint> {
+    let prefix = format!("{}:", name);
+    if !line.starts_with(prefix.as_slice()) {
+        return Err(invalid_input("treehash: incorrect proof file"));
+    }
+    match from_str(line.slice_from(prefix.len()).trim()) {
+        Some(n) => Ok(n),
+        None => Err(invalid_input("treehash: incorrect proof file")),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use utils::B16;
+    use super::{leaf_hash, node_hash, leaves, root, tree_hash, ChunkProof};
+
+    static CHUNK: uint = 4;
+
+    fn data(len: uint) -> Vec<u8> {
+        range(0, len).map(|i| (i * 7 + 1) as u8).collect()
+    }
+
+    fn chunk_leaves(data: &[u8]) -> Vec<B16> {
+        data.chunks(CHUNK).map(|c| leaf_hash(c)).collect()
+    }
+
+    #[test]
+    fn roots_have_the_rfc6962_shape() {
+        let l = chunk_leaves(data(8 * CHUNK).as_slice());
+        let n = |a: uint, b: uint| node_hash(&l[a], &l[b]);
+        assert_eq!(root(l.slice_to(1)), l[0]);
+        assert_eq!(root(l.slice_to(2)), n(0, 1));
+        assert_eq!(root(l.slice_to(3)), node_hash(&n(0, 1), &l[2]));
+        let four = node_hash(&n(0, 1), &n(2, 3));
+        assert_eq!(root(l.slice_to(4)), four);
+        assert_eq!(root(l.slice_to(5)), node_hash(&four, &l[4]));
+        let eight = node_hash(&four, &node_hash(&n(4, 5), &n(6, 7)));
+        assert_eq!(root(l.as_slice()), eight);
+        assert_eq!(root(&[]), leaf_hash(&[]));
+    }
+
+    #[test]
+    fn tree_hash_of_a_reader() {
+        for &len in [0u, 1, CHUNK, CHUNK + 1, 3 * CHUNK, 5 * CHUNK - 1, 16 * CHUNK].iter() {
+            let d = data(len);
+            let expected = if len == 0 {
+                leaf_hash(&[])
+            } else {
+                root(chunk_leaves(d.as_slice()).as_slice())
+            };
+            let h = tree_hash(&mut BufReader::new(d.as_slice()), CHUNK, 2).unwrap();
+            assert!(h == expected, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn threads_give_the_same_leaves() {
+        let d = data(37 * CHUNK + 3);
+        let one = leaves(&mut BufReader::new(d.as_slice()), CHUNK, 1).unwrap();
+        let four = leaves(&mut BufReader::new(d.as_slice()), CHUNK, 4).unwrap();
+        assert_eq!(one.len(), 38);
+        assert_eq!(one, four);
+        assert_eq!(one, chunk_leaves(d.as_slice()));
+    }
+
+    #[test]
+    fn every_proof_verifies() {
+        for count in range(1u, 10) {
+            let d = data(count * CHUNK - 1);
+            let l = chunk_leaves(d.as_slice());
+            let r = root(l.as_slice());
+            for (i, chunk) in d.as_slice().chunks(CHUNK).enumerate() {
+                let proof = ChunkProof::new(l.as_slice(), CHUNK, i).unwrap();
+                assert!(proof.verify(chunk, &r), "chunk {} of {}", i, count);
+                let read = ChunkProof::from_str(proof.to_string().as_slice()).unwrap();
+                assert_eq!(read, proof);
+            }
+        }
+    }
+
+    #[test]
+    fn bad_proofs_fail() {
+        let d = data(5 * CHUNK);
+        let l = chunk_leaves(d.as_slice());
+        let r = root(l.as_slice());
+        let chunks: Vec<&[u8]> = d.as_slice().chunks(CHUNK).collect();
+        let proof = ChunkProof::new(l.as_slice(), CHUNK, 1).unwrap();
+        assert!(proof.verify(chunks[1], &r));
+
+        assert!(!proof.verify(chunks[2], &r));
+        let mut modified = chunks[1].to_vec();
+        *modified.get_mut(0) ^= 1;
+        assert!(!proof.verify(modified.as_slice(), &r));
+
+        for &index in [0u, 2, 3, 5].iter() {
+            let mut wrong = proof.clone();
+            wrong.index = index;
+            assert!(!wrong.verify(chunks[1], &r), "index {}", index);
+        }
+        // Counts of 6 to 8 give the same tree down to chunk 1, so its
+        // proof holds for them too; the chunk's position is still fixed.
+        for &count in [1u, 2, 3, 4, 9, 16].iter() {
+            let mut wrong = proof.clone();
+            wrong.count = count;
+            assert!(!wrong.verify(chunks[1], &r), "count {}", count);
+        }
+        let mut short = proof.clone();
+        short.path.pop();
+        assert!(!short.verify(chunks[1], &r));
+        assert!(ChunkProof::new(l.as_slice(), CHUNK, 5).is_err());
+    }
+}
@@ -1,7 +1,15 @@
-use std::io::{File};
+use std::io::{File, IoResult, IoError, EndOfFile, InvalidInput, Open, Write};
+use std::io::fs::{rename, unlink};
 use std::rand;
-use num::bigint::BigUint;
-use std::num::{One, Zero};
+use std::rand::Rng;
+use num::bigint::{BigUint, ToBigUint, RandBigInt};
+use std::num::{One, Zero, ToPrimitive};
+use num::Integer;
+use serialize::hex::ToHex;
+use montgomery::MontCtx;
+use libc::consts::os::posix88::{O_WRONLY, O_CREAT, O_EXCL};
+use libc::funcs::posix88::fcntl::open;
+use libc::funcs::posix88::unistd::close;
 
 pub type B8 = [u8, ..8];
 pub type B16 = [u8, ..16];
@@ -98,9 +106,56 @@ pub fn file_size(file: &mut File) -> u64 {
 
 pub fn fill_with_file_size(file: &mut File, buf: &mut [u8]) {
     let fs: u64 = file_size(file);
+    fill_with_size(buf, fs);
+}
+
+pub fn fill_with_size(buf: &mut [u8], size: u64) {
     for i in range(0u, 8) {
-        buf[buf.len() - i - 1] = nth_byte_u64(fs, i);
+        buf[buf.len() - i - 1] = nth_byte_u64(size, i);
+    }
+}
+
+pub fn read_block<R: Reader>(reader: &mut R, buf: &mut [u8]) -> IoResult<uint> {
+    let mut filled = 0u;
+    while filled < buf.len() {
+        match reader.read(buf.slice_from_mut(filled)) {
+            Ok(i) => filled += i,
+            Err(ref e) if e.kind == EndOfFile => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+// Creates a new file that only its owner can read and write. The mode
+// is given to open(2) with O_EXCL, so the file is never accessible by
+// others, and an existing file or symlink at `path` is an error.
+pub fn create_private(path: &Path) -> IoResult<File> {
+    let fd = path.to_c_str().with_ref(|p| unsafe {
+        open(p, O_WRONLY | O_CREAT | O_EXCL, 0o600)
+    });
+    if fd < 0 {
+        return Err(IoError::last_error());
+    }
+    unsafe { close(fd) };
+    File::open_mode(path, Open, Write)
+}
+
+// Writes `content` to a new owner-only file next to `path` and renames
+// it over `path`, so a failed or interrupted write leaves the old file
+// as it was. The temporary name has a random suffix.
+pub fn replace_file(path: &Path, content: &[u8]) -> IoResult<()> {
+    let mut temp_name = path.as_vec().to_vec();
+    temp_name.push_all(format!(".{}.tmp", random_bytes(8).as_slice().to_hex()).as_bytes());
+    let temp = Path::new(temp_name);
+    let mut file = try!(create_private(&temp));
+    let res = file.write(content)
+        .and_then(|_| file.fsync())
+        .and_then(|_| rename(&temp, path));
+    if res.is_err() {
+        let _ = unlink(&temp);
     }
+    res
 }
 
 pub fn is_file_size_can_fit(byte_count: uint, buf_len: uint) -> bool {
@@ -115,7 +170,8 @@ pub fn mod_pow(num: BigUint, exp: BigUint, m: BigUint) -> BigUint {
     let one: BigUint = One::one();
 
     if m.is_zero() { fail!("non-positive modulo"); }
-    if exp == one { return exp % m; }
+    if m.is_odd() && m > one { return MontCtx::new(&m).pow(&num, &exp); }
+    if exp == one { return num % m; }
 
     let mut s = one.clone();
     let mut t = num.clone();
@@ -131,6 +187,372 @@ pub fn mod_pow(num: BigUint, exp: BigUint, m: BigUint) -> BigUint {
     s
 }
 
+pub fn biguint_from_bytes(bytes: &[u8]) -> BigUint {
+    let mut n: BigUint = Zero::zero();
+    for b in bytes.iter() {
+        n = (n << 8) + b.to_biguint().unwrap();
+    }
+    n
+}
+
+pub fn biguint_to_bytes(n: &BigUint) -> Vec<u8> {
+    let mask = 0xffu.to_biguint().unwrap();
+    let mut bytes = Vec::new();
+    let mut t = n.clone();
+    while !t.is_zero() {
+        bytes.push((t & mask).to_u8().unwrap());
+        t = t >> 8;
+    }
+    bytes.reverse();
+    bytes
+}
+
+pub fn biguint_to_fixed_bytes(n: &BigUint, width: uint) -> Vec<u8> {
+    let bytes = biguint_to_bytes(n);
+    if bytes.len() > width {
+        fail!("biguint_to_fixed_bytes: number doesn't fit in {} bytes", width);
+    }
+    let mut res = Vec::from_elem(width - bytes.len(), 0u8);
+    res.push_all(bytes.as_slice());
+    res
+}
+
+pub fn to_bits_le(n: &BigUint) -> Vec<bool> {
+    let bytes = biguint_to_bytes(n);
+    let mut bits = Vec::with_capacity(bytes.len() * 8);
+    for byte in bytes.iter().rev() {
+        for i in range(0u, 8) {
+            bits.push((*byte >> i) & 1 == 1);
+        }
+    }
+    while bits.last() == Some(&false) {
+        bits.pop();
+    }
+    bits
+}
+
+pub fn byte_len(n: &BigUint) -> uint {
+    (n.bits() + 7) / 8
+}
+
+pub fn random_bytes(len: uint) -> Vec<u8> {
+    let mut bytes = Vec::from_elem(len, 0u8);
+    rand::task_rng().fill_bytes(bytes.as_mut_slice());
+    bytes
+}
+
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= *x ^ *y;
+    }
+    diff == 0
+}
+
+pub fn invalid_input(desc: &'static str) -> IoError {
+    IoError { kind: InvalidInput, desc: desc, detail: None }
+}
+
+// Primes below 200, for trial division before the probabilistic tests.
+static SMALL_PRIMES: [uint, ..46] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71
+    , 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151
+    , 157, 163, 167, 173, 179, 181, 191, 193, 197, 199];
+
+fn big(n: uint) -> BigUint {
+    n.to_biguint().unwrap()
+}
+
+fn small(n: &BigUint, m: uint) -> uint {
+    (*n % big(m)).to_uint().unwrap()
+}
+
+// (a - b) mod m for a, b already reduced mod m.
+fn sub_mod(a: &BigUint, b: &BigUint, m: &BigUint) -> BigUint {
+    (*a + *m - *b) % *m
+}
+
+// x / 2 mod m for odd m.
+fn half_mod(x: &BigUint, m: &BigUint) -> BigUint {
+    if x.is_odd() { (*x + *m) >> 1 } else { *x >> 1 }
+}
+
+pub fn isqrt(n: &BigUint) -> BigUint {
+    if n.is_zero() {
+        return Zero::zero();
+    }
+    let one: BigUint = One::one();
+    let mut x = one << ((n.bits() + 1) / 2);
+    loop {
+        let y = (x + *n / x) >> 1;
+        if y >= x {
+            return x;
+        }
+        x = y;
+    }
+}
+
+// a^-1 mod m by the extended Euclidean algorithm, or None when
+// gcd(a, m) != 1. The Bezout coefficient is kept reduced mod m so no
+// signed arithmetic is needed.
+pub fn mod_inverse(a: &BigUint, m: &BigUint) -> Option<BigUint> {
+    let one: BigUint = One::one();
+    if *m <= one {
+        return None;
+    }
+    let (mut r0, mut r1) = (m.clone(), *a % *m);
+    let (mut t0, mut t1): (BigUint, BigUint) = (Zero::zero(), one.clone());
+    while !r1.is_zero() {
+        let (q, r2) = r0.div_rem(&r1);
+        let t2 = sub_mod(&t0, &((q * t1) % *m), m);
+        r0 = r1;
+        r1 = r2;
+        t0 = t1;
+        t1 = t2;
+    }
+    if r0 == one { Some(t0) } else { None }
+}
+
+// The x < m1 * m2 * ... with x = r_i mod m_i, for pairwise coprime
+// moduli; None when they aren't coprime or the slices differ in length.
+pub fn crt(residues: &[BigUint], moduli: &[BigUint]) -> Option<BigUint> {
+    if residues.len() != moduli.len() || moduli.is_empty() {
+        return None;
+    }
+    let mut x = residues[0] % moduli[0];
+    let mut m = moduli[0].clone();
+    for (r, mi) in residues.iter().zip(moduli.iter()).skip(1) {
+        let inv = match mod_inverse(&(m % *mi), mi) {
+            Some(inv) => inv,
+            None => return None,
+        };
+        let t = sub_mod(&(*r % *mi), &(x % *mi), mi) * inv % *mi;
+        x = x + m * t;
+        m = m * *mi;
+    }
+    Some(x)
+}
+
+// Jacobi symbol (a / n) for odd n > 0; returns -1, 0 or 1.
+pub fn jacobi(a: &BigUint, n: &BigUint) -> int {
+    if !n.is_odd() {
+        fail!("jacobi: n must be odd and positive");
+    }
+    let mut a = *a % *n;
+    let mut n = n.clone();
+    let mut res = 1;
+    while !a.is_zero() {
+        while a.is_even() {
+            a = a >> 1;
+            match small(&n, 8) {
+                3 | 5 => res = -res,
+                _ => {},
+            }
+        }
+        if small(&a, 4) == 3 && small(&n, 4) == 3 {
+            res = -res;
+        }
+        let t = n % a;
+        n = a;
+        a = t;
+    }
+    let one: BigUint = One::one();
+    if n == one { res } else { 0 }
+}
+
+// Square root of a mod an odd prime p (Tonelli-Shanks), or None when a
+// is not a quadratic residue.
+pub fn mod_sqrt(a: &BigUint, p: &BigUint) -> Option<BigUint> {
+    let one: BigUint = One::one();
+    let a = *a % *p;
+    if a.is_zero() {
+        return Some(a);
+    }
+    if jacobi(&a, p) != 1 {
+        return None;
+    }
+    if small(p, 4) == 3 {
+        return Some(mod_pow(a, (*p + one) >> 2, p.clone()));
+    }
+
+    let p1 = *p - one;
+    let mut q = p1.clone();
+    let mut s = 0u;
+    while q.is_even() {
+        q = q >> 1;
+        s += 1;
+    }
+    let mut z = big(2);
+    while jacobi(&z, p) != -1 {
+        z = z + one;
+    }
+
+    let mut m = s;
+    let mut c = mod_pow(z, q.clone(), p.clone());
+    let mut t = mod_pow(a.clone(), q.clone(), p.clone());
+    let mut r = mod_pow(a, (q + one) >> 1, p.clone());
+    while t != one {
+        let mut i = 0u;
+        let mut t2i = t.clone();
+        while t2i != one {
+            t2i = t2i * t2i % *p;
+            i += 1;
+        }
+        let mut b = c.clone();
+        for _ in range(0, m - i - 1) {
+            b = b * b % *p;
+        }
+        m = i;
+        c = b * b % *p;
+        t = t * c % *p;
+        r = r * b % *p;
+    }
+    Some(r)
+}
+
+// One Miller-Rabin round: false means n is certainly composite.
+pub fn miller_rabin(n: &BigUint, base: &BigUint) -> bool {
+    let one: BigUint = One::one();
+    let n1 = *n - one;
+    let mut d = n1.clone();
+    let mut s = 0u;
+    while d.is_even() {
+        d = d >> 1;
+        s += 1;
+    }
+
+    let mut x = mod_pow(*base % *n, d, n.clone());
+    if x == one || x == n1 {
+        return true;
+    }
+    for _ in range(1, s) {
+        x = x * x % *n;
+        if x == n1 {
+            return true;
+        }
+    }
+    false
+}
+
+// Miller-Rabin with `rounds` random bases; a composite passes with
+// probability at most 4^-rounds.
+pub fn is_probable_prime_mr(n: &BigUint, rounds: uint) -> bool {
+    match trial_division(n) {
+        Some(res) => return res,
+        None => {},
+    }
+    let mut rng = rand::task_rng();
+    let low = big(2);
+    let high = *n - low;
+    range(0, rounds).all(|_| miller_rabin(n, &rng.gen_biguint_range(&low, &high)))
+}
+
+// Baillie-PSW: a base 2 Miller-Rabin round followed by a strong Lucas
+// test. No composite is known to pass both.
+pub fn is_probable_prime(n: &BigUint) -> bool {
+    match trial_division(n) {
+        Some(res) => return res,
+        None => {},
+    }
+    miller_rabin(n, &big(2)) && strong_lucas(n)
+}
+
+pub fn gen_prime(bits: uint) -> BigUint {
+    if bits < 2 {
+        fail!("gen_prime: a prime needs at least 2 bits");
+    }
+    let one: BigUint = One::one();
+    let top = one << (bits - 1);
+    let mut rng = rand::task_rng();
+    loop {
+        let candidate = rng.gen_biguint(bits) | top | one;
+        if is_probable_prime(&candidate) {
+            return candidate;
+        }
+    }
+}
+
+// Some(answer) when n is small or has a small factor, None otherwise.
+fn trial_division(n: &BigUint) -> Option<bool> {
+    for p in SMALL_PRIMES.iter() {
+        if *n == big(*p) {
+            return Some(true);
+        }
+        if *n < big(*p) || small(n, *p) == 0 {
+            return Some(false);
+        }
+    }
+    None
+}
+
+// Strong Lucas probable prime test with Selfridge's parameters:
+// the first D in 5, -7, 9, -11, ... with (D / n) = -1, P = 1 and
+// Q = (1 - D) / 4. Expects an odd n with no small factors.
+fn strong_lucas(n: &BigUint) -> bool {
+    let one: BigUint = One::one();
+    let root = isqrt(n);
+    if root * root == *n {
+        return false;
+    }
+
+    let mut d_abs = 5u;
+    let mut negative = false;
+    let mut d;
+    loop {
+        d = if negative { *n - big(d_abs) % *n } else { big(d_abs) % *n };
+        if jacobi(&d, n) == -1 {
+            break;
+        }
+        d_abs += 2;
+        negative = !negative;
+    }
+    // Q = (1 - D) / 4, taken mod n
+    let q = if negative {
+        big((1 + d_abs) / 4) % *n
+    } else {
+        *n - big((d_abs - 1) / 4) % *n
+    };
+
+    let mut k = *n + one;
+    let mut s = 0u;
+    while k.is_even() {
+        k = k >> 1;
+        s += 1;
+    }
+
+    // U_k, V_k and Q^k mod n, left to right over the bits of k.
+    let bits = to_bits_le(&k);
+    let mut u = one.clone();
+    let mut v = one.clone();
+    let mut qk = q.clone();
+    for i in range(0, bits.len() - 1).rev() {
+        u = u * v % *n;
+        v = sub_mod(&(v * v % *n), &(qk * big(2) % *n), n);
+        qk = qk * qk % *n;
+        if bits[i] {
+            let u_next = half_mod(&((u + v) % *n), n);
+            v = half_mod(&((d * u + v) % *n), n);
+            u = u_next;
+            qk = qk * q % *n;
+        }
+    }
+
+    if u.is_zero() || v.is_zero() {
+        return true;
+    }
+    for _ in range(1, s) {
+        v = sub_mod(&(v * v % *n), &(qk * big(2) % *n), n);
+        qk = qk * qk % *n;
+        if v.is_zero() {
+            return true;
+        }
+    }
+    false
+}
+
 pub fn print_array(x: &[u8]) {
     print!("[");
     for xi in x.iter() {
@@ -138,3 +560,207 @@ pub fn print_array(x: &[u8]) {
     }
     println!("]");
 }
+
+
+#[cfg(test)]
+mod tests {
+    use num::Integer;
+    use num::bigint::{BigUint, RandBigInt};
+    use std::num::{One, Zero};
+    use std::rand;
+    use std::io::{File, TempDir, USER_READ, USER_WRITE};
+    use std::io::fs::{stat, readdir};
+    use super::{big, isqrt, mod_inverse, crt, jacobi, mod_sqrt, mod_pow, miller_rabin
+                , strong_lucas, is_probable_prime, is_probable_prime_mr, gen_prime
+                , create_private, replace_file};
+
+    static PRIMES: &'static [&'static str] = &[
+        "2", "3", "5", "197", "199", "211", "65537", "2147483647"
+        , "2305843009213693951"                       // 2^61 - 1
+        , "618970019642690137449562111"               // 2^89 - 1
+        , "170141183460469231731687303715884105727"]; // 2^127 - 1
+
+    // Carmichael numbers (561, 1105, 41041), strong pseudoprimes to
+    // base 2 (2047, 3825123056546413051), to bases 2 and 3 (1373653),
+    // 2, 3 and 5 (25326001) and 2, 3, 5 and 7 (3215031751), and strong
+    // Lucas pseudoprimes (5459, 5777, 10877).
+    static COMPOSITES: &'static [&'static str] = &[
+        "0", "1", "4", "9", "561", "1105", "41041", "2047", "1373653", "25326001"
+        , "3215031751", "5459", "5777", "10877", "3825123056546413051"
+        , "1000000016000000063"];                     // (10^9 + 7)(10^9 + 9)
+
+    static ODD_PRIMES: &'static [uint] = &[3, 5, 7, 11, 13, 17, 41, 97, 7681, 65537];
+
+    fn num(s: &str) -> BigUint {
+        from_str(s).unwrap()
+    }
+
+    #[test]
+    fn primes_pass() {
+        for p in PRIMES.iter() {
+            assert!(is_probable_prime(&num(*p)), "{} is prime", p);
+            assert!(is_probable_prime_mr(&num(*p), 20), "{} is prime", p);
+        }
+    }
+
+    #[test]
+    fn composites_fail() {
+        for c in COMPOSITES.iter() {
+            assert!(!is_probable_prime(&num(*c)), "{} is composite", c);
+            assert!(!is_probable_prime_mr(&num(*c), 20), "{} is composite", c);
+        }
+    }
+
+    // Each half of Baillie-PSW is fooled by some composites; the pair
+    // must not be.
+    #[test]
+    fn pseudoprimes_fail_bpsw() {
+        for &b in [2u, 3, 5, 7].iter() {
+            assert!(miller_rabin(&num("3215031751"), &big(b)));
+        }
+        assert!(miller_rabin(&num("2047"), &big(2)));
+
+        let spsp = num("3825123056546413051");
+        assert!(miller_rabin(&spsp, &big(2)));
+        assert!(!strong_lucas(&spsp));
+        assert!(!is_probable_prime(&spsp));
+
+        for &n in [5459u, 5777, 10877].iter() {
+            assert!(strong_lucas(&big(n)));
+            assert!(!miller_rabin(&big(n), &big(2)));
+        }
+    }
+
+    #[test]
+    fn gen_prime_has_the_bit_length() {
+        for &bits in [2u, 3, 16, 64, 256].iter() {
+            let p = gen_prime(bits);
+            assert_eq!(p.bits(), bits);
+            assert!(is_probable_prime(&p));
+        }
+    }
+
+    #[test]
+    #[should_fail]
+    fn gen_prime_needs_two_bits() {
+        gen_prime(1);
+    }
+
+    #[test]
+    fn isqrt_is_the_floor() {
+        let one: BigUint = One::one();
+        for n in range(0u, 1000) {
+            let r = isqrt(&big(n));
+            assert!(r * r <= big(n) && big(n) < (r + one) * (r + one), "isqrt({})", n);
+        }
+        let x = num("170141183460469231731687303715884105727");
+        assert_eq!(isqrt(&(x * x)), x);
+        assert_eq!(isqrt(&(x * x - one)), x - one);
+    }
+
+    #[test]
+    fn inverse_times_a_is_one() {
+        let one: BigUint = One::one();
+        for &m in [2u, 3, 8, 12, 97, 100, 256, 7681].iter() {
+            for a in range(0u, 300) {
+                match mod_inverse(&big(a), &big(m)) {
+                    Some(inv) => {
+                        assert!(inv < big(m));
+                        assert_eq!(big(a) * inv % big(m), one);
+                    },
+                    None => assert!(big(a).gcd(&big(m)) != one, "{}^-1 mod {}", a, m),
+                }
+            }
+        }
+        let p = num("170141183460469231731687303715884105727");
+        let a = num("123456789012345678901234567890");
+        assert_eq!(a * mod_inverse(&a, &p).unwrap() % p, one);
+        assert_eq!(mod_inverse(&big(5), &big(1)), None);
+        assert_eq!(mod_inverse(&big(5), &big(0)), None);
+    }
+
+    #[test]
+    fn crt_reconstructs() {
+        let moduli = [big(3), big(5), big(7)];
+        for x in range(0u, 105) {
+            let residues: Vec<BigUint> = moduli.iter().map(|m| big(x) % *m).collect();
+            assert_eq!(crt(residues.as_slice(), &moduli), Some(big(x)));
+        }
+
+        let big_moduli = [num("2305843009213693951"), num("618970019642690137449562111")
+                          , big(1024)];
+        let x = num("1234567890123456789012345678901234567890");
+        let residues: Vec<BigUint> = big_moduli.iter().map(|m| x % *m).collect();
+        assert_eq!(crt(residues.as_slice(), &big_moduli), Some(x));
+
+        assert_eq!(crt(&[big(1), big(2)], &[big(4), big(6)]), None);
+        assert_eq!(crt(&[big(1)], &[big(4), big(5)]), None);
+        assert_eq!(crt(&[], &[]), None);
+    }
+
+    #[test]
+    fn jacobi_matches_euler_for_primes() {
+        let one: BigUint = One::one();
+        for &p in ODD_PRIMES.iter() {
+            for a in range(0u, if p < 300 { p } else { 300 }) {
+                let euler = mod_pow(big(a), big((p - 1) / 2), big(p));
+                let expected = if euler.is_zero() { 0 } else if euler == one { 1 } else { -1 };
+                assert_eq!(jacobi(&big(a), &big(p)), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn jacobi_of_composites() {
+        assert_eq!(jacobi(&big(1001), &big(9907)), -1);
+        assert_eq!(jacobi(&big(19), &big(45)), 1);
+        assert_eq!(jacobi(&big(8), &big(21)), -1);
+        assert_eq!(jacobi(&big(5), &big(21)), 1);
+        assert_eq!(jacobi(&big(6), &big(9)), 0);
+        assert_eq!(jacobi(&big(0), &big(1)), 1);
+    }
+
+    #[test]
+    #[should_fail]
+    fn jacobi_needs_odd_n() {
+        jacobi(&big(3), &big(8));
+    }
+
+    #[test]
+    fn sqrt_squares_back() {
+        let mut primes: Vec<BigUint> = ODD_PRIMES.iter().map(|p| big(*p)).collect();
+        primes.push(num("170141183460469231731687303715884105727"));
+        let mut rng = rand::task_rng();
+        for p in primes.iter() {
+            let mut values: Vec<BigUint> = range(0u, 200).map(|a| big(a)).collect();
+            for _ in range(0u, 20) {
+                values.push(rng.gen_biguint_below(p));
+            }
+            for a in values.iter() {
+                match mod_sqrt(a, p) {
+                    Some(r) => assert_eq!(r * r % *p, *a % *p),
+                    None => assert_eq!(jacobi(a, p), -1),
+                }
+            }
+        }
+        // 13 is a non-residue mod 7681 = 15 * 2^9 + 1.
+        assert_eq!(mod_sqrt(&big(13), &big(7681)), None);
+    }
+
+    #[test]
+    fn replaced_files_are_owner_only() {
+        let dir = TempDir::new("magenta-utils").unwrap();
+        let path = dir.path().join("file");
+        File::create(&path).unwrap().write(b"old").unwrap();
+
+        replace_file(&path, b"new").unwrap();
+        assert_eq!(File::open(&path).read_to_end().unwrap().as_slice(), b"new");
+        assert_eq!(stat(&path).unwrap().perm, USER_READ | USER_WRITE);
+        // No temporary file is left behind.
+        assert_eq!(readdir(dir.path()).unwrap(), vec![path.clone()]);
+
+        // An existing file is never opened, let alone truncated.
+        assert!(create_private(&path).is_err());
+        assert_eq!(File::open(&path).read_to_end().unwrap().as_slice(), b"new");
+    }
+}
@@ -7,29 +7,115 @@ extern crate magenta;
 extern crate num;
 
 use std::io::{File, IoErrorKind, EndOfFile, IoResult, Truncate, ReadWrite
-              , SeekEnd, SeekSet, BufferedReader};
+              , SeekEnd, SeekSet, BufferedReader, BufReader, stdin, stderr};
+use std::io::stdio::flush;
+use std::io::process::{Command, InheritFd};
 use std::io::fs::{rmdir_recursive, copy};
 use std::fmt::Show;
 use std::os;
 use std::default::Default;
 use std::fmt::radix;
-use getopts::{optopt,optflag,getopts,OptGroup,Matches};
+use getopts::{optopt,optflag,optmulti,getopts,OptGroup,Matches};
 use num::bigint::{BigUint, ToBigUint, RandBigInt};
 use std::num::from_str_radix;
+use magenta::pbc;
 use magenta::pbc::PBC;
 use magenta::ds::DigSig;
-use magenta::hash::h_file;
-use magenta::utils::{print_array};
+use magenta::hash;
+use magenta::hash::Construction;
+use magenta::utils::{read_block, invalid_input};
+use magenta::armor::{Armored, is_armored, armor, dearmor, MESSAGE, SIGNATURE, WRAPPED_KEY};
+use magenta::keyfile;
+use magenta::kdf;
+use magenta::pbe;
+use magenta::dh;
+use magenta::dh::DhKeyPair;
+use magenta::hybrid;
+use magenta::ds::DsParams;
+use magenta::params;
+use magenta::scheme;
+use magenta::scheme::{Scheme, Signature, SignatureFile};
+use magenta::rsa;
+use magenta::rsa::{RsaPrivateKey, RsaPublicKey};
+use magenta::fingerprint;
+use magenta::fingerprint::KeyId;
+use magenta::keyring;
+use magenta::keyring::{Keyring, Entry, KeyKind};
+use magenta::agent;
+use magenta::agent::{Agent, AgentClient};
+use magenta::metadata;
+use magenta::metadata::Metadata;
+use magenta::cert;
+use magenta::cert::{Certificate, TbsCertificate};
+use magenta::bundle;
+use magenta::bundle::{Bundle, Policy};
+use magenta::manifest;
+use magenta::manifest::Manifest;
+use magenta::treehash;
+use magenta::treehash::ChunkProof;
+
+
+// Validity of a new certificate without -e.
+static CERT_DAYS: u64 = 365;
 
 
 fn print_usage(program: &str, opts: &[OptGroup]) {
-    println!("Usage: {} encrypt [-p|--private_key path] [-s|--private_ds_key path] <input_file> <output_file>", program);
+    println!("Usage: {} encrypt [-a|--armor] [-P|--params id] [-p|--private_key path] [-s|--private_ds_key path] <input_file> <output_file>", program);
+    println!("       {} encrypt [-a|--armor] <-r|--recipient path>... [-s|--private_ds_key path] <input_file> <output_file>", program);
     println!("       {} decrypt <-p|--private_key path> <-o|--open_ds_key path> <input_file> <output_file>", program);
-    println!("       {} pbc [-k|--key path] <input_file> <output_file>", program);
+    println!("       {} decrypt <-k|--key path> <-o|--open_ds_key path> <input_file> <output_file>", program);
+    println!("       {} pbc [-a|--armor] [-k|--key path] <input_file> <output_file>", program);
     println!("       {} pbc <-d|--dec> <-k|--key path> <input_file> <output_file>", program);
-    println!("       {} hash <input_file>", program);
-    println!("       {} ds [-k|--key path] <input_file>", program);
-    println!("       {} ds <-v|--verify sig> <-k|--key path> <input_file>", program);
+    println!("       {} pbc <-w|--password> [-i|--iterations n] [-a|--armor] <input_file> <output_file>", program);
+    println!("       {} pbc <-d|--dec> <-w|--password> <input_file> <output_file>", program);
+    println!("       {} hash [-H|--hash name] [input_file]...", program);
+    println!("       {} hash <-x|--check> [-H|--hash name] [checksum_file]...", program);
+    println!("       {} hash <-T|--tree> [-j|--threads n] [-Z|--chunk_size bytes] [-x|--check] [file]...", program);
+    println!("       {} tree proof [-j|--threads n] [-Z|--chunk_size bytes] <input_file> <chunk_index> <proof_file>", program);
+    println!("       {} tree verify <root> <proof_file> <chunk_file>", program);
+    println!("       {} ds [-a|--armor] [-P|--params id] [-S|--scheme name] [-H|--hash name] [-k|--key key] [-e|--expires days] [-c|--comment text] <input_file>", program);
+    println!("       {} ds <-v|--verify sig> [-k|--key key_or_dir] <input_file>", program);
+    println!("       {} dh [-a|--armor] [-k|--key path] <peer_open_ds_key> <output_key>", program);
+    println!("       {} params", program);
+    println!("       {} rsa keygen [-a|--armor] [-b|--bits n] <key_file>", program);
+    println!("       {} rsa sign [-a|--armor] <-k|--key path> <input_file>", program);
+    println!("       {} rsa verify <-v|--verify sig> <-k|--key path> <input_file>", program);
+    println!("       {} rsa wrap [-a|--armor] <-k|--key path> <pbc_key_file> <output_file>", program);
+    println!("       {} rsa unwrap [-a|--armor] <-k|--key path> <wrapped_key_file> <pbc_key_file>", program);
+    println!("       {} fingerprint <key_file>...", program);
+    println!("       {} key list", program);
+    println!("       {} key import [-l|--label name] <key_file>", program);
+    println!("       {} key export [-a|--armor] <key> <output_file>", program);
+    println!("       {} key delete <key>", program);
+    println!("       {} key default [key]", program);
+    println!("       {} agent [key]...", program);
+    println!("       {} cert issue [-a|--armor] [-H|--hash name] [-k|--key issuer_key] [-n|--name name] [-u|--usage flags] [-e|--expires days] <subject_key> <cert_file>", program);
+    println!("       {} cert verify [-u|--usage flags] <-t|--trust root_key>... <cert_file> [issuer_cert_file]...", program);
+    println!("       {} bundle append [-k|--key key] [-S|--scheme name] [-H|--hash name] [-e|--expires days] [-c|--comment text] [-C|--counter n] <input_file>", program);
+    println!("       {} bundle list <bundle_file>", program);
+    println!("       {} bundle verify [-k|--key dir] [-t|--trust key]... [-m|--min n] <bundle_file> <input_file>", program);
+    println!("       {} manifest create [-k|--key key] [-S|--scheme name] [-H|--hash name] [-e|--expires days] [-c|--comment text] <dir> <manifest_file>", program);
+    println!("       {} manifest verify [-k|--key key_or_dir] <dir> <manifest_file>", program);
+    println!("       {} protect <key_file>", program);
+    println!("       {} export [-a|--armor] <key_file> <output_file>", program);
+    println!("");
+    println!("hash prints \"<digest>  <path>\" lines and reads standard input for \"-\" or when");
+    println!("no file is given. --check exits with status 1 when a file doesn't match.");
+    println!("--hash picks the construction: magenta (default), dm, mmo, mp or hirose, the");
+    println!("last with a 256-bit digest. --tree only uses magenta. ds, bundle append,");
+    println!("manifest create and cert issue sign with it and record it, so the other");
+    println!("commands pick it up when checking.");
+    println!("");
+    println!("Keys given with -k, -p, -s, -o and -r to encrypt, decrypt and ds are key files,");
+    println!("or keys in the keyring ({}) by label or key ID. Keyring keys held by a"
+             , keyring::default_dir().display());
+    println!("running agent ({}) are used through it.", agent::socket_path().display());
+    println!("To check a signature without -k naming a key, the signer's key must be in");
+    println!("the keyring or in the -k directory; keys next to the signature are not used.");
+    println!("");
+    println!("bundle verify wants signatures by -m of the -t keys (all of them by default),");
+    println!("or without -t by -m (1 by default) of the ds keys in the keyring. Other");
+    println!("signatures are checked but don't count.");
     println!("");
     for opt in opts.iter() {
         println!("-{}, --{}\t{}", opt.short_name, opt.long_name, opt.desc);
@@ -45,11 +131,31 @@ fn main() {
     let opts = [
         optflag("h", "help", "print this help menu"),
         optflag("d", "dec", "decrypt file"),
+        optflag("a", "armor", "write keys, signatures and ciphertext as ASCII armor"),
+        optflag("w", "password", "derive the pbc key from a password"),
+        optopt("i", "iterations", "PBKDF2 iteration count for --password", "n"),
         optopt("k", "key", "key file", "hint"),
         optopt("v", "verify", "verify signature of file", "hint"),
         optopt("s", "private_ds_key", "private ds key file", "hint"),
         optopt("o", "open_ds_key", "open ds key file", "hint"),
         optopt("p", "private_key", "private pbc key file", "hint"),
+        optmulti("r", "recipient", "open ds key of a recipient", "hint"),
+        optopt("P", "params", "parameter set for new ds keys", "id"),
+        optopt("S", "scheme", "signature scheme for ds: gost (default), schnorr or dsa", "name"),
+        optopt("b", "bits", "modulus size for new rsa keys", "n"),
+        optopt("l", "label", "label of a key imported into the keyring", "name"),
+        optopt("e", "expires", "days a ds signature stays valid", "days"),
+        optopt("c", "comment", "comment signed along with a ds signature", "text"),
+        optopt("n", "name", "subject name of a certificate", "name"),
+        optopt("u", "usage", "key usage to certify or to require: sign, certify, encrypt", "flags"),
+        optmulti("t", "trust", "trusted root key, or allowed bundle signer", "key"),
+        optflag("x", "check", "check the digests listed in checksum files"),
+        optopt("H", "hash", "hash function: magenta (default), dm, mmo, mp or hirose", "name"),
+        optflag("T", "tree", "hash as a Merkle tree of chunks, on several threads"),
+        optopt("j", "threads", "threads for tree hashing (default: number of CPUs)", "n"),
+        optopt("Z", "chunk_size", "chunk size for tree hashing (default: 1 MiB)", "bytes"),
+        optopt("C", "counter", "counter-sign signature n of the bundle", "n"),
+        optopt("m", "min", "valid bundle signatures required (default: all -t keys, or 1)", "n"),
     ];
 
     let matches = match getopts(args.tail(), opts) {
@@ -64,7 +170,10 @@ fn main() {
     }
 
     let command = match matches.free[0].as_slice() {
-        c @ "pbc" | c @ "hash" | c @ "ds" | c @ "encrypt" | c @ "decrypt" => c,
+        c @ "pbc" | c @ "hash" | c @ "ds" | c @ "encrypt" | c @ "decrypt"
+            | c @ "protect" | c @ "export" | c @ "dh" | c @ "params" | c @ "rsa"
+            | c @ "fingerprint" | c @ "key" | c @ "agent" | c @ "cert"
+            | c @ "bundle" | c @ "manifest" | c @ "tree" => c,
         _ => {
             print_usage(program.as_slice(), &opts);
             return;
@@ -72,6 +181,14 @@ fn main() {
     };
 
     let free_len = matches.free.len();
+    let is_armor = matches.opt_present("a");
+    let new_params = match matches.opt_str("P") {
+        Some(id) => match DsParams::by_id(id.as_slice()) {
+            Ok(p) => p,
+            Err(e) => fail!("{}: {}", id, e.desc),
+        },
+        None => params::default(),
+    };
     match command {
         "encrypt" if free_len == 3 => {
             let path_in_str = &matches.free[1];
@@ -86,17 +203,26 @@ fn main() {
 
                 copy(&path_in, &path_temp);
 
-                let (y, ds) = do_ds(&mut temp_file, &matches.opt_str("s"));
-                save_ds_ok(&y, path_in_str);
+                let ds_key = matches.opt_str("s")
+                    .map(|k| private_key_source(k.as_slice(), &[keyring::DsSigning]));
+                let (params, y, ds) = do_ds(&mut temp_file, ds_key, &new_params, is_armor);
+                save_ds_ok(&params, &y, path_in_str, is_armor);
                 concat_ds(&ds, &mut temp_file);
             }
             
             let mut file_out = File::create(&path_out).unwrap();
             let mut temp_file = File::open(&path_temp).unwrap();
-            do_pbc(&mut temp_file, &mut file_out, true, &matches.opt_str("p"));
+            let recipients = matches.opt_strs("r");
+            if recipients.is_empty() {
+                let pbc_key = matches.opt_str("p")
+                    .map(|k| resolve_key(k.as_slice(), &[keyring::PbcSecret]));
+                do_pbc(&mut temp_file, &mut file_out, true, &pbc_key, is_armor);
+            } else {
+                do_hybrid_enc(&mut temp_file, &mut file_out, &recipients, is_armor);
+            }
         }
         "decrypt" if (free_len == 3
-            && matches.opt_str("p").is_some()
+            && (matches.opt_str("p").is_some() || matches.opt_str("k").is_some())
             && matches.opt_str("o").is_some()) => {
 
             let path_in_str = &matches.free[1];
@@ -107,7 +233,17 @@ fn main() {
             {
                 let mut file_in = File::open(&path_in).unwrap();
                 let mut file_temp = File::create(&path_temp).unwrap();
-                do_pbc(&mut file_in, &mut file_temp, false, &matches.opt_str("p"));
+                match matches.opt_str("k") {
+                    Some(k) => {
+                        let source = private_key_source(k.as_slice(), &[keyring::DsSigning]);
+                        do_hybrid_dec(&mut file_in, &mut file_temp, source)
+                    },
+                    None => {
+                        let p = matches.opt_str("p").unwrap();
+                        let source = private_key_source(p.as_slice(), &[keyring::PbcSecret]);
+                        do_pbc_dec(&mut file_in, &mut file_temp, source)
+                    },
+                }
             }
 
             let (r, s) = read_ds(&path_temp, &path_out);
@@ -125,14 +261,46 @@ fn main() {
             let path_out = Path::new(matches.free[2].as_slice());
 
             let mut file_in = File::open(&path_in).unwrap();
-            let mut file_out = File::create(&path_out).unwrap();
 
+            if matches.opt_present("w") {
+                if matches.opt_present("d") {
+                    let password = read_passphrase("Password: ");
+                    pbe_dec(password.as_slice(), &mut file_in, &path_out);
+                } else {
+                    let iterations = match matches.opt_str("i") {
+                        Some(i) => match from_str(i.as_slice()) {
+                            Some(n) if n > 0 && n <= kdf::MAX_ITERATIONS => n,
+                            _ => fail!("invalid iteration count: {}, at most {}", i
+                                       , kdf::MAX_ITERATIONS),
+                        },
+                        None => kdf::DEFAULT_ITERATIONS,
+                    };
+                    let password = read_passphrase("Password: ");
+                    if password != read_passphrase("Repeat password: ") {
+                        println!("Passwords don't match!");
+                        return;
+                    }
+                    let mut file_out = File::create(&path_out).unwrap();
+                    pbe_enc(password.as_slice(), iterations, &mut file_in, &mut file_out
+                            , is_armor);
+                }
+                return;
+            }
+
+            let mut file_out = File::create(&path_out).unwrap();
+            if matches.opt_present("d") && matches.opt_present("k") {
+                let k = matches.opt_str("k").unwrap();
+                let source = private_key_source(k.as_slice(), &[keyring::PbcSecret]);
+                do_pbc_dec(&mut file_in, &mut file_out, source);
+                return;
+            }
             let key_file = match matches.opt_str("k") {
-                Some(k) => k,
+                Some(k) => resolve_key(k.as_slice(), &[keyring::PbcSecret]),
                 None if !matches.opt_present("d") => {
                     let mut private_key_path = path_in_str.clone();
                     private_key_path.push_str(".pk");
-                    PBC::rnd_key(private_key_path.as_slice());
+                    PBC::key_to_file(private_key_path.as_slice(), &PBC::gen_key()
+                                     , is_armor);
                     private_key_path
                 },
                 _ => {
@@ -140,58 +308,589 @@ fn main() {
                     return;
                 }
             };
-            let box key = PBC::key_from_file(key_file.as_slice());
+            let key = load_pbc_key(key_file.as_slice());
 
             let pbc = PBC::new(&key, &[0u8, ..16]);
             if matches.opt_present("d") {
-                pbc.dec_file(&mut file_in, &mut file_out);
+                pbc_dec(&pbc, &mut file_in, &mut file_out);
             } else {
-                pbc.enc_file(&mut file_in, &mut file_out);
+                pbc_enc(&pbc, &mut file_in, &mut file_out, is_armor);
             }
         },
-        "hash" if free_len == 2 => {
-            let path_in = Path::new(matches.free[1].as_slice());
-            let mut file_in = File::open(&path_in).unwrap();
-            let box h = h_file(&mut file_in);
-            print_array(&h);
+        "hash" => {
+            let mut paths = matches.free.slice_from(1).to_vec();
+            if paths.is_empty() {
+                paths.push("-".to_string());
+            }
+            let construction = hash_construction(&matches);
+            let tree = tree_opts(&matches);
+            if tree.is_some() && construction != hash::Original {
+                fail!("tree hashing only uses the magenta hash");
+            }
+            let mut ok = true;
+            for path in paths.iter() {
+                let path = path.as_slice();
+                if matches.opt_present("x") {
+                    ok = check_checksums(path, construction, tree) && ok;
+                    continue;
+                }
+                match hash_path(path, construction, tree) {
+                    Ok(digest) => {
+                        println!("{}", hash::format_checksum(digest.as_slice(), path))
+                    },
+                    Err(e) => {
+                        print_error(format!("{}: {}", path, e.desc).as_slice());
+                        ok = false;
+                    },
+                }
+            }
+            if !ok {
+                os::set_exit_status(1);
+            }
         },
         "ds" if free_len == 2 => {
             let path_in_str = &matches.free[1];
             let path_in = Path::new(path_in_str.as_slice());
             let mut file_in = File::open(&path_in).unwrap();
 
-            let key_file = match matches.opt_str("k") {
-                Some(k) => k,
-                None if !matches.opt_present("v")  => {
-                    let mut private_key_path = path_in_str.clone();
-                    private_key_path.push_str(".dspk");
-                    DigSig::rnd_key(private_key_path.as_slice());
-                    private_key_path
-                },
-                _ => {
-                    print_usage(program.as_slice(), &opts);
-                    return;
-                }
-            };
-            let key = DigSig::key_from_file(key_file.as_slice());
-
             if matches.opt_present("v") {
                 let ds_path = matches.opt_str("v").unwrap();
-                let ds = DigSig::from_file(ds_path.as_slice());
-                match ds.verify_file(&mut file_in, key) {
-                    true => println!("Correct!"),
-                    false => println!("Incorrect!"),
+                let sig_file = SignatureFile::from_file(ds_path.as_slice());
+                let key = match signature_key(&matches, &sig_file) {
+                    Some(key) => key,
+                    None => return,
                 };
+                let is_valid = sig_file.verify_reader(&mut file_in, &key);
+                report_signature(&sig_file, is_valid);
             } else {
-                let (y, ds) = DigSig::of_file(&mut file_in, &key);
+                let source = match matches.opt_str("k") {
+                    Some(k) => private_key_source(k.as_slice(), &[keyring::DsSigning]),
+                    None => match default_signing_key() {
+                        Some(source) => source,
+                        None => {
+                            let mut private_key_path = path_in_str.clone();
+                            private_key_path.push_str(".dspk");
+                            gen_ds_key(private_key_path.as_slice(), &new_params, is_armor);
+                            KeyFile(private_key_path)
+                        },
+                    },
+                };
+                let attrs = sig_attrs(&matches);
+                let (y, sig_file) = sign_with(source, scheme_opt(&matches), &mut file_in
+                                              , Some(&attrs));
                 let mut ds_path = path_in_str.clone();
-                let mut y_path = path_in_str.clone();
                 ds_path.push_str(".ds");
-                y_path.push_str(".dsok");
-                ds.to_file(ds_path.as_slice());
-                DigSig::key_to_file(y_path.as_slice(), &y);
+                sig_file.to_file(ds_path.as_slice(), is_armor);
+                save_ds_ok(&sig_file.params, &y, path_in_str, is_armor);
+            }
+        },
+        "dh" if free_len == 3 => {
+            let (params, peer_y) = DigSig::key_from_file(matches.free[1].as_slice());
+            let out_path = matches.free[2].as_slice();
+
+            let pair = match matches.opt_str("k") {
+                Some(k) => {
+                    let (own_params, x) = load_ds_key(k.as_slice());
+                    if own_params != params {
+                        fail!("dh: keys use different parameter sets");
+                    }
+                    DhKeyPair::from_private(&params, x)
+                },
+                None => {
+                    let pair = DhKeyPair::generate(&params);
+                    save_ds_ok(&params, &pair.y, &out_path.to_string(), is_armor);
+                    pair
+                },
+            };
+
+            let z = match pair.agree(&params, &peer_y) {
+                Ok(z) => z,
+                Err(e) => fail!("{}", e.desc),
+            };
+            let info = dh::key_info(&params, &pair.y, &peer_y);
+            let key = dh::derive_key(&params, &z, info.as_slice());
+            PBC::key_to_file(out_path, &key, is_armor);
+        },
+        "params" if free_len == 1 => {
+            for id in params::ids().iter() {
+                if *id == params::DEFAULT {
+                    println!("{} (default)", id);
+                } else {
+                    println!("{}", id);
+                }
+            }
+        },
+        "rsa" if free_len >= 3 => {
+            let sub = matches.free[1].as_slice();
+            match (sub, free_len) {
+                ("keygen", 3) => {
+                    let bits = match matches.opt_str("b") {
+                        Some(b) => match from_str(b.as_slice()) {
+                            Some(n) if n >= rsa::MIN_BITS => n,
+                            _ => fail!("invalid rsa key size: {}", b),
+                        },
+                        None => rsa::DEFAULT_BITS,
+                    };
+                    let key_path = matches.free[2].clone();
+                    let key = RsaPrivateKey::generate(bits);
+                    keyfile::save(key_path.as_slice(), &keyfile::RsaKey(key.clone()), None
+                                  , is_armor).unwrap();
+                    let mut pub_path = key_path.clone();
+                    pub_path.push_str(".pub");
+                    let public = key.public_key();
+                    if is_armor {
+                        write_text(pub_path.as_slice(), public.to_armor().as_slice());
+                    } else {
+                        File::create(&Path::new(pub_path.as_slice())).unwrap()
+                            .write(public.to_der().as_slice()).unwrap();
+                    }
+                },
+                ("sign", 3) if matches.opt_present("k") => {
+                    let key = load_rsa_key(matches.opt_str("k").unwrap().as_slice());
+                    let path_in_str = &matches.free[2];
+                    let mut file_in = File::open(&Path::new(path_in_str.as_slice())).unwrap();
+                    let sig = key.sign_pss_reader(&mut file_in);
+
+                    let mut sig_path = path_in_str.clone();
+                    sig_path.push_str(".rsasig");
+                    if is_armor {
+                        let mut armored = Armored::new(SIGNATURE, sig);
+                        armored.add_header("Scheme", "rsa-pss");
+                        write_text(sig_path.as_slice(), armored.encode().as_slice());
+                    } else {
+                        File::create(&Path::new(sig_path.as_slice())).unwrap()
+                            .write(sig.as_slice()).unwrap();
+                    }
+                },
+                ("verify", 3) if matches.opt_present("k") && matches.opt_present("v") => {
+                    let key = read_rsa_public_key(matches.opt_str("k").unwrap().as_slice());
+                    let sig = read_blob(matches.opt_str("v").unwrap().as_slice(), SIGNATURE);
+                    let mut file_in = File::open(&Path::new(matches.free[2].as_slice())).unwrap();
+                    match key.verify_pss_reader(&mut file_in, sig.as_slice()) {
+                        true => println!("Correct!"),
+                        false => println!("Incorrect!"),
+                    };
+                },
+                ("wrap", 4) if matches.opt_present("k") => {
+                    let key = read_rsa_public_key(matches.opt_str("k").unwrap().as_slice());
+                    let content_key = load_pbc_key(matches.free[2].as_slice());
+                    let wrapped = match key.wrap_key(&content_key) {
+                        Ok(w) => w,
+                        Err(e) => fail!("{}", e.desc),
+                    };
+                    let out_path = matches.free[3].as_slice();
+                    if is_armor {
+                        write_text(out_path, armor(WRAPPED_KEY, wrapped.as_slice()).as_slice());
+                    } else {
+                        File::create(&Path::new(out_path)).unwrap()
+                            .write(wrapped.as_slice()).unwrap();
+                    }
+                },
+                ("unwrap", 4) if matches.opt_present("k") => {
+                    let key = load_rsa_key(matches.opt_str("k").unwrap().as_slice());
+                    let wrapped = read_blob(matches.free[2].as_slice(), WRAPPED_KEY);
+                    let content_key = match key.unwrap_key(wrapped.as_slice()) {
+                        Ok(k) => k,
+                        Err(e) => fail!("{}", e.desc),
+                    };
+                    PBC::key_to_file(matches.free[3].as_slice(), &content_key, is_armor);
+                },
+                _ => print_usage(program.as_slice(), &opts),
             }
         },
+        "protect" if free_len == 2 => {
+            let key_path = matches.free[1].as_slice();
+            let key = load_private_key(key_path);
+            let passphrase = read_passphrase("New passphrase: ");
+            if passphrase != read_passphrase("Repeat passphrase: ") {
+                println!("Passphrases don't match!");
+                return;
+            }
+            keyfile::save(key_path, &key, Some(passphrase.as_slice()), false).unwrap();
+        },
+        "key" if free_len >= 2 => {
+            let sub = matches.free[1].as_slice();
+            match (sub, free_len) {
+                ("list", 2) => {
+                    let ring = open_keyring();
+                    for e in ring.entries().iter() {
+                        let mark = if ring.is_default(e) { "*" } else { " " };
+                        println!("{} {}  {:<10}  {}", mark, fingerprint::format_key_id(&e.id)
+                                 , e.kind.name(), e.label);
+                    }
+                },
+                ("import", 3) => {
+                    let path = matches.free[2].as_slice();
+                    let content = read_file(path);
+                    let is_dsok = Path::new(path).extension_str() == Some("dsok");
+                    let (kind, fp) = match keyring::identify(content.as_slice(), is_dsok
+                                                             , || read_passphrase("Passphrase: ")) {
+                        Ok(res) => res,
+                        Err(e) => fail!("{}: {}", path, e.desc),
+                    };
+                    let label = match matches.opt_str("l") {
+                        Some(l) => l,
+                        None => Path::new(path).filestem_str().unwrap().to_string(),
+                    };
+                    let mut ring = match Keyring::create(&keyring::default_dir()) {
+                        Ok(ring) => ring,
+                        Err(e) => fail!("{}", e),
+                    };
+                    match ring.import(content.as_slice(), kind, &fp, label.as_slice()) {
+                        Ok(e) => println!("Imported {} key {} as {}", e.kind.name()
+                                          , fingerprint::format_key_id(&e.id), e.label),
+                        Err(e) => fail!("{}: {}", path, e.desc),
+                    }
+                },
+                ("export", 4) => {
+                    let ring = open_keyring();
+                    let entry = find_key(&ring, matches.free[2].as_slice(), &[]);
+                    export_key(&ring, entry, matches.free[3].as_slice(), is_armor);
+                },
+                ("delete", 3) => {
+                    let mut ring = open_keyring();
+                    match ring.delete(matches.free[2].as_slice()) {
+                        Ok(removed) => {
+                            for e in removed.iter() {
+                                println!("Deleted {} key {} ({})", e.kind.name()
+                                         , fingerprint::format_key_id(&e.id), e.label);
+                            }
+                        },
+                        Err(e) => fail!("{}: {}", matches.free[2], e.desc),
+                    }
+                },
+                ("default", 2) => {
+                    let ring = open_keyring();
+                    match ring.default() {
+                        Some(e) => println!("{}  {:<10}  {}", fingerprint::format_key_id(&e.id)
+                                            , e.kind.name(), e.label),
+                        None => println!("No default key!"),
+                    }
+                },
+                ("default", 3) => {
+                    let mut ring = open_keyring();
+                    match ring.set_default(matches.free[2].as_slice()) {
+                        Ok(_) => {},
+                        Err(e) => fail!("{}: {}", matches.free[2], e.desc),
+                    }
+                },
+                _ => print_usage(program.as_slice(), &opts),
+            }
+        },
+        "agent" => {
+            let queries: Vec<String> = if free_len > 1 {
+                matches.free.slice_from(1).to_vec()
+            } else {
+                open_keyring().entries().iter()
+                    .filter(|e| e.kind == keyring::DsSigning || e.kind == keyring::PbcSecret)
+                    .map(|e| fingerprint::format_key_id(&e.id))
+                    .collect()
+            };
+            let mut agent = Agent::new();
+            for q in queries.iter() {
+                let path = resolve_key(q.as_slice(), &[keyring::DsSigning, keyring::PbcSecret]);
+                match agent.add(load_private_key(path.as_slice())) {
+                    Ok(id) => println!("Added key {}", fingerprint::format_key_id(&id)),
+                    Err(e) => fail!("{}: {}", q, e.desc),
+                }
+            }
+            if agent.len() == 0 {
+                println!("No keys to hold!");
+                return;
+            }
+            let path = agent::socket_path();
+            println!("Agent listening on {}", path.display());
+            match agent.serve(&path) {
+                Ok(_) => {},
+                Err(e) => fail!("{}", e),
+            }
+        },
+        "fingerprint" if free_len >= 2 => {
+            for path in matches.free.slice_from(1).iter() {
+                let fp = key_fingerprint(path.as_slice());
+                println!("{}  {}  {}", fingerprint::format_key_id(&fingerprint::key_id(&fp))
+                         , fingerprint::format_fingerprint(&fp), path);
+            }
+        },
+        "cert" if free_len >= 3 => {
+            let sub = matches.free[1].as_slice();
+            match (sub, free_len) {
+                ("issue", 4) => {
+                    let source = signing_key_source(&matches);
+                    let (params, key) = read_ds_public_key(matches.free[2].as_slice());
+                    let usage = cert_usage(&matches);
+                    let days = match matches.opt_str("e") {
+                        Some(e) => match from_str(e.as_slice()) {
+                            Some(n) if n > 0 => n,
+                            _ => fail!("invalid number of days: {}", e),
+                        },
+                        None => CERT_DAYS,
+                    };
+                    let not_before = metadata::now();
+                    let tbs = TbsCertificate {
+                        issuer: [0u8, ..8],
+                        params: params,
+                        key: key,
+                        name: matches.opt_str("n").unwrap_or(String::new()),
+                        not_before: not_before,
+                        not_after: not_before + days * 24 * 60 * 60,
+                        usage: usage,
+                        hash: hash_construction(&matches)
+                    };
+                    let certificate = issue_cert(source, tbs);
+                    let out_path = matches.free[3].as_slice();
+                    if is_armor {
+                        write_text(out_path, certificate.to_armor().as_slice());
+                    } else {
+                        File::create(&Path::new(out_path)).unwrap()
+                            .write(certificate.to_der().as_slice()).unwrap();
+                    }
+                },
+                ("verify", _) => {
+                    let roots: Vec<(DsParams, BigUint)> = matches.opt_strs("t").iter()
+                        .map(|t| read_ds_public_key(t.as_slice())).collect();
+                    if roots.is_empty() {
+                        fail!("no trusted root keys: give them with -t");
+                    }
+                    let certs: Vec<Certificate> = matches.free.slice_from(2).iter()
+                        .map(|path| read_cert(path.as_slice())).collect();
+                    match cert::verify_chain(&certs[0], certs.slice_from(1), roots.as_slice()
+                                             , metadata::now(), cert_usage(&matches)) {
+                        Ok(chain) => {
+                            for c in chain.iter() {
+                                print_cert(c);
+                                println!("");
+                            }
+                            println!("Correct!");
+                        },
+                        Err(e) => {
+                            println!("{}", e.desc);
+                            println!("Incorrect!");
+                        },
+                    }
+                },
+                _ => print_usage(program.as_slice(), &opts),
+            }
+        },
+        "bundle" if free_len >= 3 => {
+            let sub = matches.free[1].as_slice();
+            match (sub, free_len) {
+                ("append", 3) => {
+                    let path_in_str = &matches.free[2];
+                    let mut bundle_path = path_in_str.clone();
+                    bundle_path.push_str(".dsb");
+                    let bundle_path = Path::new(bundle_path);
+                    let mut bundle = read_bundle(&bundle_path);
+                    let source = signing_key_source(&matches);
+                    let attrs = sig_attrs(&matches);
+                    let scheme = scheme_opt(&matches);
+                    match matches.opt_str("C") {
+                        Some(n) => {
+                            let target = match from_str::<uint>(n.as_slice()) {
+                                Some(i) if i > 0 && i <= bundle.entries.len() => i - 1,
+                                _ => fail!("no signature {} in {}", n, bundle_path.display()),
+                            };
+                            let msg = bundle.counter_message(target);
+                            let (_, sig_file) = sign_with(source, scheme
+                                                          , &mut BufReader::new(msg.as_slice())
+                                                          , Some(&attrs));
+                            bundle.push_counter(sig_file, target).unwrap();
+                        },
+                        None => {
+                            let mut file_in = File::open(&Path::new(path_in_str.as_slice()))
+                                .unwrap();
+                            let (_, sig_file) = sign_with(source, scheme, &mut file_in
+                                                          , Some(&attrs));
+                            bundle.push(sig_file);
+                        },
+                    }
+                    match bundle.save(&bundle_path) {
+                        Ok(_) => {},
+                        Err(e) => fail!("{}: {}", bundle_path.display(), e.desc),
+                    }
+                },
+                ("list", 3) => {
+                    let bundle = read_bundle(&Path::new(matches.free[2].as_slice()));
+                    for (i, entry) in bundle.entries.iter().enumerate() {
+                        print_bundle_entry(i, entry);
+                    }
+                },
+                ("verify", 4) => {
+                    let bundle_path = Path::new(matches.free[2].as_slice());
+                    let bundle = read_bundle(&bundle_path);
+                    let trusted: Vec<(DsParams, BigUint)> = matches.opt_strs("t").iter()
+                        .map(|t| read_ds_public_key(t.as_slice())).collect();
+                    let mut signers: Vec<KeyId> = trusted.iter()
+                        .map(|&(ref params, ref y)| fingerprint::ds_key_id(params, y)).collect();
+                    // Without -t the keyring's ds keys are the trusted ones. Keys
+                    // found in the -k directory are checked but never counted.
+                    let from_keyring = signers.is_empty();
+                    if from_keyring && keyring::default_dir().is_dir() {
+                        for e in open_keyring().entries().iter() {
+                            let is_ds = e.kind == keyring::DsVerification
+                                || e.kind == keyring::DsSigning;
+                            if is_ds && !signers.contains(&e.id) {
+                                signers.push(e.id);
+                            }
+                        }
+                    }
+                    if signers.is_empty() {
+                        fail!("no trusted keys: give them with -t or import them into the \
+                               keyring");
+                    }
+                    let required = match matches.opt_str("m") {
+                        Some(m) => match from_str(m.as_slice()) {
+                            Some(n) => n,
+                            None => fail!("invalid number of signatures: {}", m),
+                        },
+                        None if from_keyring => 1,
+                        None => signers.len(),
+                    };
+                    let n_signers = signers.len();
+                    let policy = match Policy::new(required, signers) {
+                        Ok(policy) => policy,
+                        Err(_) => fail!("{} signatures required of {} trusted keys", required
+                                        , n_signers),
+                    };
+                    let dir = matches.opt_str("k").map(|d| Path::new(d));
+
+                    // The file once for each hash the signatures use.
+                    let path_in = Path::new(matches.free[3].as_slice());
+                    let digests: Vec<(Construction, Vec<u8>)> = bundle.hashes().into_iter()
+                        .map(|h| (h, hash::hash_reader(h, &mut File::open(&path_in).unwrap())))
+                        .collect();
+                    let statuses = bundle.verify(digests.as_slice(), metadata::now(), |id| {
+                        bundle_key(trusted.as_slice(), dir.as_ref(), id)
+                    });
+                    for (i, entry) in bundle.entries.iter().enumerate() {
+                        print_bundle_entry(i, entry);
+                        println!("    {}", match statuses[i] {
+                            bundle::Valid => "correct",
+                            bundle::Expired => "expired",
+                            bundle::Invalid => "incorrect",
+                            bundle::UnknownKey => "key not found",
+                        });
+                    }
+                    println!("{} of {} required signatures"
+                             , bundle.valid_signers(statuses.as_slice()
+                                                    , policy.signers.as_slice()).len()
+                             , policy.required);
+                    match policy.is_met(&bundle, statuses.as_slice()) {
+                        true => println!("Correct!"),
+                        false => println!("Incorrect!"),
+                    }
+                },
+                _ => print_usage(program.as_slice(), &opts),
+            }
+        },
+        "manifest" if free_len == 4 => {
+            let dir = Path::new(matches.free[2].as_slice());
+            let manifest_path = Path::new(matches.free[3].as_slice());
+            match matches.free[1].as_slice() {
+                "create" => {
+                    let source = signing_key_source(&matches);
+                    let body = dir_manifest(&dir, &manifest_path, hash_construction(&matches))
+                        .to_string();
+                    let (_, sig_file) = sign_with(source, scheme_opt(&matches)
+                                                  , &mut BufReader::new(body.as_bytes())
+                                                  , Some(&sig_attrs(&matches)));
+                    write_text(matches.free[3].as_slice()
+                               , format!("{}{}", body, sig_file.to_armor()).as_slice());
+                },
+                "verify" => {
+                    let content = match String::from_utf8(read_file(matches.free[3].as_slice())) {
+                        Ok(c) => c,
+                        Err(_) => fail!("{}: not a manifest", manifest_path.display()),
+                    };
+                    let (body, sig_file) = match manifest::split_signed(content.as_slice()) {
+                        Ok(res) => res,
+                        Err(e) => fail!("{}: {}", manifest_path.display(), e.desc),
+                    };
+                    let key = match signature_key(&matches, &sig_file) {
+                        Some(key) => key,
+                        None => return,
+                    };
+                    match sig_file.metadata {
+                        Some(ref meta) => print_metadata(meta),
+                        None => {},
+                    }
+                    if !sig_file.verify_bytes(body.as_bytes(), &key) {
+                        println!("Incorrect!");
+                        return;
+                    }
+                    if sig_file.metadata.as_ref().map_or(false, |m| m.is_expired(metadata::now())) {
+                        println!("Expired!");
+                        return;
+                    }
+
+                    let expected = match Manifest::from_str(body) {
+                        Ok(m) => m,
+                        Err(e) => fail!("{}: {}", manifest_path.display(), e.desc),
+                    };
+                    let actual = dir_manifest(&dir, &manifest_path, expected.hash);
+                    let changes = expected.compare(&actual);
+                    for path in changes.added.iter() {
+                        println!("added: {}", path);
+                    }
+                    for path in changes.removed.iter() {
+                        println!("removed: {}", path);
+                    }
+                    for path in changes.modified.iter() {
+                        println!("modified: {}", path);
+                    }
+                    match changes.is_empty() {
+                        true => println!("Correct!"),
+                        false => println!("Directory does not match the manifest!"),
+                    }
+                },
+                _ => print_usage(program.as_slice(), &opts),
+            }
+        },
+        "tree" if free_len == 5 => {
+            match matches.free[1].as_slice() {
+                "proof" => {
+                    let (chunk_size, threads) = tree_params(&matches);
+                    let index = match from_str(matches.free[3].as_slice()) {
+                        Some(i) => i,
+                        None => fail!("invalid chunk index: {}", matches.free[3]),
+                    };
+                    let mut file_in = File::open(&Path::new(matches.free[2].as_slice())).unwrap();
+                    let leaves = match treehash::leaves(&mut file_in, chunk_size, threads) {
+                        Ok(l) => l,
+                        Err(e) => fail!("{}: {}", matches.free[2], e),
+                    };
+                    let proof = match ChunkProof::new(leaves.as_slice(), chunk_size, index) {
+                        Ok(p) => p,
+                        Err(e) => fail!("{}: {}", matches.free[3], e.desc),
+                    };
+                    write_text(matches.free[4].as_slice(), proof.to_string().as_slice());
+                },
+                "verify" => {
+                    let root = match treehash::parse_hash(matches.free[2].as_slice()) {
+                        Ok(r) => r,
+                        Err(e) => fail!("{}: {}", matches.free[2], e.desc),
+                    };
+                    let proof_path = matches.free[3].as_slice();
+                    let proof = match String::from_utf8(read_file(proof_path)).ok() {
+                        Some(c) => match ChunkProof::from_str(c.as_slice()) {
+                            Ok(p) => p,
+                            Err(e) => fail!("{}: {}", proof_path, e.desc),
+                        },
+                        None => fail!("{}: not a proof file", proof_path),
+                    };
+                    let chunk = read_file(matches.free[4].as_slice());
+                    match proof.verify(chunk.as_slice(), &root) {
+                        true => println!("Correct!"),
+                        false => println!("Incorrect!"),
+                    }
+                },
+                _ => print_usage(program.as_slice(), &opts),
+            }
+        },
+        "export" if free_len == 3 => {
+            let key = load_private_key(matches.free[1].as_slice());
+            keyfile::save(matches.free[2].as_slice(), &key, None, is_armor).unwrap();
+        },
         _ => {
             print_usage(program.as_slice(), &opts);
             return;
@@ -200,65 +899,910 @@ fn main() {
 }
 
 
-fn get_or_create_ds_pk(file_in: &File, key_path: &Option<String>) -> String {
+fn read_passphrase(prompt: &str) -> String {
+    fn set_echo(on: bool) {
+        let mode = if on { "echo" } else { "-echo" };
+        let _ = Command::new("stty").arg(mode).stdin(InheritFd(0)).status();
+    }
+
+    print!("{}", prompt);
+    flush();
+    set_echo(false);
+    let line = stdin().read_line();
+    set_echo(true);
+    println!("");
+
+    match line {
+        Ok(l) => l.as_slice().trim_right_chars('\n').trim_right_chars('\r').to_string(),
+        Err(e) => fail!("read_passphrase: {}", e.desc),
+    }
+}
+
+
+fn load_private_key(key_path: &str) -> keyfile::PrivateKey {
+    match keyfile::load(key_path, || read_passphrase("Passphrase: ")) {
+        Ok(k) => k,
+        Err(e) => fail!("{}: {}", key_path, e.desc),
+    }
+}
+
+
+fn load_pbc_key(key_path: &str) -> [u8, ..16] {
+    match keyfile::load_pbc_key(key_path, || read_passphrase("Passphrase: ")) {
+        Ok(k) => k,
+        Err(e) => fail!("{}: {}", key_path, e.desc),
+    }
+}
+
+
+fn load_ds_key(key_path: &str) -> (DsParams, BigUint) {
+    match keyfile::load_ds_key(key_path, || read_passphrase("Passphrase: ")) {
+        Ok(x) => x,
+        Err(e) => fail!("{}: {}", key_path, e.desc),
+    }
+}
+
+
+fn load_rsa_key(key_path: &str) -> RsaPrivateKey {
+    match keyfile::load_rsa_key(key_path, || read_passphrase("Passphrase: ")) {
+        Ok(k) => k,
+        Err(e) => fail!("{}: {}", key_path, e.desc),
+    }
+}
+
+
+fn read_rsa_public_key(key_path: &str) -> RsaPublicKey {
+    let mut file = File::open(&Path::new(key_path)).unwrap();
+    let content = file.read_to_end().unwrap();
+    match RsaPublicKey::from_bytes(content.as_slice()) {
+        Ok(k) => k,
+        Err(e) => fail!("{}: {}", key_path, e.desc),
+    }
+}
+
+
+fn read_blob(path: &str, label: &str) -> Vec<u8> {
+    let mut file = File::open(&Path::new(path)).unwrap();
+    let content = file.read_to_end().unwrap();
+    if !is_armored(content.as_slice()) {
+        return content;
+    }
+    match dearmor(content.as_slice(), label) {
+        Ok(data) => data,
+        Err(e) => fail!("{}: {}", path, e.desc),
+    }
+}
+
+
+fn key_fingerprint(path: &str) -> [u8, ..16] {
+    let content = read_file(path);
+    let is_dsok = Path::new(path).extension_str() == Some("dsok");
+    match keyring::identify(content.as_slice(), is_dsok, || read_passphrase("Passphrase: ")) {
+        Ok((_, fp)) => fp,
+        Err(e) => fail!("{}: {}", path, e.desc),
+    }
+}
+
+
+fn read_cert(path: &str) -> Certificate {
+    match Certificate::from_bytes(read_file(path).as_slice()) {
+        Ok(c) => c,
+        Err(e) => fail!("{}: {}", path, e.desc),
+    }
+}
+
+
+fn read_bundle(path: &Path) -> Bundle {
+    match Bundle::load(path) {
+        Ok(b) => b,
+        Err(e) => fail!("{}: {}", path.display(), e.desc),
+    }
+}
+
+
+// A bundle signer's key: one of the trusted keys, or else looked up as
+// for `ds -v`.
+fn bundle_key(trusted: &[(DsParams, BigUint)], dir: Option<&Path>
+              , id: &KeyId) -> Option<(DsParams, BigUint)> {
+    match trusted.iter().find(|&&(ref params, ref y)| fingerprint::ds_key_id(params, y) == *id) {
+        Some(key) => Some(key.clone()),
+        None => find_ds_key(dir, &Some(*id)),
+    }
+}
+
+
+fn print_bundle_entry(i: uint, entry: &bundle::Entry) {
+    let sig = &entry.sig;
+    let mut line = format!("{}  {}  {}", i + 1, fingerprint::format_key_id(&sig.key_id.unwrap())
+                           , sig.sig.scheme().name());
+    match entry.counter_signs {
+        Some(target) => line.push_str(format!("  counter-signs {}", target + 1).as_slice()),
+        None => {},
+    }
+    match sig.metadata {
+        Some(ref meta) => {
+            line.push_str(format!("  {}", metadata::format_time(meta.created)).as_slice());
+            match meta.comment {
+                Some(ref c) => line.push_str(format!("  {}", c).as_slice()),
+                None => {},
+            }
+        },
+        None => {},
+    }
+    println!("{}", line);
+}
+
+
+// The manifest of a directory, leaving out the manifest file itself.
+fn dir_manifest(dir: &Path, manifest_path: &Path, hash: Construction) -> Manifest {
+    match Manifest::from_dir(dir, Some(manifest_path), hash) {
+        Ok(m) => m,
+        Err(e) => fail!("{}: {}", dir.display(), e),
+    }
+}
+
+
+// "-" is standard input. With tree options, the tree hash.
+fn hash_path(path: &str, construction: Construction
+             , tree: Option<(uint, uint)>) -> IoResult<Vec<u8>> {
+    match (path, tree) {
+        ("-", Some((chunk_size, threads))) => {
+            treehash::tree_hash(&mut stdin(), chunk_size, threads).map(|h| h.to_vec())
+        },
+        ("-", None) => Ok(hash::hash_reader(construction, &mut stdin())),
+        (_, Some((chunk_size, threads))) => {
+            let mut file = try!(File::open(&Path::new(path)));
+            treehash::tree_hash(&mut file, chunk_size, threads).map(|h| h.to_vec())
+        },
+        (_, None) => {
+            let mut file = try!(File::open(&Path::new(path)));
+            Ok(hash::hash_reader(construction, &mut file))
+        },
+    }
+}
+
+
+fn hash_construction(matches: &Matches) -> Construction {
+    match matches.opt_str("H") {
+        Some(name) => match Construction::by_name(name.as_slice()) {
+            Ok(c) => c,
+            Err(e) => fail!("{}: {}", name, e.desc),
+        },
+        None => hash::Original,
+    }
+}
+
+
+// -u for cert issue and cert verify; sign when not given.
+fn cert_usage(matches: &Matches) -> u8 {
+    match matches.opt_str("u") {
+        Some(u) => match cert::parse_usage(u.as_slice()) {
+            Ok(n) if n != 0 => n,
+            _ => fail!("invalid certificate usage: {}", u),
+        },
+        None => cert::SIGN,
+    }
+}
+
+
+// Chunk size and number of threads for tree hashing, if -T is given.
+fn tree_opts(matches: &Matches) -> Option<(uint, uint)> {
+    if matches.opt_present("T") {
+        Some(tree_params(matches))
+    } else {
+        None
+    }
+}
+
+
+fn tree_params(matches: &Matches) -> (uint, uint) {
+    let chunk_size = match matches.opt_str("Z") {
+        Some(z) => match from_str(z.as_slice()) {
+            Some(n) if n > 0 => n,
+            _ => fail!("invalid chunk size: {}", z),
+        },
+        None => treehash::DEFAULT_CHUNK_SIZE,
+    };
+    let threads = match matches.opt_str("j") {
+        Some(j) => match from_str(j.as_slice()) {
+            Some(n) if n > 0 => n,
+            _ => fail!("invalid number of threads: {}", j),
+        },
+        None => os::num_cpus(),
+    };
+    (chunk_size, threads)
+}
+
+
+// Checks every line of a checksum file, printing "<path>: OK" or
+// "<path>: FAILED" for each. True when every file matched.
+fn check_checksums(list_path: &str, construction: Construction
+                   , tree: Option<(uint, uint)>) -> bool {
+    let content = if list_path == "-" {
+        stdin().read_to_end()
+    } else {
+        File::open(&Path::new(list_path)).and_then(|mut f| f.read_to_end())
+    };
+    let content = match content.ok().and_then(|c| String::from_utf8(c).ok()) {
+        Some(c) => c,
+        None => {
+            print_error(format!("{}: can't read the checksum file", list_path).as_slice());
+            return false;
+        },
+    };
+
+    let (mut checked, mut mismatched, mut unreadable, mut malformed) = (0u, 0u, 0u, 0u);
+    for line in content.as_slice().lines().filter(|l| !l.trim().is_empty()) {
+        let (expected, path) = match hash::parse_checksum(line) {
+            Ok(c) => c,
+            Err(_) => {
+                malformed += 1;
+                continue;
+            },
+        };
+        checked += 1;
+        match hash_path(path.as_slice(), construction, tree) {
+            Ok(digest) if digest == expected => println!("{}: OK", path),
+            Ok(_) => {
+                println!("{}: FAILED", path);
+                mismatched += 1;
+            },
+            Err(_) => {
+                println!("{}: FAILED open or read", path);
+                unreadable += 1;
+            },
+        }
+    }
+
+    if malformed > 0 {
+        print_error(format!("{}: {} lines are improperly formatted", list_path, malformed)
+                    .as_slice());
+    }
+    if unreadable > 0 {
+        print_error(format!("{}: {} listed files could not be read", list_path, unreadable)
+                    .as_slice());
+    }
+    if mismatched > 0 {
+        print_error(format!("{}: {} computed checksums did NOT match", list_path, mismatched)
+                    .as_slice());
+    }
+    if checked == 0 {
+        print_error(format!("{}: no properly formatted checksum lines found", list_path)
+                    .as_slice());
+    }
+    checked > 0 && mismatched == 0 && unreadable == 0
+}
+
+
+fn print_error(msg: &str) {
+    let _ = stderr().write_line(msg);
+}
+
+
+fn read_file(path: &str) -> Vec<u8> {
+    let mut file = File::open(&Path::new(path)).unwrap();
+    file.read_to_end().unwrap()
+}
+
+
+// Finds the key that made a signature, in the keyring and then among the
+// keys in a directory given on the command line. A signature's own
+// directory is never searched: whoever supplied the signature may have
+// put a matching key next to it.
+fn find_ds_key(dir: Option<&Path>, key_id: &Option<KeyId>) -> Option<(DsParams, BigUint)> {
+    let id = match *key_id {
+        Some(ref id) => id,
+        None => {
+            println!("Signature has no key ID, give the key file with -k!");
+            return None;
+        },
+    };
+    if keyring::default_dir().is_dir() {
+        let ring = open_keyring();
+        match ring.find_id(id, &[keyring::DsVerification, keyring::DsSigning]) {
+            Some(entry) => return Some(ds_public_key_of(&ring, entry)),
+            None => {},
+        }
+    }
+    let dir = match dir {
+        Some(dir) => dir,
+        None => {
+            println!("No key {} in the keyring, give the key or its directory with -k!"
+                     , fingerprint::format_key_id(id));
+            return None;
+        },
+    };
+    match fingerprint::find_ds_key(dir, id) {
+        Ok(Some(key)) => Some(key),
+        Ok(None) => {
+            println!("No key {} in {}!", fingerprint::format_key_id(id), dir.display());
+            None
+        },
+        Err(e) => fail!("{}: {}", dir.display(), e.desc),
+    }
+}
+
+
+fn open_keyring() -> Keyring {
+    match Keyring::open(&keyring::default_dir()) {
+        Ok(ring) => ring,
+        Err(e) => fail!("{}", e),
+    }
+}
+
+
+fn find_key<'a>(ring: &'a Keyring, query: &str, kinds: &[KeyKind]) -> &'a Entry {
+    match ring.find(query, kinds) {
+        Ok(entry) => entry,
+        Err(e) => fail!("{}: {}", query, e.desc),
+    }
+}
+
+
+fn key_path(ring: &Keyring, entry: &Entry) -> String {
+    match ring.key_path(entry) {
+        Ok(path) => path.as_str().unwrap().to_string(),
+        Err(e) => fail!("{}", e),
+    }
+}
+
+
+// A key file if there is one at that path, otherwise a key of one of
+// the given kinds in the keyring.
+fn resolve_key(query: &str, kinds: &[KeyKind]) -> String {
+    if Path::new(query).exists() {
+        return query.to_string();
+    }
+    let ring = open_keyring();
+    let entry = find_key(&ring, query, kinds);
+    key_path(&ring, entry)
+}
+
+
+fn default_signing_key() -> Option<KeySource> {
+    if !keyring::default_dir().is_dir() {
+        return None;
+    }
+    let ring = open_keyring();
+    match ring.default() {
+        Some(entry) if entry.kind == keyring::DsSigning => Some(entry_source(&ring, entry)),
+        _ => None,
+    }
+}
+
+
+// -k, or the default signing key.
+fn signing_key_source(matches: &Matches) -> KeySource {
+    match matches.opt_str("k") {
+        Some(k) => private_key_source(k.as_slice(), &[keyring::DsSigning]),
+        None => match default_signing_key() {
+            Some(source) => source,
+            None => fail!("no signing key: give one with -k or set a default"),
+        },
+    }
+}
+
+
+// Where a private key is used: in the agent when it is running and holds
+// the keyring key named, otherwise read from its key file.
+enum KeySource {
+    AgentKey(AgentClient, KeyId),
+    KeyFile(String)
+}
+
+
+fn private_key_source(query: &str, kinds: &[KeyKind]) -> KeySource {
+    if Path::new(query).exists() {
+        return KeyFile(query.to_string());
+    }
+    let ring = open_keyring();
+    let entry = find_key(&ring, query, kinds);
+    entry_source(&ring, entry)
+}
+
+
+fn entry_source(ring: &Keyring, entry: &Entry) -> KeySource {
+    match AgentClient::running() {
+        Some(mut client) => {
+            if client.has_key(&entry.id) {
+                return AgentKey(client, entry.id);
+            }
+        },
+        None => {},
+    }
+    KeyFile(key_path(ring, entry))
+}
+
+
+// The key to check a signature with: -k if it is a key, otherwise the
+// key with the signature's key ID in the keyring or the -k directory.
+// None, after saying why, when there is no suitable key.
+fn signature_key(matches: &Matches, sig_file: &SignatureFile) -> Option<(DsParams, BigUint)> {
+    let (params, key) = match matches.opt_str("k") {
+        Some(ref k) if !Path::new(k.as_slice()).is_dir() => read_ds_public_key(k.as_slice()),
+        k => {
+            let dir = k.map(|d| Path::new(d));
+            match find_ds_key(dir.as_ref(), &sig_file.key_id) {
+                Some(key) => key,
+                None => return None,
+            }
+        },
+    };
+    if sig_file.params != params {
+        println!("Signature and key use different parameter sets!");
+        return None;
+    }
+    match sig_file.key_id {
+        Some(ref id) if *id != fingerprint::ds_key_id(&params, &key) => {
+            println!("Signature was made by key {}!", fingerprint::format_key_id(id));
+            return None;
+        },
+        _ => {},
+    }
+    match matches.opt_str("S") {
+        Some(ref name) if name.as_slice() != sig_file.sig.scheme().name() => {
+            println!("Signature uses the {} scheme!", sig_file.sig.scheme().name());
+            return None;
+        },
+        _ => {},
+    }
+    Some((params, key))
+}
+
+
+fn report_signature(sig_file: &SignatureFile, is_valid: bool) {
+    match sig_file.metadata {
+        Some(ref meta) => print_metadata(meta),
+        None => println!("Signature has no signed attributes."),
+    }
+    match is_valid {
+        true => match sig_file.metadata {
+            Some(ref meta) if meta.is_expired(metadata::now()) => println!("Expired!"),
+            _ => println!("Correct!"),
+        },
+        false => println!("Incorrect!"),
+    }
+}
+
+
+// Signs `tbs` with the issuer key, filling in its key ID.
+fn issue_cert(source: KeySource, mut tbs: TbsCertificate) -> Certificate {
+    match source {
+        AgentKey(mut client, id) => {
+            tbs.issuer = id;
+            match client.sign(&id, scheme::Gost, tbs.digest().as_slice()) {
+                Ok((_, _, scheme::GostSignature(sig))) => Certificate { tbs: tbs, sig: sig },
+                Ok(_) => unreachable!(),
+                Err(e) => fail!("{}", e),
+            }
+        },
+        KeyFile(path) => {
+            let (params, x) = load_ds_key(path.as_slice());
+            tbs.issuer = fingerprint::ds_key_id(&params, &DigSig::public_key(&params, &x));
+            Certificate::sign(tbs, &params, &x)
+        },
+    }
+}
+
+
+fn print_cert(c: &Certificate) {
+    println!("Subject: {}", c.tbs.name);
+    println!("Key-Id: {}", fingerprint::format_key_id(&c.tbs.key_id()));
+    println!("Issuer: {}", fingerprint::format_key_id(&c.tbs.issuer));
+    println!("Valid: {} to {}", metadata::format_time(c.tbs.not_before)
+             , metadata::format_time(c.tbs.not_after));
+    println!("Usage: {}", cert::format_usage(c.tbs.usage));
+    println!("Hash: {}", c.tbs.hash.name());
+}
+
+
+// Signed attributes asked for on the command line.
+struct SigAttrs {
+    validity: Option<u64>,
+    comment: Option<String>,
+    hash: Construction
+}
+
+
+fn sig_attrs(matches: &Matches) -> SigAttrs {
+    SigAttrs {
+        validity: match matches.opt_str("e") {
+            Some(e) => match from_str(e.as_slice()) {
+                Some(n) if n > 0 => Some(n),
+                _ => fail!("invalid number of days: {}", e),
+            },
+            None => None,
+        },
+        comment: match matches.opt_str("c") {
+            Some(c) => match metadata::check_comment(c.as_slice()) {
+                Ok(_) => Some(c),
+                Err(e) => fail!("{}", e.desc),
+            },
+            None => None,
+        },
+        hash: hash_construction(matches),
+    }
+}
+
+
+fn scheme_opt(matches: &Matches) -> Scheme {
+    match matches.opt_str("S") {
+        Some(name) => match Scheme::by_name(name.as_slice()) {
+            Ok(s) => s,
+            Err(e) => fail!("{}: {}", name, e.desc),
+        },
+        None => scheme::Gost,
+    }
+}
+
+
+// Signs with signed metadata when attributes are given, otherwise the
+// bare magenta digest, as there is no metadata to name another hash.
+fn sign_with<R: Reader>(source: KeySource, scheme: Scheme, reader: &mut R
+                        , attrs: Option<&SigAttrs>) -> (BigUint, SignatureFile) {
+    let digest = hash::hash_reader(attrs.map_or(hash::Original, |a| a.hash), reader);
+    match source {
+        AgentKey(mut client, id) => {
+            let params = match client.params_of(&id) {
+                Ok(p) => p,
+                Err(e) => fail!("{}", e),
+            };
+            let meta = attrs.map(|a| new_metadata(&id, &params, a));
+            match client.sign(&id, scheme, signed_digest(&meta, digest).as_slice()) {
+                Ok((params, y, sig)) => (y, SignatureFile::new(params, sig, id, meta)),
+                Err(e) => fail!("{}", e),
+            }
+        },
+        KeyFile(path) => {
+            let (params, x) = load_ds_key(path.as_slice());
+            let y = DigSig::public_key(&params, &x);
+            let id = fingerprint::ds_key_id(&params, &y);
+            let meta = attrs.map(|a| new_metadata(&id, &params, a));
+            let digest = signed_digest(&meta, digest);
+            let (y, sig) = Signature::sign_prehashed(scheme, &params, digest.as_slice(), &x);
+            (y, SignatureFile::new(params, sig, id, meta))
+        },
+    }
+}
+
+
+fn new_metadata(id: &KeyId, params: &DsParams, attrs: &SigAttrs) -> Metadata {
+    let mut meta = Metadata::new(id, params, attrs.hash);
+    let created = meta.created;
+    meta.expires = attrs.validity.map(|days| created + days * 24 * 60 * 60);
+    meta.comment = attrs.comment.clone();
+    meta
+}
+
+
+fn signed_digest(meta: &Option<Metadata>, digest: Vec<u8>) -> Vec<u8> {
+    match *meta {
+        Some(ref meta) => meta.signed_digest(digest.as_slice()),
+        None => digest,
+    }
+}
+
+
+fn print_metadata(meta: &Metadata) {
+    println!("Signed by: {}", fingerprint::format_key_id(&meta.key_id));
+    println!("Created: {}", metadata::format_time(meta.created));
+    match meta.expires {
+        Some(t) => println!("Expires: {}", metadata::format_time(t)),
+        None => println!("Expires: never"),
+    }
+    println!("Hash: {}", meta.hash.name());
+    match meta.comment {
+        Some(ref c) => println!("Comment: {}", c),
+        None => {},
+    }
+}
+
+
+fn read_ds_public_key(query: &str) -> (DsParams, BigUint) {
+    if Path::new(query).exists() {
+        return DigSig::key_from_file(query);
+    }
+    let ring = open_keyring();
+    let entry = find_key(&ring, query, &[keyring::DsVerification, keyring::DsSigning]);
+    ds_public_key_of(&ring, entry)
+}
+
+
+// A signing key in the keyring stands for its own public key.
+fn ds_public_key_of(ring: &Keyring, entry: &Entry) -> (DsParams, BigUint) {
+    let path = key_path(ring, entry);
+    match entry.kind {
+        keyring::DsSigning => {
+            let (params, x) = load_ds_key(path.as_slice());
+            let y = DigSig::public_key(&params, &x);
+            (params, y)
+        },
+        _ => DigSig::key_from_file(path.as_slice()),
+    }
+}
+
+
+// Private keys are written like `export` does; public keys as text or
+// DER, or armored with -a.
+fn export_key(ring: &Keyring, entry: &Entry, out_path: &str, is_armor: bool) {
+    let path = key_path(ring, entry);
+    match entry.kind {
+        keyring::DsVerification => {
+            let (params, y) = DigSig::key_from_file(path.as_slice());
+            if is_armor {
+                write_text(out_path, DigSig::public_key_to_armor(&params, &y).as_slice());
+            } else {
+                DigSig::key_to_file(out_path, &params, &y);
+            }
+        },
+        keyring::RsaPublic => {
+            let key = read_rsa_public_key(path.as_slice());
+            if is_armor {
+                write_text(out_path, key.to_armor().as_slice());
+            } else {
+                File::create(&Path::new(out_path)).unwrap()
+                    .write(key.to_der().as_slice()).unwrap();
+            }
+        },
+        _ => {
+            let key = load_private_key(path.as_slice());
+            keyfile::save(out_path, &key, None, is_armor).unwrap();
+        },
+    }
+}
+
+
+fn get_or_create_ds_pk(file_in: &File, key_path: &Option<String>, params: &DsParams
+                       , is_armor: bool) -> String {
     match *key_path {
         Some(ref k) => k.clone(),
         None => {
             let mut private_key_path
                 = file_in.path().as_str().unwrap().into_string();
             private_key_path.push_str(".dspk");
-            DigSig::rnd_key(private_key_path.as_slice());
+            gen_ds_key(private_key_path.as_slice(), params, is_armor);
             private_key_path
         },
     }    
 }
 
-fn get_or_create_pbc_pk(file_in: &File, key_path: &Option<String>) -> String {
+fn get_or_create_pbc_pk(file_in: &File, key_path: &Option<String>, is_armor: bool) -> String {
     match *key_path {
         Some(ref k) => k.clone(),
         None => {
             let mut private_key_path =
                 file_in.path().as_str().unwrap().into_string();
             private_key_path.push_str(".pk");
-            PBC::rnd_key(private_key_path.as_slice());
+            PBC::key_to_file(private_key_path.as_slice(), &PBC::gen_key(), is_armor);
             private_key_path
         },
     }
 }
 
 
-fn do_ds(file_in: &mut File, key_path: &Option<String>) -> (BigUint, DigSig) {
-    let key_file = get_or_create_ds_pk(file_in, key_path);
-    let key = DigSig::key_from_file(key_file.as_slice());
-    DigSig::of_file(file_in, &key)
+fn gen_ds_key(key_path: &str, params: &DsParams, is_armor: bool) {
+    if is_armor {
+        let key = DigSig::gen_key(params);
+        write_text(key_path, DigSig::private_key_to_armor(params, &key).as_slice());
+    } else {
+        DigSig::rnd_key(key_path, params);
+    }
+}
+
+
+fn write_text(path: &str, text: &str) {
+    let mut file = File::create(&Path::new(path)).unwrap();
+    file.write_str(text).unwrap();
+}
+
+
+fn do_ds(file_in: &mut File, source: Option<KeySource>, new_params: &DsParams
+         , is_armor: bool) -> (DsParams, BigUint, DigSig) {
+    let source = match source {
+        Some(source) => source,
+        None => KeyFile(get_or_create_ds_pk(file_in, &None, new_params, is_armor)),
+    };
+    match sign_with(source, scheme::Gost, file_in, None) {
+        (y, SignatureFile { params, sig: scheme::GostSignature(ds), .. }) => (params, y, ds),
+        _ => unreachable!(),
+    }
 }
 
 
 fn do_ds_verifying(file_in: &mut File, key_path: &String, sig: (&BigUint, &BigUint)) -> bool {
-    let key = DigSig::key_from_file(key_path.as_slice());
+    let (params, key) = read_ds_public_key(key_path.as_slice());
     let (r, s) = sig;
     let ds = DigSig::new(r.clone(), s.clone());
-    ds.verify_file(file_in, key)
+    ds.verify_reader(&params, file_in, &key)
 }
 
 
-fn do_pbc(file_in: &mut File, file_out: &mut File, is_enc: bool, key_path: &Option<String>) {
-    let key_file = get_or_create_pbc_pk(file_in, key_path);
-    let box key = PBC::key_from_file(key_file.as_slice());
+fn do_pbc(file_in: &mut File, file_out: &mut File, is_enc: bool, key_path: &Option<String>
+          , is_armor: bool) {
+    let key_file = get_or_create_pbc_pk(file_in, key_path, is_armor);
+    let key = load_pbc_key(key_file.as_slice());
 
     let pbc = PBC::new(&key, &[0u8, ..16]);
     if is_enc {
-        pbc.enc_file(file_in, file_out);
+        pbc_enc(&pbc, file_in, file_out, is_armor);
     } else {
-        pbc.dec_file(file_in, file_out);
+        pbc_dec(&pbc, file_in, file_out);
+    }
+}
+
+
+fn do_pbc_dec(file_in: &mut File, file_out: &mut File, source: KeySource) {
+    match source {
+        AgentKey(mut client, id) => {
+            let res = match read_armored_message(file_in) {
+                Some(ct) => {
+                    let mut reader = BufReader::new(ct.as_slice());
+                    agent_pbc_dec(&mut client, &id, &mut reader, file_out)
+                },
+                None => agent_pbc_dec(&mut client, &id, file_in, file_out),
+            };
+            match res {
+                Err(e) => fail!("pbc: {}", e),
+                Ok(_) => {},
+            }
+        },
+        KeyFile(path) => {
+            let key = load_pbc_key(path.as_slice());
+            pbc_dec(&PBC::new(&key, &[0u8, ..16]), file_in, file_out);
+        },
     }
 }
 
 
-fn save_ds_ok(y: &BigUint, y_path_str: &String) {
+// The ciphertext goes to the agent a chunk at a time, so there is no
+// limit on the size of the file.
+fn agent_pbc_dec<R: Reader, W: Writer>(client: &mut AgentClient, id: &KeyId
+                                       , reader: &mut R, writer: &mut W) -> IoResult<u64> {
+    pbc::dec_chunks(reader, writer, &[0u8, ..16], |chain, ct| client.decrypt(id, chain, ct))
+}
+
+
+fn pbc_enc(pbc: &PBC, file_in: &mut File, file_out: &mut File, is_armor: bool) {
+    if is_armor {
+        let data = file_in.read_to_end().unwrap();
+        let ct = pbc.enc_bytes(data.as_slice());
+        file_out.write_str(armor(MESSAGE, ct.as_slice()).as_slice()).unwrap();
+    } else {
+        pbc.enc_file(file_in, file_out).unwrap();
+    }
+}
+
+
+fn pbc_dec(pbc: &PBC, file_in: &mut File, file_out: &mut File) {
+    match read_armored_message(file_in) {
+        Some(ct) => {
+            let pt = pbc.dec_bytes(ct.as_slice()).unwrap();
+            file_out.write(pt.as_slice()).unwrap();
+        },
+        None => pbc.dec_file(file_in, file_out),
+    }
+}
+
+
+fn do_hybrid_enc(file_in: &mut File, file_out: &mut File, recipient_paths: &Vec<String>
+                 , is_armor: bool) {
+    let recipients: Vec<(DsParams, BigUint)> = recipient_paths.iter()
+        .map(|p| read_ds_public_key(p.as_slice()))
+        .collect();
+
+    let res = if is_armor {
+        let data = file_in.read_to_end().unwrap();
+        hybrid::enc_bytes(recipients.as_slice(), data.as_slice())
+            .and_then(|ct| file_out.write_str(armor(MESSAGE, ct.as_slice()).as_slice()))
+    } else {
+        hybrid::enc_stream(recipients.as_slice(), file_in, file_out).map(|_| ())
+    };
+    match res {
+        Err(e) => fail!("encrypt: {}", e.desc),
+        Ok(_) => {},
+    }
+}
+
+
+fn do_hybrid_dec(file_in: &mut File, file_out: &mut File, source: KeySource) {
+    let (mut client, id) = match source {
+        AgentKey(client, id) => (client, id),
+        KeyFile(path) => return do_hybrid_dec_file(file_in, file_out, path.as_slice()),
+    };
+
+    let res = match read_armored_message(file_in) {
+        Some(ct) => {
+            agent_hybrid_dec(&mut client, &id, &mut BufReader::new(ct.as_slice()), file_out)
+        },
+        None => agent_hybrid_dec(&mut client, &id, file_in, file_out),
+    };
+    match res {
+        Err(e) => fail!("decrypt: {}", e),
+        Ok(_) => {},
+    }
+}
+
+
+// Only the slots with the key's ID go to the agent. The content is
+// checked and decrypted here with the content key it returns.
+fn agent_hybrid_dec<R: Reader, W: Writer>(client: &mut AgentClient, id: &KeyId
+                                          , reader: &mut R, writer: &mut W) -> IoResult<()> {
+    let slots = try!(hybrid::read_header(reader));
+    let mut cek = Err(invalid_input("decrypt: not encrypted to this key"));
+    for slot in slots.iter().filter(|s| s.key_id == Some(*id)) {
+        cek = client.unwrap(id, slot);
+        if cek.is_ok() {
+            break;
+        }
+    }
+    hybrid::dec_content(&try!(cek), slots.as_slice(), reader, writer).map(|_| ())
+}
+
+
+fn do_hybrid_dec_file(file_in: &mut File, file_out: &mut File, key_path: &str) {
+    let (params, x) = load_ds_key(key_path);
+
+    let res = match read_armored_message(file_in) {
+        Some(ct) => hybrid::dec_bytes(&params, &x, ct.as_slice())
+            .and_then(|pt| file_out.write(pt.as_slice())),
+        None => hybrid::dec_stream(&params, &x, file_in, file_out).map(|_| ()),
+    };
+    match res {
+        Err(e) => fail!("decrypt: {}", e.desc),
+        Ok(_) => {},
+    }
+}
+
+
+fn pbe_enc(password: &str, iterations: uint, file_in: &mut File, file_out: &mut File
+           , is_armor: bool) {
+    if is_armor {
+        let data = file_in.read_to_end().unwrap();
+        let ct = pbe::enc_bytes(password, iterations, data.as_slice());
+        file_out.write_str(armor(MESSAGE, ct.as_slice()).as_slice()).unwrap();
+    } else {
+        pbe::enc_stream(password, iterations, file_in, file_out).unwrap();
+    }
+}
+
+
+// The output file is only created once the password and the MAC check out.
+fn pbe_dec(password: &str, file_in: &mut File, path_out: &Path) {
+    let ct = match read_armored_message(file_in) {
+        Some(ct) => Ok(ct),
+        None => file_in.read_to_end(),
+    };
+    let res = ct.and_then(|ct| pbe::dec_bytes(password, ct.as_slice()))
+        .and_then(|pt| File::create(path_out).and_then(|mut f| f.write(pt.as_slice())));
+    match res {
+        Err(e) => fail!("pbc: {}", e.desc),
+        Ok(_) => {},
+    }
+}
+
+
+fn read_armored_message(file_in: &mut File) -> Option<Vec<u8>> {
+    let mut head = [0u8, ..64];
+    let head_len = read_block(file_in, &mut head).unwrap();
+    file_in.seek(0, SeekSet).unwrap();
+
+    if is_armored(head.slice_to(head_len)) {
+        let content = file_in.read_to_end().unwrap();
+        Some(dearmor(content.as_slice(), MESSAGE).unwrap())
+    } else {
+        None
+    }
+}
+
+
+fn save_ds_ok(params: &DsParams, y: &BigUint, y_path_str: &String, is_armor: bool) {
     let mut y_path = y_path_str.clone();
     y_path.push_str(".dsok");
-    DigSig::key_to_file(y_path.as_slice(), y);
+    if is_armor {
+        write_text(y_path.as_slice(), DigSig::public_key_to_armor(params, y).as_slice());
+    } else {
+        DigSig::key_to_file(y_path.as_slice(), params, y);
+    }
 }
 
 